@@ -14,6 +14,7 @@
  * this program. If not, see https://www.gnu.org/licenses/.
  *
  */
+use crate::history::{self, Event, EventKind};
 use vsmtp_common::{transport::DeserializerFn, ContextFinished};
 use vsmtp_config::Config;
 use vsmtp_mail_parser::MessageBody;
@@ -21,8 +22,19 @@ extern crate alloc;
 
 /// identifiers for all mail queues.
 #[allow(clippy::exhaustive_enums)]
-#[derive(Debug, PartialEq, Eq, Clone, strum::IntoStaticStr, strum::EnumString, strum::EnumIter)]
+#[derive(
+    Debug,
+    PartialEq,
+    Eq,
+    Clone,
+    strum::IntoStaticStr,
+    strum::EnumString,
+    strum::EnumIter,
+    serde::Serialize,
+    serde::Deserialize,
+)]
 #[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
 pub enum QueueID {
     /// Postq.
     Working,
@@ -158,6 +170,9 @@ where
     /// Get the list of message IDs in the queue.
     async fn list(&self, queue: &QueueID) -> anyhow::Result<Vec<anyhow::Result<String>>>;
 
+    /// Get the names of the existing quarantines.
+    async fn list_quarantines(&self) -> anyhow::Result<Vec<String>>;
+
     ///
     async fn get_ctx(
         &self,
@@ -175,6 +190,9 @@ where
     ///
     async fn get_msg(&self, msg_uuid: &uuid::Uuid) -> anyhow::Result<MessageBody>;
 
+    /// Size in bytes of the message as stored, without parsing it.
+    async fn get_msg_size(&self, msg_uuid: &uuid::Uuid) -> anyhow::Result<usize>;
+
     ///
     #[inline]
     async fn get_both(
@@ -191,6 +209,18 @@ where
         ))
     }
 
+    /// Append the events to the history of the message.
+    async fn append_history(&self, msg_uuid: &uuid::Uuid, events: &[Event]) -> anyhow::Result<()>;
+
+    /// Get the history of the message, the oldest event first.
+    async fn get_history(&self, msg_uuid: &uuid::Uuid) -> anyhow::Result<Vec<Event>>;
+
+    /// Get the IDs of the messages with a history, and the date of their last event.
+    async fn list_history(&self) -> anyhow::Result<Vec<(uuid::Uuid, time::OffsetDateTime)>>;
+
+    /// Remove the history of the message.
+    async fn remove_history(&self, msg_uuid: &uuid::Uuid) -> anyhow::Result<()>;
+
     ///
     #[inline]
     async fn move_to_from_id(
@@ -228,6 +258,16 @@ where
         self.write_ctx(after, ctx).await?;
         self.remove_ctx(before, &ctx.mail_from.message_uuid).await?;
 
+        history::record(
+            self,
+            &ctx.mail_from.message_uuid,
+            &[Event::now(EventKind::Moved {
+                from: before.to_string(),
+                to: after.to_string(),
+            })],
+        )
+        .await;
+
         Ok(())
     }
 }
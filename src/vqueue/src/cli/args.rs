@@ -14,7 +14,11 @@
  * this program. If not, see https://www.gnu.org/licenses/.
  *
  */
-use crate::QueueID;
+use crate::{
+    control::{FlushQueue, ReRunStage, ReleaseAction},
+    QueueID,
+};
+use vsmtp_config::field::QueueBackend;
 
 ///
 #[non_exhaustive]
@@ -45,6 +49,7 @@ impl Args {
 
 ///
 #[non_exhaustive]
+#[allow(clippy::large_enum_variant)]
 #[derive(clap::Subcommand)]
 #[cfg_attr(test, derive(Debug, PartialEq, Eq))]
 pub enum Commands {
@@ -66,6 +71,50 @@ pub enum Commands {
         #[clap(subcommand)]
         command: MessageCommand,
     },
+    /// Select the messages matching the filters, and operate on all of them
+    Select {
+        ///
+        #[clap(flatten)]
+        filter: Filter,
+        /// Format of the output
+        #[clap(short, long, value_enum, value_parser, default_value = "table")]
+        format: SelectFormat,
+        /// Print the messages the action would be applied to, without applying it
+        #[clap(long, action)]
+        dry_run: bool,
+        ///
+        #[clap(subcommand)]
+        command: SelectCommand,
+    },
+    /// Ask the running server to process the messages of a queue now
+    Flush {
+        /// Queue to flush
+        #[clap(value_enum, value_parser)]
+        queue: FlushQueue,
+    },
+    /// Ask the running server to read its configuration and rules again
+    Reload,
+    /// List the SMTP sessions in progress in the running server
+    Sessions,
+    /// Print the counters of the running server
+    Counters,
+    /// List the MTA-STS policies cached by the running server
+    MtaSts,
+    /// Remove the messages of the `dead` queue and of the quarantines expired by their retention policy
+    Purge {
+        /// Print the messages that would be removed, without removing them
+        #[clap(long, action)]
+        dry_run: bool,
+    },
+    /// Move all the messages from a queues backend to another, the server must be stopped
+    Migrate {
+        /// Backend the messages are read from
+        #[clap(long, value_parser)]
+        from: QueueBackend,
+        /// Backend the messages are written to
+        #[clap(long, value_parser)]
+        to: QueueBackend,
+    },
 }
 
 fn parse_uuid(value: &str) -> Result<uuid::Uuid, clap::Error> {
@@ -73,6 +122,11 @@ fn parse_uuid(value: &str) -> Result<uuid::Uuid, clap::Error> {
         .map_err(|_err| clap::Error::new(clap::error::ErrorKind::ValueValidation))
 }
 
+fn parse_address(value: &str) -> Result<vsmtp_common::Address, clap::Error> {
+    <vsmtp_common::Address as core::str::FromStr>::from_str(value)
+        .map_err(|_err| clap::Error::new(clap::error::ErrorKind::ValueValidation))
+}
+
 ///
 #[non_exhaustive]
 #[derive(Clone, clap::Subcommand)]
@@ -84,6 +138,12 @@ pub enum MessageCommand {
         #[clap(value_enum, value_parser, default_value = "json")]
         format: MessageShowFormat,
     },
+    /// Print the events of the message, from its reception to its final outcome
+    History {
+        /// Format of the output
+        #[clap(short, long, value_enum, value_parser, default_value = "text")]
+        format: HistoryFormat,
+    },
     /// Move the message to the given queue
     Move {
         ///
@@ -96,8 +156,156 @@ pub enum MessageCommand {
         #[clap(short, long, value_parser)]
         yes: bool,
     },
-    /// Re-introduce the message in the delivery system
-    ReRun {},
+    /// Re-introduce the message in the delivery system of the running server
+    ReRun {
+        /// Process the message is sent to
+        #[clap(value_enum, value_parser, default_value = "working")]
+        stage: ReRunStage,
+    },
+    /// Release the message from its quarantine, in the running server
+    Release {
+        /// Name of the quarantine, all of them are searched if not set
+        #[clap(short, long, value_parser)]
+        quarantine: Option<String>,
+        /// Why the message is released
+        #[clap(short, long, value_parser)]
+        reason: String,
+        /// Who releases the message (default: the current user)
+        #[clap(long, value_parser)]
+        operator: Option<String>,
+        ///
+        #[clap(subcommand)]
+        action: ReleaseCommand,
+    },
+}
+
+/// What is done with the released message
+#[non_exhaustive]
+#[derive(Clone, clap::Subcommand)]
+#[cfg_attr(test, derive(Debug, PartialEq, Eq))]
+pub enum ReleaseCommand {
+    /// Send the message to a process again
+    Resume {
+        /// Process the message is sent to
+        #[clap(value_enum, value_parser, default_value = "delivery")]
+        stage: ReRunStage,
+        /// Run the rules of the stages the message goes through, instead of skipping them
+        #[clap(long, action)]
+        rerun: bool,
+        /// Release the message only to this recipient, the others stay in quarantine
+        #[clap(long = "recipient", value_parser = parse_address)]
+        recipients: Vec<vsmtp_common::Address>,
+    },
+    /// Fail all the recipients, and send a bounce to the sender
+    Reject,
+    /// Send a copy of the message to an analyst, the message stays in quarantine
+    Forward {
+        /// Address of the analyst
+        #[clap(value_parser = parse_address)]
+        to: vsmtp_common::Address,
+    },
+}
+
+impl From<ReleaseCommand> for ReleaseAction {
+    #[inline]
+    fn from(value: ReleaseCommand) -> Self {
+        match value {
+            ReleaseCommand::Resume {
+                stage,
+                rerun,
+                recipients,
+            } => Self::Resume {
+                stage,
+                rerun,
+                recipients,
+            },
+            ReleaseCommand::Reject => Self::Reject,
+            ReleaseCommand::Forward { to } => Self::Forward { to },
+        }
+    }
+}
+
+/// Criteria of the messages selected, all of them must match.
+#[non_exhaustive]
+#[derive(Clone, Default, clap::Args)]
+#[cfg_attr(test, derive(Debug, PartialEq, Eq))]
+pub struct Filter {
+    /// Queues to search, `quarantine` for all the quarantines (default: all queues)
+    #[clap(short, long, value_parser)]
+    pub queue: Vec<QueueID>,
+    /// Name of a quarantine to search
+    #[clap(long, value_parser)]
+    pub quarantine: Vec<String>,
+    /// Address of the sender, `<>` for the null sender
+    #[clap(long, value_parser)]
+    pub sender: Option<String>,
+    /// Address of one of the recipients
+    #[clap(long, value_parser)]
+    pub recipient: Option<String>,
+    /// Domain of one of the recipients
+    #[clap(long, value_parser)]
+    pub recipient_domain: Option<String>,
+    /// Received more than this duration ago (ex: "30m", "2days")
+    #[clap(long, value_parser = humantime::parse_duration)]
+    pub older_than: Option<core::time::Duration>,
+    /// Received less than this duration ago (ex: "30m", "2days")
+    #[clap(long, value_parser = humantime::parse_duration)]
+    pub newer_than: Option<core::time::Duration>,
+    /// Minimum size of the message, in bytes
+    #[clap(long, value_parser)]
+    pub min_size: Option<usize>,
+    /// Maximum size of the message, in bytes
+    #[clap(long, value_parser)]
+    pub max_size: Option<usize>,
+    /// Text contained in the last delivery error (case insensitive)
+    #[clap(long, value_parser)]
+    pub error: Option<String>,
+}
+
+/// Action applied to the selected messages
+#[non_exhaustive]
+#[derive(Clone, clap::Subcommand)]
+#[cfg_attr(test, derive(Debug, PartialEq, Eq))]
+pub enum SelectCommand {
+    /// Print the messages
+    Show,
+    /// Move the messages to the given queue
+    Move {
+        ///
+        #[clap(value_parser)]
+        queue: QueueID,
+    },
+    /// Remove the messages from the filesystem
+    Remove {
+        /// If true, do not ask to confirm the deletion
+        #[clap(short, long, value_parser)]
+        yes: bool,
+    },
+    /// Re-introduce the messages in the delivery system of the running server
+    Requeue {
+        /// Process the messages are sent to
+        #[clap(value_enum, value_parser, default_value = "working")]
+        stage: ReRunStage,
+    },
+    /// Copy the context and the body of the messages to a directory
+    Export {
+        /// Directory to write the files to, created if missing
+        #[clap(value_parser)]
+        directory: std::path::PathBuf,
+    },
+}
+
+/// Format of the selected messages
+#[non_exhaustive]
+#[derive(Clone, Copy, clap::ValueEnum)]
+#[cfg_attr(test, derive(Debug, PartialEq, Eq))]
+pub enum SelectFormat {
+    /// Aligned columns
+    Table,
+    /// Array of objects
+    Json,
+    /// Comma separated values, with a header line
+    Csv,
 }
 
 ///
@@ -111,6 +319,17 @@ pub enum MessageShowFormat {
     Json,
 }
 
+/// Format of the history of a message
+#[non_exhaustive]
+#[derive(Clone, Copy, clap::ValueEnum)]
+#[cfg_attr(test, derive(Debug, PartialEq, Eq))]
+pub enum HistoryFormat {
+    /// One event per line
+    Text,
+    /// Array of events
+    Json,
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -254,6 +473,51 @@ mod tests {
         );
     }
 
+    #[test]
+    fn arg_history_message() {
+        assert_eq!(
+            Args {
+                version: false,
+                config: Args::default_config_location(),
+                command: Some(Commands::Msg {
+                    msg: uuid::Uuid::nil(),
+                    command: MessageCommand::History {
+                        format: HistoryFormat::Text
+                    }
+                })
+            },
+            <Args as clap::Parser>::try_parse_from([
+                "",
+                "msg",
+                "00000000-0000-0000-0000-000000000000",
+                "history"
+            ])
+            .unwrap()
+        );
+
+        assert_eq!(
+            Args {
+                version: false,
+                config: Args::default_config_location(),
+                command: Some(Commands::Msg {
+                    msg: uuid::Uuid::nil(),
+                    command: MessageCommand::History {
+                        format: HistoryFormat::Json
+                    }
+                })
+            },
+            <Args as clap::Parser>::try_parse_from([
+                "",
+                "msg",
+                "00000000-0000-0000-0000-000000000000",
+                "history",
+                "--format",
+                "json"
+            ])
+            .unwrap()
+        );
+    }
+
     #[test]
     fn arg_move_message() {
         assert_eq!(
@@ -317,4 +581,312 @@ mod tests {
             .unwrap()
         );
     }
+
+    #[test]
+    fn arg_rerun_message() {
+        assert_eq!(
+            Args {
+                version: false,
+                config: Args::default_config_location(),
+                command: Some(Commands::Msg {
+                    msg: uuid::Uuid::nil(),
+                    command: MessageCommand::ReRun {
+                        stage: ReRunStage::Working
+                    }
+                })
+            },
+            <Args as clap::Parser>::try_parse_from([
+                "",
+                "msg",
+                "00000000-0000-0000-0000-000000000000",
+                "re-run"
+            ])
+            .unwrap()
+        );
+
+        assert_eq!(
+            Args {
+                version: false,
+                config: Args::default_config_location(),
+                command: Some(Commands::Msg {
+                    msg: uuid::Uuid::nil(),
+                    command: MessageCommand::ReRun {
+                        stage: ReRunStage::Delivery
+                    }
+                })
+            },
+            <Args as clap::Parser>::try_parse_from([
+                "",
+                "msg",
+                "00000000-0000-0000-0000-000000000000",
+                "re-run",
+                "delivery"
+            ])
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn arg_release_message() {
+        assert_eq!(
+            Args {
+                version: false,
+                config: Args::default_config_location(),
+                command: Some(Commands::Msg {
+                    msg: uuid::Uuid::nil(),
+                    command: MessageCommand::Release {
+                        quarantine: Some("spam".to_owned()),
+                        reason: "false positive".to_owned(),
+                        operator: None,
+                        action: ReleaseCommand::Resume {
+                            stage: ReRunStage::Working,
+                            rerun: true,
+                            recipients: vec![
+                                "a@example.com".parse().unwrap(),
+                                "b@example.com".parse().unwrap()
+                            ],
+                        }
+                    }
+                })
+            },
+            <Args as clap::Parser>::try_parse_from([
+                "",
+                "msg",
+                "00000000-0000-0000-0000-000000000000",
+                "release",
+                "--quarantine",
+                "spam",
+                "--reason",
+                "false positive",
+                "resume",
+                "working",
+                "--rerun",
+                "--recipient",
+                "a@example.com",
+                "--recipient",
+                "b@example.com"
+            ])
+            .unwrap()
+        );
+
+        assert_eq!(
+            Args {
+                version: false,
+                config: Args::default_config_location(),
+                command: Some(Commands::Msg {
+                    msg: uuid::Uuid::nil(),
+                    command: MessageCommand::Release {
+                        quarantine: None,
+                        reason: "malware".to_owned(),
+                        operator: Some("jdoe".to_owned()),
+                        action: ReleaseCommand::Forward {
+                            to: "analyst@example.com".parse().unwrap()
+                        }
+                    }
+                })
+            },
+            <Args as clap::Parser>::try_parse_from([
+                "",
+                "msg",
+                "00000000-0000-0000-0000-000000000000",
+                "release",
+                "-r",
+                "malware",
+                "--operator",
+                "jdoe",
+                "forward",
+                "analyst@example.com"
+            ])
+            .unwrap()
+        );
+
+        assert!(<Args as clap::Parser>::try_parse_from([
+            "",
+            "msg",
+            "00000000-0000-0000-0000-000000000000",
+            "release",
+            "reject"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn arg_select() {
+        assert_eq!(
+            Args {
+                version: false,
+                config: Args::default_config_location(),
+                command: Some(Commands::Select {
+                    filter: Filter::default(),
+                    format: SelectFormat::Table,
+                    dry_run: false,
+                    command: SelectCommand::Show,
+                })
+            },
+            <Args as clap::Parser>::try_parse_from(["", "select", "show"]).unwrap()
+        );
+
+        assert_eq!(
+            Args {
+                version: false,
+                config: Args::default_config_location(),
+                command: Some(Commands::Select {
+                    filter: Filter {
+                        queue: vec![QueueID::Deferred, QueueID::Dead],
+                        quarantine: vec!["spam".to_owned()],
+                        sender: Some("john@doe.com".to_owned()),
+                        recipient: None,
+                        recipient_domain: Some("example.com".to_owned()),
+                        older_than: Some(std::time::Duration::from_secs(2 * 60 * 60)),
+                        newer_than: None,
+                        min_size: None,
+                        max_size: Some(1024),
+                        error: Some("timeout".to_owned()),
+                    },
+                    format: SelectFormat::Csv,
+                    dry_run: true,
+                    command: SelectCommand::Move {
+                        queue: QueueID::Deliver
+                    },
+                })
+            },
+            <Args as clap::Parser>::try_parse_from([
+                "",
+                "select",
+                "-q",
+                "deferred",
+                "--queue",
+                "dead",
+                "--quarantine",
+                "spam",
+                "--sender",
+                "john@doe.com",
+                "--recipient-domain",
+                "example.com",
+                "--older-than",
+                "2h",
+                "--max-size",
+                "1024",
+                "--error",
+                "timeout",
+                "--format",
+                "csv",
+                "--dry-run",
+                "move",
+                "deliver"
+            ])
+            .unwrap()
+        );
+
+        assert_eq!(
+            Args {
+                version: false,
+                config: Args::default_config_location(),
+                command: Some(Commands::Select {
+                    filter: Filter::default(),
+                    format: SelectFormat::Json,
+                    dry_run: false,
+                    command: SelectCommand::Requeue {
+                        stage: ReRunStage::Delivery
+                    },
+                })
+            },
+            <Args as clap::Parser>::try_parse_from([
+                "", "select", "-f", "json", "requeue", "delivery"
+            ])
+            .unwrap()
+        );
+
+        assert_eq!(
+            <Args as clap::Parser>::try_parse_from(["", "select", "--older-than", "soon", "show"])
+                .unwrap_err()
+                .kind(),
+            clap::error::ErrorKind::ValueValidation,
+        );
+    }
+
+    #[test]
+    fn arg_control() {
+        for (command, args) in [
+            (
+                Commands::Flush {
+                    queue: FlushQueue::Deferred,
+                },
+                vec!["", "flush", "deferred"],
+            ),
+            (Commands::Reload, vec!["", "reload"]),
+            (Commands::Sessions, vec!["", "sessions"]),
+            (Commands::Counters, vec!["", "counters"]),
+            (Commands::MtaSts, vec!["", "mta-sts"]),
+        ] {
+            assert_eq!(
+                Args {
+                    version: false,
+                    config: Args::default_config_location(),
+                    command: Some(command)
+                },
+                <Args as clap::Parser>::try_parse_from(args).unwrap()
+            );
+        }
+
+        assert_eq!(
+            <Args as clap::Parser>::try_parse_from(["", "flush", "dead"])
+                .unwrap_err()
+                .kind(),
+            clap::error::ErrorKind::InvalidValue,
+        );
+    }
+
+    #[test]
+    fn arg_purge() {
+        assert_eq!(
+            Args {
+                version: false,
+                config: Args::default_config_location(),
+                command: Some(Commands::Purge { dry_run: false })
+            },
+            <Args as clap::Parser>::try_parse_from(["", "purge"]).unwrap()
+        );
+
+        assert_eq!(
+            Args {
+                version: false,
+                config: Args::default_config_location(),
+                command: Some(Commands::Purge { dry_run: true })
+            },
+            <Args as clap::Parser>::try_parse_from(["", "purge", "--dry-run"]).unwrap()
+        );
+    }
+
+    #[test]
+    fn arg_migrate() {
+        assert_eq!(
+            Args {
+                version: false,
+                config: Args::default_config_location(),
+                command: Some(Commands::Migrate {
+                    from: QueueBackend::Filesystem,
+                    to: QueueBackend::Embedded,
+                })
+            },
+            <Args as clap::Parser>::try_parse_from([
+                "",
+                "migrate",
+                "--from",
+                "filesystem",
+                "--to",
+                "embedded"
+            ])
+            .unwrap()
+        );
+
+        assert_eq!(
+            <Args as clap::Parser>::try_parse_from([
+                "", "migrate", "--from", "sql", "--to", "embedded"
+            ])
+            .unwrap_err()
+            .kind(),
+            clap::error::ErrorKind::ValueValidation,
+        );
+    }
 }
@@ -0,0 +1,133 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+ */
+use crate::{
+    cli::args::Commands,
+    control::{Client, Request, Response},
+};
+
+#[allow(clippy::multiple_inherent_impl)]
+impl Commands {
+    pub(crate) async fn control<OUT: std::io::Write + Send + Sync>(
+        request: Request,
+        socket: &std::path::Path,
+        output: &mut OUT,
+    ) -> anyhow::Result<()> {
+        let mut client = Client::connect(socket).await?;
+
+        match client.send(&request).await? {
+            Response::Done => output.write_all(b"Ok\n")?,
+            Response::Sessions(sessions) => output.write_fmt(format_args!(
+                "{}\n",
+                serde_json::to_string_pretty(&sessions)?
+            ))?,
+            Response::Counters(counters) => output.write_fmt(format_args!(
+                "{}\n",
+                serde_json::to_string_pretty(&counters)?
+            ))?,
+            Response::MtaSts(policies) => output.write_fmt(format_args!(
+                "{}\n",
+                serde_json::to_string_pretty(&policies)?
+            ))?,
+            Response::Error(error) => anyhow::bail!(error),
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::{Counters, FlushQueue};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    /// Answer `response` to the first request, and return it.
+    fn server(socket: &std::path::Path, response: Response) -> tokio::task::JoinHandle<Request> {
+        let listener = tokio::net::UnixListener::bind(socket).unwrap();
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (read, mut write) = stream.into_split();
+            let line = tokio::io::BufReader::new(read)
+                .lines()
+                .next_line()
+                .await
+                .unwrap()
+                .unwrap();
+
+            let mut response = serde_json::to_vec(&response).unwrap();
+            response.push(b'\n');
+            write.write_all(&response).await.unwrap();
+
+            serde_json::from_str(&line).unwrap()
+        })
+    }
+
+    fn socket() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("vqueue-{}.sock", uuid::Uuid::new_v4()))
+    }
+
+    #[tokio::test]
+    async fn counters() {
+        let socket = socket();
+        let server = server(
+            &socket,
+            Response::Counters(Counters {
+                messages_received: 2,
+                ..Counters::default()
+            }),
+        );
+
+        let mut output = vec![];
+        Commands::control(Request::Counters, &socket, &mut output)
+            .await
+            .unwrap();
+
+        assert_eq!(server.await.unwrap(), Request::Counters);
+        assert!(std::str::from_utf8(&output)
+            .unwrap()
+            .contains("\"messages_received\": 2"));
+        std::fs::remove_file(socket).unwrap();
+    }
+
+    #[tokio::test]
+    async fn error() {
+        let socket = socket();
+        let server = server(&socket, Response::Error("failure".to_owned()));
+
+        let request = Request::Flush {
+            queue: FlushQueue::Deliver,
+        };
+        let mut output = vec![];
+        let error = Commands::control(request.clone(), &socket, &mut output)
+            .await
+            .unwrap_err();
+
+        assert_eq!(server.await.unwrap(), request);
+        assert_eq!(error.to_string(), "failure");
+        assert!(output.is_empty());
+        std::fs::remove_file(socket).unwrap();
+    }
+
+    #[tokio::test]
+    async fn not_running() {
+        let mut output = vec![];
+        Commands::control(Request::Reload, &socket(), &mut output)
+            .await
+            .unwrap_err();
+    }
+}
@@ -0,0 +1,158 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+ */
+use crate::{
+    cli::args::{Commands, HistoryFormat},
+    GenericQueueManager,
+};
+
+#[allow(clippy::multiple_inherent_impl)]
+impl Commands {
+    /// Print the history of the message.
+    ///
+    /// # Errors
+    ///
+    /// * the message has no history (unknown, or expired)
+    /// * the history cannot be read
+    #[inline]
+    pub async fn message_history(
+        msg_uuid: &uuid::Uuid,
+        queue_manager: &impl GenericQueueManager,
+        format: HistoryFormat,
+        output: &mut impl std::io::Write,
+    ) -> anyhow::Result<()> {
+        let events = queue_manager.get_history(msg_uuid).await?;
+        if events.is_empty() {
+            anyhow::bail!("No history for the message '{msg_uuid}'");
+        }
+
+        match format {
+            HistoryFormat::Text => {
+                for event in &events {
+                    writeln!(output, "{event}")?;
+                }
+            }
+            HistoryFormat::Json => {
+                writeln!(output, "{}", serde_json::to_string_pretty(&events)?)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        history::{Event, EventKind, Outcome},
+        QueueID,
+    };
+    use vsmtp_test::config::{local_ctx, local_msg, local_test};
+    extern crate alloc;
+
+    #[tokio::test]
+    async fn history() {
+        let queue_manager = <crate::temp::QueueManager as GenericQueueManager>::init(
+            alloc::sync::Arc::new(local_test()),
+            vec![],
+        )
+        .unwrap();
+
+        let mut ctx = local_ctx();
+        let msg_uuid = uuid::Uuid::new_v4();
+        ctx.mail_from.message_uuid = msg_uuid;
+
+        Commands::message_history(
+            &msg_uuid,
+            queue_manager.as_ref(),
+            HistoryFormat::Text,
+            &mut vec![],
+        )
+        .await
+        .unwrap_err();
+
+        queue_manager
+            .write_both(&QueueID::Working, &ctx, &local_msg())
+            .await
+            .unwrap();
+        queue_manager
+            .append_history(
+                &msg_uuid,
+                &[
+                    Event::now(EventKind::received(&ctx)),
+                    Event::now(EventKind::Queued {
+                        queue: QueueID::Working.to_string(),
+                    }),
+                ],
+            )
+            .await
+            .unwrap();
+        queue_manager
+            .move_to(&QueueID::Working, &QueueID::Deliver, &ctx)
+            .await
+            .unwrap();
+        queue_manager
+            .remove_both(&QueueID::Deliver, &msg_uuid)
+            .await
+            .unwrap();
+        queue_manager
+            .append_history(
+                &msg_uuid,
+                &[Event::now(EventKind::Finished {
+                    outcome: Outcome::Delivered,
+                })],
+            )
+            .await
+            .unwrap();
+
+        let mut output = vec![];
+        Commands::message_history(
+            &msg_uuid,
+            queue_manager.as_ref(),
+            HistoryFormat::Text,
+            &mut output,
+        )
+        .await
+        .unwrap();
+        let lines = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| line.split_once(' ').unwrap().1.to_owned())
+            .collect::<Vec<_>>();
+        pretty_assertions::assert_eq!(
+            lines,
+            [
+                "received from client.testserver.com (127.0.0.1:25) on 127.0.0.1:5977, from <client@testserver.com> to <recipient@testserver.com>",
+                "queued in working",
+                "moved from working to deliver",
+                "finished: delivered",
+            ]
+        );
+
+        let mut output = vec![];
+        Commands::message_history(
+            &msg_uuid,
+            queue_manager.as_ref(),
+            HistoryFormat::Json,
+            &mut output,
+        )
+        .await
+        .unwrap();
+        let events = serde_json::from_slice::<Vec<Event>>(&output).unwrap();
+        assert_eq!(events.len(), 4);
+        assert_eq!(queue_manager.list_history().await.unwrap()[0].0, msg_uuid);
+    }
+}
@@ -133,12 +133,20 @@ mod tests {
   "mail_timestamp": "{mail_timestamp}",
   "message_uuid": "{msg_uuid}",
   "spf": null,
+  "message_size": null,
+  "priority": 0,
+  "mail_from_dsn": {{
+    "ret": null,
+    "envelope_id": null
+  }},
   "forward_paths": [
     "recipient@testserver.com"
   ],
   "delivery": {{}},
   "transaction_type": "internal",
-  "dkim": null
+  "rcpt_to_dsn": {{}},
+  "dkim": null,
+  "released": []
 }}
 Message body:
 {{
@@ -221,12 +229,20 @@ Message body:
   "mail_timestamp": "{mail_timestamp}",
   "message_uuid": "{msg_uuid}",
   "spf": null,
+  "message_size": null,
+  "priority": 0,
+  "mail_from_dsn": {{
+    "ret": null,
+    "envelope_id": null
+  }},
   "forward_paths": [
     "recipient@testserver.com"
   ],
   "delivery": {{}},
   "transaction_type": "internal",
-  "dkim": null
+  "rcpt_to_dsn": {{}},
+  "dkim": null,
+  "released": []
 }}
 Message body:
 {}"#,
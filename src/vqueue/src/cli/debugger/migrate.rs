@@ -0,0 +1,256 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+ */
+use crate::{cli::args::Commands, GenericQueueManager, QueueID};
+use vsmtp_common::{
+    transport::{AbstractTransport, DeliverTo, DeserializerFn, GetID},
+    ContextFinished,
+};
+use vsmtp_config::{field::QueueBackend, Config};
+extern crate alloc;
+
+/// Transport read from a queue and written to another one as is,
+/// so that `vqueue` does not need the transports of the server and its plugins.
+///
+/// It cannot deliver the message, the recipients are returned unchanged.
+#[derive(Debug, serde::Deserialize)]
+#[serde(transparent)]
+pub struct Opaque(serde_json::Value);
+
+impl serde::Serialize for Opaque {
+    #[inline]
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&self.0.to_string())
+    }
+}
+
+#[allow(clippy::missing_trait_methods)]
+impl GetID for Opaque {}
+
+#[allow(clippy::missing_trait_methods)]
+#[async_trait::async_trait]
+impl AbstractTransport for Opaque {
+    #[inline]
+    async fn deliver(
+        self: alloc::sync::Arc<Self>,
+        _: &ContextFinished,
+        rcpt_to: DeliverTo,
+        _: &[u8],
+    ) -> DeliverTo {
+        rcpt_to
+    }
+}
+
+impl Opaque {
+    /// The deserializer to give to the queue managers.
+    #[must_use]
+    #[inline]
+    pub fn deserializer() -> Vec<DeserializerFn> {
+        vec![<Self as AbstractTransport>::get_symbol()]
+    }
+}
+
+#[allow(clippy::multiple_inherent_impl)]
+impl Commands {
+    /// Move all the messages stored with the `from` backend to the `to` backend.
+    ///
+    /// The server must be stopped.
+    ///
+    /// # Errors
+    ///
+    /// * the backends are the same
+    /// * see [`Commands::migrate`]
+    #[inline]
+    pub async fn migrate_backends(
+        config: alloc::sync::Arc<Config>,
+        from: QueueBackend,
+        to: QueueBackend,
+        output: &mut impl std::io::Write,
+    ) -> anyhow::Result<()> {
+        #[allow(clippy::wildcard_enum_match_arm)]
+        match (from, to) {
+            (QueueBackend::Filesystem, QueueBackend::Embedded) => {
+                Self::migrate(
+                    crate::fs::QueueManager::init(
+                        alloc::sync::Arc::clone(&config),
+                        Opaque::deserializer(),
+                    )?
+                    .as_ref(),
+                    crate::embedded::QueueManager::init(config, Opaque::deserializer())?.as_ref(),
+                    output,
+                )
+                .await
+            }
+            (QueueBackend::Embedded, QueueBackend::Filesystem) => {
+                Self::migrate(
+                    crate::embedded::QueueManager::init(
+                        alloc::sync::Arc::clone(&config),
+                        Opaque::deserializer(),
+                    )?
+                    .as_ref(),
+                    crate::fs::QueueManager::init(config, Opaque::deserializer())?.as_ref(),
+                    output,
+                )
+                .await
+            }
+            _ => anyhow::bail!("The source and target backends are the same: `{from}`"),
+        }
+    }
+
+    /// Move all the messages of `source` to `target`.
+    ///
+    /// The queue managers must have been initialized with [`Opaque::deserializer`].
+    ///
+    /// # Errors
+    ///
+    /// * the queues cannot be listed
+    /// * a message cannot be moved, the other messages are moved anyway
+    #[inline]
+    pub async fn migrate(
+        source: &impl GenericQueueManager,
+        target: &impl GenericQueueManager,
+        output: &mut impl std::io::Write,
+    ) -> anyhow::Result<()> {
+        let queues = <QueueID as strum::IntoEnumIterator>::iter()
+            .filter(|queue| !matches!(queue, QueueID::Quarantine { .. }))
+            .chain(
+                source
+                    .list_quarantines()
+                    .await?
+                    .into_iter()
+                    .map(|name| QueueID::Quarantine { name }),
+            )
+            .collect::<Vec<_>>();
+
+        let (mut migrated, mut failures) = (0usize, 0usize);
+        for queue in &queues {
+            for msg_uuid in source.list(queue).await? {
+                let result = match msg_uuid.and_then(|i| Ok(uuid::Uuid::parse_str(&i)?)) {
+                    Ok(msg_uuid) => Self::migrate_one(source, target, queue, &msg_uuid).await,
+                    Err(error) => Err(error),
+                };
+                match result {
+                    Ok(()) => migrated += 1,
+                    Err(error) => {
+                        failures += 1;
+                        writeln!(
+                            output,
+                            "Failed to migrate a message of `{queue}`: {error:#}"
+                        )?;
+                    }
+                }
+            }
+        }
+
+        writeln!(output, "{migrated} message(s) migrated.")?;
+        anyhow::ensure!(failures == 0, "{failures} message(s) could not be migrated");
+        Ok(())
+    }
+
+    async fn migrate_one(
+        source: &impl GenericQueueManager,
+        target: &impl GenericQueueManager,
+        queue: &QueueID,
+        msg_uuid: &uuid::Uuid,
+    ) -> anyhow::Result<()> {
+        let ctx = source.get_ctx(queue, msg_uuid).await?;
+        let msg = source.get_msg(msg_uuid).await?;
+
+        target.write_ctx(queue, &ctx).await?;
+        target.write_msg(msg_uuid, &msg).await?;
+
+        source.remove_ctx(queue, msg_uuid).await?;
+        source.remove_msg(msg_uuid).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vsmtp_common::transport::WrapperSerde;
+    use vsmtp_test::config::{local_ctx, local_msg, local_test};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn fs_to_embedded_and_back() {
+        let source = <crate::temp::QueueManager as GenericQueueManager>::init(
+            alloc::sync::Arc::new(local_test()),
+            Opaque::deserializer(),
+        )
+        .unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = local_test();
+        config.server.queues.dirpath = dir.path().to_path_buf();
+        let target = <crate::embedded::QueueManager as GenericQueueManager>::init(
+            alloc::sync::Arc::new(config),
+            Opaque::deserializer(),
+        )
+        .unwrap();
+
+        let mut ctx = local_ctx();
+        ctx.mail_from.message_uuid = uuid::Uuid::new_v4();
+        ctx.rcpt_to.delivery.insert(
+            WrapperSerde::Raw(r#"{"name":"deliver"}"#.to_owned()),
+            vec![(
+                "john@doe.com".parse().unwrap(),
+                vsmtp_common::transfer::Status::default(),
+            )],
+        );
+        source
+            .write_both(&QueueID::Deferred, &ctx, &local_msg())
+            .await
+            .unwrap();
+        let quarantined = uuid::Uuid::new_v4();
+        ctx.mail_from.message_uuid = quarantined;
+        let spam = QueueID::Quarantine {
+            name: "spam".to_owned(),
+        };
+        source.write_both(&spam, &ctx, &local_msg()).await.unwrap();
+
+        let mut output = vec![];
+        Commands::migrate(source.as_ref(), target.as_ref(), &mut output)
+            .await
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "2 message(s) migrated.\n"
+        );
+        assert!(source.list(&QueueID::Deferred).await.unwrap().is_empty());
+        assert!(source.list(&spam).await.unwrap().is_empty());
+        assert_eq!(target.list_quarantines().await.unwrap(), vec!["spam"]);
+
+        let migrated = target.get_ctx(&spam, &quarantined).await.unwrap();
+        assert_eq!(
+            serde_json::to_value(&migrated.rcpt_to.delivery).unwrap(),
+            serde_json::to_value(&ctx.rcpt_to.delivery).unwrap()
+        );
+
+        let mut output = vec![];
+        Commands::migrate(target.as_ref(), source.as_ref(), &mut output)
+            .await
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "2 message(s) migrated.\n"
+        );
+        assert_eq!(source.list(&QueueID::Deferred).await.unwrap().len(), 1);
+        assert!(target.list(&QueueID::Deferred).await.unwrap().is_empty());
+        source.get_both(&spam, &quarantined).await.unwrap();
+    }
+}
@@ -0,0 +1,157 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+ */
+use crate::{cli::args::Commands, history, retention, GenericQueueManager};
+
+#[allow(clippy::multiple_inherent_impl)]
+impl Commands {
+    /// Remove the expired messages of the `dead` queue and of the quarantines,
+    /// and write them to the audit log. The expired histories are removed too.
+    ///
+    /// # Errors
+    ///
+    /// * see [`retention::enforce`]
+    /// * see [`retention::audit`]
+    /// * see [`history::expire`]
+    #[inline]
+    pub async fn purge(
+        queue_manager: &impl GenericQueueManager,
+        dry_run: bool,
+        output: &mut impl std::io::Write,
+    ) -> anyhow::Result<()> {
+        let now = time::OffsetDateTime::now_utc();
+        let expired = retention::enforce(queue_manager, now, dry_run).await?;
+        let histories = history::expire(queue_manager, now, dry_run).await?;
+
+        for i in &expired {
+            writeln!(output, "{i}")?;
+        }
+        if !histories.is_empty() {
+            writeln!(
+                output,
+                "{} expired history(ies) {}.",
+                histories.len(),
+                if dry_run {
+                    "would be removed"
+                } else {
+                    "removed"
+                }
+            )?;
+        }
+        if dry_run {
+            writeln!(output, "{} message(s) would be removed.", expired.len())?;
+        } else {
+            retention::audit(
+                &queue_manager.get_config().server.queues.retention.audit_log,
+                &expired,
+                now,
+            )?;
+            writeln!(output, "{} message(s) removed.", expired.len())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::QueueID;
+    use vsmtp_config::field::RetentionPolicy;
+    use vsmtp_test::config::{local_ctx, local_msg, local_test};
+    extern crate alloc;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn purge_dead_and_quarantine() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = local_test();
+        config.server.queues.retention.audit_log = dir.path().join("expired.log");
+        config.server.queues.retention.dead.max_count = Some(1);
+        config.server.queues.retention.quarantines.insert(
+            "spam".to_owned(),
+            RetentionPolicy {
+                max_age: Some(core::time::Duration::from_secs(60 * 60)),
+                ..RetentionPolicy::default()
+            },
+        );
+        let queue_manager = <crate::temp::QueueManager as GenericQueueManager>::init(
+            alloc::sync::Arc::new(config),
+            vec![],
+        )
+        .unwrap();
+
+        let spam = QueueID::Quarantine {
+            name: "spam".to_owned(),
+        };
+        let virus = QueueID::Quarantine {
+            name: "virus".to_owned(),
+        };
+        let now = time::OffsetDateTime::now_utc();
+        let write = |queue: QueueID, age: time::Duration| {
+            let mut ctx = local_ctx();
+            ctx.mail_from.message_uuid = uuid::Uuid::new_v4();
+            ctx.mail_from.mail_timestamp = now - age;
+            let queue_manager = alloc::sync::Arc::clone(&queue_manager);
+            async move {
+                queue_manager
+                    .write_both(&queue, &ctx, &local_msg())
+                    .await
+                    .unwrap();
+                ctx.mail_from.message_uuid
+            }
+        };
+        let old_dead = write(QueueID::Dead, time::Duration::days(2)).await;
+        let dead = write(QueueID::Dead, time::Duration::days(1)).await;
+        let old_spam = write(spam.clone(), time::Duration::hours(2)).await;
+        let new_spam = write(spam.clone(), time::Duration::minutes(2)).await;
+        let virus_msg = write(virus.clone(), time::Duration::days(3)).await;
+
+        let mut output = vec![];
+        Commands::purge(queue_manager.as_ref(), true, &mut output)
+            .await
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains(&format!(
+            "expired msg={old_dead} queue=dead reason=max_count=1\n"
+        )));
+        assert!(output.contains(&format!(
+            "expired msg={old_spam} queue=quarantine/spam reason=max_age=1h\n"
+        )));
+        assert!(output.ends_with("2 message(s) would be removed.\n"));
+        assert_eq!(queue_manager.list(&QueueID::Dead).await.unwrap().len(), 2);
+
+        let mut removed = vec![];
+        Commands::purge(queue_manager.as_ref(), false, &mut removed)
+            .await
+            .unwrap();
+        assert!(String::from_utf8(removed)
+            .unwrap()
+            .ends_with("2 message(s) removed.\n"));
+
+        queue_manager.get_both(&QueueID::Dead, &dead).await.unwrap();
+        queue_manager.get_both(&spam, &new_spam).await.unwrap();
+        queue_manager.get_both(&virus, &virus_msg).await.unwrap();
+        queue_manager
+            .get_ctx(&QueueID::Dead, &old_dead)
+            .await
+            .unwrap_err();
+        queue_manager.get_ctx(&spam, &old_spam).await.unwrap_err();
+
+        let audit = std::fs::read_to_string(dir.path().join("expired.log")).unwrap();
+        assert_eq!(audit.lines().count(), 2);
+        assert!(audit.contains(&format!("expired msg={old_dead} queue=dead")));
+        assert!(audit.contains(&format!("expired msg={old_spam} queue=quarantine/spam")));
+    }
+}
@@ -0,0 +1,787 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+ */
+use crate::{
+    cli::args::{Commands, Filter, SelectCommand, SelectFormat},
+    control::{Client, Request, Response},
+    GenericQueueManager, QueueID,
+};
+use anyhow::Context;
+use vsmtp_common::{transfer::Status, ContextFinished};
+extern crate alloc;
+
+/// A message matching the filter, and the result of the action applied to it.
+#[derive(Debug, serde::Serialize)]
+struct Row {
+    uuid: uuid::Uuid,
+    queue: String,
+    #[serde(with = "time::serde::iso8601")]
+    received_at: time::OffsetDateTime,
+    sender: String,
+    recipients: Vec<String>,
+    size: Option<usize>,
+    last_error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    outcome: Option<String>,
+    #[serde(skip)]
+    queue_id: QueueID,
+}
+
+impl Row {
+    fn new(queue_id: QueueID, ctx: &ContextFinished, size: Option<usize>) -> Self {
+        let last_error = ctx
+            .rcpt_to
+            .delivery
+            .values()
+            .flatten()
+            .filter_map(|(_, status)| {
+                #[allow(clippy::wildcard_enum_match_arm)]
+                match status {
+                    Status::HeldBack { errors } => errors.last(),
+                    Status::Failed { error } => Some(error),
+                    _ => None,
+                }
+            })
+            .max_by_key(|error| *error.timestamp())
+            .map(|error| error.variant().to_string());
+
+        Self {
+            uuid: ctx.mail_from.message_uuid,
+            queue: queue_id.to_string(),
+            received_at: ctx.mail_from.mail_timestamp,
+            sender: ctx
+                .mail_from
+                .reverse_path
+                .as_ref()
+                .map_or_else(|| "<>".to_owned(), |sender| sender.full().to_owned()),
+            recipients: ctx
+                .rcpt_to
+                .forward_paths
+                .iter()
+                .map(|rcpt| rcpt.full().to_owned())
+                .collect(),
+            size,
+            last_error,
+            outcome: None,
+            queue_id,
+        }
+    }
+}
+
+impl Filter {
+    fn matches(&self, row: &Row, now: time::OffsetDateTime) -> bool {
+        let age = now - row.received_at;
+
+        self.sender
+            .as_ref()
+            .map_or(true, |sender| sender.eq_ignore_ascii_case(&row.sender))
+            && self.recipient.as_ref().map_or(true, |recipient| {
+                row.recipients
+                    .iter()
+                    .any(|i| i.eq_ignore_ascii_case(recipient))
+            })
+            && self.recipient_domain.as_ref().map_or(true, |domain| {
+                row.recipients.iter().any(|i| {
+                    i.rsplit_once('@')
+                        .map_or(false, |(_, i)| i.eq_ignore_ascii_case(domain))
+                })
+            })
+            && self.older_than.map_or(true, |older| age >= older)
+            && self.newer_than.map_or(true, |newer| age < newer)
+            && self
+                .min_size
+                .map_or(true, |min| row.size.map_or(false, |size| size >= min))
+            && self
+                .max_size
+                .map_or(true, |max| row.size.map_or(false, |size| size <= max))
+            && self.error.as_ref().map_or(true, |error| {
+                row.last_error.as_ref().map_or(false, |last| {
+                    last.to_lowercase().contains(&error.to_lowercase())
+                })
+            })
+    }
+
+    /// Queues to search, the quarantines being expanded to their names.
+    async fn queues(
+        &self,
+        queue_manager: &(impl GenericQueueManager + Send + Sync),
+    ) -> anyhow::Result<Vec<QueueID>> {
+        let requested = if self.queue.is_empty() && self.quarantine.is_empty() {
+            <QueueID as strum::IntoEnumIterator>::iter().collect::<Vec<_>>()
+        } else {
+            self.queue.clone()
+        };
+
+        let mut queues = vec![];
+        for queue in requested {
+            if queue
+                == (QueueID::Quarantine {
+                    name: String::new(),
+                })
+            {
+                queues.extend(
+                    queue_manager
+                        .list_quarantines()
+                        .await?
+                        .into_iter()
+                        .map(|name| QueueID::Quarantine { name }),
+                );
+            } else {
+                queues.push(queue);
+            }
+        }
+        queues.extend(
+            self.quarantine
+                .iter()
+                .map(|name| QueueID::Quarantine { name: name.clone() }),
+        );
+
+        let mut unique = Vec::with_capacity(queues.len());
+        for queue in queues {
+            if !unique.contains(&queue) {
+                unique.push(queue);
+            }
+        }
+        Ok(unique)
+    }
+}
+
+impl SelectCommand {
+    fn outcome(&self, dry_run: bool) -> String {
+        let action = match self {
+            Self::Show => return String::new(),
+            Self::Move { queue } => format!("move to {queue}"),
+            Self::Remove { .. } => "remove".to_owned(),
+            Self::Requeue { stage } => format!("requeue to {}", stage_name(*stage)),
+            Self::Export { directory } => format!("export to {}", directory.display()),
+        };
+        if dry_run {
+            format!("would {action}")
+        } else {
+            action
+        }
+    }
+}
+
+const fn stage_name(stage: crate::control::ReRunStage) -> &'static str {
+    match stage {
+        crate::control::ReRunStage::Working => "working",
+        crate::control::ReRunStage::Delivery => "delivery",
+    }
+}
+
+async fn select(
+    filter: &Filter,
+    queue_manager: &(impl GenericQueueManager + Send + Sync),
+) -> anyhow::Result<Vec<Row>> {
+    let now = time::OffsetDateTime::now_utc();
+    let mut rows = vec![];
+
+    for queue in filter.queues(queue_manager).await? {
+        // a missing queue has no message.
+        let Ok(list) = queue_manager.list(&queue).await else {
+            continue;
+        };
+
+        for msg_uuid in list.into_iter().flatten() {
+            let Ok(msg_uuid) = uuid::Uuid::try_parse(&msg_uuid) else {
+                continue;
+            };
+            let Ok(ctx) = queue_manager.get_ctx(&queue, &msg_uuid).await else {
+                continue;
+            };
+            let size = if filter.min_size.is_some() || filter.max_size.is_some() {
+                queue_manager.get_msg_size(&msg_uuid).await.ok()
+            } else {
+                None
+            };
+
+            let row = Row::new(queue.clone(), &ctx, size);
+            if filter.matches(&row, now) {
+                rows.push(row);
+            }
+        }
+    }
+
+    rows.sort_by_key(|row| row.received_at);
+    Ok(rows)
+}
+
+async fn export(
+    directory: &std::path::Path,
+    row: &Row,
+    queue_manager: &(impl GenericQueueManager + Send + Sync),
+) -> anyhow::Result<()> {
+    let (ctx, msg) = (
+        queue_manager.get_ctx(&row.queue_id, &row.uuid).await?,
+        queue_manager.get_msg(&row.uuid).await?,
+    );
+
+    std::fs::create_dir_all(directory)
+        .with_context(|| format!("Cannot create directory '{}'", directory.display()))?;
+    std::fs::write(
+        directory.join(format!("{}.json", row.uuid)),
+        serde_json::to_string_pretty(&ctx)?,
+    )?;
+    std::fs::write(
+        directory.join(format!("{}.eml", row.uuid)),
+        msg.inner().to_string(),
+    )?;
+
+    Ok(())
+}
+
+fn write_rows<OUT: std::io::Write + Send + Sync>(
+    rows: &[Row],
+    format: SelectFormat,
+    output: &mut OUT,
+) -> anyhow::Result<()> {
+    match format {
+        SelectFormat::Table => {
+            if rows.is_empty() {
+                output.write_all(b"No message selected\n")?;
+                return Ok(());
+            }
+            output.write_fmt(format_args!(
+                "{:<36}  {:<20}  {:<20}  {:>8}  {:<30}  {:<30}  {}\n",
+                "ID", "QUEUE", "RECEIVED", "SIZE", "SENDER", "RECIPIENTS", "LAST ERROR"
+            ))?;
+            for row in rows {
+                output.write_fmt(format_args!(
+                    "{:<36}  {:<20}  {:<20}  {:>8}  {:<30}  {:<30}  {}\n",
+                    row.uuid,
+                    row.queue,
+                    row.received_at
+                        .format(&time::format_description::well_known::Rfc3339)
+                        .unwrap_or_default(),
+                    row.size
+                        .map_or_else(|| "-".to_owned(), |size| size.to_string()),
+                    row.sender,
+                    row.recipients.join(","),
+                    row.last_error.as_deref().unwrap_or("-"),
+                ))?;
+                if let Some(outcome) = &row.outcome {
+                    output.write_fmt(format_args!("{:<36}  => {outcome}\n", ""))?;
+                }
+            }
+        }
+        SelectFormat::Json => {
+            output.write_fmt(format_args!("{}\n", serde_json::to_string_pretty(rows)?))?;
+        }
+        SelectFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(output);
+            writer.write_record([
+                "uuid",
+                "queue",
+                "received_at",
+                "sender",
+                "recipients",
+                "size",
+                "last_error",
+                "outcome",
+            ])?;
+            for row in rows {
+                writer.write_record([
+                    row.uuid.to_string(),
+                    row.queue.clone(),
+                    row.received_at
+                        .format(&time::format_description::well_known::Rfc3339)
+                        .unwrap_or_default(),
+                    row.sender.clone(),
+                    row.recipients.join(" "),
+                    row.size.map(|size| size.to_string()).unwrap_or_default(),
+                    row.last_error.clone().unwrap_or_default(),
+                    row.outcome.clone().unwrap_or_default(),
+                ])?;
+            }
+            writer.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+#[allow(clippy::multiple_inherent_impl)]
+impl Commands {
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) async fn select<
+        OUT: std::io::Write + Send + Sync,
+        IN: tokio::io::AsyncRead + Send + Sync + core::marker::Unpin,
+    >(
+        filter: &Filter,
+        format: SelectFormat,
+        dry_run: bool,
+        command: &SelectCommand,
+        queue_manager: alloc::sync::Arc<impl GenericQueueManager + Send + Sync>,
+        socket: &std::path::Path,
+        output: &mut OUT,
+        mut input: IN,
+    ) -> anyhow::Result<()> {
+        let mut rows = select(filter, queue_manager.as_ref()).await?;
+
+        if matches!(command, SelectCommand::Show) || rows.is_empty() {
+            return write_rows(&rows, format, output);
+        }
+
+        let outcome = command.outcome(dry_run);
+        if dry_run {
+            for row in &mut rows {
+                row.outcome = Some(outcome.clone());
+            }
+            return write_rows(&rows, format, output);
+        }
+
+        if matches!(command, SelectCommand::Remove { yes: false }) {
+            output.write_fmt(format_args!(
+                "Removing {} message(s)\nConfirm ? [y|yes] ",
+                rows.len()
+            ))?;
+            output.flush()?;
+
+            let buf = &mut [0u8; 1];
+            tokio::io::AsyncReadExt::read(&mut input, buf).await?;
+
+            if buf[0] != b'y' {
+                output.write_all(b"Canceled\n")?;
+                return Ok(());
+            }
+        }
+
+        let mut client = match command {
+            SelectCommand::Requeue { .. } => Some(Client::connect(socket).await?),
+            SelectCommand::Show
+            | SelectCommand::Move { .. }
+            | SelectCommand::Remove { .. }
+            | SelectCommand::Export { .. } => None,
+        };
+
+        let mut failures = 0usize;
+        for row in &mut rows {
+            let result = match (command, client.as_mut()) {
+                (SelectCommand::Move { queue }, _) => {
+                    queue_manager
+                        .move_to_from_id(&row.queue_id, queue, &row.uuid)
+                        .await
+                }
+                (SelectCommand::Remove { .. }, _) => {
+                    queue_manager.remove_both(&row.queue_id, &row.uuid).await
+                }
+                (SelectCommand::Requeue { stage }, Some(client)) => client
+                    .send(&Request::ReRun {
+                        msg: row.uuid,
+                        stage: *stage,
+                        queue: Some(row.queue_id.clone()),
+                    })
+                    .await
+                    .and_then(|response| match response {
+                        Response::Done => Ok(()),
+                        Response::Error(error) => Err(anyhow::anyhow!(error)),
+                        otherwise @ (Response::Sessions(_)
+                        | Response::Counters(_)
+                        | Response::MtaSts(_)) => {
+                            Err(anyhow::anyhow!("Unexpected response: {otherwise:?}"))
+                        }
+                    }),
+                (SelectCommand::Export { directory }, _) => {
+                    export(directory, row, queue_manager.as_ref()).await
+                }
+                (SelectCommand::Show | SelectCommand::Requeue { .. }, _) => Ok(()),
+            };
+
+            row.outcome = Some(match result {
+                Ok(()) => outcome.clone(),
+                Err(error) => {
+                    failures += 1;
+                    format!("failed to {outcome}: {error:#}")
+                }
+            });
+        }
+
+        write_rows(&rows, format, output)?;
+        anyhow::ensure!(failures == 0, "{failures} message(s) failed");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vsmtp_common::transfer::error::{Queuer, Variant};
+    use vsmtp_test::config::{local_ctx, local_msg, local_test};
+
+    async fn fill(queue_manager: &alloc::sync::Arc<crate::temp::QueueManager>) -> [uuid::Uuid; 3] {
+        let mut old_ctx = local_ctx();
+        let old = uuid::Uuid::new_v4();
+        old_ctx.mail_from.message_uuid = old;
+        old_ctx.mail_from.mail_timestamp -= time::Duration::hours(3);
+        queue_manager
+            .write_both(&QueueID::Deferred, &old_ctx, &local_msg())
+            .await
+            .unwrap();
+
+        let mut other_ctx = local_ctx();
+        let other = uuid::Uuid::new_v4();
+        other_ctx.mail_from.message_uuid = other;
+        other_ctx.rcpt_to.forward_paths = vec!["john@other.com".parse().unwrap()];
+        queue_manager
+            .write_both(&QueueID::Deferred, &other_ctx, &local_msg())
+            .await
+            .unwrap();
+
+        let mut spam_ctx = local_ctx();
+        let spam = uuid::Uuid::new_v4();
+        spam_ctx.mail_from.message_uuid = spam;
+        queue_manager
+            .write_both(
+                &QueueID::Quarantine {
+                    name: "spam".to_owned(),
+                },
+                &spam_ctx,
+                &local_msg(),
+            )
+            .await
+            .unwrap();
+        [old, other, spam]
+    }
+
+    async fn run(
+        filter: Filter,
+        format: SelectFormat,
+        dry_run: bool,
+        command: SelectCommand,
+        queue_manager: &alloc::sync::Arc<crate::temp::QueueManager>,
+    ) -> anyhow::Result<String> {
+        let mut output = vec![];
+        Commands::select(
+            &filter,
+            format,
+            dry_run,
+            &command,
+            alloc::sync::Arc::clone(queue_manager),
+            std::path::Path::new("/nonexistent.sock"),
+            &mut output,
+            std::io::Cursor::new(b"y"),
+        )
+        .await?;
+        Ok(String::from_utf8(output).unwrap())
+    }
+
+    #[tokio::test]
+    async fn show_filtered() {
+        let queue_manager = crate::temp::QueueManager::init(local_test().into(), vec![]).unwrap();
+        let [old, other, spam] = fill(&queue_manager).await;
+
+        let all = run(
+            Filter::default(),
+            SelectFormat::Json,
+            false,
+            SelectCommand::Show,
+            &queue_manager,
+        )
+        .await
+        .unwrap();
+        let all = serde_json::from_str::<Vec<serde_json::Value>>(&all).unwrap();
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[0]["uuid"], old.to_string());
+        assert_eq!(all[0]["queue"], "deferred");
+        assert_eq!(all[0]["sender"], "client@testserver.com");
+        assert_eq!(all[0]["last_error"], serde_json::Value::Null);
+        assert_eq!(all[0]["size"], serde_json::Value::Null);
+        assert!(all.iter().any(|i| i["queue"] == "quarantine/spam"));
+
+        let sized = select(
+            &Filter {
+                min_size: Some(1),
+                ..Filter::default()
+            },
+            queue_manager.as_ref(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(sized.len(), 3);
+        for row in &sized {
+            assert_eq!(
+                row.size,
+                Some(
+                    queue_manager
+                        .get_msg(&row.uuid)
+                        .await
+                        .unwrap()
+                        .inner()
+                        .to_string()
+                        .len()
+                )
+            );
+        }
+
+        for (filter, expected) in [
+            (
+                Filter {
+                    queue: vec![QueueID::Quarantine {
+                        name: String::new(),
+                    }],
+                    ..Filter::default()
+                },
+                vec![spam],
+            ),
+            (
+                Filter {
+                    recipient_domain: Some("OTHER.com".to_owned()),
+                    ..Filter::default()
+                },
+                vec![other],
+            ),
+            (
+                Filter {
+                    older_than: Some(std::time::Duration::from_secs(60 * 60)),
+                    ..Filter::default()
+                },
+                vec![old],
+            ),
+            (
+                Filter {
+                    queue: vec![QueueID::Deferred],
+                    recipient: Some("recipient@testserver.com".to_owned()),
+                    max_size: Some(1),
+                    ..Filter::default()
+                },
+                vec![],
+            ),
+        ] {
+            let rows = select(&filter, queue_manager.as_ref()).await.unwrap();
+            assert_eq!(
+                rows.iter().map(|row| row.uuid).collect::<Vec<_>>(),
+                expected,
+                "{filter:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn last_error() {
+        let mut ctx = local_ctx();
+        ctx.rcpt_to.delivery.insert(
+            vsmtp_common::transport::WrapperSerde::Raw("transport".to_owned()),
+            vec![
+                (
+                    "recipient@testserver.com".parse().unwrap(),
+                    Status::HeldBack {
+                        errors: vec![vsmtp_common::transfer::Error::new(Variant::Queuer(
+                            Queuer::StillWaiting,
+                        ))],
+                    },
+                ),
+                (
+                    "other@testserver.com".parse().unwrap(),
+                    Status::failed(Variant::Queuer(Queuer::MaxDeferredAttemptReached)),
+                ),
+            ],
+        );
+
+        let row = Row::new(QueueID::Dead, &ctx, None);
+        assert_eq!(
+            row.last_error.as_deref(),
+            Some("<queuer>: max deferred attempt reached")
+        );
+
+        let now = time::OffsetDateTime::now_utc();
+        for (error, expected) in [("MAX DEFERRED", true), ("waiting", false)] {
+            assert_eq!(
+                Filter {
+                    error: Some(error.to_owned()),
+                    ..Filter::default()
+                }
+                .matches(&row, now),
+                expected
+            );
+        }
+        assert!(!Filter {
+            min_size: Some(0),
+            ..Filter::default()
+        }
+        .matches(&row, now));
+    }
+
+    #[tokio::test]
+    async fn move_dry_run_and_csv() {
+        let queue_manager = crate::temp::QueueManager::init(local_test().into(), vec![]).unwrap();
+        let [old, other, _] = fill(&queue_manager).await;
+        let filter = Filter {
+            queue: vec![QueueID::Deferred],
+            ..Filter::default()
+        };
+        let command = SelectCommand::Move {
+            queue: QueueID::Dead,
+        };
+
+        let dry_run = run(
+            filter.clone(),
+            SelectFormat::Csv,
+            true,
+            command.clone(),
+            &queue_manager,
+        )
+        .await
+        .unwrap();
+        let lines = dry_run.lines().collect::<Vec<_>>();
+        assert_eq!(
+            lines[0],
+            "uuid,queue,received_at,sender,recipients,size,last_error,outcome"
+        );
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].starts_with(&format!("{old},deferred,")));
+        assert!(lines[1].ends_with(",would move to dead"));
+        queue_manager
+            .get_ctx(&QueueID::Deferred, &other)
+            .await
+            .unwrap();
+
+        let applied = run(filter, SelectFormat::Table, false, command, &queue_manager)
+            .await
+            .unwrap();
+        assert_eq!(applied.matches("=> move to dead").count(), 2);
+        for uuid in [old, other] {
+            queue_manager.get_ctx(&QueueID::Dead, &uuid).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn remove_and_export() {
+        let queue_manager = crate::temp::QueueManager::init(local_test().into(), vec![]).unwrap();
+        let [_, _, spam] = fill(&queue_manager).await;
+        let filter = Filter {
+            quarantine: vec!["spam".to_owned()],
+            ..Filter::default()
+        };
+
+        let directory = queue_manager.tempdir.path().join("export");
+        run(
+            filter.clone(),
+            SelectFormat::Json,
+            false,
+            SelectCommand::Export {
+                directory: directory.clone(),
+            },
+            &queue_manager,
+        )
+        .await
+        .unwrap();
+        assert!(directory.join(format!("{spam}.json")).exists());
+        assert!(directory.join(format!("{spam}.eml")).exists());
+
+        let output = run(
+            filter.clone(),
+            SelectFormat::Table,
+            false,
+            SelectCommand::Remove { yes: false },
+            &queue_manager,
+        )
+        .await
+        .unwrap();
+        assert!(output.starts_with("Removing 1 message(s)\nConfirm ? [y|yes] "));
+        assert!(output.ends_with("=> remove\n"));
+
+        assert_eq!(
+            run(
+                filter,
+                SelectFormat::Table,
+                false,
+                SelectCommand::Show,
+                &queue_manager
+            )
+            .await
+            .unwrap(),
+            "No message selected\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn requeue_without_server() {
+        let queue_manager = crate::temp::QueueManager::init(local_test().into(), vec![]).unwrap();
+        fill(&queue_manager).await;
+
+        run(
+            Filter::default(),
+            SelectFormat::Json,
+            false,
+            SelectCommand::Requeue {
+                stage: crate::control::ReRunStage::Working,
+            },
+            &queue_manager,
+        )
+        .await
+        .unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn requeue_quarantined() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+        let queue_manager = crate::temp::QueueManager::init(local_test().into(), vec![]).unwrap();
+        let [_, _, spam] = fill(&queue_manager).await;
+
+        let socket = std::env::temp_dir().join(format!("vqueue-{}.sock", uuid::Uuid::new_v4()));
+        let listener = tokio::net::UnixListener::bind(&socket).unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (read, mut write) = stream.into_split();
+            let line = tokio::io::BufReader::new(read)
+                .lines()
+                .next_line()
+                .await
+                .unwrap()
+                .unwrap();
+
+            let mut response = serde_json::to_vec(&Response::Done).unwrap();
+            response.push(b'\n');
+            write.write_all(&response).await.unwrap();
+
+            serde_json::from_str::<Request>(&line).unwrap()
+        });
+
+        let spam_queue = QueueID::Quarantine {
+            name: "spam".to_owned(),
+        };
+        let mut output = vec![];
+        Commands::select(
+            &Filter {
+                queue: vec![spam_queue.clone()],
+                ..Filter::default()
+            },
+            SelectFormat::Csv,
+            false,
+            &SelectCommand::Requeue {
+                stage: crate::control::ReRunStage::Delivery,
+            },
+            alloc::sync::Arc::clone(&queue_manager),
+            &socket,
+            &mut output,
+            std::io::Cursor::new(b"y"),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            server.await.unwrap(),
+            Request::ReRun {
+                msg: spam,
+                stage: crate::control::ReRunStage::Delivery,
+                queue: Some(spam_queue),
+            }
+        );
+        std::fs::remove_file(socket).unwrap();
+    }
+}
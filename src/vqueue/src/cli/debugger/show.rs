@@ -312,7 +312,13 @@ mod tests {
             .filter(|i| !matches!(i, &QueueID::Quarantine { .. }))
             .collect::<Vec<_>>();
 
-        std::fs::remove_dir_all(queue_manager.tempdir.path()).unwrap();
+        for queue in &queues {
+            std::fs::remove_dir_all(crate::FilesystemQueueManagerExt::get_queue_path(
+                &*queue_manager,
+                queue,
+            ))
+            .unwrap();
+        }
 
         Commands::show(queues, queue_manager, '.', &mut output)
             .await
@@ -341,11 +347,8 @@ mod tests {
             crate::temp::QueueManager::init(alloc::sync::Arc::clone(&config), vec![]).unwrap();
 
         std::fs::write(
-            format!(
-                "{root}/{spool}/working/00.json",
-                root = queue_manager.tempdir.path().display(),
-                spool = config.server.queues.dirpath.display(),
-            ),
+            crate::FilesystemQueueManagerExt::get_queue_path(&*queue_manager, &QueueID::Working)
+                .join("00.json"),
             "foobar",
         )
         .unwrap();
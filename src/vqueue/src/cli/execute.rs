@@ -15,15 +15,26 @@
  *
  */
 use super::args::{Commands, MessageCommand};
-use crate::{GenericQueueManager, QueueID};
+use crate::{
+    control::{self, Release, Request},
+    GenericQueueManager, QueueID,
+};
 
 extern crate alloc;
 
+/// Name of the user running the command, recorded as the operator of a release.
+fn current_user() -> String {
+    std::env::var("SUDO_USER")
+        .or_else(|_| std::env::var("USER"))
+        .unwrap_or_else(|_| "unknown".to_owned())
+}
+
 impl Commands {
     /// Execute the vQueue command
     ///
     /// # Errors
     #[inline]
+    #[allow(clippy::too_many_lines)]
     pub async fn execute(
         self,
         queue_manager: alloc::sync::Arc<impl GenericQueueManager + Send + Sync>,
@@ -50,6 +61,15 @@ impl Commands {
                 MessageCommand::Show { format } => {
                     Self::message_show(&msg, &queue_manager, &format, &mut std::io::stdout()).await
                 }
+                MessageCommand::History { format } => {
+                    Self::message_history(
+                        &msg,
+                        queue_manager.as_ref(),
+                        format,
+                        &mut std::io::stdout(),
+                    )
+                    .await
+                }
                 MessageCommand::Move { queue } => {
                     Self::message_move(&msg, &queue, queue_manager).await
                 }
@@ -63,9 +83,106 @@ impl Commands {
                     )
                     .await
                 }
-                #[allow(clippy::unimplemented)]
-                MessageCommand::ReRun {} => unimplemented!(),
+                MessageCommand::ReRun { stage } => {
+                    Self::control(
+                        Request::ReRun {
+                            msg,
+                            stage,
+                            queue: None,
+                        },
+                        &control::socket_path(queue_manager.get_config()),
+                        &mut std::io::stdout(),
+                    )
+                    .await
+                }
+                MessageCommand::Release {
+                    quarantine,
+                    reason,
+                    operator,
+                    action,
+                } => {
+                    Self::control(
+                        Request::Release {
+                            msg,
+                            quarantine,
+                            release: Release::new(
+                                operator.unwrap_or_else(current_user),
+                                reason,
+                                action.into(),
+                            ),
+                        },
+                        &control::socket_path(queue_manager.get_config()),
+                        &mut std::io::stdout(),
+                    )
+                    .await
+                }
             },
+            Self::Select {
+                filter,
+                format,
+                dry_run,
+                command,
+            } => {
+                Self::select(
+                    &filter,
+                    format,
+                    dry_run,
+                    &command,
+                    alloc::sync::Arc::clone(&queue_manager),
+                    &control::socket_path(queue_manager.get_config()),
+                    &mut std::io::stdout(),
+                    tokio::io::stdin(),
+                )
+                .await
+            }
+            Self::Flush { queue } => {
+                Self::control(
+                    Request::Flush { queue },
+                    &control::socket_path(queue_manager.get_config()),
+                    &mut std::io::stdout(),
+                )
+                .await
+            }
+            Self::Reload => {
+                Self::control(
+                    Request::Reload,
+                    &control::socket_path(queue_manager.get_config()),
+                    &mut std::io::stdout(),
+                )
+                .await
+            }
+            Self::Sessions => {
+                Self::control(
+                    Request::Sessions,
+                    &control::socket_path(queue_manager.get_config()),
+                    &mut std::io::stdout(),
+                )
+                .await
+            }
+            Self::Counters => {
+                Self::control(
+                    Request::Counters,
+                    &control::socket_path(queue_manager.get_config()),
+                    &mut std::io::stdout(),
+                )
+                .await
+            }
+            Self::MtaSts => {
+                Self::control(
+                    Request::MtaSts,
+                    &control::socket_path(queue_manager.get_config()),
+                    &mut std::io::stdout(),
+                )
+                .await
+            }
+            Self::Purge { dry_run } => {
+                Self::purge(queue_manager.as_ref(), dry_run, &mut std::io::stdout()).await
+            }
+            Self::Migrate { from, to } => {
+                anyhow::bail!(
+                    "The migration from `{from}` to `{to}` cannot use an opened queue manager"
+                )
+            }
         }
     }
 }
@@ -0,0 +1,366 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+ */
+
+//! Control API of the running server, exposed on a Unix socket.
+//!
+//! Each request and each response is a JSON document on a single line.
+
+use crate::QueueID;
+use anyhow::Context;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+use vsmtp_config::Config;
+
+/// Path of the socket of the control API.
+#[must_use]
+#[inline]
+pub fn socket_path(config: &Config) -> std::path::PathBuf {
+    config
+        .server
+        .queues
+        .dirpath
+        .join(&config.server.interfaces.control)
+}
+
+/// Queue flushed on request.
+#[allow(clippy::exhaustive_enums)]
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize, clap::ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum FlushQueue {
+    /// Messages waiting for their first delivery attempt.
+    Deliver,
+    /// Messages waiting for a new delivery attempt.
+    Deferred,
+}
+
+/// Process a message is sent to again.
+#[allow(clippy::exhaustive_enums)]
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize, clap::ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum ReRunStage {
+    /// Run the post-queue rules again, and then deliver the message.
+    Working,
+    /// Deliver the message without running the rules.
+    Delivery,
+}
+
+/// What is done with a message released from a quarantine.
+#[allow(clippy::exhaustive_enums)]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum ReleaseAction {
+    /// Send the message to a process again.
+    Resume {
+        /// Process the message is sent to.
+        #[serde(default = "ReleaseAction::default_stage")]
+        stage: ReRunStage,
+        /// Run the rules of the stages the message goes through, instead of skipping them.
+        #[serde(default)]
+        rerun: bool,
+        /// Recipients the message is released to, all of them if empty.
+        /// The other recipients stay in quarantine.
+        #[serde(default)]
+        recipients: Vec<vsmtp_common::Address>,
+    },
+    /// Fail all the recipients, and send a bounce to the sender.
+    Reject,
+    /// Send a copy of the message to an analyst, the message stays in quarantine.
+    Forward {
+        /// Address of the analyst.
+        to: vsmtp_common::Address,
+    },
+}
+
+impl ReleaseAction {
+    const fn default_stage() -> ReRunStage {
+        ReRunStage::Delivery
+    }
+}
+
+impl core::fmt::Display for ReleaseAction {
+    #[inline]
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Resume {
+                stage,
+                rerun,
+                recipients,
+            } => {
+                write!(
+                    f,
+                    "resume in {}",
+                    match stage {
+                        ReRunStage::Working => "working",
+                        ReRunStage::Delivery => "delivery",
+                    }
+                )?;
+                if !*rerun {
+                    f.write_str(" without rules")?;
+                }
+                if !recipients.is_empty() {
+                    write!(
+                        f,
+                        " for {}",
+                        recipients
+                            .iter()
+                            .map(vsmtp_common::Address::full)
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    )?;
+                }
+                Ok(())
+            }
+            Self::Reject => f.write_str("reject"),
+            Self::Forward { to } => write!(f, "forward to {}", to.full()),
+        }
+    }
+}
+
+/// Release of a message from a quarantine.
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Release {
+    /// Who releases the message.
+    pub operator: String,
+    /// Why the message is released.
+    pub reason: String,
+    /// What is done with the message.
+    #[serde(flatten)]
+    pub action: ReleaseAction,
+}
+
+impl Release {
+    /// Create a new release.
+    #[must_use]
+    #[inline]
+    pub const fn new(operator: String, reason: String, action: ReleaseAction) -> Self {
+        Self {
+            operator,
+            reason,
+            action,
+        }
+    }
+}
+
+/// Command sent to the server.
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    /// Process the messages of the queue now, instead of waiting for the next period.
+    Flush {
+        /// Queue to flush.
+        queue: FlushQueue,
+    },
+    /// Move the message out of its queue and send it to a process again.
+    ReRun {
+        /// ID of the message.
+        msg: uuid::Uuid,
+        /// Process to send the message to.
+        stage: ReRunStage,
+        /// Queue of the message, all the queues and quarantines are searched if not set.
+        #[serde(default)]
+        queue: Option<QueueID>,
+    },
+    /// Release the message from its quarantine.
+    Release {
+        /// ID of the message.
+        msg: uuid::Uuid,
+        /// Name of the quarantine, all of them are searched if not set.
+        #[serde(default)]
+        quarantine: Option<String>,
+        /// What is done with the message.
+        release: Release,
+    },
+    /// Read the configuration file again and compile the rules.
+    Reload,
+    /// List the SMTP sessions in progress.
+    Sessions,
+    /// Read the counters of the server.
+    Counters,
+    /// List the MTA-STS policies cached by the delivery pool.
+    MtaSts,
+}
+
+/// SMTP session in progress.
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Session {
+    /// ID of the session.
+    pub uuid: uuid::Uuid,
+    /// Address of the client.
+    pub client_addr: std::net::SocketAddr,
+    /// Address of the server the client is connected to.
+    pub server_addr: std::net::SocketAddr,
+    /// Kind of connection (relay, submission, tunneled).
+    pub kind: String,
+    /// Date of the connection.
+    #[serde(with = "time::serde::iso8601")]
+    pub connected_at: time::OffsetDateTime,
+}
+
+impl Session {
+    /// Create a new session.
+    #[must_use]
+    #[inline]
+    pub const fn new(
+        uuid: uuid::Uuid,
+        client_addr: std::net::SocketAddr,
+        server_addr: std::net::SocketAddr,
+        kind: String,
+        connected_at: time::OffsetDateTime,
+    ) -> Self {
+        Self {
+            uuid,
+            client_addr,
+            server_addr,
+            kind,
+            connected_at,
+        }
+    }
+}
+
+/// Counters of the server since its start.
+#[non_exhaustive]
+#[derive(Debug, Default, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Counters {
+    /// Connections accepted.
+    pub connections: u64,
+    /// SMTP sessions in progress.
+    pub sessions: u64,
+    /// Messages accepted by the receiver.
+    pub messages_received: u64,
+    /// Messages processed by the working pool.
+    pub messages_processed: u64,
+    /// Delivery attempts of the delivery pool.
+    pub delivery_attempts: u64,
+    /// Configuration reloads succeeded.
+    pub reloads: u64,
+}
+
+/// MTA-STS policy of a destination domain, cached by the delivery pool.
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct MtaStsPolicy {
+    /// Domain publishing the policy.
+    pub domain: String,
+    /// Identifier of the policy, from the TXT record.
+    pub id: String,
+    /// Mode of the policy (enforce, testing or none).
+    pub mode: String,
+    /// Patterns of the allowed mail exchangers.
+    pub mx: Vec<String>,
+    /// Lifetime of the policy, in seconds.
+    pub max_age: u64,
+    /// Date of the fetch of the policy.
+    #[serde(with = "time::serde::iso8601")]
+    pub fetched_at: time::OffsetDateTime,
+    /// The policy has expired, and is fetched again at the next delivery to the domain.
+    pub expired: bool,
+}
+
+impl MtaStsPolicy {
+    /// Create a new cached policy.
+    #[must_use]
+    #[inline]
+    pub const fn new(
+        domain: String,
+        id: String,
+        mode: String,
+        mx: Vec<String>,
+        max_age: u64,
+        fetched_at: time::OffsetDateTime,
+        expired: bool,
+    ) -> Self {
+        Self {
+            domain,
+            id,
+            mode,
+            mx,
+            max_age,
+            fetched_at,
+            expired,
+        }
+    }
+}
+
+/// Answer of the server.
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "status", content = "content", rename_all = "snake_case")]
+pub enum Response {
+    /// The command has been executed.
+    Done,
+    /// see [`Request::Sessions`]
+    Sessions(Vec<Session>),
+    /// see [`Request::Counters`]
+    Counters(Counters),
+    /// see [`Request::MtaSts`]
+    MtaSts(Vec<MtaStsPolicy>),
+    /// The command has failed.
+    Error(String),
+}
+
+/// Connection to the control API of a running server.
+pub struct Client {
+    lines: tokio::io::Lines<tokio::io::BufReader<tokio::net::unix::OwnedReadHalf>>,
+    write: tokio::net::unix::OwnedWriteHalf,
+}
+
+impl Client {
+    /// Connect to the server listening on `path`.
+    ///
+    /// # Errors
+    ///
+    /// * the server is not running, or does not listen on this path
+    #[inline]
+    pub async fn connect(path: &std::path::Path) -> anyhow::Result<Self> {
+        let (read, write) = tokio::net::UnixStream::connect(path)
+            .await
+            .with_context(|| format!("Cannot connect to the server on '{}'", path.display()))?
+            .into_split();
+
+        Ok(Self {
+            lines: tokio::io::BufReader::new(read).lines(),
+            write,
+        })
+    }
+
+    /// Send a command and wait for its response.
+    ///
+    /// # Errors
+    ///
+    /// * the connection has been closed
+    /// * the response is invalid
+    #[inline]
+    pub async fn send(&mut self, request: &Request) -> anyhow::Result<Response> {
+        let mut line = serde_json::to_vec(request)?;
+        line.push(b'\n');
+        self.write.write_all(&line).await?;
+
+        let response = self
+            .lines
+            .next_line()
+            .await?
+            .context("The server has closed the connection")?;
+
+        Ok(serde_json::from_str(&response)?)
+    }
+}
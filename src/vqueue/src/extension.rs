@@ -14,7 +14,7 @@
  * this program. If not, see https://www.gnu.org/licenses/.
  *
  */
-use crate::{api::DetailedMailContext, GenericQueueManager, QueueID};
+use crate::{api::DetailedMailContext, history::Event, GenericQueueManager, QueueID};
 use anyhow::Context;
 use vsmtp_common::{transport::DeserializerFn, ContextFinished};
 use vsmtp_config::Config;
@@ -44,6 +44,18 @@ pub trait FilesystemQueueManagerExt {
         Self::get_root_folder(self.get_config(), queue).join(queue.to_string())
     }
 
+    /// Folder of the histories of the messages, one file per message.
+    #[inline]
+    fn get_history_path(&self) -> std::path::PathBuf {
+        self.get_config().server.queues.dirpath.join("history")
+    }
+
+    /// Folder of the bodies of the messages.
+    #[inline]
+    fn get_mails_path(&self) -> std::path::PathBuf {
+        self.get_config().server.queues.dirpath.join("mails")
+    }
+
     ///
     fn init(
         config: alloc::sync::Arc<Config>,
@@ -119,7 +131,7 @@ impl<T: FilesystemQueueManagerExt + Send + Sync + core::fmt::Debug> GenericQueue
     #[inline]
     #[tracing::instrument(skip(self))]
     async fn write_msg(&self, msg_uuid: &uuid::Uuid, msg: &MessageBody) -> anyhow::Result<()> {
-        let mails = self.get_config().server.queues.dirpath.join("mails");
+        let mails = self.get_mails_path();
         if !mails.exists() {
             std::fs::DirBuilder::new().recursive(true).create(&mails)?;
         }
@@ -168,7 +180,7 @@ impl<T: FilesystemQueueManagerExt + Send + Sync + core::fmt::Debug> GenericQueue
     #[inline]
     #[tracing::instrument(skip(self))]
     async fn remove_msg(&self, msg_uuid: &uuid::Uuid) -> anyhow::Result<()> {
-        let mails = self.get_config().server.queues.dirpath.join("mails");
+        let mails = self.get_mails_path();
 
         let mails_eml = mails.join(format!("{msg_uuid}.eml"));
         std::fs::remove_file(&mails_eml)
@@ -202,6 +214,35 @@ impl<T: FilesystemQueueManagerExt + Send + Sync + core::fmt::Debug> GenericQueue
             .collect::<Vec<Result<_, _>>>())
     }
 
+    #[inline]
+    async fn list_quarantines(&self) -> anyhow::Result<Vec<String>> {
+        let root = self.get_queue_path(&QueueID::Quarantine {
+            name: String::new(),
+        });
+        if !root.exists() {
+            return Ok(vec![]);
+        }
+
+        let mut names = vec![];
+        for entry in root
+            .read_dir()
+            .with_context(|| format!("Error from read dir '{}'", root.display()))?
+        {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                names.push(
+                    entry
+                        .file_name()
+                        .into_string()
+                        .map_err(|name| anyhow::anyhow!("Invalid quarantine name: {name:?}"))?,
+                );
+            }
+        }
+        names.sort();
+
+        Ok(names)
+    }
+
     #[inline]
     #[tracing::instrument(skip(self))]
     async fn get_ctx(
@@ -271,11 +312,7 @@ impl<T: FilesystemQueueManagerExt + Send + Sync + core::fmt::Debug> GenericQueue
     #[inline]
     #[tracing::instrument(skip(self))]
     async fn get_msg(&self, msg_uuid: &uuid::Uuid) -> anyhow::Result<MessageBody> {
-        let msg_filepath = std::path::PathBuf::from_iter([
-            self.get_config().server.queues.dirpath.clone(),
-            "mails".into(),
-            format!("{msg_uuid}.eml").into(),
-        ]);
+        let msg_filepath = self.get_mails_path().join(format!("{msg_uuid}.eml"));
 
         let content = std::fs::read_to_string(&msg_filepath)
             .with_context(|| format!("Cannot read file '{}'", msg_filepath.display()))?;
@@ -284,4 +321,93 @@ impl<T: FilesystemQueueManagerExt + Send + Sync + core::fmt::Debug> GenericQueue
 
         MessageBody::try_from(content.as_str())
     }
+
+    #[inline]
+    #[tracing::instrument(skip(self))]
+    async fn get_msg_size(&self, msg_uuid: &uuid::Uuid) -> anyhow::Result<usize> {
+        let msg_filepath = self.get_mails_path().join(format!("{msg_uuid}.eml"));
+
+        let metadata = std::fs::metadata(&msg_filepath)
+            .with_context(|| format!("Cannot read file '{}'", msg_filepath.display()))?;
+
+        Ok(usize::try_from(metadata.len())?)
+    }
+
+    #[inline]
+    #[tracing::instrument(skip(self, events))]
+    async fn append_history(&self, msg_uuid: &uuid::Uuid, events: &[Event]) -> anyhow::Result<()> {
+        let history = self.get_history_path();
+        if !history.exists() {
+            std::fs::DirBuilder::new()
+                .recursive(true)
+                .create(&history)?;
+        }
+
+        let mut lines = vec![];
+        for event in events {
+            serde_json::to_writer(&mut lines, event)?;
+            lines.push(b'\n');
+        }
+
+        let filepath = history.join(format!("{msg_uuid}.jsonl"));
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&filepath)
+            .with_context(|| format!("Cannot open file '{}'", filepath.display()))?;
+        std::io::Write::write_all(&mut file, &lines)?;
+
+        Ok(())
+    }
+
+    #[inline]
+    #[tracing::instrument(skip(self))]
+    async fn get_history(&self, msg_uuid: &uuid::Uuid) -> anyhow::Result<Vec<Event>> {
+        let filepath = self.get_history_path().join(format!("{msg_uuid}.jsonl"));
+        let content = std::fs::read_to_string(&filepath)
+            .with_context(|| format!("Cannot read file '{}'", filepath.display()))?;
+
+        content
+            .lines()
+            .map(|line| {
+                serde_json::from_str(line)
+                    .with_context(|| format!("Cannot deserialize at '{}'", filepath.display()))
+            })
+            .collect()
+    }
+
+    #[inline]
+    async fn list_history(&self) -> anyhow::Result<Vec<(uuid::Uuid, time::OffsetDateTime)>> {
+        let history = self.get_history_path();
+        if !history.exists() {
+            return Ok(vec![]);
+        }
+
+        let mut all = vec![];
+        for entry in history
+            .read_dir()
+            .with_context(|| format!("Error from read dir '{}'", history.display()))?
+        {
+            let entry = entry?;
+            let Some(msg_uuid) = entry
+                .path()
+                .file_stem()
+                .and_then(std::ffi::OsStr::to_str)
+                .and_then(|name| uuid::Uuid::parse_str(name).ok())
+            else {
+                continue;
+            };
+            all.push((msg_uuid, entry.metadata()?.modified()?.into()));
+        }
+
+        Ok(all)
+    }
+
+    #[inline]
+    #[tracing::instrument(skip(self))]
+    async fn remove_history(&self, msg_uuid: &uuid::Uuid) -> anyhow::Result<()> {
+        let filepath = self.get_history_path().join(format!("{msg_uuid}.jsonl"));
+        std::fs::remove_file(&filepath)
+            .with_context(|| format!("failed to remove `{}`", filepath.display()))
+    }
 }
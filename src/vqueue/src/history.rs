@@ -0,0 +1,393 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+ */
+
+//! Journal of the events of a message, from its reception to its final outcome.
+//!
+//! The events are only appended to the history of a message, which is stored next to
+//! the queues and kept after the message has left them, until it expires
+//! (see `server.queues.retention.history`).
+
+use crate::GenericQueueManager;
+use vsmtp_common::{status, transfer::Status, Address, ContextFinished, Target};
+
+/// Something that happened to a message.
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Event {
+    /// Date of the event.
+    #[serde(with = "time::serde::iso8601")]
+    pub timestamp: time::OffsetDateTime,
+    /// What happened.
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+impl Event {
+    /// Create an event happening now.
+    #[must_use]
+    #[inline]
+    pub fn now(kind: EventKind) -> Self {
+        Self {
+            timestamp: time::OffsetDateTime::now_utc(),
+            kind,
+        }
+    }
+}
+
+impl core::fmt::Display for Event {
+    #[inline]
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let timestamp = self
+            .timestamp
+            .format(&time::format_description::well_known::Rfc3339)
+            .map_err(|_err| core::fmt::Error)?;
+        write!(f, "{timestamp} {}", self.kind)
+    }
+}
+
+/// Final outcome of a message.
+#[allow(clippy::exhaustive_enums)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// The message has been sent to all its recipients.
+    Delivered,
+    /// The message has not been sent to all its recipients, and is moved to the `dead` queue.
+    Dead,
+    /// The message has been removed by the retention policy of its queue.
+    Expired,
+}
+
+/// Outcome of a delivery attempt for a recipient.
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Attempt {
+    /// The recipient.
+    pub rcpt: Address,
+    /// Status of the recipient after the attempt (`sent`, `held_back` or `failed`).
+    pub status: String,
+    /// Server which accepted the message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<Target>,
+    /// The connection to the server was encrypted with TLS.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<bool>,
+    /// Reply of the server to the message content.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply: Option<String>,
+    /// Why the message has not been sent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Attempt {
+    /// Outcome of the attempt for `rcpt`, with its status after the attempt.
+    #[must_use]
+    #[inline]
+    pub fn new(rcpt: Address, status: &Status) -> Self {
+        #[allow(clippy::wildcard_enum_match_arm)]
+        let (status, receipt, error) = match status {
+            Status::Sent { receipt, .. } => ("sent", receipt.as_ref(), None),
+            Status::HeldBack { errors } => (
+                "held_back",
+                None,
+                errors.last().map(|error| error.variant().to_string()),
+            ),
+            Status::Failed { error } => ("failed", None, Some(error.variant().to_string())),
+            _ => ("waiting", None, None),
+        };
+        Self {
+            rcpt,
+            status: status.to_owned(),
+            target: receipt.map(|receipt| receipt.target.clone()),
+            tls: receipt.map(|receipt| receipt.tls),
+            reply: receipt.map(|receipt| receipt.reply.clone()),
+            error,
+        }
+    }
+}
+
+impl core::fmt::Display for Attempt {
+    #[inline]
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "<{}> {}", self.rcpt.full(), self.status)?;
+        if let Some(target) = &self.target {
+            write!(f, " by {target}")?;
+        }
+        match self.tls {
+            Some(true) => f.write_str(" with tls")?,
+            Some(false) => f.write_str(" without tls")?,
+            None => (),
+        }
+        if let Some(reply) = &self.reply {
+            write!(f, ": {reply}")?;
+        }
+        if let Some(error) = &self.error {
+            write!(f, ": {error}")?;
+        }
+        Ok(())
+    }
+}
+
+/// What happened to a message.
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EventKind {
+    /// The message has been received by the server.
+    Received {
+        /// Address of the client.
+        client_addr: std::net::SocketAddr,
+        /// Address of the server the client is connected to.
+        server_addr: std::net::SocketAddr,
+        /// Name given by the client in `HELO`/`EHLO`.
+        client_name: String,
+        /// Protocol and cipher suite of the TLS session.
+        tls: Option<String>,
+        /// Sender of the envelop.
+        reverse_path: Option<Address>,
+        /// Recipients of the envelop.
+        recipients: Vec<Address>,
+    },
+    /// The rules of a stage have been run.
+    Rules {
+        /// The stage.
+        stage: String,
+        /// Status returned by the rules.
+        status: String,
+    },
+    /// The message has been written in a queue.
+    Queued {
+        /// The queue.
+        queue: String,
+    },
+    /// The message has been moved to another queue.
+    Moved {
+        /// Queue the message was in.
+        from: String,
+        /// Queue the message is now in.
+        to: String,
+    },
+    /// The message has been released from a quarantine.
+    Released {
+        /// The quarantine.
+        quarantine: String,
+        /// Who released the message.
+        operator: String,
+        /// What has been done with the message.
+        action: String,
+    },
+    /// The message has been sent to its recipients.
+    Delivery {
+        /// Outcome of the attempt for each recipient.
+        recipients: Vec<Attempt>,
+    },
+    /// The message has left the queues.
+    Finished {
+        /// Final outcome of the message.
+        outcome: Outcome,
+    },
+}
+
+impl EventKind {
+    /// Reception of the message described by its context.
+    #[must_use]
+    #[inline]
+    pub fn received(ctx: &ContextFinished) -> Self {
+        Self::Received {
+            client_addr: ctx.connect.client_addr,
+            server_addr: ctx.connect.server_addr,
+            client_name: ctx.helo.client_name.to_string(),
+            tls: ctx
+                .connect
+                .tls
+                .as_ref()
+                .map(|tls| format!("{} {}", tls.protocol_version, tls.cipher_suite)),
+            reverse_path: ctx.mail_from.reverse_path.clone(),
+            recipients: ctx.rcpt_to.forward_paths.clone(),
+        }
+    }
+
+    /// Result of the rules of `stage`.
+    #[must_use]
+    #[inline]
+    pub fn rules(stage: impl core::fmt::Display, status: &status::Status) -> Self {
+        Self::Rules {
+            stage: stage.to_string(),
+            status: match status {
+                status::Status::Accept(reply)
+                | status::Status::Deny(reply)
+                | status::Status::Faccept(reply) => {
+                    format!("{} {}", status.as_ref(), reply.as_ref().trim_end())
+                }
+                status::Status::Quarantine(name) => format!("{} {name}", status.as_ref()),
+                status::Status::Next
+                | status::Status::Delegated(_)
+                | status::Status::DelegationResult => status.as_ref().to_owned(),
+            },
+        }
+    }
+}
+
+impl core::fmt::Display for EventKind {
+    #[inline]
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Received {
+                client_addr,
+                server_addr,
+                client_name,
+                tls,
+                reverse_path,
+                recipients,
+            } => {
+                write!(
+                    f,
+                    "received from {client_name} ({client_addr}) on {server_addr}"
+                )?;
+                if let Some(tls) = tls {
+                    write!(f, " with {tls}")?;
+                }
+                write!(
+                    f,
+                    ", from <{}> to {}",
+                    reverse_path.as_ref().map_or("", Address::full),
+                    recipients
+                        .iter()
+                        .map(|rcpt| format!("<{}>", rcpt.full()))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            }
+            Self::Rules { stage, status } => write!(f, "rules of {stage}: {status}"),
+            Self::Queued { queue } => write!(f, "queued in {queue}"),
+            Self::Moved { from, to } => write!(f, "moved from {from} to {to}"),
+            Self::Released {
+                quarantine,
+                operator,
+                action,
+            } => write!(f, "released from {quarantine} by {operator}: {action}"),
+            Self::Delivery { recipients } => {
+                f.write_str("delivery attempt")?;
+                for rcpt in recipients {
+                    write!(f, "\n  {rcpt}")?;
+                }
+                Ok(())
+            }
+            Self::Finished { outcome } => write!(
+                f,
+                "finished: {}",
+                match outcome {
+                    Outcome::Delivered => "delivered",
+                    Outcome::Dead => "dead",
+                    Outcome::Expired => "expired",
+                }
+            ),
+        }
+    }
+}
+
+/// Append the events to the history of the message.
+///
+/// The history is informative: a failure is logged, and does not stop the processing
+/// of the message.
+#[inline]
+pub async fn record<Q: GenericQueueManager + ?Sized>(
+    queue_manager: &Q,
+    msg_uuid: &uuid::Uuid,
+    events: &[Event],
+) {
+    if let Err(error) = queue_manager.append_history(msg_uuid, events).await {
+        tracing::warn!(%error, uuid = %msg_uuid, "Cannot write the history of the message.");
+    }
+}
+
+/// Remove the histories whose last event is older than `server.queues.retention.history`,
+/// and return the IDs of their messages.
+///
+/// If `dry_run` is set, the histories are returned but not removed.
+///
+/// # Errors
+///
+/// * the histories cannot be listed
+#[inline]
+pub async fn expire<Q: GenericQueueManager + ?Sized>(
+    queue_manager: &Q,
+    now: time::OffsetDateTime,
+    dry_run: bool,
+) -> anyhow::Result<Vec<uuid::Uuid>> {
+    let Some(max_age) = queue_manager.get_config().server.queues.retention.history else {
+        return Ok(vec![]);
+    };
+
+    let mut expired = vec![];
+    for (msg_uuid, last_event) in queue_manager.list_history().await? {
+        if now - last_event <= max_age {
+            continue;
+        }
+        if !dry_run {
+            if let Err(error) = queue_manager.remove_history(&msg_uuid).await {
+                tracing::error!(%error, uuid = %msg_uuid, "Cannot remove the expired history.");
+                continue;
+            }
+        }
+        expired.push(msg_uuid);
+    }
+    Ok(expired)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vsmtp_common::transfer::{error::Queuer, Receipt};
+
+    #[test]
+    fn display() {
+        let event = Event {
+            timestamp: time::macros::datetime!(2023-01-01 12:00:00 UTC),
+            kind: EventKind::Delivery {
+                recipients: vec![
+                    Attempt::new(
+                        "a@example.com".parse().unwrap(),
+                        &Status::sent_with(Receipt::new(
+                            "mx.example.com".parse().unwrap(),
+                            true,
+                            "250 2.0.0 Ok: queued".to_owned(),
+                        )),
+                    ),
+                    Attempt::new(
+                        "b@example.com".parse().unwrap(),
+                        &Status::failed(Queuer::MaxDeferredAttemptReached),
+                    ),
+                ],
+            },
+        };
+
+        assert_eq!(
+            event.to_string(),
+            [
+                "2023-01-01T12:00:00Z delivery attempt",
+                "  <a@example.com> sent by mx.example.com with tls: 250 2.0.0 Ok: queued",
+                "  <b@example.com> failed: <queuer>: max deferred attempt reached",
+            ]
+            .join("\n")
+        );
+
+        let json = serde_json::to_string(&event).unwrap();
+        assert_eq!(serde_json::from_str::<Event>(&json).unwrap(), event);
+    }
+}
@@ -0,0 +1,750 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+ */
+use crate::{
+    api::DetailedMailContext,
+    history::{self, Event, EventKind},
+    GenericQueueManager, QueueID,
+};
+use anyhow::Context;
+use vsmtp_common::{transport::DeserializerFn, ContextFinished};
+use vsmtp_config::Config;
+use vsmtp_mail_parser::MessageBody;
+
+extern crate alloc;
+
+/// Name of the database file, in the queues directory.
+pub const DATABASE: &str = "queues.db";
+
+/// Maximum size of the database, the file grows up to it as the queues are filled.
+const MAP_SIZE: usize = 1 << 36;
+
+/// All the entries of the queues, with their indexes, ordered by key.
+type Entries = heed::Database<heed::types::Str, heed::types::Bytes>;
+
+/// The LMDB environment holding the queues, shared by the processes opening it.
+struct Store {
+    env: heed::Env,
+    entries: Entries,
+}
+
+impl Store {
+    fn open(path: &std::path::Path) -> anyhow::Result<Self> {
+        let mut options = heed::EnvOpenOptions::new();
+        options.map_size(MAP_SIZE);
+        #[allow(unsafe_code)]
+        // SAFETY: `NO_SUB_DIR` is not one of the unsafe flags.
+        unsafe {
+            options.flags(heed::EnvFlags::NO_SUB_DIR);
+        }
+        #[allow(unsafe_code)]
+        // SAFETY: the file is only modified through LMDB, which synchronizes
+        // the processes sharing it.
+        let env = unsafe { options.open(path) }
+            .with_context(|| format!("Cannot open the queues database at '{}'", path.display()))?;
+
+        let mut tx = env.write_txn()?;
+        let entries = env.create_database(&mut tx, None)?;
+        tx.commit()?;
+
+        Ok(Self { env, entries })
+    }
+
+    fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let tx = self.env.read_txn()?;
+        Ok(self.entries.get(&tx, key)?.map(<[u8]>::to_vec))
+    }
+
+    /// Get the keys starting with `prefix`, in order.
+    fn keys(&self, prefix: &str) -> anyhow::Result<Vec<String>> {
+        let tx = self.env.read_txn()?;
+        let keys = self
+            .entries
+            .prefix_iter(&tx, prefix)?
+            .map(|entry| Ok(entry?.0.to_owned()))
+            .collect();
+        keys
+    }
+
+    /// Run `f` in a write transaction, committed if `f` succeeds.
+    ///
+    /// The transactions of all the processes are serialized by LMDB.
+    fn update<T>(
+        &self,
+        f: impl FnOnce(Entries, &mut heed::RwTxn<'_>) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let mut tx = self.env.write_txn()?;
+        let out = f(self.entries, &mut tx)?;
+        tx.commit()?;
+        Ok(out)
+    }
+}
+
+///
+pub struct QueueManager {
+    config: alloc::sync::Arc<Config>,
+    transport_deserializer: Vec<DeserializerFn>,
+    store: alloc::sync::Arc<Store>,
+}
+
+impl core::fmt::Debug for QueueManager {
+    #[inline]
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("EmbeddedQueueManager")
+            .finish_non_exhaustive()
+    }
+}
+
+fn ctx_key(queue: &QueueID, msg_uuid: &uuid::Uuid) -> String {
+    format!("ctx/{queue}/{msg_uuid}")
+}
+
+fn refs_key(queue: &QueueID, msg_uuid: &uuid::Uuid) -> String {
+    format!("refs/{queue}/{msg_uuid}")
+}
+
+fn msg_key(msg_uuid: &uuid::Uuid) -> String {
+    format!("msg/{msg_uuid}")
+}
+
+fn size_key(msg_uuid: &uuid::Uuid) -> String {
+    format!("size/{msg_uuid}")
+}
+
+fn history_key(msg_uuid: &uuid::Uuid) -> String {
+    format!("history/{msg_uuid}")
+}
+
+fn retry_key(at: time::OffsetDateTime, msg_uuid: &uuid::Uuid) -> String {
+    format!("retry/{:020}/{msg_uuid}", at.unix_timestamp().max(0))
+}
+
+fn domain_key(domain: &str, queue: &QueueID, msg_uuid: &uuid::Uuid) -> String {
+    format!("domain/{domain}/{queue}/{msg_uuid}")
+}
+
+/// A context serialized with its indexes, ready to be written.
+struct CtxEntry {
+    queue: QueueID,
+    msg_uuid: uuid::Uuid,
+    value: Vec<u8>,
+    index_keys: Vec<String>,
+}
+
+impl CtxEntry {
+    fn put(self, entries: Entries, tx: &mut heed::RwTxn<'_>) -> anyhow::Result<()> {
+        delete_ctx(entries, tx, &self.queue, &self.msg_uuid)?;
+
+        entries.put(tx, &ctx_key(&self.queue, &self.msg_uuid), &self.value)?;
+        for key in &self.index_keys {
+            entries.put(tx, key, &[])?;
+        }
+        entries.put(
+            tx,
+            &refs_key(&self.queue, &self.msg_uuid),
+            self.index_keys.join("\n").as_bytes(),
+        )?;
+
+        Ok(())
+    }
+}
+
+/// Delete the context and its indexes, returns false if there was no context.
+fn delete_ctx(
+    entries: Entries,
+    tx: &mut heed::RwTxn<'_>,
+    queue: &QueueID,
+    msg_uuid: &uuid::Uuid,
+) -> anyhow::Result<bool> {
+    if !entries.delete(tx, &ctx_key(queue, msg_uuid))? {
+        return Ok(false);
+    }
+
+    let refs = refs_key(queue, msg_uuid);
+    if let Some(index_keys) = entries.get(tx, &refs)? {
+        let index_keys = String::from_utf8(index_keys.to_vec())?;
+        for index_key in index_keys.lines() {
+            entries.delete(tx, index_key)?;
+        }
+        entries.delete(tx, &refs)?;
+    }
+
+    Ok(true)
+}
+
+/// Write the message and its size, read without loading the message.
+fn put_msg(
+    entries: Entries,
+    tx: &mut heed::RwTxn<'_>,
+    msg_uuid: &uuid::Uuid,
+    value: &str,
+) -> anyhow::Result<()> {
+    entries.put(tx, &msg_key(msg_uuid), value.as_bytes())?;
+    entries.put(
+        tx,
+        &size_key(msg_uuid),
+        &u64::try_from(value.len())?.to_le_bytes(),
+    )?;
+    Ok(())
+}
+
+/// Delete the message and its size, returns false if there was no message.
+fn delete_msg(
+    entries: Entries,
+    tx: &mut heed::RwTxn<'_>,
+    msg_uuid: &uuid::Uuid,
+) -> anyhow::Result<bool> {
+    entries.delete(tx, &size_key(msg_uuid))?;
+    Ok(entries.delete(tx, &msg_key(msg_uuid))?)
+}
+
+impl QueueManager {
+    /// Run `f` on the store in the blocking thread pool, as it waits for the other writers and the disk.
+    async fn blocking<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&Store) -> anyhow::Result<T> + Send + 'static,
+    ) -> anyhow::Result<T> {
+        let store = alloc::sync::Arc::clone(&self.store);
+        tokio::task::spawn_blocking(move || f(&store)).await?
+    }
+
+    /// Keys of the indexes referencing the context.
+    fn index_keys(&self, queue: &QueueID, ctx: &ContextFinished) -> Vec<String> {
+        let msg_uuid = &ctx.mail_from.message_uuid;
+
+        let mut domains = ctx
+            .rcpt_to
+            .forward_paths
+            .iter()
+            .chain(
+                ctx.rcpt_to
+                    .delivery
+                    .values()
+                    .flatten()
+                    .map(|(addr, _)| addr),
+            )
+            .map(|addr| addr.domain().to_string().to_lowercase())
+            .collect::<Vec<_>>();
+        domains.sort();
+        domains.dedup();
+
+        let mut keys = domains
+            .iter()
+            .map(|domain| domain_key(domain, queue, msg_uuid))
+            .collect::<Vec<_>>();
+
+        if *queue == QueueID::Deferred {
+            keys.push(retry_key(
+                self.config.server.queues.delivery.retry.next_flush(ctx),
+                msg_uuid,
+            ));
+        }
+
+        keys
+    }
+
+    fn ctx_entry(&self, queue: &QueueID, ctx: &ContextFinished) -> anyhow::Result<CtxEntry> {
+        #[allow(clippy::cast_possible_truncation, clippy::as_conversions)]
+        let modified_at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let mut value = modified_at.to_le_bytes().to_vec();
+        serde_json::to_writer(&mut value, ctx).context("failed to write context")?;
+
+        Ok(CtxEntry {
+            queue: queue.clone(),
+            msg_uuid: ctx.mail_from.message_uuid,
+            value,
+            index_keys: self.index_keys(queue, ctx),
+        })
+    }
+
+    async fn read_ctx(
+        &self,
+        queue: &QueueID,
+        msg_uuid: &uuid::Uuid,
+    ) -> anyhow::Result<DetailedMailContext> {
+        let key = ctx_key(queue, msg_uuid);
+        let value = self
+            .blocking(move |store| store.get(&key))
+            .await?
+            .with_context(|| format!("Cannot find the context of `{msg_uuid}` in `{queue}`"))?;
+        anyhow::ensure!(
+            value.len() >= 8,
+            "Invalid context of `{msg_uuid}` in `{queue}`"
+        );
+        let (modified_at, ctx) = value.split_at(8);
+
+        let mut deserialized: ContextFinished = serde_json::from_slice(ctx)
+            .with_context(|| format!("Cannot deserialize `{msg_uuid}` in `{queue}`"))?;
+
+        deserialized.rcpt_to.delivery = deserialized
+            .rcpt_to
+            .delivery
+            .into_iter()
+            .map(|(transport, rcpt)| {
+                transport
+                    .to_ready(&self.transport_deserializer)
+                    .map(|t| (t, rcpt))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(DetailedMailContext {
+            ctx: deserialized,
+            modified_at: std::time::UNIX_EPOCH
+                + core::time::Duration::from_millis(u64::from_le_bytes(modified_at.try_into()?)),
+        })
+    }
+
+    /// Get the messages of the deferred queue due for a delivery attempt at `until`.
+    ///
+    /// # Errors
+    ///
+    /// * the database cannot be read
+    #[inline]
+    pub async fn due(&self, until: time::OffsetDateTime) -> anyhow::Result<Vec<uuid::Uuid>> {
+        let until = retry_key(until, &uuid::Uuid::from_u128(u128::MAX));
+        Ok(self
+            .blocking(|store| store.keys("retry/"))
+            .await?
+            .into_iter()
+            .take_while(|key| *key <= until)
+            .filter_map(|key| uuid::Uuid::parse_str(key.rsplit('/').next()?).ok())
+            .collect())
+    }
+
+    /// Get the messages with a recipient at `domain`, and their queue.
+    ///
+    /// # Errors
+    ///
+    /// * the database cannot be read
+    #[inline]
+    pub async fn by_domain(&self, domain: &str) -> anyhow::Result<Vec<(QueueID, uuid::Uuid)>> {
+        let prefix = format!("domain/{}/", domain.to_lowercase());
+        let keys = {
+            let prefix = prefix.clone();
+            self.blocking(move |store| store.keys(&prefix)).await?
+        };
+        Ok(keys
+            .into_iter()
+            .filter_map(|key| {
+                let (queue, msg_uuid) = key.strip_prefix(&prefix)?.rsplit_once('/')?;
+                let queue = match queue.split_once('/') {
+                    Some(("quarantine", name)) => QueueID::Quarantine {
+                        name: name.to_owned(),
+                    },
+                    _ => queue.parse().ok()?,
+                };
+                Some((queue, uuid::Uuid::parse_str(msg_uuid).ok()?))
+            })
+            .collect())
+    }
+}
+
+#[allow(clippy::missing_trait_methods)]
+#[async_trait::async_trait]
+impl GenericQueueManager for QueueManager {
+    #[inline]
+    fn init(
+        config: alloc::sync::Arc<Config>,
+        transport_deserializer: Vec<DeserializerFn>,
+    ) -> anyhow::Result<alloc::sync::Arc<Self>> {
+        let dirpath = &config.server.queues.dirpath;
+        std::fs::create_dir_all(dirpath).with_context(|| {
+            format!(
+                "could not create queues directory at `{}`",
+                dirpath.display()
+            )
+        })?;
+        let store = alloc::sync::Arc::new(Store::open(&dirpath.join(DATABASE))?);
+
+        Ok(alloc::sync::Arc::new(Self {
+            config,
+            transport_deserializer,
+            store,
+        }))
+    }
+
+    #[inline]
+    fn get_config(&self) -> &Config {
+        &self.config
+    }
+
+    #[inline]
+    fn get_transport_deserializer(&self) -> &[DeserializerFn] {
+        &self.transport_deserializer
+    }
+
+    #[inline]
+    #[tracing::instrument(skip(self))]
+    async fn write_ctx(&self, queue: &QueueID, ctx: &ContextFinished) -> anyhow::Result<()> {
+        let entry = self.ctx_entry(queue, ctx)?;
+        self.blocking(|store| store.update(|entries, tx| entry.put(entries, tx)))
+            .await?;
+
+        tracing::debug!(to = %queue, "Email context written.");
+        Ok(())
+    }
+
+    #[inline]
+    #[tracing::instrument(skip(self))]
+    async fn write_msg(&self, msg_uuid: &uuid::Uuid, msg: &MessageBody) -> anyhow::Result<()> {
+        let (msg_uuid, value) = (*msg_uuid, msg.inner().to_string());
+        self.blocking(move |store| {
+            store.update(|entries, tx| put_msg(entries, tx, &msg_uuid, &value))
+        })
+        .await?;
+
+        tracing::debug!("Email written.");
+        Ok(())
+    }
+
+    #[inline]
+    #[tracing::instrument(skip(self))]
+    async fn write_both(
+        &self,
+        queue: &QueueID,
+        ctx: &ContextFinished,
+        msg: &MessageBody,
+    ) -> anyhow::Result<()> {
+        let entry = self.ctx_entry(queue, ctx)?;
+        let value = msg.inner().to_string();
+        self.blocking(move |store| {
+            store.update(|entries, tx| {
+                put_msg(entries, tx, &entry.msg_uuid, &value)?;
+                entry.put(entries, tx)
+            })
+        })
+        .await?;
+
+        tracing::debug!(to = %queue, "Email context and message written.");
+        Ok(())
+    }
+
+    #[inline]
+    #[tracing::instrument(skip(self))]
+    async fn remove_ctx(&self, queue: &QueueID, msg_uuid: &uuid::Uuid) -> anyhow::Result<()> {
+        self.blocking({
+            let (queue, msg_uuid) = (queue.clone(), *msg_uuid);
+            move |store| {
+                store.update(|entries, tx| {
+                    anyhow::ensure!(
+                        delete_ctx(entries, tx, &queue, &msg_uuid)?,
+                        "failed to remove `{msg_uuid}` from `{queue}`: not found"
+                    );
+                    Ok(())
+                })
+            }
+        })
+        .await?;
+
+        tracing::debug!(from = %queue, "Email context removed.");
+        Ok(())
+    }
+
+    #[inline]
+    #[tracing::instrument(skip(self))]
+    async fn remove_msg(&self, msg_uuid: &uuid::Uuid) -> anyhow::Result<()> {
+        self.blocking({
+            let msg_uuid = *msg_uuid;
+            move |store| {
+                store.update(|entries, tx| {
+                    anyhow::ensure!(
+                        delete_msg(entries, tx, &msg_uuid)?,
+                        "failed to remove message `{msg_uuid}`: not found"
+                    );
+                    Ok(())
+                })
+            }
+        })
+        .await?;
+
+        tracing::debug!("Email removed.");
+        Ok(())
+    }
+
+    #[inline]
+    #[tracing::instrument(skip(self))]
+    async fn remove_both(&self, queue: &QueueID, msg_uuid: &uuid::Uuid) -> anyhow::Result<()> {
+        self.blocking({
+            let (queue, msg_uuid) = (queue.clone(), *msg_uuid);
+            move |store| {
+                store.update(|entries, tx| {
+                    anyhow::ensure!(
+                        delete_ctx(entries, tx, &queue, &msg_uuid)?,
+                        "failed to remove `{msg_uuid}` from `{queue}`: not found"
+                    );
+                    anyhow::ensure!(
+                        delete_msg(entries, tx, &msg_uuid)?,
+                        "failed to remove message `{msg_uuid}`: not found"
+                    );
+                    Ok(())
+                })
+            }
+        })
+        .await?;
+
+        tracing::debug!(from = %queue, "Email context and message removed.");
+        Ok(())
+    }
+
+    #[inline]
+    async fn list(&self, queue: &QueueID) -> anyhow::Result<Vec<anyhow::Result<String>>> {
+        let prefix = format!("ctx/{queue}/");
+        let keys = {
+            let prefix = prefix.clone();
+            self.blocking(move |store| store.keys(&prefix)).await?
+        };
+        Ok(keys
+            .into_iter()
+            .filter_map(|key| {
+                let msg_uuid = key.get(prefix.len()..)?;
+                // contexts of a nested quarantine
+                if msg_uuid.contains('/') {
+                    return None;
+                }
+                Some(Ok(msg_uuid.to_owned()))
+            })
+            .collect())
+    }
+
+    #[inline]
+    async fn list_quarantines(&self) -> anyhow::Result<Vec<String>> {
+        let prefix = "ctx/quarantine/";
+        let mut names = self
+            .blocking(|store| store.keys(prefix))
+            .await?
+            .into_iter()
+            .map(|key| {
+                key.get(prefix.len()..)
+                    .and_then(|key| key.rsplit_once('/'))
+                    .map(|(name, _)| name.to_owned())
+                    .with_context(|| format!("Invalid key in the queues database: `{key}`"))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        names.sort();
+        names.dedup();
+
+        Ok(names)
+    }
+
+    #[inline]
+    #[tracing::instrument(skip(self))]
+    async fn get_ctx(
+        &self,
+        queue: &QueueID,
+        msg_uuid: &uuid::Uuid,
+    ) -> anyhow::Result<ContextFinished> {
+        self.read_ctx(queue, msg_uuid)
+            .await
+            .map(|detailed| detailed.ctx)
+    }
+
+    #[inline]
+    #[tracing::instrument(skip(self))]
+    async fn get_detailed_ctx(
+        &self,
+        queue: &QueueID,
+        msg_uuid: &uuid::Uuid,
+    ) -> anyhow::Result<DetailedMailContext> {
+        self.read_ctx(queue, msg_uuid).await
+    }
+
+    #[inline]
+    #[tracing::instrument(skip(self))]
+    async fn get_msg(&self, msg_uuid: &uuid::Uuid) -> anyhow::Result<MessageBody> {
+        let key = msg_key(msg_uuid);
+        let content = self
+            .blocking(move |store| store.get(&key))
+            .await?
+            .with_context(|| format!("Cannot find the message `{msg_uuid}`"))?;
+
+        MessageBody::try_from(String::from_utf8(content)?.as_str())
+    }
+
+    #[inline]
+    async fn get_msg_size(&self, msg_uuid: &uuid::Uuid) -> anyhow::Result<usize> {
+        let key = size_key(msg_uuid);
+        let size = self
+            .blocking(move |store| store.get(&key))
+            .await?
+            .with_context(|| format!("Cannot find the message `{msg_uuid}`"))?;
+
+        Ok(usize::try_from(u64::from_le_bytes(
+            size.as_slice()
+                .try_into()
+                .with_context(|| format!("Invalid size of the message `{msg_uuid}`"))?,
+        ))?)
+    }
+
+    #[inline]
+    async fn move_to(
+        &self,
+        before: &QueueID,
+        after: &QueueID,
+        ctx: &ContextFinished,
+    ) -> anyhow::Result<()> {
+        anyhow::ensure!(before != after, "Queues are the same: `{before}`");
+
+        tracing::debug!(from = %before, to = %after, "Moving email.");
+
+        let msg_uuid = &ctx.mail_from.message_uuid;
+        let entry = self.ctx_entry(after, ctx)?;
+        self.blocking({
+            let (before, msg_uuid) = (before.clone(), *msg_uuid);
+            move |store| {
+                store.update(|entries, tx| {
+                    entry.put(entries, tx)?;
+                    anyhow::ensure!(
+                        delete_ctx(entries, tx, &before, &msg_uuid)?,
+                        "failed to remove `{msg_uuid}` from `{before}`: not found"
+                    );
+                    Ok(())
+                })
+            }
+        })
+        .await?;
+
+        history::record(
+            self,
+            msg_uuid,
+            &[Event::now(EventKind::Moved {
+                from: before.to_string(),
+                to: after.to_string(),
+            })],
+        )
+        .await;
+
+        Ok(())
+    }
+
+    #[inline]
+    #[tracing::instrument(skip(self, events))]
+    async fn append_history(&self, msg_uuid: &uuid::Uuid, events: &[Event]) -> anyhow::Result<()> {
+        let key = history_key(msg_uuid);
+        let mut appended = vec![];
+        for event in events {
+            serde_json::to_writer(&mut appended, event)?;
+            appended.push(b'\n');
+        }
+        self.blocking(move |store| {
+            store.update(|entries, tx| {
+                let mut lines = entries.get(tx, &key)?.unwrap_or_default().to_vec();
+                lines.extend(appended);
+                Ok(entries.put(tx, &key, &lines)?)
+            })
+        })
+        .await
+    }
+
+    #[inline]
+    #[tracing::instrument(skip(self))]
+    async fn get_history(&self, msg_uuid: &uuid::Uuid) -> anyhow::Result<Vec<Event>> {
+        let key = history_key(msg_uuid);
+        let content = self
+            .blocking(move |store| store.get(&key))
+            .await?
+            .with_context(|| format!("Cannot find the history of `{msg_uuid}`"))?;
+
+        String::from_utf8(content)?
+            .lines()
+            .map(|line| {
+                serde_json::from_str(line)
+                    .with_context(|| format!("Cannot deserialize the history of `{msg_uuid}`"))
+            })
+            .collect()
+    }
+
+    #[inline]
+    async fn list_history(&self) -> anyhow::Result<Vec<(uuid::Uuid, time::OffsetDateTime)>> {
+        let prefix = "history/";
+        let mut all = vec![];
+        for key in self.blocking(|store| store.keys(prefix)).await? {
+            let Some(msg_uuid) = key
+                .get(prefix.len()..)
+                .and_then(|msg_uuid| uuid::Uuid::parse_str(msg_uuid).ok())
+            else {
+                continue;
+            };
+            if let Some(last) = self.get_history(&msg_uuid).await?.last() {
+                all.push((msg_uuid, last.timestamp));
+            }
+        }
+        Ok(all)
+    }
+
+    #[inline]
+    #[tracing::instrument(skip(self))]
+    async fn remove_history(&self, msg_uuid: &uuid::Uuid) -> anyhow::Result<()> {
+        self.blocking({
+            let msg_uuid = *msg_uuid;
+            move |store| {
+                store.update(|entries, tx| {
+                    anyhow::ensure!(
+                        entries.delete(tx, &history_key(&msg_uuid))?,
+                        "failed to remove the history of `{msg_uuid}`: not found"
+                    );
+                    Ok(())
+                })
+            }
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn not_a_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(DATABASE);
+        std::fs::write(&path, "not a database\n".repeat(512)).unwrap();
+
+        assert!(Store::open(&path).is_err());
+    }
+
+    #[test]
+    fn prefix_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(&dir.path().join(DATABASE)).unwrap();
+
+        store
+            .update(|entries, tx| {
+                for key in ["ctx/deferred/b", "ctx/deliver/a", "ctx/deferred/a", "msg/a"] {
+                    entries.put(tx, key, b"")?;
+                }
+                Ok(())
+            })
+            .unwrap();
+        // nothing is committed if the transaction fails.
+        store
+            .update(|entries, tx| -> anyhow::Result<()> {
+                entries.put(tx, "ctx/deferred/c", b"")?;
+                anyhow::bail!("abort")
+            })
+            .unwrap_err();
+
+        assert_eq!(
+            store.keys("ctx/deferred/").unwrap(),
+            ["ctx/deferred/a", "ctx/deferred/b"]
+        );
+        assert_eq!(store.get("msg/a").unwrap(), Some(vec![]));
+        assert_eq!(store.get("msg/b").unwrap(), None);
+    }
+}
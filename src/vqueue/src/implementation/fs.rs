@@ -76,12 +76,17 @@ mod tests {
 
     #[test]
     fn debug() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = local_test();
+        config.server.queues.dirpath = dir.path().join("spool");
+        config.app.dirpath = dir.path().join("app");
+
         assert_eq!(
             "QueueManager { .. }",
             format!(
                 "{:?}",
                 <super::QueueManager as crate::GenericQueueManager>::init(
-                    alloc::sync::Arc::new(local_test()),
+                    alloc::sync::Arc::new(config),
                     vec![]
                 )
                 .unwrap()
@@ -71,6 +71,16 @@ impl FilesystemQueueManagerExt for QueueManager {
         &self.transport_deserializer
     }
 
+    #[inline]
+    fn get_history_path(&self) -> std::path::PathBuf {
+        self.tempdir.path().join("history")
+    }
+
+    #[inline]
+    fn get_mails_path(&self) -> std::path::PathBuf {
+        self.tempdir.path().join("mails")
+    }
+
     #[inline]
     fn get_queue_path(&self, queue: &QueueID) -> std::path::PathBuf {
         self.tempdir
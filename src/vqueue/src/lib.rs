@@ -19,7 +19,7 @@
 
 #![doc(html_no_source)]
 #![deny(missing_docs)]
-#![forbid(unsafe_code)]
+#![deny(unsafe_code)]
 //
 #![warn(rust_2018_idioms)]
 #![warn(clippy::all)]
@@ -54,6 +54,10 @@ pub mod cli {
     pub mod execute;
     ///
     pub mod debugger {
+        ///
+        pub mod control;
+        ///
+        pub mod message_history;
         ///
         pub mod message_move;
         ///
@@ -61,13 +65,23 @@ pub mod cli {
         ///
         pub mod message_show;
         ///
+        pub mod migrate;
+        ///
+        pub mod purge;
+        ///
+        pub mod select;
+        ///
         pub mod show;
     }
 }
 
+pub mod control;
+pub mod history;
+pub mod retention;
+
 mod api;
 mod extension;
-pub use api::{GenericQueueManager, QueueID};
+pub use api::{DetailedMailContext, GenericQueueManager, QueueID};
 pub use extension::FilesystemQueueManagerExt;
 
 mod implementation {
@@ -91,6 +105,18 @@ mod implementation {
     /// ```
     pub mod fs;
 
+    /// The embedded implementation of the queue manager, storing all the queues
+    /// in a single LMDB database file (`queues.db`, locked with `queues.db-lock`)
+    /// in the queues directory.
+    ///
+    /// Every write is synced to the disk before returning, and the operations moving a
+    /// mail between two queues are atomic. The database is indexed by queue,
+    /// next delivery attempt (for the deferred queue) and recipient domain.
+    ///
+    /// The database can be opened by several processes, `vqueue` can read and edit the
+    /// queues while the server is running.
+    pub mod embedded;
+
     /// Similar to the filesystem implementation, but using a temporary directory.
     ///
     /// Only used for testing.
@@ -99,7 +125,7 @@ mod implementation {
     pub mod temp;
 }
 
-pub use implementation::fs;
+pub use implementation::{embedded, fs};
 
 #[cfg(feature = "testing")]
 #[cfg_attr(docsrs, doc(cfg(feature = "testing")))]
@@ -15,8 +15,11 @@
  *
  */
 use anyhow::Context;
-use vqueue::{cli::args::Args, GenericQueueManager};
-use vsmtp_config::Config;
+use vqueue::{
+    cli::args::{Args, Commands},
+    GenericQueueManager,
+};
+use vsmtp_config::{field::QueueBackend, Config};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -36,9 +39,22 @@ async fn main() -> anyhow::Result<()> {
         let config =
             Config::from_vsl_file(args.config).context("Cannot parse the configuration")?;
         let config = std::sync::Arc::new(config);
-        let manager = vqueue::fs::QueueManager::init(config, vec![])?;
 
-        command.execute(manager).await
+        match (command, config.server.queues.backend) {
+            (Commands::Migrate { from, to }, _) => {
+                Commands::migrate_backends(config, from, to, &mut std::io::stdout()).await
+            }
+            (command, QueueBackend::Filesystem) => {
+                command
+                    .execute(vqueue::fs::QueueManager::init(config, vec![])?)
+                    .await
+            }
+            (command, QueueBackend::Embedded) => {
+                command
+                    .execute(vqueue::embedded::QueueManager::init(config, vec![])?)
+                    .await
+            }
+        }
     } else {
         anyhow::bail!("no commands where specified")
     }
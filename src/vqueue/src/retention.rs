@@ -0,0 +1,339 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+ */
+//! Retention of the messages in the `dead` queue and in the quarantines.
+//!
+//! The policies are enforced periodically by the server, and on demand by `vqueue purge`.
+//! Each expired message is removed, and a line is appended to the audit log.
+
+use crate::{
+    history::{self, Event, EventKind, Outcome},
+    GenericQueueManager, QueueID,
+};
+use vsmtp_config::field::{FieldQueueRetention, RetentionPolicy};
+extern crate alloc;
+
+/// Why a message has expired.
+#[allow(clippy::exhaustive_enums)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reason {
+    /// The message has been received more than `max_age` ago.
+    Age(core::time::Duration),
+    /// There is more than `max_count` messages in the queue.
+    Count(usize),
+    /// The messages of the queue are bigger than `max_bytes`.
+    Bytes(usize),
+}
+
+impl core::fmt::Display for Reason {
+    #[inline]
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::Age(max_age) => write!(f, "max_age={}", humantime::format_duration(*max_age)),
+            Self::Count(max_count) => write!(f, "max_count={max_count}"),
+            Self::Bytes(max_bytes) => write!(f, "max_bytes={max_bytes}"),
+        }
+    }
+}
+
+/// A message removed by the retention policy of its queue.
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expired {
+    /// Queue the message was in.
+    pub queue: QueueID,
+    /// ID of the message.
+    pub msg_uuid: uuid::Uuid,
+    /// Why the message has expired.
+    pub reason: Reason,
+}
+
+impl core::fmt::Display for Expired {
+    #[inline]
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "expired msg={} queue={} reason={}",
+            self.msg_uuid, self.queue, self.reason
+        )
+    }
+}
+
+/// A message of a queue, as seen by the retention policy.
+#[derive(Debug, Clone, Copy)]
+struct Stored {
+    msg_uuid: uuid::Uuid,
+    received_at: time::OffsetDateTime,
+    size: usize,
+}
+
+/// Policy applied to `queue`, the other queues than `dead` and the quarantines have none.
+#[must_use]
+#[inline]
+pub fn policy<'retention>(
+    retention: &'retention FieldQueueRetention,
+    queue: &QueueID,
+) -> Option<&'retention RetentionPolicy> {
+    #[allow(clippy::wildcard_enum_match_arm)]
+    match queue {
+        QueueID::Dead => Some(&retention.dead),
+        QueueID::Quarantine { name } => Some(
+            retention
+                .quarantines
+                .get(name)
+                .unwrap_or(&retention.quarantine),
+        ),
+        _ => None,
+    }
+}
+
+/// Messages to remove so that the queue respects the policy, the oldest first.
+fn expired(
+    policy: &RetentionPolicy,
+    mut messages: Vec<Stored>,
+    now: time::OffsetDateTime,
+) -> Vec<(uuid::Uuid, Reason)> {
+    messages.sort_by_key(|message| message.received_at);
+    let mut messages = messages.into_iter().peekable();
+    let mut expired = vec![];
+
+    if let Some(max_age) = policy.max_age {
+        while let Some(message) = messages.next_if(|message| now - message.received_at > max_age) {
+            expired.push((message.msg_uuid, Reason::Age(max_age)));
+        }
+    }
+
+    let mut kept = messages.collect::<alloc::collections::VecDeque<_>>();
+    if let Some(max_count) = policy.max_count {
+        while kept.len() > max_count {
+            if let Some(message) = kept.pop_front() {
+                expired.push((message.msg_uuid, Reason::Count(max_count)));
+            }
+        }
+    }
+    if let Some(max_bytes) = policy.max_bytes {
+        let mut total = kept.iter().map(|message| message.size).sum::<usize>();
+        while total > max_bytes {
+            if let Some(message) = kept.pop_front() {
+                total -= message.size;
+                expired.push((message.msg_uuid, Reason::Bytes(max_bytes)));
+            }
+        }
+    }
+
+    expired
+}
+
+/// Read the messages of `queue`, with their size only if `with_size` is set.
+async fn read_queue(
+    queue_manager: &impl GenericQueueManager,
+    queue: &QueueID,
+    with_size: bool,
+) -> anyhow::Result<Vec<Stored>> {
+    let mut messages = vec![];
+    for msg_uuid in queue_manager.list(queue).await? {
+        let msg_uuid = match msg_uuid.and_then(|i| Ok(uuid::Uuid::parse_str(&i)?)) {
+            Ok(msg_uuid) => msg_uuid,
+            Err(error) => {
+                tracing::error!(%error, %queue, "Invalid message id.");
+                continue;
+            }
+        };
+        let ctx = match queue_manager.get_ctx(queue, &msg_uuid).await {
+            Ok(ctx) => ctx,
+            Err(error) => {
+                tracing::error!(%error, %queue, uuid = %msg_uuid, "Cannot read the message.");
+                continue;
+            }
+        };
+        messages.push(Stored {
+            msg_uuid,
+            received_at: ctx.mail_from.mail_timestamp,
+            size: if with_size {
+                queue_manager.get_msg_size(&msg_uuid).await.unwrap_or(0)
+            } else {
+                0
+            },
+        });
+    }
+    Ok(messages)
+}
+
+/// Remove the messages of the `dead` queue and of the quarantines not respecting
+/// their retention policy.
+///
+/// If `dry_run` is set, the messages are returned but not removed.
+///
+/// # Errors
+///
+/// * the queues cannot be listed
+#[inline]
+pub async fn enforce(
+    queue_manager: &impl GenericQueueManager,
+    now: time::OffsetDateTime,
+    dry_run: bool,
+) -> anyhow::Result<Vec<Expired>> {
+    let retention = &queue_manager.get_config().server.queues.retention;
+    let queues = core::iter::once(QueueID::Dead)
+        .chain(
+            queue_manager
+                .list_quarantines()
+                .await?
+                .into_iter()
+                .map(|name| QueueID::Quarantine { name }),
+        )
+        .collect::<Vec<_>>();
+
+    let mut all = vec![];
+    for queue in queues {
+        let Some(policy) = policy(retention, &queue) else {
+            continue;
+        };
+        if *policy == RetentionPolicy::default() {
+            continue;
+        }
+
+        let messages = read_queue(queue_manager, &queue, policy.max_bytes.is_some()).await?;
+        for (msg_uuid, reason) in expired(policy, messages, now) {
+            if !dry_run {
+                if let Err(error) = queue_manager.remove_both(&queue, &msg_uuid).await {
+                    tracing::error!(%error, %queue, uuid = %msg_uuid, "Cannot remove the expired message.");
+                    continue;
+                }
+                history::record(
+                    queue_manager,
+                    &msg_uuid,
+                    &[Event::now(EventKind::Finished {
+                        outcome: Outcome::Expired,
+                    })],
+                )
+                .await;
+            }
+            all.push(Expired {
+                queue: queue.clone(),
+                msg_uuid,
+                reason,
+            });
+        }
+    }
+    Ok(all)
+}
+
+/// Append a line for each expired message to the audit log.
+///
+/// # Errors
+///
+/// * the audit log cannot be opened or written
+#[inline]
+pub fn audit(
+    path: &std::path::Path,
+    expired: &[Expired],
+    now: time::OffsetDateTime,
+) -> anyhow::Result<()> {
+    if expired.is_empty() {
+        return Ok(());
+    }
+
+    let now = now.format(&time::format_description::well_known::Rfc3339)?;
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|error| {
+            anyhow::anyhow!("Cannot open the audit log '{}': {error}", path.display())
+        })?;
+    for i in expired {
+        std::io::Write::write_fmt(&mut file, format_args!("{now} {i}\n"))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stored(age: time::Duration, size: usize) -> (uuid::Uuid, Stored) {
+        let msg_uuid = uuid::Uuid::new_v4();
+        (
+            msg_uuid,
+            Stored {
+                msg_uuid,
+                received_at: time::OffsetDateTime::UNIX_EPOCH + time::Duration::days(1) - age,
+                size,
+            },
+        )
+    }
+
+    #[test]
+    fn oldest_first() {
+        let now = time::OffsetDateTime::UNIX_EPOCH + time::Duration::days(1);
+        let (old, old_msg) = stored(time::Duration::hours(3), 10);
+        let (older, older_msg) = stored(time::Duration::hours(4), 10);
+        let (recent, recent_msg) = stored(time::Duration::minutes(2), 100);
+        let (_, new_msg) = stored(time::Duration::minutes(1), 1);
+        let messages = vec![recent_msg, old_msg, new_msg, older_msg];
+
+        assert!(expired(&RetentionPolicy::default(), messages.clone(), now).is_empty());
+
+        let mut policy = RetentionPolicy {
+            max_age: Some(core::time::Duration::from_secs(2 * 60 * 60)),
+            max_count: None,
+            max_bytes: None,
+        };
+        let hours = Reason::Age(core::time::Duration::from_secs(2 * 60 * 60));
+        assert_eq!(
+            expired(&policy, messages.clone(), now),
+            vec![(older, hours.clone()), (old, hours.clone())]
+        );
+
+        policy.max_count = Some(1);
+        assert_eq!(
+            expired(&policy, messages.clone(), now),
+            vec![
+                (older, hours.clone()),
+                (old, hours.clone()),
+                (recent, Reason::Count(1))
+            ]
+        );
+
+        policy.max_count = None;
+        policy.max_bytes = Some(50);
+        assert_eq!(
+            expired(&policy, messages, now),
+            vec![
+                (older, hours.clone()),
+                (old, hours),
+                (recent, Reason::Bytes(50))
+            ]
+        );
+    }
+
+    #[test]
+    fn display() {
+        let msg_uuid = uuid::Uuid::nil();
+        assert_eq!(
+            Expired {
+                queue: QueueID::Quarantine {
+                    name: "spam".to_owned()
+                },
+                msg_uuid,
+                reason: Reason::Age(core::time::Duration::from_secs(30 * 24 * 60 * 60)),
+            }
+            .to_string(),
+            format!("expired msg={msg_uuid} queue=quarantine/spam reason=max_age=30days")
+        );
+    }
+}
@@ -16,9 +16,9 @@
 */
 use crate::{
     auth::Credentials,
-    status, transfer,
+    dsn, status, transfer,
     transport::{AbstractTransport, DeliverTo, WrapperSerde},
-    Address, CipherSuite, ClientName, Domain, ProtocolVersion,
+    Address, CipherSuite, ClientName, Domain, Priority, ProtocolVersion,
 };
 use vsmtp_auth::{dkim, spf};
 
@@ -275,6 +275,9 @@ impl Context {
                         mail_timestamp: now,
                         message_uuid: uuid::Uuid::new_v4(),
                         spf: None,
+                        message_size: None,
+                        priority: Priority::default(),
+                        dsn: dsn::MailFromParameters::default(),
                     },
                 });
                 Ok(())
@@ -306,7 +309,10 @@ impl Context {
                     helo: helo.clone(),
                     mail_from: mail_from.clone(),
                     rcpt_to: rcpt_to.clone(),
-                    finished: FinishedProperties { dkim: None },
+                    finished: FinishedProperties {
+                        dkim: None,
+                        released: vec![],
+                    },
                 });
                 Ok(())
             }
@@ -681,6 +687,7 @@ impl Context {
                         ))
                         .collect::<_>(),
                         forward_paths: vec![forward_path],
+                        dsn: std::collections::HashMap::new(),
                     },
                 });
                 Ok(())
@@ -920,6 +927,7 @@ impl Context {
                         transaction_type,
                         delivery: std::collections::HashMap::new(),
                         forward_paths: vec![],
+                        dsn: std::collections::HashMap::new(),
                     },
                 });
                 Ok(())
@@ -975,6 +983,161 @@ impl Context {
         }
     }
 
+    /// Get the size of the message declared by the client with the `SIZE` parameter.
+    ///
+    /// # Errors
+    ///
+    /// * state if not [`Stage::MailFrom`] or after
+    #[inline]
+    #[function_name::named]
+    pub fn message_size(&self) -> Result<Option<usize>, Error> {
+        match self {
+            Self::Connect(_) | Self::Helo(_) => Err(FieldAccessError {
+                field: function_name!().to_owned(),
+                stage: after!(MailFrom),
+            }
+            .into()),
+            Self::MailFrom(ContextMailFrom { mail_from, .. })
+            | Self::RcptTo(ContextRcptTo { mail_from, .. })
+            | Self::Finished(ContextFinished { mail_from, .. }) => Ok(mail_from.message_size),
+        }
+    }
+
+    /// Set the size of the message declared by the client with the `SIZE` parameter.
+    ///
+    /// # Errors
+    ///
+    /// * state if not [`Stage::MailFrom`] or after
+    #[inline]
+    #[function_name::named]
+    pub fn set_message_size(&mut self, message_size: Option<usize>) -> Result<(), Error> {
+        match self {
+            Self::Connect(_) | Self::Helo(_) => Err(FieldAccessError {
+                field: function_name!().to_owned(),
+                stage: after!(MailFrom),
+            }
+            .into()),
+            Self::MailFrom(ContextMailFrom { mail_from, .. })
+            | Self::RcptTo(ContextRcptTo { mail_from, .. })
+            | Self::Finished(ContextFinished { mail_from, .. }) => {
+                mail_from.message_size = message_size;
+                Ok(())
+            }
+        }
+    }
+
+    /// Get the priority of the message (MT-PRIORITY).
+    ///
+    /// # Errors
+    ///
+    /// * state if not [`Stage::MailFrom`] or after
+    #[inline]
+    #[function_name::named]
+    pub fn priority(&self) -> Result<Priority, Error> {
+        match self {
+            Self::Connect(_) | Self::Helo(_) => Err(FieldAccessError {
+                field: function_name!().to_owned(),
+                stage: after!(MailFrom),
+            }
+            .into()),
+            Self::MailFrom(ContextMailFrom { mail_from, .. })
+            | Self::RcptTo(ContextRcptTo { mail_from, .. })
+            | Self::Finished(ContextFinished { mail_from, .. }) => Ok(mail_from.priority),
+        }
+    }
+
+    /// Set the priority of the message (MT-PRIORITY).
+    ///
+    /// # Errors
+    ///
+    /// * state if not [`Stage::MailFrom`] or after
+    #[inline]
+    #[function_name::named]
+    pub fn set_priority(&mut self, priority: Priority) -> Result<(), Error> {
+        match self {
+            Self::Connect(_) | Self::Helo(_) => Err(FieldAccessError {
+                field: function_name!().to_owned(),
+                stage: after!(MailFrom),
+            }
+            .into()),
+            Self::MailFrom(ContextMailFrom { mail_from, .. })
+            | Self::RcptTo(ContextRcptTo { mail_from, .. })
+            | Self::Finished(ContextFinished { mail_from, .. }) => {
+                mail_from.priority = priority;
+                Ok(())
+            }
+        }
+    }
+
+    /// Get the delivery status notification parameters of the `MAIL FROM` command.
+    ///
+    /// # Errors
+    ///
+    /// * state if not [`Stage::MailFrom`] or after
+    #[inline]
+    #[function_name::named]
+    pub fn mail_from_dsn(&self) -> Result<&dsn::MailFromParameters, Error> {
+        match self {
+            Self::Connect(_) | Self::Helo(_) => Err(FieldAccessError {
+                field: function_name!().to_owned(),
+                stage: after!(MailFrom),
+            }
+            .into()),
+            Self::MailFrom(ContextMailFrom { mail_from, .. })
+            | Self::RcptTo(ContextRcptTo { mail_from, .. })
+            | Self::Finished(ContextFinished { mail_from, .. }) => Ok(&mail_from.dsn),
+        }
+    }
+
+    /// Set the delivery status notification parameters of the `MAIL FROM` command.
+    ///
+    /// # Errors
+    ///
+    /// * state if not [`Stage::MailFrom`] or after
+    #[inline]
+    #[function_name::named]
+    pub fn set_mail_from_dsn(&mut self, parameters: dsn::MailFromParameters) -> Result<(), Error> {
+        match self {
+            Self::Connect(_) | Self::Helo(_) => Err(FieldAccessError {
+                field: function_name!().to_owned(),
+                stage: after!(MailFrom),
+            }
+            .into()),
+            Self::MailFrom(ContextMailFrom { mail_from, .. })
+            | Self::RcptTo(ContextRcptTo { mail_from, .. })
+            | Self::Finished(ContextFinished { mail_from, .. }) => {
+                mail_from.dsn = parameters;
+                Ok(())
+            }
+        }
+    }
+
+    /// Set the delivery status notification parameters of the recipient `forward_path`.
+    ///
+    /// # Errors
+    ///
+    /// * state if not [`Stage::RcptTo`] or after
+    #[inline]
+    #[function_name::named]
+    pub fn set_rcpt_to_dsn(
+        &mut self,
+        forward_path: Address,
+        parameters: dsn::RcptToParameters,
+    ) -> Result<(), Error> {
+        match self {
+            Self::Connect(_) | Self::Helo(_) | Self::MailFrom(_) => Err(FieldAccessError {
+                field: function_name!().to_owned(),
+                stage: after!(RcptTo),
+            }
+            .into()),
+            Self::RcptTo(ContextRcptTo { rcpt_to, .. })
+            | Self::Finished(ContextFinished { rcpt_to, .. }) => {
+                rcpt_to.dsn.insert(forward_path, parameters);
+                Ok(())
+            }
+        }
+    }
+
     /// Get the [`dkim::VerificationResult`] if it exists.
     ///
     /// # Errors
@@ -1139,6 +1302,15 @@ pub struct MailFromProperties {
     pub message_uuid: uuid::Uuid,
     ///
     pub spf: Option<spf::Result>,
+    /// Size of the message declared with the `SIZE` parameter (RFC 1870).
+    #[serde(default)]
+    pub message_size: Option<usize>,
+    /// Priority of the message (MT-PRIORITY, RFC 6710).
+    #[serde(default)]
+    pub priority: Priority,
+    /// Delivery status notification parameters (RFC 3461).
+    #[serde(default, rename = "mail_from_dsn")]
+    pub dsn: dsn::MailFromParameters,
 }
 
 /// Properties accessible after the RCPT TO command
@@ -1151,6 +1323,9 @@ pub struct RcptToProperties {
     pub delivery: std::collections::HashMap<WrapperSerde, DeliverTo>,
     ///
     pub transaction_type: TransactionType,
+    /// Delivery status notification parameters of each recipient (RFC 3461).
+    #[serde(default, rename = "rcpt_to_dsn")]
+    pub dsn: std::collections::HashMap<Address, dsn::RcptToParameters>,
 }
 
 /// Properties accessible once the message has been fully received
@@ -1159,6 +1334,26 @@ pub struct RcptToProperties {
 pub struct FinishedProperties {
     ///
     pub dkim: Option<dkim::VerificationResult>,
+    /// Releases of the message from a quarantine, the oldest first.
+    #[serde(default)]
+    pub released: Vec<Release>,
+}
+
+/// Release of the message from a quarantine by an operator.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "testing", derive(PartialEq, Eq))]
+pub struct Release {
+    /// Date of the release.
+    #[serde(with = "time::serde::iso8601")]
+    pub released_at: time::OffsetDateTime,
+    /// Name of the quarantine the message was in.
+    pub quarantine: String,
+    /// Who released the message.
+    pub operator: String,
+    /// Why the message has been released.
+    pub reason: String,
+    /// What has been done with the message.
+    pub action: String,
 }
 #[doc(hidden)]
 #[allow(clippy::module_name_repetitions)]
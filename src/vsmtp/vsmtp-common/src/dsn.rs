@@ -0,0 +1,277 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+*/
+
+//! Delivery Status Notification parameters.
+//! See <https://datatracker.ietf.org/doc/html/rfc3461>
+
+/// Maximum length of the `ENVID` parameter.
+pub const ENVELOPE_ID_MAX_LENGTH: usize = 100;
+
+/// Content of the original message to return in a notification (`RET=` parameter).
+#[derive(
+    Debug,
+    PartialEq,
+    Eq,
+    Copy,
+    Clone,
+    strum::Display,
+    strum::EnumString,
+    serde_with::SerializeDisplay,
+    serde_with::DeserializeFromStr,
+)]
+#[strum(ascii_case_insensitive)]
+pub enum Ret {
+    /// Return the full message.
+    #[strum(serialize = "FULL")]
+    Full,
+    /// Return only the headers of the message.
+    #[strum(serialize = "HDRS")]
+    Headers,
+}
+
+/// Conditions for which a notification must be produced (`NOTIFY=` parameter).
+#[derive(Debug, PartialEq, Eq, Copy, Clone, serde::Serialize, serde::Deserialize)]
+pub enum NotifyOn {
+    /// A notification must never be produced.
+    Never,
+    /// A notification must be produced for the given conditions.
+    Some {
+        /// On successful delivery.
+        success: bool,
+        /// On delivery failure.
+        failure: bool,
+        /// On delayed delivery.
+        delay: bool,
+    },
+}
+
+impl NotifyOn {
+    /// Does the `action` must be reported to the sender ?
+    #[inline]
+    #[must_use]
+    pub const fn should_notify(&self, action: Action) -> bool {
+        match self {
+            Self::Never => false,
+            Self::Some {
+                success,
+                failure,
+                delay,
+            } => match action {
+                Action::Failed => *failure,
+                Action::Delayed => *delay,
+                Action::Delivered => *success,
+            },
+        }
+    }
+}
+
+impl std::str::FromStr for NotifyOn {
+    type Err = ();
+
+    #[inline]
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("NEVER") {
+            return Ok(Self::Never);
+        }
+
+        let (mut success, mut failure, mut delay) = (false, false, false);
+        for i in s.split(',') {
+            let flag = match i.to_ascii_uppercase().as_str() {
+                "SUCCESS" => &mut success,
+                "FAILURE" => &mut failure,
+                "DELAY" => &mut delay,
+                _ => return Err(()),
+            };
+            if *flag {
+                return Err(());
+            }
+            *flag = true;
+        }
+
+        Ok(Self::Some {
+            success,
+            failure,
+            delay,
+        })
+    }
+}
+
+/// Original recipient of the message, as received by the first MTA (`ORCPT=` parameter).
+#[derive(Debug, PartialEq, Eq, Clone, serde::Serialize, serde::Deserialize)]
+pub struct OriginalRecipient {
+    /// Type of the address, usually `rfc822`.
+    pub addr_type: String,
+    /// The xtext decoded address.
+    pub mailbox: String,
+}
+
+/// Formatted as the `ORCPT=` parameter, with the address encoded in `xtext`.
+impl std::fmt::Display for OriginalRecipient {
+    #[inline]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{};{}", self.addr_type, encode_xtext(&self.mailbox))
+    }
+}
+
+/// Action reported in a delivery status notification for a recipient.
+/// See <https://datatracker.ietf.org/doc/html/rfc3464#section-2.3.3>
+#[derive(
+    Debug,
+    PartialEq,
+    Eq,
+    Copy,
+    Clone,
+    strum::Display,
+    strum::EnumString,
+    serde_with::SerializeDisplay,
+    serde_with::DeserializeFromStr,
+)]
+#[strum(serialize_all = "lowercase")]
+pub enum Action {
+    /// The message could not be delivered.
+    Failed,
+    /// The delivery of the message has been delayed, and will be retried.
+    Delayed,
+    /// The message has been delivered.
+    Delivered,
+}
+
+/// DSN parameters of the `MAIL FROM` command.
+#[derive(Debug, Default, PartialEq, Eq, Clone, serde::Serialize, serde::Deserialize)]
+pub struct MailFromParameters {
+    /// Content of the message to return.
+    pub ret: Option<Ret>,
+    /// Identifier of the transaction given by the sender.
+    pub envelope_id: Option<String>,
+}
+
+/// DSN parameters of a `RCPT TO` command.
+#[derive(Debug, Default, PartialEq, Eq, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RcptToParameters {
+    /// Conditions of notification, `None` if the parameter was not given.
+    pub notify_on: Option<NotifyOn>,
+    /// Original recipient.
+    pub original_forward_path: Option<OriginalRecipient>,
+    /// Last action reported to the sender, to avoid producing the same notification twice.
+    #[serde(default)]
+    pub last_notification: Option<Action>,
+}
+
+impl RcptToParameters {
+    /// Does the `action` must be reported to the sender ?
+    ///
+    /// When the `NOTIFY` parameter was not given, failures are always reported,
+    /// and delays only if `delay_by_default` is true.
+    #[inline]
+    #[must_use]
+    pub const fn should_notify(&self, action: Action, delay_by_default: bool) -> bool {
+        match &self.notify_on {
+            Some(notify_on) => notify_on.should_notify(action),
+            None => match action {
+                Action::Failed => true,
+                Action::Delayed => delay_by_default,
+                Action::Delivered => false,
+            },
+        }
+    }
+}
+
+/// Decode a string encoded with the `xtext` format.
+/// See <https://datatracker.ietf.org/doc/html/rfc3461#section-4>
+///
+/// Return `None` if the input is not valid `xtext`, or if a decoded character is not
+/// printable ASCII.
+#[inline]
+#[must_use]
+pub fn decode_xtext(input: &[u8]) -> Option<String> {
+    let mut out = Vec::with_capacity(input.len());
+    let mut iter = input.iter();
+    while let Some(c) = iter.next() {
+        match c {
+            b'+' => {
+                let hex = [*iter.next()?, *iter.next()?];
+                if !hex
+                    .iter()
+                    .all(|i| i.is_ascii_hexdigit() && !i.is_ascii_lowercase())
+                {
+                    return None;
+                }
+                let decoded = u8::from_str_radix(core::str::from_utf8(&hex).ok()?, 16).ok()?;
+                if !(b' '..=b'~').contains(&decoded) {
+                    return None;
+                }
+                out.push(decoded);
+            }
+            b'!'..=b'~' if *c != b'=' => out.push(*c),
+            _ => return None,
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+/// Encode a string with the `xtext` format.
+#[inline]
+#[must_use]
+pub fn encode_xtext(input: &str) -> String {
+    input
+        .bytes()
+        .map(|c| match c {
+            b'!'..=b'~' if c != b'+' && c != b'=' => char::from(c).to_string(),
+            _ => format!("+{c:02X}"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xtext() {
+        assert_eq!(
+            decode_xtext(b"rfc822+3Bjohn+2Bdoe@example.com").unwrap(),
+            "rfc822;john+doe@example.com"
+        );
+        assert_eq!(
+            encode_xtext("john+doe=@example.com"),
+            "john+2Bdoe+3D@example.com"
+        );
+        assert!(decode_xtext(b"foo+2").is_none());
+        assert!(decode_xtext(b"foo+2b").is_none());
+        assert!(decode_xtext(b"foo=bar").is_none());
+        assert_eq!(decode_xtext(b"foo+20bar").unwrap(), "foo bar");
+        assert!(decode_xtext(b"x+0D+0ABcc:+20victim@example.com").is_none());
+        assert!(decode_xtext(b"foo+7F").is_none());
+        assert!(decode_xtext(b"foo+C3+A9").is_none());
+    }
+
+    #[test]
+    fn notify_on() {
+        assert_eq!("never".parse::<NotifyOn>(), Ok(NotifyOn::Never));
+        assert_eq!(
+            "SUCCESS,DELAY".parse::<NotifyOn>(),
+            Ok(NotifyOn::Some {
+                success: true,
+                failure: false,
+                delay: true
+            })
+        );
+        assert!("SUCCESS,SUCCESS".parse::<NotifyOn>().is_err());
+        assert!("NEVER,SUCCESS".parse::<NotifyOn>().is_err());
+        assert!("".parse::<NotifyOn>().is_err());
+    }
+}
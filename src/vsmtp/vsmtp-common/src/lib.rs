@@ -72,6 +72,7 @@ mod types {
     pub mod address;
     pub mod client_name;
     pub mod domain;
+    pub mod priority;
     pub mod reply;
     pub mod reply_code;
     pub mod target;
@@ -83,6 +84,7 @@ pub use types::{
     address::Address,
     client_name::ClientName,
     domain::{domain_iter, Domain},
+    priority::Priority,
     reply::Reply,
     reply_code::*,
     target::Target,
@@ -93,11 +95,13 @@ pub use types::{
 ///
 pub mod transport;
 
+pub mod dsn;
+
 mod context;
 pub use context::{
     AuthProperties, ConnectProperties, Context, ContextConnect, ContextFinished, ContextHelo,
     ContextMailFrom, ContextRcptTo, Error, FieldAccessError, FinishedProperties, HeloProperties,
-    MailFromProperties, RcptToProperties, Stage, TlsProperties, TransactionType,
+    MailFromProperties, RcptToProperties, Release, Stage, TlsProperties, TransactionType,
 };
 
 /// abstraction of the libc
@@ -112,7 +116,7 @@ pub mod transfer {
     pub mod error;
     mod status;
 
-    pub use status::{Error, Status};
+    pub use status::{Error, Receipt, Status};
 }
 
 /// parsing utils.
@@ -101,6 +101,30 @@ pub enum Queuer {
     /// Failed too many time to deliver the email
     #[error("max deferred attempt reached")]
     MaxDeferredAttemptReached,
+
+    /// The message has been in the queue for too long
+    #[error("message lifetime expired")]
+    LifetimeExpired,
+}
+
+/// The delivery was postponed because of a limit of the destination
+#[derive(Debug, Clone, thiserror::Error, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[cfg_attr(feature = "testing", derive(PartialEq, Eq))]
+pub enum Throttle {
+    /// The maximum number of concurrent connections to the destination is reached
+    #[error("too many concurrent connections to '{domain}'")]
+    Connections {
+        /// The destination domain
+        domain: Domain,
+    },
+
+    /// The maximum number of messages per minute sent to the destination is reached
+    #[error("too many messages sent to '{domain}' in the last minute")]
+    Rate {
+        /// The destination domain
+        domain: Domain,
+    },
 }
 
 /// Errors produced by a SMTP exchange
@@ -231,6 +255,10 @@ pub enum Variant {
     /// An error produced by the rules engine
     #[error("<rules>: {0}")]
     Rules(#[from] Rule),
+
+    /// The delivery was postponed, and the recipient requeued
+    #[error("<throttled>: {0}")]
+    Throttled(#[from] Throttle),
 }
 
 impl From<trust_dns_resolver::error::ResolveError> for Lookup {
@@ -256,6 +284,12 @@ impl From<trust_dns_resolver::error::ResolveError> for Lookup {
     }
 }
 
+/// Reply code of a negative reply of the server, including the enhanced status code if any.
+fn reply_code(code: lettre::transport::smtp::response::Code, text: Option<&str>) -> ReplyCode {
+    text.and_then(|text| format!("{code} {text}").parse::<Reply>().ok())
+        .map_or_else(|| code.into(), |reply| reply.code().clone())
+}
+
 impl From<lettre::transport::smtp::Error> for Delivery {
     #[inline]
     fn from(value: lettre::transport::smtp::Error) -> Self {
@@ -266,20 +300,20 @@ impl From<lettre::transport::smtp::Error> for Delivery {
         } else if value.is_permanent() {
             #[allow(clippy::expect_used)]
             Self::Permanent {
+                reply: reply_code(
+                    value.status().expect("error is permanent and has code"),
+                    with_source.as_deref(),
+                ),
                 with_source,
-                reply: value
-                    .status()
-                    .expect("error is permanent and has code")
-                    .into(),
             }
         } else if value.is_transient() {
             #[allow(clippy::expect_used)]
             Self::Transient {
+                reply: reply_code(
+                    value.status().expect("error is transient and has code"),
+                    with_source.as_deref(),
+                ),
                 with_source,
-                reply: value
-                    .status()
-                    .expect("error is transient and has code")
-                    .into(),
             }
         } else if value.is_response() {
             Self::ReplyParsing { with_source }
@@ -316,7 +350,9 @@ impl Variant {
                 LocalDelivery::MailboxDoNotExist { .. } | LocalDelivery::Other(_),
             )
             | Self::Envelop(Envelop::NoRecipient)
-            | Self::Queuer(Queuer::StillWaiting | Queuer::MaxDeferredAttemptReached) => true,
+            | Self::Queuer(
+                Queuer::StillWaiting | Queuer::MaxDeferredAttemptReached | Queuer::LifetimeExpired,
+            ) => true,
 
             Self::Lookup(
                 Lookup::NoRecords {}
@@ -328,7 +364,8 @@ impl Variant {
                 | Lookup::ContainsNullMX { .. }
                 | Lookup::NotImplemented,
             )
-            | Self::Rules(Rule::Denied(_)) => false,
+            | Self::Rules(Rule::Denied(_))
+            | Self::Throttled(Throttle::Connections { .. } | Throttle::Rate { .. }) => false,
 
             Self::Delivery(attempts) => attempts.iter().all(|(_, e)| e.is_permanent()),
         }
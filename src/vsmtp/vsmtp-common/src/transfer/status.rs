@@ -16,6 +16,7 @@
 */
 
 use super::error::Variant;
+use crate::Target;
 
 /// the delivery status of the email of the current rcpt.
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -36,7 +37,9 @@ pub enum Status {
         /// timestamp when the status has been set
         #[serde(with = "time::serde::iso8601")]
         timestamp: time::OffsetDateTime,
-        // TODO: keep the response ? and previous error ?
+        /// where and how the email has been sent, for a remote server.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        receipt: Option<Receipt>,
     },
     /// the delivery failed, the system is trying to re-send the email.
     /// the email is located in the deferred queue at this point.
@@ -116,6 +119,17 @@ impl Status {
     pub fn sent() -> Self {
         Self::Sent {
             timestamp: time::OffsetDateTime::now_utc(),
+            receipt: None,
+        }
+    }
+
+    /// Set the status to [`Status::Sent`] with the acknowledgment of the remote server.
+    #[inline]
+    #[must_use]
+    pub fn sent_with(receipt: Receipt) -> Self {
+        Self::Sent {
+            timestamp: time::OffsetDateTime::now_utc(),
+            receipt: Some(receipt),
         }
     }
 
@@ -129,6 +143,28 @@ impl Status {
     }
 }
 
+/// Acknowledgment of a remote server accepting the email for a recipient.
+#[non_exhaustive]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "testing", derive(PartialEq, Eq))]
+pub struct Receipt {
+    /// the server the email has been sent to.
+    pub target: Target,
+    /// the connection was encrypted with TLS.
+    pub tls: bool,
+    /// the reply of the server to the message content.
+    pub reply: String,
+}
+
+impl Receipt {
+    /// Create a new receipt.
+    #[inline]
+    #[must_use]
+    pub const fn new(target: Target, tls: bool, reply: String) -> Self {
+        Self { target, tls, reply }
+    }
+}
+
 ///
 #[allow(clippy::module_name_repetitions)]
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -170,8 +206,7 @@ impl Error {
         }
     }
 
-    /// Get the underlying error (only for testing)
-    #[cfg(feature = "testing")]
+    /// Get the underlying error
     #[must_use]
     #[inline]
     pub const fn variant(&self) -> &Variant {
@@ -0,0 +1,123 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+*/
+
+/// Priority of a message (MT-PRIORITY, RFC 6710), from -9 (lowest) to 9 (highest).
+///
+/// A message without priority has the priority 0.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize,
+)]
+#[serde(try_from = "i8", into = "i8")]
+pub struct Priority(i8);
+
+impl Priority {
+    /// Lowest priority.
+    pub const MIN: Self = Self(-9);
+    /// Highest priority.
+    pub const MAX: Self = Self(9);
+    /// Priority of a message without MT-PRIORITY.
+    pub const DEFAULT: Self = Self(0);
+
+    /// Value of the priority.
+    #[must_use]
+    #[inline]
+    pub const fn get(self) -> i8 {
+        self.0
+    }
+}
+
+impl Default for Priority {
+    #[inline]
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl TryFrom<i8> for Priority {
+    type Error = anyhow::Error;
+
+    #[inline]
+    fn try_from(value: i8) -> Result<Self, Self::Error> {
+        anyhow::ensure!(
+            (Self::MIN.0..=Self::MAX.0).contains(&value),
+            "priority must be between {} and {}, got {value}",
+            Self::MIN.0,
+            Self::MAX.0
+        );
+        Ok(Self(value))
+    }
+}
+
+impl From<Priority> for i8 {
+    #[inline]
+    fn from(value: Priority) -> Self {
+        value.0
+    }
+}
+
+impl std::str::FromStr for Priority {
+    type Err = anyhow::Error;
+
+    #[inline]
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // priority-value = [ "+" / "-" ] DIGIT
+        let digit = s.strip_prefix(['+', '-']).unwrap_or(s);
+        anyhow::ensure!(
+            digit.len() == 1 && digit.bytes().all(|c| c.is_ascii_digit()),
+            "invalid priority '{s}'"
+        );
+        Self::try_from(s.parse::<i8>()?)
+    }
+}
+
+impl std::fmt::Display for Priority {
+    #[inline]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Priority;
+
+    #[test]
+    fn parse() {
+        assert_eq!("0".parse::<Priority>().unwrap(), Priority::default());
+        assert_eq!("+9".parse::<Priority>().unwrap(), Priority::MAX);
+        assert_eq!("-9".parse::<Priority>().unwrap(), Priority::MIN);
+        assert_eq!("3".parse::<Priority>().unwrap().get(), 3);
+
+        for invalid in ["", "+", "10", "-10", "+-1", "1.0", "a"] {
+            assert!(invalid.parse::<Priority>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn order() {
+        assert!(Priority::MIN < Priority::default());
+        assert!(Priority::try_from(1).unwrap() < Priority::MAX);
+    }
+
+    #[test]
+    fn serialize() {
+        let priority = Priority::try_from(-3).unwrap();
+        assert_eq!(serde_json::to_string(&priority).unwrap(), "-3");
+        assert_eq!(serde_json::from_str::<Priority>("-3").unwrap(), priority);
+        serde_json::from_str::<Priority>("12").unwrap_err();
+    }
+}
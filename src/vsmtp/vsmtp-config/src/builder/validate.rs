@@ -17,9 +17,10 @@
 use super::{wants::WantsValidate, with::Builder};
 use crate::{
     config::field::{
-        FieldApp, FieldAppLogs, FieldAppVSL, FieldServer, FieldServerInterfaces, FieldServerLogs,
-        FieldServerQueues, FieldServerSMTP, FieldServerSMTPError, FieldServerSMTPTimeoutClient,
-        FieldServerSystem, FieldServerSystemThreadPool,
+        FieldApp, FieldAppLogs, FieldAppVSL, FieldQueueRetention, FieldServer,
+        FieldServerInterfaces, FieldServerLogs, FieldServerQueues, FieldServerSMTP,
+        FieldServerSMTPError, FieldServerSMTPTimeoutClient, FieldServerSystem,
+        FieldServerSystemThreadPool, QueueBackend,
     },
     Config,
 };
@@ -65,11 +66,13 @@ impl Builder<WantsValidate> {
                         processing: srv_syst.thread_pool_processing,
                         delivery: srv_syst.thread_pool_delivery,
                     },
+                    shutdown_grace_period: FieldServerSystem::default_shutdown_grace_period(),
                 },
                 interfaces: FieldServerInterfaces {
                     addr: srv_inet.addr,
                     addr_submission: srv_inet.addr_submission,
                     addr_submissions: srv_inet.addr_submissions,
+                    control: FieldServerInterfaces::default_control(),
                 },
                 logs: FieldServerLogs {
                     filename: srv_logs.filename,
@@ -81,8 +84,10 @@ impl Builder<WantsValidate> {
                 },
                 queues: FieldServerQueues {
                     dirpath: srv_delivery.dirpath,
+                    backend: QueueBackend::default(),
                     working: srv_delivery.working,
                     delivery: srv_delivery.delivery,
+                    retention: FieldQueueRetention::default(),
                 },
                 tls: srv_tls.tls,
                 smtp: FieldServerSMTP {
@@ -118,10 +123,78 @@ impl Builder<WantsValidate> {
     }
 }
 
+impl Config {
+    /// Check the values which cannot be expressed by the types of the fields.
+    ///
+    /// # Errors
+    ///
+    /// * A period of the `delivery` pool or of the retention policies is zero.
+    pub(crate) fn ensure_valid(&self) -> anyhow::Result<()> {
+        let delivery = &self.server.queues.delivery;
+
+        for (field, period) in [
+            (
+                "server.queues.delivery.deferred_retry_period",
+                delivery.deferred_retry_period,
+            ),
+            (
+                "server.queues.delivery.connection_pool.idle_timeout",
+                delivery.connection_pool.idle_timeout,
+            ),
+            (
+                "server.queues.retention.period",
+                self.server.queues.retention.period,
+            ),
+        ] {
+            anyhow::ensure!(!period.is_zero(), "'{field}' must not be zero");
+        }
+
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::Config;
 
+    fn from_script(statement: &str) -> anyhow::Result<Config> {
+        Config::from_vsl_script(
+            format!(
+                r#"
+fn on_config(config) {{
+    config.server.system = #{{ user: "root", group: "root" }};
+    {statement}
+    config
+}}
+"#
+            ),
+            None,
+        )
+    }
+
+    #[test]
+    fn zero_period() {
+        from_script("").unwrap();
+
+        for field in [
+            "delivery.deferred_retry_period",
+            "delivery.connection_pool.idle_timeout",
+            "retention.period",
+        ] {
+            let error = from_script(&format!(r#"config.server.queues.{field} = "0s";"#))
+                .unwrap_err()
+                .to_string();
+            assert_eq!(error, format!("'server.queues.{field}' must not be zero"));
+        }
+    }
+
+    #[test]
+    fn zero_concurrency() {
+        for queue in ["working", "delivery"] {
+            from_script(&format!("config.server.queues.{queue}.concurrency = 0;")).unwrap_err();
+        }
+    }
+
     #[test]
     fn default_build() {
         let _config = Config::builder()
@@ -126,6 +126,15 @@ pub mod field {
         /// see [`FieldServerSystemThreadPool`]
         #[serde(default)]
         pub thread_pool: FieldServerSystemThreadPool,
+        /// Time given to the transactions in progress and to the messages in the
+        /// queues to be processed when the server is stopped.
+        ///
+        /// The messages not processed in time are kept in the queues for the next start.
+        #[serde(
+            default = "FieldServerSystem::default_shutdown_grace_period",
+            with = "humantime_serde"
+        )]
+        pub shutdown_grace_period: std::time::Duration,
     }
 
     impl PartialEq for FieldServerSystem {
@@ -135,6 +144,7 @@ pub mod field {
                 && self.group_local.as_ref().map(users::Group::gid)
                     == other.group_local.as_ref().map(users::Group::gid)
                 && self.thread_pool == other.thread_pool
+                && self.shutdown_grace_period == other.shutdown_grace_period
         }
     }
 
@@ -178,6 +188,11 @@ pub mod field {
         #[serde(default)]
         #[serde(deserialize_with = "crate::parser::socket_addr::deserialize")]
         pub addr_submissions: Vec<std::net::SocketAddr>,
+        /// Path of the Unix socket of the control API used by `vqueue`.
+        ///
+        /// A relative path is resolved from `server.queues.dirpath`.
+        #[serde(default = "FieldServerInterfaces::default_control")]
+        pub control: std::path::PathBuf,
     }
 
     /// The field related to the logs.
@@ -241,6 +256,10 @@ pub mod field {
         /// Size of the channel queue communicating the mails from the `receiver` pool to the `processing` pool.
         #[serde(default = "FieldQueueWorking::default_channel_size")]
         pub channel_size: usize,
+        /// Maximum number of mails processed at the same time by the `processing` pool,
+        /// the others wait in the channel and are taken by order of priority.
+        #[serde(default = "FieldQueueWorking::default_concurrency")]
+        pub concurrency: std::num::NonZeroUsize,
     }
 
     /// The configuration of the `vqueue`
@@ -250,13 +269,120 @@ pub mod field {
         /// Size of the channel queue communicating the mails from the `processing` pool to the `delivery` pool.
         #[serde(default = "FieldQueueDelivery::default_channel_size")]
         pub channel_size: usize,
+        /// Maximum number of mails delivered at the same time by the `delivery` pool,
+        /// the others wait in the channel and are taken by order of priority.
+        #[serde(default = "FieldQueueDelivery::default_concurrency")]
+        pub concurrency: std::num::NonZeroUsize,
         /// Maximum number of attempt to deliver the mail before being considered dead.
         #[serde(default = "FieldQueueDelivery::default_deferred_retry_max")]
         pub deferred_retry_max: usize,
-        /// The mail in the `deferred` are resend in a clock with this period.
+        /// The mails of the `deferred` queue are resent at the next attempt of their
+        /// recipients (see `retry`), a mail whose flush failed is retried after this delay.
         #[serde(with = "humantime_serde")]
         #[serde(default = "FieldQueueDelivery::default_deferred_retry_period")]
         pub deferred_retry_period: std::time::Duration,
+        /// Policy used to schedule the next attempt of each recipient in the `deferred` queue.
+        #[serde(default)]
+        pub retry: FieldQueueDeliveryRetry,
+        /// Reuse of the outbound SMTP connections by the `delivery` pool.
+        #[serde(default)]
+        pub connection_pool: FieldQueueDeliveryConnectionPool,
+        /// Limits of the outbound delivery for each destination domain.
+        #[serde(default)]
+        pub limits: std::collections::BTreeMap<Domain, FieldQueueDeliveryLimits>,
+    }
+
+    /// The limits of the outbound delivery to a destination domain, the messages
+    /// over the limits are requeued in the `deferred` queue.
+    #[derive(Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct FieldQueueDeliveryLimits {
+        /// Maximum number of concurrent deliveries to the destination. The idle connections
+        /// kept by the connection pool are not counted.
+        #[serde(default)]
+        pub max_connections: Option<usize>,
+        /// Maximum number of messages sent to the destination in a minute.
+        #[serde(default)]
+        pub messages_per_minute: Option<usize>,
+        /// Maximum number of recipients in one transaction, the recipients of a message
+        /// are split in several transactions if needed.
+        #[serde(default)]
+        pub max_recipients: Option<usize>,
+    }
+
+    /// The pool of outbound SMTP connections, shared by the `delivery` pool.
+    ///
+    /// A connection is kept open after a transaction and reused for the next message
+    /// sent to the same destination, the session being reset with `RSET`.
+    #[derive(Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct FieldQueueDeliveryConnectionPool {
+        /// Duration after which an unused connection is closed.
+        #[serde(with = "humantime_serde")]
+        #[serde(default = "FieldQueueDeliveryConnectionPool::default_idle_timeout")]
+        pub idle_timeout: std::time::Duration,
+        /// Maximum number of messages sent over one connection, `1` disables the reuse.
+        #[serde(default = "FieldQueueDeliveryConnectionPool::default_max_messages")]
+        pub max_messages: usize,
+        /// Maximum number of unused connections kept open for one destination.
+        #[serde(default = "FieldQueueDeliveryConnectionPool::default_max_idle")]
+        pub max_idle: usize,
+    }
+
+    /// The retry policy of the recipients held back in the `deferred` queue.
+    #[derive(Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct FieldQueueDeliveryRetry {
+        /// Delay between the attempts of a recipient.
+        #[serde(default)]
+        pub schedule: RetrySchedule,
+        /// Maximum random delay added to each delay of the schedule, to spread the attempts.
+        #[serde(with = "humantime_serde")]
+        #[serde(default = "FieldQueueDeliveryRetry::default_jitter")]
+        pub jitter: std::time::Duration,
+        /// Delay before the next attempt of a recipient postponed by the limits of its
+        /// destination, such postponements are not counted as attempts.
+        #[serde(with = "humantime_serde")]
+        #[serde(default = "FieldQueueDeliveryRetry::default_throttle_delay")]
+        pub throttle_delay: std::time::Duration,
+        /// Maximum lifetime of a message, the recipients still held back after
+        /// this delay are considered failed and a notification is sent to the sender.
+        #[serde(with = "humantime_serde")]
+        #[serde(default = "FieldQueueDeliveryRetry::default_bounce_after")]
+        pub bounce_after: std::time::Duration,
+        /// Delay after which a `delayed` notification is sent to the sender
+        /// for the recipients still held back. No warning is sent if not set.
+        #[serde(with = "humantime_serde")]
+        #[serde(default)]
+        pub warn_after: Option<std::time::Duration>,
+    }
+
+    /// Schedule of the attempts of a recipient.
+    #[derive(Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+    #[serde(tag = "type", deny_unknown_fields)]
+    pub enum RetrySchedule {
+        /// The delay is multiplied by `factor` after each attempt: `initial * factor ^ (attempt - 1)`.
+        #[serde(rename = "exponential")]
+        Exponential {
+            /// Delay after the first failed attempt.
+            #[serde(with = "humantime_serde")]
+            initial: std::time::Duration,
+            /// Multiplier applied to the delay after each attempt.
+            factor: u32,
+            /// Upper bound of the delay.
+            #[serde(with = "humantime_serde")]
+            max: std::time::Duration,
+        },
+        /// The delay of the n-th attempt is the n-th step, the last step is used once they are exhausted.
+        #[serde(rename = "stepped")]
+        Stepped {
+            /// Delays between the attempts.
+            #[serde(
+                serialize_with = "crate::parser::duration_list::serialize",
+                deserialize_with = "crate::parser::duration_list::deserialize"
+            )]
+            steps: Vec<std::time::Duration>,
+        },
     }
 
     /// The configuration of the filesystem for the mail queuer.
@@ -265,12 +391,86 @@ pub mod field {
     pub struct FieldServerQueues {
         /// The root directory for the queuer system.
         pub dirpath: std::path::PathBuf,
+        /// see [`QueueBackend`]
+        #[serde(default)]
+        pub backend: QueueBackend,
         /// see [`FieldQueueWorking`]
         #[serde(default)]
         pub working: FieldQueueWorking,
         /// see [`FieldQueueDelivery`]
         #[serde(default)]
         pub delivery: FieldQueueDelivery,
+        /// see [`FieldQueueRetention`]
+        #[serde(default)]
+        pub retention: FieldQueueRetention,
+    }
+
+    /// Retention of the messages in the `dead` queue and in the quarantines,
+    /// the messages are kept forever by default.
+    #[derive(Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct FieldQueueRetention {
+        /// Period of the enforcement of the policies by the server.
+        #[serde(with = "humantime_serde")]
+        #[serde(default = "FieldQueueRetention::default_period")]
+        pub period: std::time::Duration,
+        /// File in which a line is appended for each expired message.
+        #[serde(default = "FieldQueueRetention::default_audit_log")]
+        pub audit_log: std::path::PathBuf,
+        /// Policy of the `dead` queue.
+        #[serde(default)]
+        pub dead: RetentionPolicy,
+        /// Policy of the quarantines without a policy in `quarantines`.
+        #[serde(default)]
+        pub quarantine: RetentionPolicy,
+        /// Policy of each quarantine, by name.
+        #[serde(default)]
+        pub quarantines: std::collections::BTreeMap<String, RetentionPolicy>,
+        /// Time the history of a message is kept after its last event,
+        /// 30 days by default, forever if `null`.
+        #[serde(with = "humantime_serde")]
+        #[serde(default = "FieldQueueRetention::default_history")]
+        pub history: Option<std::time::Duration>,
+    }
+
+    /// Limits of a queue, the oldest messages are removed first.
+    #[derive(Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct RetentionPolicy {
+        /// Maximum time since the reception of a message.
+        #[serde(with = "humantime_serde")]
+        #[serde(default)]
+        pub max_age: Option<std::time::Duration>,
+        /// Maximum number of messages.
+        #[serde(default)]
+        pub max_count: Option<usize>,
+        /// Maximum size of all the messages, in bytes.
+        #[serde(default)]
+        pub max_bytes: Option<usize>,
+    }
+
+    /// Storage of the queues and of the messages.
+    #[derive(
+        Debug,
+        Default,
+        Clone,
+        Copy,
+        PartialEq,
+        Eq,
+        serde::Deserialize,
+        serde::Serialize,
+        strum::Display,
+        strum::EnumString,
+    )]
+    #[serde(rename_all = "lowercase")]
+    #[strum(serialize_all = "lowercase")]
+    pub enum QueueBackend {
+        /// One file per mail context and per message, in a directory per queue.
+        #[default]
+        Filesystem,
+        /// A single LMDB database file (`queues.db`) in the `dirpath`,
+        /// indexed by queue, next delivery attempt and recipient domain.
+        Embedded,
     }
 
     /// The configuration of one virtual entry for the server.
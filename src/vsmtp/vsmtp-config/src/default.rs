@@ -19,10 +19,12 @@
 use crate::config::field::SyslogSocket;
 use crate::{
     config::field::{
-        FieldApp, FieldAppLogs, FieldAppVSL, FieldQueueDelivery, FieldQueueWorking, FieldServer,
+        FieldApp, FieldAppLogs, FieldAppVSL, FieldQueueDelivery, FieldQueueDeliveryConnectionPool,
+        FieldQueueDeliveryRetry, FieldQueueRetention, FieldQueueWorking, FieldServer,
         FieldServerDNS, FieldServerInterfaces, FieldServerLogs, FieldServerQueues, FieldServerSMTP,
         FieldServerSMTPAuth, FieldServerSMTPError, FieldServerSMTPTimeoutClient, FieldServerSystem,
-        FieldServerSystemThreadPool, FieldServerTls, FieldServerVirtual, ResolverOptsWrapper,
+        FieldServerSystemThreadPool, FieldServerTls, FieldServerVirtual, QueueBackend,
+        ResolverOptsWrapper, RetentionPolicy, RetrySchedule,
     },
     Config,
 };
@@ -106,6 +108,7 @@ impl Config {
                     },
                     group_local: None,
                     thread_pool: FieldServerSystemThreadPool::default(),
+                    shutdown_grace_period: FieldServerSystem::default_shutdown_grace_period(),
                 },
                 // All of this is necessary since `FieldServer` implements a custom
                 // default function instead of using the derivative macro.
@@ -170,6 +173,7 @@ impl Default for FieldServerSystem {
             group: Self::default_group(),
             group_local: None,
             thread_pool: FieldServerSystemThreadPool::default(),
+            shutdown_grace_period: Self::default_shutdown_grace_period(),
         }
     }
 }
@@ -190,6 +194,10 @@ impl FieldServerSystem {
         })
         .expect("default group 'vsmtp' not found.")
     }
+
+    pub(crate) const fn default_shutdown_grace_period() -> std::time::Duration {
+        std::time::Duration::from_secs(60)
+    }
 }
 
 impl Default for FieldServerSystemThreadPool {
@@ -228,8 +236,13 @@ impl FieldServerInterfaces {
             addr: vec!["127.0.0.1:25".parse().expect("valid")],
             addr_submission: vec!["127.0.0.1:587".parse().expect("valid")],
             addr_submissions: vec!["127.0.0.1:465".parse().expect("valid")],
+            control: Self::default_control(),
         }
     }
+
+    pub(crate) fn default_control() -> std::path::PathBuf {
+        "control.sock".into()
+    }
 }
 
 impl Default for FieldServerLogs {
@@ -309,8 +322,10 @@ impl Default for FieldServerQueues {
     fn default() -> Self {
         Self {
             dirpath: Self::default_dirpath(),
+            backend: QueueBackend::default(),
             working: FieldQueueWorking::default(),
             delivery: FieldQueueDelivery::default(),
+            retention: FieldQueueRetention::default(),
         }
     }
 }
@@ -321,10 +336,38 @@ impl FieldServerQueues {
     }
 }
 
+impl Default for FieldQueueRetention {
+    fn default() -> Self {
+        Self {
+            period: Self::default_period(),
+            audit_log: Self::default_audit_log(),
+            dead: RetentionPolicy::default(),
+            quarantine: RetentionPolicy::default(),
+            quarantines: std::collections::BTreeMap::new(),
+            history: Self::default_history(),
+        }
+    }
+}
+
+impl FieldQueueRetention {
+    pub(crate) const fn default_period() -> std::time::Duration {
+        std::time::Duration::from_secs(60 * 60)
+    }
+
+    pub(crate) fn default_audit_log() -> std::path::PathBuf {
+        "/var/log/vsmtp/expired.log".into()
+    }
+
+    pub(crate) const fn default_history() -> Option<std::time::Duration> {
+        Some(std::time::Duration::from_secs(30 * 24 * 60 * 60))
+    }
+}
+
 impl Default for FieldQueueWorking {
     fn default() -> Self {
         Self {
             channel_size: Self::default_channel_size(),
+            concurrency: Self::default_concurrency(),
         }
     }
 }
@@ -333,14 +376,22 @@ impl FieldQueueWorking {
     pub(crate) const fn default_channel_size() -> usize {
         32
     }
+
+    pub(crate) fn default_concurrency() -> std::num::NonZeroUsize {
+        std::num::NonZeroUsize::new(128).expect("128 is non-zero")
+    }
 }
 
 impl Default for FieldQueueDelivery {
     fn default() -> Self {
         Self {
             channel_size: Self::default_channel_size(),
+            concurrency: Self::default_concurrency(),
             deferred_retry_max: Self::default_deferred_retry_max(),
             deferred_retry_period: Self::default_deferred_retry_period(),
+            retry: FieldQueueDeliveryRetry::default(),
+            connection_pool: FieldQueueDeliveryConnectionPool::default(),
+            limits: std::collections::BTreeMap::new(),
         }
     }
 }
@@ -350,6 +401,10 @@ impl FieldQueueDelivery {
         32
     }
 
+    pub(crate) fn default_concurrency() -> std::num::NonZeroUsize {
+        std::num::NonZeroUsize::new(128).expect("128 is non-zero")
+    }
+
     pub(crate) const fn default_deferred_retry_max() -> usize {
         100
     }
@@ -359,6 +414,67 @@ impl FieldQueueDelivery {
     }
 }
 
+impl Default for FieldQueueDeliveryRetry {
+    fn default() -> Self {
+        Self {
+            schedule: RetrySchedule::default(),
+            jitter: Self::default_jitter(),
+            throttle_delay: Self::default_throttle_delay(),
+            bounce_after: Self::default_bounce_after(),
+            warn_after: None,
+        }
+    }
+}
+
+impl FieldQueueDeliveryRetry {
+    pub(crate) const fn default_jitter() -> std::time::Duration {
+        std::time::Duration::from_secs(30)
+    }
+
+    pub(crate) const fn default_throttle_delay() -> std::time::Duration {
+        std::time::Duration::from_secs(60)
+    }
+
+    pub(crate) const fn default_bounce_after() -> std::time::Duration {
+        // 5 days, see <https://datatracker.ietf.org/doc/html/rfc5321#section-4.5.4.1>
+        std::time::Duration::from_secs(5 * 24 * 60 * 60)
+    }
+}
+
+impl Default for FieldQueueDeliveryConnectionPool {
+    fn default() -> Self {
+        Self {
+            idle_timeout: Self::default_idle_timeout(),
+            max_messages: Self::default_max_messages(),
+            max_idle: Self::default_max_idle(),
+        }
+    }
+}
+
+impl FieldQueueDeliveryConnectionPool {
+    pub(crate) const fn default_idle_timeout() -> std::time::Duration {
+        std::time::Duration::from_secs(30)
+    }
+
+    pub(crate) const fn default_max_messages() -> usize {
+        100
+    }
+
+    pub(crate) const fn default_max_idle() -> usize {
+        4
+    }
+}
+
+impl Default for RetrySchedule {
+    fn default() -> Self {
+        Self::Exponential {
+            initial: std::time::Duration::from_secs(5 * 60),
+            factor: 2,
+            max: std::time::Duration::from_secs(4 * 60 * 60),
+        }
+    }
+}
+
 impl FieldServerVirtual {
     pub(crate) fn default_json() -> anyhow::Result<rhai::Map> {
         Ok(rhai::Engine::new().parse_json(serde_json::to_string(&Self::default())?, true)?)
@@ -59,6 +59,7 @@ mod tests;
 
 ///
 pub mod parser {
+    pub(crate) mod duration_list;
     pub(crate) mod socket_addr;
     ///
     pub mod syst_group;
@@ -80,6 +81,7 @@ pub mod builder {
 
 mod config;
 mod default;
+mod retry;
 mod rustls_helper;
 mod virtual_tls;
 
@@ -111,6 +113,7 @@ impl Config {
     /// * Data is not valid vsl.
     /// * Found an unknown field.
     /// * Version requirements are not fulfilled.
+    /// * A value is out of its range, such as a zero period.
     /// * A mandatory field is missing. (when no default value is provided)
     /// * File could not be opened or read.
     ///
@@ -142,6 +145,7 @@ impl Config {
     /// * Data is not valid vsl.
     /// * Found an unknown field.
     /// * Version requirements are not fulfilled.
+    /// * A value is out of its range, such as a zero period.
     /// * A mandatory field is missing. (when no default value is provided)
     pub fn from_vsl_script(
         script: impl AsRef<str>,
@@ -198,6 +202,7 @@ impl Config {
             );
         }
 
+        config.ensure_valid()?;
         config.get_domain_config(&engine)?;
 
         Ok(config)
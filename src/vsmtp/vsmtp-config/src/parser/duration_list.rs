@@ -0,0 +1,71 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+*/
+
+pub fn serialize<S: serde::Serializer>(
+    value: &Vec<std::time::Duration>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let mut x = serializer.serialize_seq(Some(value.len()))?;
+    for i in value {
+        serde::ser::SerializeSeq::serialize_element(&mut x, &humantime_serde::Serde::from(*i))?;
+    }
+    serde::ser::SerializeSeq::end(x)
+}
+
+pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<std::time::Duration>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let value =
+        <Vec<humantime_serde::Serde<std::time::Duration>> as serde::Deserialize>::deserialize(
+            deserializer,
+        )?;
+
+    Ok(value
+        .into_iter()
+        .map(humantime_serde::Serde::into_inner)
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    #[derive(Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+    struct S {
+        #[serde(
+            serialize_with = "crate::parser::duration_list::serialize",
+            deserialize_with = "crate::parser::duration_list::deserialize"
+        )]
+        v: Vec<std::time::Duration>,
+    }
+
+    #[test]
+    fn duration_list() {
+        let s = serde_json::from_str::<S>(r#"{"v": ["5m", "1h 30m"]}"#).unwrap();
+        assert_eq!(
+            s.v,
+            vec![
+                std::time::Duration::from_secs(300),
+                std::time::Duration::from_secs(5400)
+            ]
+        );
+        assert_eq!(
+            serde_json::to_string(&s).unwrap(),
+            r#"{"v":["5m","1h 30m"]}"#
+        );
+        assert!(serde_json::from_str::<S>(r#"{"v": ["foobar"]}"#).is_err());
+    }
+}
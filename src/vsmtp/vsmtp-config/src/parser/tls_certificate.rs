@@ -42,7 +42,6 @@ pub fn from_string(input: &str) -> anyhow::Result<Vec<rustls::Certificate>> {
 #[cfg(test)]
 mod tests {
     use crate::field::SecretFile;
-    use std::io::Write;
     use vsmtp_test::get_tls_file;
 
     #[derive(Debug, serde::Serialize, serde::Deserialize)]
@@ -52,17 +51,11 @@ mod tests {
 
     #[test]
     fn basic() {
-        let _droppable = std::fs::DirBuilder::new().create("./tmp");
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("crt");
+        std::fs::write(&path, get_tls_file::get_certificate()).unwrap();
 
-        let mut file = std::fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open("./tmp/crt")
-            .unwrap();
-        file.write_all(get_tls_file::get_certificate().as_bytes())
-            .unwrap();
-
-        serde_json::from_str::<S>(r#"{"v": "./tmp/crt"}"#).unwrap();
+        serde_json::from_str::<S>(&format!(r#"{{"v": "{}"}}"#, path.display())).unwrap();
     }
 
     #[test]
@@ -44,8 +44,6 @@ pub fn from_string(input: &str) -> anyhow::Result<rustls::PrivateKey> {
 
 #[cfg(test)]
 mod tests {
-    use std::io::Write;
-
     use crate::field::SecretFile;
     use vsmtp_test::get_tls_file;
 
@@ -54,64 +52,40 @@ mod tests {
         v: SecretFile<rustls::PrivateKey>,
     }
 
+    fn file_of(name: &str, content: &str) -> (tempfile::TempDir, String) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(name);
+        std::fs::write(&path, content).unwrap();
+
+        (dir, format!(r#"{{"v": "{}"}}"#, path.display()))
+    }
+
     #[test]
     fn rsa_ok() {
-        let _droppable = std::fs::DirBuilder::new().create("./tmp");
+        let (_dir, json) = file_of("rsa_key", get_tls_file::get_rsa_key());
 
-        let mut file = std::fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open("./tmp/rsa_key")
-            .unwrap();
-        file.write_all(get_tls_file::get_rsa_key().as_bytes())
-            .unwrap();
-
-        serde_json::from_str::<S>(r#"{"v": "./tmp/rsa_key"}"#).unwrap();
+        serde_json::from_str::<S>(&json).unwrap();
     }
 
     #[test]
     fn pkcs8_ok() {
-        let _droppable = std::fs::DirBuilder::new().create("./tmp");
-
-        let mut file = std::fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open("./tmp/pkcs8_key")
-            .unwrap();
-        file.write_all(get_tls_file::get_pkcs8_key().as_bytes())
-            .unwrap();
+        let (_dir, json) = file_of("pkcs8_key", get_tls_file::get_pkcs8_key());
 
-        serde_json::from_str::<S>(r#"{"v": "./tmp/pkcs8_key"}"#).unwrap();
+        serde_json::from_str::<S>(&json).unwrap();
     }
 
     #[test]
     fn ec256_ok() {
-        let _droppable = std::fs::DirBuilder::new().create("./tmp");
+        let (_dir, json) = file_of("ec256_key", get_tls_file::get_ec256_key());
 
-        let mut file = std::fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open("./tmp/ec256_key")
-            .unwrap();
-        file.write_all(get_tls_file::get_ec256_key().as_bytes())
-            .unwrap();
-
-        serde_json::from_str::<S>(r#"{"v": "./tmp/ec256_key"}"#).unwrap();
+        serde_json::from_str::<S>(&json).unwrap();
     }
 
     #[test]
     fn not_good_format() {
-        let _droppable = std::fs::DirBuilder::new().create("./tmp");
-
-        let mut file = std::fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open("./tmp/crt2")
-            .unwrap();
-        file.write_all(get_tls_file::get_certificate().as_bytes())
-            .unwrap();
+        let (_dir, json) = file_of("crt2", get_tls_file::get_certificate());
 
-        serde_json::from_str::<S>(r#"{"v": "./tmp/crt2"}"#).unwrap_err();
+        serde_json::from_str::<S>(&json).unwrap_err();
     }
 
     #[test]
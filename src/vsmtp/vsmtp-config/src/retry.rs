@@ -0,0 +1,124 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+*/
+use crate::field::{FieldQueueDeliveryRetry, RetrySchedule};
+use vsmtp_common::{
+    dsn::Action,
+    transfer::{error::Variant, Error, Status},
+    ContextFinished,
+};
+
+impl FieldQueueDeliveryRetry {
+    /// Compute the date of the next delivery attempt of a recipient held back in
+    /// the deferred queue, `None` if it has never been attempted.
+    ///
+    /// The jitter is derived from the message id and the attempt count, so the
+    /// result is stable across flushes of the queue.
+    ///
+    /// A recipient postponed by the limits of its destination is retried after the
+    /// `throttle_delay`, and the postponements are not counted as attempts.
+    #[must_use]
+    pub fn next_attempt(
+        &self,
+        message_uuid: &uuid::Uuid,
+        errors: &[Error],
+    ) -> Option<time::OffsetDateTime> {
+        let last_error = errors.last()?;
+        if matches!(last_error.variant(), Variant::Throttled(_)) {
+            return Some(*last_error.timestamp() + self.throttle_delay);
+        }
+        let last_error = *last_error.timestamp();
+
+        let attempts = errors
+            .iter()
+            .filter(|error| !matches!(error.variant(), Variant::Throttled(_)))
+            .count();
+        let attempt = u32::try_from(attempts).unwrap_or(u32::MAX);
+
+        let delay = match &self.schedule {
+            RetrySchedule::Exponential {
+                initial,
+                factor,
+                max,
+            } => factor
+                .checked_pow(attempt.saturating_sub(1))
+                .and_then(|multiplier| initial.checked_mul(multiplier))
+                .map_or(*max, |delay| delay.min(*max)),
+            RetrySchedule::Stepped { steps } => steps
+                .get(attempt.saturating_sub(1) as usize)
+                .or_else(|| steps.last())
+                .copied()
+                .unwrap_or_default(),
+        };
+
+        #[allow(clippy::cast_possible_truncation)]
+        let jitter = std::time::Duration::from_millis(
+            fastrand::Rng::with_seed(message_uuid.as_u128() as u64 ^ u64::from(attempt))
+                .u64(0..=self.jitter.as_millis() as u64),
+        );
+
+        Some(last_error + delay + jitter)
+    }
+
+    /// Compute the date the delay notification of a message is due at, `None` if
+    /// `warn_after` is not set, if the reverse path is null, or if no recipient held
+    /// back is still waiting for it.
+    #[must_use]
+    pub fn warn_at(&self, ctx: &ContextFinished) -> Option<time::OffsetDateTime> {
+        let warn_after = self.warn_after?;
+        ctx.mail_from.reverse_path.as_ref()?;
+
+        ctx.rcpt_to
+            .delivery
+            .values()
+            .flatten()
+            .any(|(addr, status)| {
+                matches!(status, Status::HeldBack { .. })
+                    && ctx.rcpt_to.dsn.get(addr).map_or(true, |parameters| {
+                        parameters.should_notify(Action::Delayed, true)
+                            && parameters.last_notification != Some(Action::Delayed)
+                    })
+            })
+            .then(|| ctx.mail_from.mail_timestamp + warn_after)
+    }
+
+    /// Compute the date a message of the deferred queue must be flushed at: the earliest
+    /// next attempt of its recipients, the delay notification not sent yet, or the expiry
+    /// of its lifetime.
+    ///
+    /// A recipient waiting for its first attempt is due immediately.
+    #[must_use]
+    pub fn next_flush(&self, ctx: &ContextFinished) -> time::OffsetDateTime {
+        let expire_at = ctx.mail_from.mail_timestamp + self.bounce_after;
+        let due_at = self
+            .warn_at(ctx)
+            .map_or(expire_at, |warn_at| warn_at.min(expire_at));
+
+        ctx.rcpt_to
+            .delivery
+            .values()
+            .flatten()
+            .filter_map(|(_, status)| match status {
+                Status::HeldBack { errors } => Some(
+                    self.next_attempt(&ctx.mail_from.message_uuid, errors)
+                        .unwrap_or(time::OffsetDateTime::UNIX_EPOCH),
+                ),
+                status if status.is_sendable() => Some(time::OffsetDateTime::UNIX_EPOCH),
+                _ => None,
+            })
+            .fold(due_at, core::cmp::Ord::min)
+    }
+}
@@ -15,7 +15,11 @@
  *
 */
 use crate::{
-    config::field::{FieldQueueDelivery, FieldQueueWorking},
+    config::field::{
+        FieldQueueDelivery, FieldQueueDeliveryConnectionPool, FieldQueueDeliveryLimits,
+        FieldQueueDeliveryRetry, FieldQueueRetention, FieldQueueWorking, RetentionPolicy,
+        RetrySchedule,
+    },
     Config,
 };
 use vsmtp_common::{collection, Stage};
@@ -27,62 +31,111 @@ fn parse() {
         "../../../examples/config/secured.vsl",
     ]);
 
-    pretty_assertions::assert_eq!(
-        Config::from_vsl_file(&path_to_config).unwrap(),
-        Config::builder()
-            .with_version_str(&format!(">={}, <3.0.0", env!("CARGO_PKG_VERSION")))
-            .unwrap()
-            .with_path(path_to_config)
-            .with_hostname_and_client_count_max(8)
-            .with_default_user_and_thread_pool(
-                std::num::NonZeroUsize::new(3).unwrap(),
-                std::num::NonZeroUsize::new(3).unwrap(),
-                std::num::NonZeroUsize::new(3).unwrap()
-            )
-            .with_ipv4_localhost()
-            .with_default_logs_settings()
-            .with_spool_dir_and_queues(
-                "/var/spool/vsmtp",
-                FieldQueueWorking { channel_size: 16 },
-                FieldQueueDelivery {
-                    channel_size: 16,
-                    deferred_retry_max: 10,
-                    deferred_retry_period: std::time::Duration::from_secs(600)
-                }
-            )
-            .without_tls_support()
-            .with_rcpt_count_and_default(25)
-            .with_error_handler_and_timeout(
-                5,
-                10,
-                std::time::Duration::from_millis(50_000),
-                &collection! {
-                    Stage::Connect => std::time::Duration::from_millis(50),
-                    Stage::Helo => std::time::Duration::from_millis(100),
-                    Stage::MailFrom => std::time::Duration::from_millis(200),
-                    Stage::RcptTo => std::time::Duration::from_millis(400),
-                }
-            )
-            .without_auth()
-            .with_default_app()
-            .with_default_vsl_settings()
-            .with_default_app_logs()
-            .with_dns(
-                {
-                    let mut cfg = trust_dns_resolver::config::ResolverConfig::new();
+    let mut expected = Config::builder()
+        .with_version_str(&format!(">={}, <3.0.0", env!("CARGO_PKG_VERSION")))
+        .unwrap()
+        .with_path(path_to_config.clone())
+        .with_hostname_and_client_count_max(8)
+        .with_default_user_and_thread_pool(
+            std::num::NonZeroUsize::new(3).unwrap(),
+            std::num::NonZeroUsize::new(3).unwrap(),
+            std::num::NonZeroUsize::new(3).unwrap(),
+        )
+        .with_ipv4_localhost()
+        .with_default_logs_settings()
+        .with_spool_dir_and_queues(
+            "/var/spool/vsmtp",
+            FieldQueueWorking {
+                channel_size: 16,
+                concurrency: std::num::NonZeroUsize::new(64).unwrap(),
+            },
+            FieldQueueDelivery {
+                channel_size: 16,
+                concurrency: std::num::NonZeroUsize::new(64).unwrap(),
+                deferred_retry_max: 10,
+                deferred_retry_period: std::time::Duration::from_secs(600),
+                retry: FieldQueueDeliveryRetry {
+                    schedule: RetrySchedule::Stepped {
+                        steps: vec![
+                            std::time::Duration::from_secs(600),
+                            std::time::Duration::from_secs(1800),
+                            std::time::Duration::from_secs(7200),
+                        ],
+                    },
+                    jitter: std::time::Duration::from_secs(60),
+                    throttle_delay: std::time::Duration::from_secs(60),
+                    bounce_after: std::time::Duration::from_secs(3 * 24 * 60 * 60),
+                    warn_after: Some(std::time::Duration::from_secs(4 * 60 * 60)),
+                },
+                connection_pool: FieldQueueDeliveryConnectionPool {
+                    idle_timeout: std::time::Duration::from_secs(60),
+                    max_messages: 50,
+                    max_idle: 2,
+                },
+                limits: std::collections::BTreeMap::from([(
+                    "gmail.com".parse().unwrap(),
+                    FieldQueueDeliveryLimits {
+                        max_connections: Some(4),
+                        messages_per_minute: Some(120),
+                        max_recipients: Some(50),
+                    },
+                )]),
+            },
+        )
+        .without_tls_support()
+        .with_rcpt_count_and_default(25)
+        .with_error_handler_and_timeout(
+            5,
+            10,
+            std::time::Duration::from_millis(50_000),
+            &collection! {
+                Stage::Connect => std::time::Duration::from_millis(50),
+                Stage::Helo => std::time::Duration::from_millis(100),
+                Stage::MailFrom => std::time::Duration::from_millis(200),
+                Stage::RcptTo => std::time::Duration::from_millis(400),
+            },
+        )
+        .without_auth()
+        .with_default_app()
+        .with_default_vsl_settings()
+        .with_default_app_logs()
+        .with_dns(
+            {
+                let mut cfg = trust_dns_resolver::config::ResolverConfig::new();
 
-                    cfg.set_domain(
-                        <trust_dns_resolver::Name as std::str::FromStr>::from_str(
-                            "example.dns.com",
-                        )
+                cfg.set_domain(
+                    <trust_dns_resolver::Name as std::str::FromStr>::from_str("example.dns.com")
                         .unwrap(),
-                    );
+                );
 
-                    cfg
-                },
-                crate::field::ResolverOptsWrapper::default()
-            )
-            .without_virtual_entries()
-            .validate()
-    );
+                cfg
+            },
+            crate::field::ResolverOptsWrapper::default(),
+        )
+        .without_virtual_entries()
+        .validate();
+    expected.server.queues.retention = FieldQueueRetention {
+        dead: RetentionPolicy {
+            max_age: Some(std::time::Duration::from_secs(30 * 24 * 60 * 60)),
+            max_count: None,
+            max_bytes: Some(1_073_741_824),
+        },
+        quarantine: RetentionPolicy {
+            max_age: Some(std::time::Duration::from_secs(7 * 24 * 60 * 60)),
+            max_count: None,
+            max_bytes: None,
+        },
+        quarantines: std::collections::BTreeMap::from([(
+            "virus".to_owned(),
+            RetentionPolicy {
+                max_age: Some(std::time::Duration::from_secs(24 * 60 * 60)),
+                max_count: Some(100),
+                max_bytes: None,
+            },
+        )]),
+        history: Some(std::time::Duration::from_secs(90 * 24 * 60 * 60)),
+        ..FieldQueueRetention::default()
+    };
+
+    pretty_assertions::assert_eq!(Config::from_vsl_file(&path_to_config).unwrap(), expected);
 }
@@ -0,0 +1,947 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+*/
+
+//! SMTP security via opportunistic DANE TLS.
+//! See <https://datatracker.ietf.org/doc/html/rfc7672>
+
+use lettre::transport::smtp::{
+    client::{AsyncNetworkStream, AsyncSmtpConnection, AsyncTokioStream},
+    commands::{Ehlo, Starttls},
+    extension::{ClientId, Extension},
+};
+use sha2::Digest;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use trust_dns_resolver::proto::{
+    op::{Edns, Message, MessageType, OpCode, Query, ResponseCode},
+    rr::{
+        rdata::tlsa::{CertUsage, Matching, Selector},
+        rdata::TLSA,
+        Name, RData, RecordType,
+    },
+};
+use vsmtp_common::{transfer::error::Delivery, Domain};
+extern crate alloc;
+
+/// Maximum size of the answers received over UDP.
+const UDP_PAYLOAD: u16 = 1232;
+
+/// The usable TLSA records of a mail exchanger, obtained with a DNSSEC validated lookup.
+#[derive(Debug, Clone)]
+pub struct Dane {
+    /// Name of the mail exchanger, used as reference identifier for the `DANE-TA` records.
+    pub name: Domain,
+    /// Records with the usage `DANE-TA(2)` or `DANE-EE(3)`.
+    pub records: Vec<TLSA>,
+}
+
+impl Dane {
+    /// Lookup the TLSA records of the mail exchanger at `_25._tcp.<mx>`.
+    ///
+    /// The records are queried to the resolvers at `name_servers`, which must validate the
+    /// answers with DNSSEC, and set their `AD` bit if they are secure.
+    /// See <https://datatracker.ietf.org/doc/html/rfc7672#section-2.2>
+    ///
+    /// Return `None` if the mail exchanger has no usable TLSA records, or if the answer is
+    /// insecure (not signed), and an error if the lookup failed or the answer is bogus,
+    /// in which case the mail exchanger must not be used.
+    pub async fn lookup(
+        name_servers: &[std::net::SocketAddr],
+        timeout: core::time::Duration,
+        mx: &Domain,
+    ) -> Result<Option<Self>, Delivery> {
+        let mut query =
+            Name::from_ascii(format!("_25._tcp.{}", mx.to_string().trim_end_matches('.')))
+                .map_err(Self::error)?;
+        query.set_fqdn(true);
+
+        let mut error = Self::error("no resolver to lookup the TLSA records");
+        for name_server in name_servers {
+            match Self::query(*name_server, &query, timeout).await {
+                Ok(answer) if !answer.authentic_data() => {
+                    tracing::debug!(%mx, "TLSA records lookup is insecure.");
+                    return Ok(None);
+                }
+                Ok(answer) => {
+                    let records = answer
+                        .answers()
+                        .iter()
+                        .filter_map(|record| match record.data() {
+                            Some(RData::TLSA(tlsa)) => Some(tlsa.clone()),
+                            _ => None,
+                        })
+                        .collect();
+                    return Ok(Self::new(mx.clone(), records));
+                }
+                Err(otherwise) => {
+                    tracing::warn!(%mx, %name_server, %otherwise, "TLSA records lookup failed.");
+                    error = otherwise;
+                }
+            }
+        }
+
+        Err(error)
+    }
+
+    /// Send the TLSA query to `name_server` over UDP, and over TCP if the answer is truncated.
+    ///
+    /// A validating resolver answers `SERVFAIL` if the answer is bogus.
+    async fn query(
+        name_server: std::net::SocketAddr,
+        query: &Name,
+        timeout: core::time::Duration,
+    ) -> Result<Message, Delivery> {
+        let [first, second, ..] = uuid::Uuid::new_v4().into_bytes();
+        let id = u16::from_le_bytes([first, second]);
+
+        let mut edns = Edns::new();
+        edns.set_dnssec_ok(true).set_max_payload(UDP_PAYLOAD);
+        let mut request = Message::new();
+        request
+            .set_id(id)
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Query)
+            .set_recursion_desired(true)
+            .set_authentic_data(true)
+            .add_query(Query::query(query.clone(), RecordType::TLSA))
+            .set_edns(edns);
+        let request = request.to_vec().map_err(Self::error)?;
+
+        let answer = tokio::time::timeout(timeout, async {
+            let socket = tokio::net::UdpSocket::bind(if name_server.is_ipv4() {
+                "0.0.0.0:0"
+            } else {
+                "[::]:0"
+            })
+            .await?;
+            socket.connect(name_server).await?;
+            socket.send(&request).await?;
+
+            let mut buffer = vec![0; usize::from(UDP_PAYLOAD)];
+            let answer = loop {
+                let len = socket.recv(&mut buffer).await?;
+                // NOTE: the datagrams which are not the answer of the query are ignored.
+                if let Ok(answer) = Message::from_vec(buffer.get(..len).unwrap_or_default()) {
+                    if answer.id() == id {
+                        break answer;
+                    }
+                }
+            };
+            if !answer.truncated() {
+                return Ok::<_, Delivery>(answer);
+            }
+
+            let mut stream = tokio::net::TcpStream::connect(name_server).await?;
+            let len = u16::try_from(request.len()).map_err(Self::error)?;
+            stream.write_all(&len.to_be_bytes()).await?;
+            stream.write_all(&request).await?;
+            let mut answer = vec![0; usize::from(stream.read_u16().await?)];
+            stream.read_exact(&mut answer).await?;
+            Message::from_vec(&answer).map_err(Self::error)
+        })
+        .await
+        .map_err(std::io::Error::from)??;
+
+        match answer.response_code() {
+            _ if answer.id() != id => Err(Self::error("the answer does not match the query")),
+            ResponseCode::NoError | ResponseCode::NXDomain => Ok(answer),
+            code => Err(Self::error(format!(
+                "failed to lookup the TLSA records: {code}"
+            ))),
+        }
+    }
+
+    /// Keep only the usable records, `None` if there is none.
+    /// See <https://datatracker.ietf.org/doc/html/rfc7672#section-3.1.3>
+    #[must_use]
+    pub fn new(name: Domain, records: Vec<TLSA>) -> Option<Self> {
+        let records = records
+            .into_iter()
+            .filter(|record| {
+                matches!(
+                    record.cert_usage(),
+                    CertUsage::TrustAnchor | CertUsage::DomainIssued
+                ) && matches!(record.selector(), Selector::Full | Selector::Spki)
+                    && matches!(
+                        record.matching(),
+                        Matching::Raw | Matching::Sha256 | Matching::Sha512
+                    )
+            })
+            .collect::<Vec<_>>();
+
+        (!records.is_empty()).then_some(Self { name, records })
+    }
+
+    /// Open a connection to the server, and upgrade it with `STARTTLS`, within `timeout`.
+    ///
+    /// The certificate chain of the server is authenticated with the TLSA records,
+    /// any failure (including the absence of `STARTTLS`) is a [`Delivery::Tls`] error.
+    pub async fn connect(
+        &self,
+        server: (String, u16),
+        hello_name: &ClientId,
+        timeout: core::time::Duration,
+    ) -> Result<AsyncSmtpConnection, Delivery> {
+        tokio::time::timeout(timeout, self.connect_starttls(server, hello_name))
+            .await
+            .map_err(std::io::Error::from)?
+    }
+
+    async fn connect_starttls(
+        &self,
+        server: (String, u16),
+        hello_name: &ClientId,
+    ) -> Result<AsyncSmtpConnection, Delivery> {
+        // NOTE: lettre does not allow a custom certificate verifier, so the socket is taken
+        // back from lettre after `STARTTLS` for the handshake, and the TLS stream replaces it.
+        let plain_stream = PlainStream::new(tokio::net::TcpStream::connect(server).await?);
+        let mut conn =
+            AsyncSmtpConnection::connect_with_transport(Box::new(plain_stream.clone()), hello_name)
+                .await?;
+
+        if !conn.server_info().supports_feature(Extension::StartTls) {
+            return Err(Self::error("the server does not support STARTTLS"));
+        }
+        conn.command(Starttls).await?;
+
+        let config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(alloc::sync::Arc::new(self.clone()))
+            .with_no_client_auth();
+
+        let server_name = rustls::ServerName::try_from(self.name.to_string().trim_end_matches('.'))
+            .map_err(Self::error)?;
+
+        let tls_stream = tokio_rustls::TlsConnector::from(alloc::sync::Arc::new(config))
+            .connect(server_name, plain_stream.take_socket()?)
+            .await
+            .map_err(Self::error)?;
+
+        conn.set_stream(AsyncNetworkStream::use_existing_tokio1(Box::new(
+            TlsStream(tls_stream),
+        )));
+        conn.command(Ehlo::new(hello_name.clone())).await?;
+
+        Ok(conn)
+    }
+
+    fn error(error: impl core::fmt::Display) -> Delivery {
+        Delivery::Tls {
+            with_source: Some(format!("dane: {error}")),
+        }
+    }
+
+    /// Does the certificate match the record ?
+    fn matches(record: &TLSA, certificate: &rustls::Certificate) -> bool {
+        let selected = match record.selector() {
+            Selector::Full => certificate.0.clone(),
+            Selector::Spki => match x509_parser::parse_x509_certificate(&certificate.0) {
+                Ok((_, parsed)) => parsed.tbs_certificate.subject_pki.raw.to_vec(),
+                Err(_) => return false,
+            },
+            Selector::Unassigned(_) | Selector::Private => return false,
+        };
+
+        let digest = match record.matching() {
+            Matching::Raw => selected,
+            Matching::Sha256 => sha2::Sha256::digest(selected).to_vec(),
+            Matching::Sha512 => sha2::Sha512::digest(selected).to_vec(),
+            Matching::Unassigned(_) | Matching::Private => return false,
+        };
+
+        digest == record.cert_data()
+    }
+}
+
+impl rustls::client::ServerCertVerifier for Dane {
+    #[inline]
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        intermediates: &[rustls::Certificate],
+        server_name: &rustls::ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: std::time::SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        for record in &self.records {
+            match record.cert_usage() {
+                // the name and the validity of the certificate are not checked.
+                CertUsage::DomainIssued if Self::matches(record, end_entity) => {
+                    return Ok(rustls::client::ServerCertVerified::assertion());
+                }
+                CertUsage::TrustAnchor => {
+                    for trust_anchor in intermediates
+                        .iter()
+                        .filter(|certificate| Self::matches(record, certificate))
+                    {
+                        let mut roots = rustls::RootCertStore::empty();
+                        if roots.add(trust_anchor).is_err() {
+                            continue;
+                        }
+
+                        if rustls::client::WebPkiVerifier::new(roots, None)
+                            .verify_server_cert(
+                                end_entity,
+                                intermediates,
+                                server_name,
+                                scts,
+                                ocsp_response,
+                                now,
+                            )
+                            .is_ok()
+                        {
+                            return Ok(rustls::client::ServerCertVerified::assertion());
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Err(rustls::Error::General(
+            "no TLSA record matches the certificate chain of the server".to_owned(),
+        ))
+    }
+}
+
+/// Plain text stream given to lettre until `STARTTLS`.
+#[derive(Debug, Clone)]
+struct PlainStream(alloc::sync::Arc<std::sync::Mutex<Option<tokio::net::TcpStream>>>);
+
+impl PlainStream {
+    fn new(stream: tokio::net::TcpStream) -> Self {
+        Self(alloc::sync::Arc::new(std::sync::Mutex::new(Some(stream))))
+    }
+
+    /// Take the socket back, lettre cannot use it anymore.
+    fn take_socket(&self) -> std::io::Result<tokio::net::TcpStream> {
+        self.0
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .take()
+            .ok_or_else(|| std::io::ErrorKind::NotConnected.into())
+    }
+
+    fn with<T>(
+        &self,
+        f: impl FnOnce(core::pin::Pin<&mut tokio::net::TcpStream>) -> T,
+        closed: T,
+    ) -> T {
+        match self
+            .0
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .as_mut()
+        {
+            Some(stream) => f(core::pin::Pin::new(stream)),
+            None => closed,
+        }
+    }
+}
+
+impl tokio::io::AsyncRead for PlainStream {
+    #[inline]
+    fn poll_read(
+        self: core::pin::Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> core::task::Poll<std::io::Result<()>> {
+        self.with(
+            |stream| stream.poll_read(cx, buf),
+            core::task::Poll::Ready(Err(std::io::ErrorKind::NotConnected.into())),
+        )
+    }
+}
+
+impl tokio::io::AsyncWrite for PlainStream {
+    #[inline]
+    fn poll_write(
+        self: core::pin::Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+        buf: &[u8],
+    ) -> core::task::Poll<Result<usize, std::io::Error>> {
+        self.with(
+            |stream| stream.poll_write(cx, buf),
+            core::task::Poll::Ready(Err(std::io::ErrorKind::NotConnected.into())),
+        )
+    }
+
+    #[inline]
+    fn poll_flush(
+        self: core::pin::Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+    ) -> core::task::Poll<Result<(), std::io::Error>> {
+        self.with(
+            |stream| stream.poll_flush(cx),
+            core::task::Poll::Ready(Ok(())),
+        )
+    }
+
+    #[inline]
+    fn poll_shutdown(
+        self: core::pin::Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+    ) -> core::task::Poll<Result<(), std::io::Error>> {
+        self.with(
+            |stream| stream.poll_shutdown(cx),
+            core::task::Poll::Ready(Ok(())),
+        )
+    }
+}
+
+impl AsyncTokioStream for PlainStream {
+    #[inline]
+    fn peer_addr(&self) -> std::io::Result<std::net::SocketAddr> {
+        self.with(
+            |stream| stream.peer_addr(),
+            Err(std::io::ErrorKind::NotConnected.into()),
+        )
+    }
+}
+
+/// TLS stream negotiated with the [`Dane`] verifier, given to lettre.
+#[derive(Debug)]
+struct TlsStream(tokio_rustls::client::TlsStream<tokio::net::TcpStream>);
+
+impl tokio::io::AsyncRead for TlsStream {
+    #[inline]
+    fn poll_read(
+        mut self: core::pin::Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> core::task::Poll<std::io::Result<()>> {
+        core::pin::Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl tokio::io::AsyncWrite for TlsStream {
+    #[inline]
+    fn poll_write(
+        mut self: core::pin::Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+        buf: &[u8],
+    ) -> core::task::Poll<Result<usize, std::io::Error>> {
+        core::pin::Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    #[inline]
+    fn poll_flush(
+        mut self: core::pin::Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+    ) -> core::task::Poll<Result<(), std::io::Error>> {
+        core::pin::Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(
+        mut self: core::pin::Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+    ) -> core::task::Poll<Result<(), std::io::Error>> {
+        core::pin::Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+impl AsyncTokioStream for TlsStream {
+    #[inline]
+    fn peer_addr(&self) -> std::io::Result<std::net::SocketAddr> {
+        self.0.get_ref().0.peer_addr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::client::ServerCertVerifier;
+    use vsmtp_test::get_tls_file::{
+        get_dane_ca_certificate, get_dane_mx_certificate, get_dane_mx_pkcs8_key,
+    };
+
+    fn der(input: &str) -> Vec<u8> {
+        pem::parse(input).unwrap().into_contents()
+    }
+
+    fn spki(certificate: &[u8]) -> Vec<u8> {
+        x509_parser::parse_x509_certificate(certificate)
+            .unwrap()
+            .1
+            .tbs_certificate
+            .subject_pki
+            .raw
+            .to_vec()
+    }
+
+    fn dane(records: Vec<TLSA>) -> Dane {
+        Dane::new("mx.testserver.com".parse().unwrap(), records).unwrap()
+    }
+
+    fn verify(dane: &Dane, chain: &[Vec<u8>], name: &str) -> Result<(), rustls::Error> {
+        let chain = chain
+            .iter()
+            .cloned()
+            .map(rustls::Certificate)
+            .collect::<Vec<_>>();
+
+        dane.verify_server_cert(
+            &chain[0],
+            &chain[1..],
+            &rustls::ServerName::try_from(name).unwrap(),
+            &mut core::iter::empty(),
+            &[],
+            std::time::SystemTime::now(),
+        )
+        .map(|_| ())
+    }
+
+    #[test]
+    fn unusable_records() {
+        let mx = der(get_dane_mx_certificate());
+
+        assert!(Dane::new(
+            "mx.testserver.com".parse().unwrap(),
+            vec![
+                TLSA::new(CertUsage::CA, Selector::Full, Matching::Raw, mx.clone()),
+                TLSA::new(
+                    CertUsage::Service,
+                    Selector::Full,
+                    Matching::Raw,
+                    mx.clone()
+                ),
+                TLSA::new(
+                    CertUsage::DomainIssued,
+                    Selector::Private,
+                    Matching::Raw,
+                    mx
+                ),
+            ]
+        )
+        .is_none());
+    }
+
+    #[rstest::rstest]
+    #[case::full_raw(Selector::Full, Matching::Raw)]
+    #[case::full_sha256(Selector::Full, Matching::Sha256)]
+    #[case::spki_sha256(Selector::Spki, Matching::Sha256)]
+    #[case::spki_sha512(Selector::Spki, Matching::Sha512)]
+    fn domain_issued(#[case] selector: Selector, #[case] matching: Matching) {
+        let mx = der(get_dane_mx_certificate());
+        let selected = match selector {
+            Selector::Spki => spki(&mx),
+            _ => mx.clone(),
+        };
+        let data = match matching {
+            Matching::Sha256 => sha2::Sha256::digest(&selected).to_vec(),
+            Matching::Sha512 => sha2::Sha512::digest(&selected).to_vec(),
+            _ => selected,
+        };
+
+        let dane = dane(vec![TLSA::new(
+            CertUsage::DomainIssued,
+            selector,
+            matching,
+            data,
+        )]);
+
+        // the name is not checked for the `DANE-EE` records.
+        verify(&dane, &[mx.clone()], "mx.testserver.com").unwrap();
+        verify(&dane, &[mx], "other.testserver.com").unwrap();
+    }
+
+    #[test]
+    fn domain_issued_mismatch() {
+        let dane = dane(vec![TLSA::new(
+            CertUsage::DomainIssued,
+            Selector::Spki,
+            Matching::Sha256,
+            sha2::Sha256::digest(spki(&der(get_dane_ca_certificate()))).to_vec(),
+        )]);
+
+        assert!(verify(
+            &dane,
+            &[der(get_dane_mx_certificate())],
+            "mx.testserver.com"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn trust_anchor() {
+        let (mx, ca) = (
+            der(get_dane_mx_certificate()),
+            der(get_dane_ca_certificate()),
+        );
+        let dane = dane(vec![TLSA::new(
+            CertUsage::TrustAnchor,
+            Selector::Full,
+            Matching::Sha256,
+            sha2::Sha256::digest(&ca).to_vec(),
+        )]);
+
+        verify(&dane, &[mx.clone(), ca.clone()], "mx.testserver.com").unwrap();
+        assert!(verify(&dane, &[mx.clone(), ca], "other.testserver.com").is_err());
+        // the trust anchor must be in the chain sent by the server.
+        assert!(verify(&dane, &[mx], "mx.testserver.com").is_err());
+    }
+
+    /// Minimal SMTP server advertising `STARTTLS` (if `tls` is set) and closing the connection
+    /// after the `EHLO` following the handshake.
+    async fn mock_server(listener: tokio::net::TcpListener, tls: bool) {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        stream.write_all(b"220 mock ESMTP\r\n").await.unwrap();
+
+        let mut lines = tokio::io::BufReader::new(&mut stream).lines();
+        while let Some(line) = lines.next_line().await.unwrap() {
+            let reply = match line.as_str() {
+                ehlo if ehlo.starts_with("EHLO") && tls => "250-mock\r\n250 STARTTLS\r\n",
+                ehlo if ehlo.starts_with("EHLO") => "250 mock\r\n",
+                "STARTTLS" => break,
+                _ => "221 Bye\r\n",
+            };
+            lines.get_mut().write_all(reply.as_bytes()).await.unwrap();
+        }
+        if !tls {
+            return;
+        }
+        stream
+            .write_all(b"220 Ready to start TLS\r\n")
+            .await
+            .unwrap();
+
+        let config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![
+                    rustls::Certificate(der(get_dane_mx_certificate())),
+                    rustls::Certificate(der(get_dane_ca_certificate())),
+                ],
+                rustls::PrivateKey(der(get_dane_mx_pkcs8_key())),
+            )
+            .unwrap();
+
+        // the handshake fails if the client refuses the certificate.
+        let Ok(mut stream) = tokio_rustls::TlsAcceptor::from(alloc::sync::Arc::new(config))
+            .accept(stream)
+            .await
+        else {
+            return;
+        };
+
+        let mut lines = tokio::io::BufReader::new(&mut stream).lines();
+        while let Some(line) = lines.next_line().await.unwrap() {
+            if line == "QUIT" {
+                lines.get_mut().write_all(b"221 Bye\r\n").await.unwrap();
+                return;
+            }
+            lines.get_mut().write_all(b"250 mock\r\n").await.unwrap();
+        }
+    }
+
+    async fn connect(records: Vec<TLSA>, tls: bool) -> Result<(), Delivery> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(mock_server(listener, tls));
+
+        let outcome = dane(records)
+            .connect(
+                ("127.0.0.1".to_owned(), port),
+                &ClientId::Domain("client.testserver.com".to_owned()),
+                core::time::Duration::from_secs(10),
+            )
+            .await;
+        let outcome = match outcome {
+            Ok(mut conn) => conn.quit().await.map(|_| ()).map_err(Into::into),
+            Err(error) => Err(error),
+        };
+
+        server.await.unwrap();
+        outcome
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn starttls() {
+        connect(
+            vec![TLSA::new(
+                CertUsage::DomainIssued,
+                Selector::Spki,
+                Matching::Sha256,
+                sha2::Sha256::digest(spki(&der(get_dane_mx_certificate()))).to_vec(),
+            )],
+            true,
+        )
+        .await
+        .unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn starttls_mismatch() {
+        let error = connect(
+            vec![TLSA::new(
+                CertUsage::DomainIssued,
+                Selector::Full,
+                Matching::Raw,
+                der(get_dane_ca_certificate()),
+            )],
+            true,
+        )
+        .await
+        .unwrap_err();
+
+        assert!(matches!(error, Delivery::Tls { .. }), "{error}");
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn starttls_not_supported() {
+        let error = connect(
+            vec![TLSA::new(
+                CertUsage::DomainIssued,
+                Selector::Full,
+                Matching::Raw,
+                der(get_dane_mx_certificate()),
+            )],
+            false,
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(
+            error,
+            Delivery::Tls {
+                with_source: Some("dane: the server does not support STARTTLS".to_owned())
+            }
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn connect_timeout() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        // the server never sends its greeting.
+        let Err(error) = dane(vec![TLSA::new(
+            CertUsage::DomainIssued,
+            Selector::Full,
+            Matching::Raw,
+            der(get_dane_mx_certificate()),
+        )])
+        .connect(
+            ("127.0.0.1".to_owned(), port),
+            &ClientId::Domain("client.testserver.com".to_owned()),
+            core::time::Duration::from_millis(200),
+        )
+        .await
+        else {
+            panic!("the connection must time out");
+        };
+
+        assert!(matches!(error, Delivery::Connection { .. }), "{error}");
+        drop(listener);
+    }
+
+    /// Answer of the stand-in of a validating resolver for a name.
+    #[derive(Clone)]
+    enum Answer {
+        /// The zone is signed, the answer has the `AD` bit.
+        Secure(Vec<TLSA>),
+        /// The zone is not signed.
+        Insecure(Vec<TLSA>),
+        /// The signatures are invalid, the resolver answers `SERVFAIL`.
+        Bogus,
+    }
+
+    /// Stand-in of a validating resolver serving the TLSA records of `zone`, the other names
+    /// do not exist in a signed zone. The answers over UDP are truncated if `truncate`.
+    async fn validating_resolver(
+        zone: &'static [(&'static str, fn() -> Answer)],
+        truncate: bool,
+    ) -> std::net::SocketAddr {
+        fn answer(request: &Message, zone: &[(&str, fn() -> Answer)], truncate: bool) -> Vec<u8> {
+            let query = request.queries()[0].clone();
+            let mut response = Message::new();
+            response
+                .set_id(request.id())
+                .set_message_type(MessageType::Response)
+                .set_recursion_desired(true)
+                .set_recursion_available(true)
+                .add_query(query.clone());
+
+            if truncate {
+                response.set_truncated(true);
+                return response.to_vec().unwrap();
+            }
+
+            let (secure, records) = match zone
+                .iter()
+                .find(|(name, _)| *name == query.name().to_string())
+                .map(|(_, answer)| answer())
+            {
+                Some(Answer::Secure(records)) => (true, records),
+                Some(Answer::Insecure(records)) => (false, records),
+                Some(Answer::Bogus) => {
+                    response.set_response_code(ResponseCode::ServFail);
+                    return response.to_vec().unwrap();
+                }
+                None => {
+                    response.set_response_code(ResponseCode::NXDomain);
+                    (true, vec![])
+                }
+            };
+            response
+                .set_authentic_data(secure && request.authentic_data())
+                .add_answers(records.into_iter().map(|tlsa| {
+                    trust_dns_resolver::proto::rr::Record::from_rdata(
+                        query.name().clone(),
+                        60,
+                        RData::TLSA(tlsa),
+                    )
+                }));
+            response.to_vec().unwrap()
+        }
+
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+        let listener = tokio::net::TcpListener::bind(address).await.unwrap();
+
+        tokio::spawn(async move {
+            let mut buffer = [0; 512];
+            loop {
+                let (size, peer) = socket.recv_from(&mut buffer).await.unwrap();
+                let request = Message::from_vec(&buffer[..size]).unwrap();
+                socket
+                    .send_to(&answer(&request, zone, truncate), peer)
+                    .await
+                    .unwrap();
+            }
+        });
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buffer = vec![0; usize::from(stream.read_u16().await.unwrap())];
+                stream.read_exact(&mut buffer).await.unwrap();
+
+                let response = answer(&Message::from_vec(&buffer).unwrap(), zone, false);
+                stream
+                    .write_all(&u16::try_from(response.len()).unwrap().to_be_bytes())
+                    .await
+                    .unwrap();
+                stream.write_all(&response).await.unwrap();
+            }
+        });
+
+        address
+    }
+
+    fn mx_records() -> Vec<TLSA> {
+        vec![
+            TLSA::new(
+                CertUsage::DomainIssued,
+                Selector::Spki,
+                Matching::Sha256,
+                sha2::Sha256::digest(spki(&der(get_dane_mx_certificate()))).to_vec(),
+            ),
+            TLSA::new(
+                CertUsage::CA,
+                Selector::Full,
+                Matching::Raw,
+                der(get_dane_ca_certificate()),
+            ),
+        ]
+    }
+
+    const ZONE: &[(&str, fn() -> Answer)] = &[
+        ("_25._tcp.mx.signed.test.", || Answer::Secure(mx_records())),
+        ("_25._tcp.mx.unsigned.test.", || {
+            Answer::Insecure(mx_records())
+        }),
+        ("_25._tcp.mx.bogus.test.", || Answer::Bogus),
+    ];
+
+    async fn lookup(
+        name_servers: &[std::net::SocketAddr],
+        mx: &str,
+    ) -> Result<Option<Dane>, Delivery> {
+        Dane::lookup(
+            name_servers,
+            core::time::Duration::from_millis(500),
+            &mx.parse().unwrap(),
+        )
+        .await
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn lookup_secure() {
+        let resolver = validating_resolver(ZONE, false).await;
+
+        let dane = lookup(&[resolver], "mx.signed.test")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(dane.name, "mx.signed.test".parse().unwrap());
+        // the `PKIX-TA` record is not usable.
+        assert_eq!(dane.records, mx_records()[..1]);
+
+        // the name server answers over TCP if the answer does not fit in a datagram.
+        let truncated = validating_resolver(ZONE, true).await;
+        assert_eq!(
+            lookup(&[truncated], "mx.signed.test.")
+                .await
+                .unwrap()
+                .unwrap()
+                .records,
+            mx_records()[..1]
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn lookup_insecure() {
+        let resolver = validating_resolver(ZONE, false).await;
+
+        // DANE is not used for the unsigned zones, and the secure denial of existence.
+        assert!(lookup(&[resolver], "mx.unsigned.test")
+            .await
+            .unwrap()
+            .is_none());
+        assert!(lookup(&[resolver], "mx.missing.test")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn lookup_failure() {
+        let resolver = validating_resolver(ZONE, false).await;
+        let unreachable = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let unreachable = unreachable.local_addr().unwrap();
+
+        assert!(matches!(
+            lookup(&[resolver], "mx.bogus.test").await.unwrap_err(),
+            Delivery::Tls { .. }
+        ));
+        assert!(matches!(
+            lookup(&[unreachable], "mx.signed.test").await.unwrap_err(),
+            Delivery::Connection { .. }
+        ));
+        lookup(&[], "mx.signed.test").await.unwrap_err();
+
+        // the next name server is queried if one fails.
+        assert!(lookup(&[unreachable, resolver], "mx.signed.test")
+            .await
+            .unwrap()
+            .is_some());
+    }
+}
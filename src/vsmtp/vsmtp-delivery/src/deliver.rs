@@ -14,17 +14,25 @@
  * this program. If not, see https://www.gnu.org/licenses/.
  *
 */
-use crate::{send::SenderParameters, to_lettre_envelope};
-use trust_dns_resolver::TokioAsyncResolver;
+use crate::{
+    dane::Dane,
+    limits::Permit,
+    mta_sts::{Mode, Policy},
+    pool::ConnectionPool,
+    receipts,
+    send::{SenderParameters, TlsPolicy},
+    to_lettre_envelope, update_status,
+};
+use trust_dns_resolver::{config::ResolverConfig, TokioAsyncResolver};
 use vsmtp_common::{
     transfer::{
-        error::{Lookup, Variant},
-        Status,
+        error::{Delivery, Lookup, Variant},
+        Receipt, Status,
     },
     transport::{AbstractTransport, DeliverTo},
     Address, ContextFinished, Domain, Target,
 };
-use vsmtp_config::Config;
+use vsmtp_config::{field::FieldServerDNS, Config};
 extern crate alloc;
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
@@ -43,7 +51,6 @@ pub struct Deliver {
     #[serde(skip, default = "crate::dns::default")]
     resolver: alloc::sync::Arc<TokioAsyncResolver>,
     #[serde(skip)]
-    #[allow(dead_code)]
     config: alloc::sync::Arc<Config>,
     #[serde(flatten)]
     payload: Payload,
@@ -114,50 +121,79 @@ impl Deliver {
         domain: Domain,
         mut rcpt: DeliverTo,
     ) -> DeliverTo {
-        match self
-            .deliver_one_domain_inner(ctx, message, from, &domain, &rcpt)
-            .await
-        {
-            Ok(()) => {
-                for i in &mut rcpt {
-                    i.1 = Status::sent();
+        let permit = match ConnectionPool::current() {
+            Some(pool) => match pool.acquire(&domain).await {
+                Ok(permit) => Some(permit),
+                Err(throttle) => {
+                    tracing::info!(%throttle, "Delivery postponed.");
+
+                    for (_, status) in &mut rcpt {
+                        update_status(status, Err(throttle.clone().into()));
+                    }
+                    return rcpt;
                 }
-                rcpt
-            }
-            Err(error) => {
-                tracing::warn!(?error);
-
-                tracing::trace!(
-                    rcpt = ?rcpt.iter()
-                        .map(|r| r.0.clone())
-                        .collect::<Vec<_>>(),
-                    sender = ?from,
-                    %domain
-                );
-
-                let is_permanent = error.is_permanent();
-
-                for i in &mut rcpt {
-                    if is_permanent {
-                        i.1 = Status::failed(error.clone());
-                    } else {
-                        i.1.held_back(error.clone());
+            },
+            None => None,
+        };
+        let max_recipients = permit
+            .as_ref()
+            .and_then(Permit::max_recipients)
+            .unwrap_or(rcpt.len())
+            .max(1);
+
+        // the policy is resolved once for all the transactions to the domain.
+        let policy = match ConnectionPool::current() {
+            Some(pool) => pool.mta_sts().policy(&self.resolver, &domain).await,
+            None => None,
+        };
+        tracing::debug!(?policy, "MTA-STS policy lookup done.");
+
+        // the recipients are split in several transactions if they exceed the limit of the destination.
+        for chunk in rcpt.chunks_mut(max_recipients) {
+            match self
+                .deliver_one_domain_inner(ctx, message, from, &domain, policy.as_ref(), chunk)
+                .await
+            {
+                Ok(outcome) => {
+                    for ((_, status), rcpt_outcome) in chunk.iter_mut().zip(outcome) {
+                        update_status(status, rcpt_outcome);
                     }
                 }
+                Err(error) => {
+                    tracing::warn!(?error);
+
+                    tracing::trace!(
+                        rcpt = ?chunk.iter()
+                            .map(|r| r.0.clone())
+                            .collect::<Vec<_>>(),
+                        sender = ?from,
+                        %domain
+                    );
 
-                rcpt
+                    for (_, status) in chunk {
+                        update_status(status, Err(error.clone()));
+                    }
+                }
             }
         }
+
+        // NOTE: the delivery slot of the destination is released once all the transactions are done.
+        drop(permit);
+
+        rcpt
     }
 
+    /// Send the message to the first mail exchanger of the domain accepting the transaction,
+    /// and return the outcome of each recipient.
     async fn deliver_one_domain_inner(
         &self,
         ctx: &ContextFinished,
         message: &[u8],
         from: &Option<Address>,
         domain: &Domain,
-        rcpt: &DeliverTo,
-    ) -> Result<(), Variant> {
+        policy: Option<&Policy>,
+        rcpt: &[(Address, Status)],
+    ) -> Result<Vec<Result<Receipt, Variant>>, Variant> {
         let envelop = to_lettre_envelope(from, rcpt.iter().map(|(r, _)| r))?;
         tracing::trace!(?envelop);
 
@@ -175,11 +211,14 @@ impl Deliver {
             // get_cert_for_server(&ctx.connect.server_name, &self.config)
             // .ok_or(TransferErrorsVariant::TlsNoCertificate {})?,
 
-            SenderParameters::from(Target::Domain(domain.clone()))
-                .smtp_send(&ctx.connect.server_name, &envelop, message, None)
+            let target = Target::Domain(domain.clone());
+            let (outcome, encrypted) = Self::sender_parameters(policy, domain, domain)
+                .map_err(|e| Variant::Delivery(vec![(target.clone(), e)]))?
+                .smtp_send(&ctx.connect.server_name, &envelop, message, None, None)
                 .await
-                .map_err(|e| Variant::Delivery(vec![(Target::Domain(domain.clone()), e)]))?;
-            return Ok(());
+                .map_err(|e| Variant::Delivery(vec![(target.clone(), e)]))?;
+
+            return Ok(receipts(&target, encrypted, outcome));
         }
 
         let mxs = records
@@ -207,15 +246,50 @@ impl Deliver {
             // get_cert_for_server(&ctx.connect.server_name, &self.config)
             // .ok_or(TransferErrorsVariant::TlsNoCertificate {})?,
 
-            match SenderParameters::from(Target::Domain((*mx).clone()))
-                .smtp_send(&ctx.connect.server_name, &envelop, message, None)
+            let dane = if let Some((name_servers, timeout)) = self.dnssec_name_servers() {
+                match Dane::lookup(&name_servers, timeout, mx).await {
+                    Ok(dane) => dane,
+                    Err(err) => {
+                        tracing::error!(%mx, %err, "failed to lookup the TLSA records");
+                        e.push((Target::Domain(mx.clone()), err));
+                        continue;
+                    }
+                }
+            } else {
+                None
+            };
+            tracing::debug!(%mx, dane = dane.is_some(), "TLSA records lookup done.");
+
+            // NOTE: DANE takes precedence over MTA-STS.
+            // see https://www.rfc-editor.org/rfc/rfc8461#section-2
+            let params = if dane.is_some() {
+                SenderParameters::from(Target::Domain(mx.clone()))
+            } else {
+                match Self::sender_parameters(policy, domain, mx) {
+                    Ok(params) => params,
+                    Err(err) => {
+                        tracing::error!(%mx, %err, "mail exchanger refused by the MTA-STS policy");
+                        e.push((Target::Domain(mx.clone()), err));
+                        continue;
+                    }
+                }
+            };
+
+            match params
+                .smtp_send(
+                    &ctx.connect.server_name,
+                    &envelop,
+                    message,
+                    None,
+                    dane.as_ref(),
+                )
                 .await
             {
-                Ok(response) => {
+                Ok((outcome, encrypted)) => {
                     tracing::info!("Email sent successfully");
-                    tracing::trace!(%mx, sender = ?from, ?envelop, ?response);
+                    tracing::trace!(%mx, sender = ?from, ?envelop, ?outcome);
 
-                    return Ok(());
+                    return Ok(receipts(&Target::Domain(mx.clone()), encrypted, outcome));
                 }
                 Err(err) => {
                     tracing::error!(
@@ -231,6 +305,58 @@ impl Deliver {
 
         Err(Variant::Delivery(e))
     }
+
+    /// Parameters to send the message to the mail exchanger, according to the MTA-STS policy
+    /// of the domain.
+    ///
+    /// With a policy in `enforce` mode, the mail exchanger must be listed in the policy, and
+    /// the connection must be secured with a certificate valid for its name.
+    fn sender_parameters(
+        policy: Option<&Policy>,
+        domain: &Domain,
+        mx: &Domain,
+    ) -> Result<SenderParameters, Delivery> {
+        let mut params = SenderParameters::from(Target::Domain(mx.clone()));
+
+        match policy.map(|policy| (policy.mode, policy.matches(mx))) {
+            Some((Mode::Enforce, true)) => params.tls = TlsPolicy::StarttlsRequired,
+            Some((Mode::Enforce, false)) => {
+                return Err(Delivery::Tls {
+                    with_source: Some(format!(
+                        "mta-sts: '{mx}' is not allowed by the policy of '{domain}'"
+                    )),
+                })
+            }
+            Some((Mode::Testing, false)) => {
+                tracing::warn!(%mx, %domain, "The mail exchanger is not allowed by the MTA-STS policy (testing mode).");
+            }
+            _ => (),
+        }
+
+        Ok(params)
+    }
+
+    /// The resolvers queried for the TLSA records, and the timeout of a query.
+    ///
+    /// DANE is used only if the answers of the resolvers are validated with DNSSEC.
+    fn dnssec_name_servers(&self) -> Option<(Vec<std::net::SocketAddr>, core::time::Duration)> {
+        let (config, options) = match &self.config.server.dns {
+            FieldServerDNS::System => return None,
+            FieldServerDNS::Google { options } => (ResolverConfig::google(), options),
+            FieldServerDNS::CloudFlare { options } => (ResolverConfig::cloudflare(), options),
+            FieldServerDNS::Custom { config, options } => (config.clone(), options),
+        };
+
+        options.dnssec.then(|| {
+            let mut name_servers = config
+                .name_servers()
+                .iter()
+                .map(|name_server| name_server.socket_addr)
+                .collect::<Vec<_>>();
+            name_servers.dedup();
+            (name_servers, options.timeout)
+        })
+    }
 }
 
 impl vsmtp_common::transport::GetID for Deliver {}
@@ -279,9 +405,13 @@ mod test {
         TokioAsyncResolver,
     };
     use vsmtp_common::{
-        transfer::{error::Variant, Status},
+        transfer::{
+            error::{Throttle, Variant},
+            Status,
+        },
         transport::{AbstractTransport, WrapperSerde},
     };
+    use vsmtp_config::field::{FieldQueueDelivery, FieldQueueDeliveryLimits};
     use vsmtp_test::config::{local_ctx, local_msg, local_test};
 
     #[test_log::test(tokio::test)]
@@ -315,6 +445,85 @@ mod test {
         }
     }
 
+    #[test_log::test(tokio::test)]
+    async fn throttled() {
+        let config = local_test();
+        let ctx = local_ctx();
+        let msg = local_msg();
+
+        let pool = alloc::sync::Arc::new(ConnectionPool::new(&FieldQueueDelivery {
+            limits: std::collections::BTreeMap::from([(
+                "foo.bar".parse().unwrap(),
+                FieldQueueDeliveryLimits {
+                    max_connections: Some(0),
+                    ..Default::default()
+                },
+            )]),
+            ..Default::default()
+        }));
+
+        let transport = alloc::sync::Arc::new(Deliver::new(
+            alloc::sync::Arc::new(
+                TokioAsyncResolver::tokio(ResolverConfig::google(), ResolverOpts::default())
+                    .unwrap(),
+            ),
+            alloc::sync::Arc::new(config),
+        ));
+        let mut updated_rcpt = vec![
+            (vsmtp_common::addr!("john@foo.bar"), Status::default()),
+            (vsmtp_common::addr!("jane@foo.bar"), Status::default()),
+        ];
+        // the recipients postponed again keep only their latest postponement.
+        for _ in 0..2 {
+            updated_rcpt = alloc::sync::Arc::clone(&pool)
+                .scope(alloc::sync::Arc::clone(&transport).deliver(
+                    &ctx,
+                    updated_rcpt,
+                    msg.inner().to_string().as_bytes(),
+                ))
+                .await;
+        }
+
+        for (_, status) in updated_rcpt {
+            let mut expected = Status::default();
+            expected.held_back(Throttle::Connections {
+                domain: "foo.bar".parse().unwrap(),
+            });
+            assert_eq!(status, expected);
+        }
+    }
+
+    #[rstest::rstest]
+    #[case::no_policy(None, "mx.foo.bar", Some(TlsPolicy::StarttlsOpportunistic))]
+    #[case::enforce("enforce", "mx.foo.bar", Some(TlsPolicy::StarttlsRequired))]
+    #[case::enforce_mismatch("enforce", "mx.other.bar", None)]
+    #[case::testing_mismatch("testing", "mx.other.bar", Some(TlsPolicy::StarttlsOpportunistic))]
+    fn mta_sts(
+        #[case] mode: impl Into<Option<&'static str>>,
+        #[case] mx: &str,
+        #[case] expected: Option<TlsPolicy>,
+    ) {
+        let policy = mode.into().map(|mode| {
+            format!("version: STSv1\nmode: {mode}\nmx: *.foo.bar\nmax_age: 86400\n")
+                .parse::<Policy>()
+                .unwrap()
+        });
+
+        let params = Deliver::sender_parameters(
+            policy.as_ref(),
+            &"foo.bar".parse().unwrap(),
+            &mx.parse().unwrap(),
+        );
+
+        match expected {
+            Some(tls) => assert_eq!(params.unwrap().tls, tls),
+            None => assert!(
+                matches!(params, Err(Delivery::Tls { with_source: Some(ref e) }) if e.starts_with("mta-sts: ")),
+                "{params:?}"
+            ),
+        }
+    }
+
     #[rstest::rstest]
     #[case(
         &serde_json::json!({
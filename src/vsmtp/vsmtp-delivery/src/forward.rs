@@ -14,9 +14,9 @@
  * this program. If not, see https://www.gnu.org/licenses/.
  *
 */
-use crate::{send::SenderParameters, to_lettre_envelope};
+use crate::{receipts, send::SenderParameters, to_lettre_envelope, update_status};
 use vsmtp_common::{
-    transfer::{error::Variant, Status},
+    transfer::{error::Variant, Receipt},
     transport::{AbstractTransport, DeliverTo},
     Address, ContextFinished,
 };
@@ -70,7 +70,7 @@ impl Forward {
         from: &Option<Address>,
         to: &DeliverTo,
         message: &[u8],
-    ) -> Result<lettre::transport::smtp::response::Response, Variant> {
+    ) -> Result<Vec<Result<Receipt, Variant>>, Variant> {
         let envelop = to_lettre_envelope(from, to.iter().map(|(rcpt, _)| rcpt))?;
 
         tracing::debug!(?self.payload.params, "Forwarding email.");
@@ -78,11 +78,16 @@ impl Forward {
         //  get_cert_for_server(&ctx.connect.server_name, &self.config)
         //  .ok_or(TransferErrorsVariant::TlsNoCertificate {})?;
 
-        self.payload
+        let host = &self.payload.params.host;
+        let (outcome, encrypted) = self
+            .payload
             .params
-            .smtp_send(&ctx.connect.server_name, &envelop, message, None)
+            .smtp_send(&ctx.connect.server_name, &envelop, message, None, None)
             .await
-            .map_err(|e| Variant::Delivery(vec![(self.payload.params.host.clone(), e)]))
+            .map_err(|e| Variant::Delivery(vec![(host.clone(), e)]))?;
+        tracing::debug!(?outcome);
+
+        Ok(receipts(host, encrypted, outcome))
     }
 }
 
@@ -101,25 +106,18 @@ impl AbstractTransport for Forward {
             .deliver_inner(ctx, &ctx.mail_from.reverse_path, &to, message)
             .await
         {
-            Ok(code) => {
+            Ok(outcome) => {
                 tracing::info!("Email delivered.");
-                tracing::debug!(?code);
 
-                for i in &mut to {
-                    i.1 = Status::sent();
+                for ((_, status), rcpt) in to.iter_mut().zip(outcome) {
+                    update_status(status, rcpt);
                 }
             }
             Err(error) => {
                 tracing::error!(%error, "Email delivery failure.");
 
-                let is_permanent = error.is_permanent();
-
-                for i in &mut to {
-                    if is_permanent {
-                        i.1 = Status::failed(error.clone());
-                    } else {
-                        i.1.held_back(error.clone());
-                    }
+                for (_, status) in &mut to {
+                    update_status(status, Err(error.clone()));
                 }
             }
         }
@@ -170,6 +168,76 @@ mod tests {
         }
     }
 
+    /// Minimal SMTP server refusing the recipients starting with `unknown`.
+    async fn mock_server(listener: tokio::net::TcpListener) {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let (read, mut write) = stream.into_split();
+        let mut lines = tokio::io::BufReader::new(read).lines();
+
+        write.write_all(b"220 mock ESMTP\r\n").await.unwrap();
+        while let Some(line) = lines.next_line().await.unwrap() {
+            let reply = if line.starts_with("EHLO") {
+                "250 mock\r\n"
+            } else if line.starts_with("RCPT TO:<unknown") {
+                "550 5.1.1 No such user\r\n"
+            } else if line == "DATA" {
+                write.write_all(b"354 Go ahead\r\n").await.unwrap();
+                while lines.next_line().await.unwrap().unwrap() != "." {}
+                "250 2.0.0 Queued\r\n"
+            } else if line == "QUIT" {
+                write.write_all(b"221 Bye\r\n").await.unwrap();
+                return;
+            } else {
+                "250 Ok\r\n"
+            };
+            write.write_all(reply.as_bytes()).await.unwrap();
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn forward_recipient_refused() {
+        let ctx = local_ctx();
+        let msg = local_msg();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = format!("smtp://{}?tls=none", listener.local_addr().unwrap())
+            .parse::<SenderParameters>()
+            .unwrap();
+        let server = tokio::spawn(mock_server(listener));
+
+        let transport = alloc::sync::Arc::new(Forward::new(target));
+        let updated_rcpt = alloc::sync::Arc::clone(&transport)
+            .deliver(
+                &ctx,
+                vec![
+                    ("john@localhost".parse().unwrap(), Status::default()),
+                    ("unknown@localhost".parse().unwrap(), Status::default()),
+                    ("jane@localhost".parse().unwrap(), Status::default()),
+                ],
+                msg.inner().to_string().as_bytes(),
+            )
+            .await;
+        server.await.unwrap();
+
+        assert!(matches!(updated_rcpt[0].1, Status::Sent { .. }));
+        assert!(matches!(updated_rcpt[2].1, Status::Sent { .. }));
+        assert_eq!(
+            updated_rcpt[1].1,
+            Status::failed(Variant::Delivery(vec![(
+                "127.0.0.1".parse().unwrap(),
+                Delivery::Permanent {
+                    reply: vsmtp_common::ReplyCode::Enhanced {
+                        code: 550,
+                        enhanced: "5.1.1".to_owned()
+                    },
+                    with_source: Some("5.1.1 No such user".to_owned())
+                }
+            )]))
+        );
+    }
+
     #[rstest::rstest]
     #[case(
         &serde_json::json!({
@@ -54,8 +54,15 @@
 
 mod send;
 
+use send::RecipientsOutcome;
 pub use send::{split_and_sort_and_send, SenderOutcome, SenderParameters, TlsPolicy};
-use vsmtp_common::{transfer::error::Envelop, Address};
+use vsmtp_common::{
+    transfer::{
+        error::{Envelop, Variant},
+        Receipt, Status,
+    },
+    Address, Target,
+};
 extern crate alloc;
 
 mod dns {
@@ -115,6 +122,48 @@ fn to_lettre_envelope<'item>(
     }
 }
 
+/// Update the status of a recipient with the outcome of its delivery.
+///
+/// A recipient postponed by the limits of its destination keeps only its latest postponement.
+fn update_status(status: &mut Status, outcome: Result<Receipt, Variant>) {
+    match outcome {
+        Ok(receipt) => *status = Status::sent_with(receipt),
+        Err(error) if error.is_permanent() => *status = Status::failed(error),
+        Err(error @ Variant::Throttled(_)) => {
+            if let Status::HeldBack { errors } = status {
+                errors.retain(|error| !matches!(error.variant(), Variant::Throttled(_)));
+            }
+            status.held_back(error);
+        }
+        Err(error) => status.held_back(error),
+    }
+}
+
+/// Receipt of each recipient accepted by `target`, or the error of its transaction.
+fn receipts(
+    target: &Target,
+    encrypted: bool,
+    outcome: RecipientsOutcome,
+) -> Vec<Result<Receipt, Variant>> {
+    outcome
+        .into_iter()
+        .map(|rcpt| {
+            rcpt.map(|response| {
+                Receipt::new(
+                    target.clone(),
+                    encrypted,
+                    format!(
+                        "{} {}",
+                        response.code(),
+                        response.message().collect::<Vec<_>>().join(" ")
+                    ),
+                )
+            })
+            .map_err(|e| Variant::Delivery(vec![(target.clone(), e)]))
+        })
+        .collect()
+}
+
 /*
 fn get_cert_for_server(server_name: &Domain, config: &Config) -> Option<Vec<rustls::Certificate>> {
     config
@@ -125,12 +174,17 @@ fn get_cert_for_server(server_name: &Domain, config: &Config) -> Option<Vec<rust
 }
 */
 
+mod dane;
 mod deliver;
 mod forward;
+mod limits;
 mod maildir;
 mod mbox;
+pub mod mta_sts;
+mod pool;
 
 pub use deliver::Deliver;
 pub use forward::Forward;
 pub use maildir::Maildir;
 pub use mbox::MBox;
+pub use pool::ConnectionPool;
@@ -0,0 +1,195 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+*/
+
+//! Limits of the outbound delivery for each destination domain.
+
+use vsmtp_common::{transfer::error::Throttle, Domain};
+use vsmtp_config::field::FieldQueueDeliveryLimits;
+extern crate alloc;
+
+const WINDOW: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// The state of the limits of one destination.
+struct Destination {
+    connections: Option<alloc::sync::Arc<tokio::sync::Semaphore>>,
+    messages_per_minute: Option<usize>,
+    max_recipients: Option<usize>,
+    /// Date of the messages sent in the last minute.
+    sent: tokio::sync::Mutex<std::collections::VecDeque<std::time::Instant>>,
+}
+
+/// Authorization to deliver a message to a destination, the slot of `max_connections`
+/// is released on drop, even if the connection is kept idle in the pool.
+#[derive(Debug, Default)]
+pub struct Permit {
+    _connection: Option<tokio::sync::OwnedSemaphorePermit>,
+    max_recipients: Option<usize>,
+}
+
+impl Permit {
+    /// Maximum number of recipients in one transaction.
+    pub const fn max_recipients(&self) -> Option<usize> {
+        self.max_recipients
+    }
+}
+
+/// Limits of the outbound delivery, shared by the delivery pool.
+pub struct Limits {
+    destinations: std::collections::HashMap<Domain, Destination>,
+}
+
+impl core::fmt::Debug for Limits {
+    #[inline]
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Limits")
+            .field("destinations", &self.destinations.keys())
+            .finish()
+    }
+}
+
+impl Limits {
+    /// Build the limits of the destinations from the configuration.
+    pub fn new(config: &std::collections::BTreeMap<Domain, FieldQueueDeliveryLimits>) -> Self {
+        Self {
+            destinations: config
+                .iter()
+                .map(|(domain, limits)| {
+                    (
+                        domain.clone(),
+                        Destination {
+                            connections: limits
+                                .max_connections
+                                .map(|max| alloc::sync::Arc::new(tokio::sync::Semaphore::new(max))),
+                            messages_per_minute: limits.messages_per_minute,
+                            max_recipients: limits.max_recipients,
+                            sent: tokio::sync::Mutex::new(std::collections::VecDeque::new()),
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    /// Reserve the delivery of one message to the domain, without waiting.
+    ///
+    /// `max_connections` bounds the deliveries in progress, the permit is held for the
+    /// transactions of the message and not for the lifetime of the connection.
+    pub async fn acquire(&self, domain: &Domain) -> Result<Permit, Throttle> {
+        let Some(destination) = self.destinations.get(domain) else {
+            return Ok(Permit::default());
+        };
+
+        let connection = destination
+            .connections
+            .as_ref()
+            .map(|connections| alloc::sync::Arc::clone(connections).try_acquire_owned())
+            .transpose()
+            .map_err(|_| Throttle::Connections {
+                domain: domain.clone(),
+            })?;
+
+        if let Some(messages_per_minute) = destination.messages_per_minute {
+            let now = std::time::Instant::now();
+            let mut sent = destination.sent.lock().await;
+
+            while sent
+                .front()
+                .map_or(false, |date| now.duration_since(*date) >= WINDOW)
+            {
+                sent.pop_front();
+            }
+            if sent.len() >= messages_per_minute {
+                return Err(Throttle::Rate {
+                    domain: domain.clone(),
+                });
+            }
+            sent.push_back(now);
+        }
+
+        Ok(Permit {
+            _connection: connection,
+            max_recipients: destination.max_recipients,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(limits: FieldQueueDeliveryLimits) -> Limits {
+        Limits::new(&std::collections::BTreeMap::from([(
+            "example.com".parse().unwrap(),
+            limits,
+        )]))
+    }
+
+    #[tokio::test]
+    async fn unlimited() {
+        let limits = limits(FieldQueueDeliveryLimits::default());
+
+        for domain in ["example.com", "other.com"] {
+            let domain = domain.parse::<Domain>().unwrap();
+            let permits =
+                futures_util::future::join_all((0..100).map(|_| limits.acquire(&domain))).await;
+            assert!(permits.iter().all(Result::is_ok));
+        }
+    }
+
+    #[tokio::test]
+    async fn max_connections() {
+        let limits = limits(FieldQueueDeliveryLimits {
+            max_connections: Some(2),
+            ..Default::default()
+        });
+        let domain = "example.com".parse::<Domain>().unwrap();
+
+        let first = limits.acquire(&domain).await.unwrap();
+        let _second = limits.acquire(&domain).await.unwrap();
+        assert_eq!(
+            limits.acquire(&domain).await.unwrap_err(),
+            Throttle::Connections {
+                domain: domain.clone()
+            }
+        );
+
+        drop(first);
+        limits.acquire(&domain).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn messages_per_minute() {
+        let limits = limits(FieldQueueDeliveryLimits {
+            messages_per_minute: Some(3),
+            max_recipients: Some(10),
+            ..Default::default()
+        });
+        let domain = "example.com".parse::<Domain>().unwrap();
+
+        for _ in 0..3 {
+            // the permits are dropped, only the messages sent are counted.
+            assert_eq!(
+                limits.acquire(&domain).await.unwrap().max_recipients(),
+                Some(10)
+            );
+        }
+        assert_eq!(
+            limits.acquire(&domain).await.unwrap_err(),
+            Throttle::Rate { domain }
+        );
+    }
+}
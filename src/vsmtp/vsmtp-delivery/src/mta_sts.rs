@@ -0,0 +1,714 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+*/
+
+//! SMTP MTA Strict Transport Security, see <https://www.rfc-editor.org/rfc/rfc8461>.
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use trust_dns_resolver::TokioAsyncResolver;
+use vsmtp_common::{transfer::error::Lookup, Domain};
+extern crate alloc;
+
+/// Path of the policy on the policy host.
+const WELL_KNOWN: &str = "/.well-known/mta-sts.txt";
+/// Maximum size of a policy, see <https://www.rfc-editor.org/rfc/rfc8461#section-3.3>.
+const MAX_POLICY_SIZE: usize = 64 * 1024;
+/// Maximum size of the response, headers included.
+const MAX_RESPONSE_SIZE: u64 = 72 * 1024;
+/// Timeout of the fetch of a policy.
+const FETCH_TIMEOUT: core::time::Duration = core::time::Duration::from_secs(60);
+/// Delay before fetching again a policy whose fetch failed.
+const FETCH_BACKOFF: core::time::Duration = core::time::Duration::from_secs(5 * 60);
+/// Upper bound of the `max_age` of a policy, about one year.
+const MAX_AGE: u64 = 31_557_600;
+
+/// Errors while obtaining the policy of a domain.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// The policy could not be downloaded.
+    #[error("failed to fetch the policy: {0}")]
+    Fetch(String),
+    /// The policy is malformed.
+    #[error("invalid policy: {0}")]
+    Policy(String),
+}
+
+/// How the sending MTA must handle a failure to validate the policy.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    strum::AsRefStr,
+    strum::Display,
+    strum::EnumString,
+    serde::Serialize,
+)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+#[non_exhaustive]
+pub enum Mode {
+    /// The message must not be delivered to a mail exchanger failing the validation.
+    Enforce,
+    /// The failures are reported, but the message is delivered anyway.
+    Testing,
+    /// The domain has no active policy.
+    None,
+}
+
+/// Policy published by a domain at `https://mta-sts.<domain>/.well-known/mta-sts.txt`.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+#[non_exhaustive]
+pub struct Policy {
+    /// Mode of the policy.
+    pub mode: Mode,
+    /// Patterns of the allowed mail exchangers, with an optional leading `*.` wildcard.
+    pub mx: Vec<String>,
+    /// Lifetime of the policy.
+    pub max_age: core::time::Duration,
+}
+
+impl core::str::FromStr for Policy {
+    type Err = Error;
+
+    #[inline]
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mut version, mut mode, mut mx, mut max_age) = (None, None, vec![], None);
+
+        for line in s.lines() {
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+
+            match key.trim() {
+                "version" => version = Some(value),
+                "mode" => {
+                    mode = Some(
+                        value
+                            .parse::<Mode>()
+                            .map_err(|e| Error::Policy(format!("mode '{value}': {e}")))?,
+                    );
+                }
+                "mx" => mx.push(value.to_ascii_lowercase()),
+                "max_age" => {
+                    max_age = Some(
+                        value
+                            .parse::<u64>()
+                            .map_err(|e| Error::Policy(format!("max_age '{value}': {e}")))?
+                            .min(MAX_AGE),
+                    );
+                }
+                // NOTE: unknown fields must be ignored.
+                _ => (),
+            }
+        }
+
+        if version != Some("STSv1") {
+            return Err(Error::Policy("expected 'version: STSv1'".to_owned()));
+        }
+        let mode = mode.ok_or_else(|| Error::Policy("missing mode".to_owned()))?;
+        if mode != Mode::None && mx.is_empty() {
+            return Err(Error::Policy("missing mx".to_owned()));
+        }
+
+        Ok(Self {
+            mode,
+            mx,
+            max_age: core::time::Duration::from_secs(
+                max_age.ok_or_else(|| Error::Policy("missing max_age".to_owned()))?,
+            ),
+        })
+    }
+}
+
+impl Policy {
+    /// Is the mail exchanger allowed by the policy ?
+    ///
+    /// A wildcard only matches the left-most label, `*.example.com` matches `mx.example.com`
+    /// but neither `example.com` nor `a.mx.example.com`.
+    #[must_use]
+    #[inline]
+    pub fn matches(&self, mx: &Domain) -> bool {
+        let host = mx.to_string().trim_end_matches('.').to_ascii_lowercase();
+
+        self.mx.iter().any(|pattern| {
+            pattern.strip_prefix("*.").map_or_else(
+                || host == *pattern,
+                |parent| {
+                    host.split_once('.').map_or(false, |(label, host_parent)| {
+                        !label.is_empty() && host_parent == parent
+                    })
+                },
+            )
+        })
+    }
+}
+
+/// Extract the `id` of the `v=STSv1; id=<id>;` TXT record, `None` if the record is invalid.
+fn parse_record(record: &str) -> Option<&str> {
+    let mut fields = record
+        .split(';')
+        .map(str::trim)
+        .filter(|field| !field.is_empty())
+        .map(|field| field.split_once('='));
+
+    if fields.next()? != Some(("v", "STSv1")) {
+        return None;
+    }
+
+    fields.find_map(|field| match field {
+        Some(("id", id))
+            if (1..=32).contains(&id.len()) && id.chars().all(|c| c.is_ascii_alphanumeric()) =>
+        {
+            Some(id)
+        }
+        _ => None,
+    })
+}
+
+/// Source of the policies.
+#[async_trait::async_trait]
+pub trait PolicyFetcher: Send + Sync {
+    /// Fetch the body of the policy of the domain.
+    async fn fetch(&self, domain: &Domain) -> Result<String, Error>;
+}
+
+/// Download the policy over HTTPS, the certificate of the policy host must be valid.
+pub struct HttpsFetcher {
+    tls: alloc::sync::Arc<rustls::ClientConfig>,
+    address: Option<std::net::SocketAddr>,
+}
+
+impl core::fmt::Debug for HttpsFetcher {
+    #[inline]
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("HttpsFetcher")
+            .field("address", &self.address)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for HttpsFetcher {
+    #[inline]
+    fn default() -> Self {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+            rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+                ta.subject,
+                ta.spki,
+                ta.name_constraints,
+            )
+        }));
+        Self::new(roots)
+    }
+}
+
+impl HttpsFetcher {
+    /// Verify the certificate of the policy hosts with these root certificates.
+    #[must_use]
+    #[inline]
+    pub fn new(roots: rustls::RootCertStore) -> Self {
+        Self {
+            tls: alloc::sync::Arc::new(
+                rustls::ClientConfig::builder()
+                    .with_safe_defaults()
+                    .with_root_certificates(roots)
+                    .with_no_client_auth(),
+            ),
+            address: None,
+        }
+    }
+
+    /// Connect to this address instead of resolving `mta-sts.<domain>`.
+    #[must_use]
+    #[inline]
+    pub const fn with_address(mut self, address: std::net::SocketAddr) -> Self {
+        self.address = Some(address);
+        self
+    }
+
+    async fn get(&self, host: &str) -> Result<Vec<u8>, Error> {
+        let server_name = rustls::ServerName::try_from(host)
+            .map_err(|e| Error::Fetch(format!("host '{host}': {e}")))?;
+
+        let stream = match self.address {
+            Some(address) => tokio::net::TcpStream::connect(address).await,
+            None => tokio::net::TcpStream::connect((host, 443)).await,
+        }
+        .map_err(|e| Error::Fetch(e.to_string()))?;
+
+        let mut stream = tokio_rustls::TlsConnector::from(alloc::sync::Arc::clone(&self.tls))
+            .connect(server_name, stream)
+            .await
+            .map_err(|e| Error::Fetch(e.to_string()))?;
+
+        // NOTE: HTTP/1.0 so the server does not use a chunked transfer encoding.
+        stream
+            .write_all(format!("GET {WELL_KNOWN} HTTP/1.0\r\nHost: {host}\r\n\r\n").as_bytes())
+            .await
+            .map_err(|e| Error::Fetch(e.to_string()))?;
+
+        let mut response = vec![];
+        match (&mut stream)
+            .take(MAX_RESPONSE_SIZE)
+            .read_to_end(&mut response)
+            .await
+        {
+            // NOTE: some servers close the connection without a `close_notify`.
+            Err(e) if e.kind() != std::io::ErrorKind::UnexpectedEof => {
+                Err(Error::Fetch(e.to_string()))
+            }
+            _ => Ok(response),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl PolicyFetcher for HttpsFetcher {
+    #[inline]
+    async fn fetch(&self, domain: &Domain) -> Result<String, Error> {
+        let host = format!("mta-sts.{}", domain.to_string().trim_end_matches('.'));
+
+        let response = tokio::time::timeout(FETCH_TIMEOUT, self.get(&host))
+            .await
+            .map_err(|e| Error::Fetch(e.to_string()))??;
+
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut parsed = httparse::Response::new(&mut headers);
+        let httparse::Status::Complete(offset) = parsed
+            .parse(&response)
+            .map_err(|e| Error::Fetch(e.to_string()))?
+        else {
+            return Err(Error::Fetch("incomplete response".to_owned()));
+        };
+
+        // NOTE: the redirections must not be followed.
+        if parsed.code != Some(200) {
+            return Err(Error::Fetch(format!(
+                "unexpected status code {}",
+                parsed.code.unwrap_or_default()
+            )));
+        }
+        let is_text_plain = parsed.headers.iter().any(|header| {
+            header.name.eq_ignore_ascii_case("content-type")
+                && header
+                    .value
+                    .get(.."text/plain".len())
+                    .map_or(false, |mime| mime.eq_ignore_ascii_case(b"text/plain"))
+        });
+        if !is_text_plain {
+            return Err(Error::Fetch("the media type must be text/plain".to_owned()));
+        }
+
+        let body = response.get(offset..).unwrap_or_default();
+        if body.len() > MAX_POLICY_SIZE {
+            return Err(Error::Fetch("the policy is too large".to_owned()));
+        }
+
+        String::from_utf8(body.to_vec()).map_err(|e| Error::Fetch(e.to_string()))
+    }
+}
+
+/// A policy stored in the cache.
+#[derive(Debug, Clone, serde::Serialize)]
+#[non_exhaustive]
+pub struct CachedPolicy {
+    /// Identifier of the policy, from the TXT record.
+    pub id: String,
+    /// The policy.
+    pub policy: Policy,
+    /// Date of the fetch of the policy.
+    pub fetched_at: std::time::SystemTime,
+}
+
+impl CachedPolicy {
+    /// The `max_age` of the policy has elapsed since its fetch.
+    #[must_use]
+    #[inline]
+    pub fn is_expired(&self) -> bool {
+        self.fetched_at
+            .elapsed()
+            .map_or(false, |elapsed| elapsed >= self.policy.max_age)
+    }
+}
+
+/// Discovery and cache of the policies of the destination domains.
+pub struct MtaSts {
+    fetcher: Box<dyn PolicyFetcher>,
+    cache: std::sync::Mutex<std::collections::BTreeMap<Domain, CachedPolicy>>,
+    /// `id` and date of the last failed fetch of each domain.
+    failures: std::sync::Mutex<std::collections::BTreeMap<Domain, (String, std::time::SystemTime)>>,
+}
+
+impl core::fmt::Debug for MtaSts {
+    #[inline]
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("MtaSts")
+            .field("cache", &self.cached())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for MtaSts {
+    #[inline]
+    fn default() -> Self {
+        Self::new(Box::<HttpsFetcher>::default())
+    }
+}
+
+impl MtaSts {
+    /// Create an empty cache, the policies are obtained with the fetcher.
+    #[must_use]
+    #[inline]
+    pub fn new(fetcher: Box<dyn PolicyFetcher>) -> Self {
+        Self {
+            fetcher,
+            cache: std::sync::Mutex::new(std::collections::BTreeMap::new()),
+            failures: std::sync::Mutex::new(std::collections::BTreeMap::new()),
+        }
+    }
+
+    /// Snapshot of the policies in the cache, including the expired ones.
+    #[must_use]
+    #[inline]
+    pub fn cached(&self) -> std::collections::BTreeMap<Domain, CachedPolicy> {
+        self.cache
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Policy of the domain, `None` if the domain has no valid policy.
+    ///
+    /// The policy is fetched again only if the `id` of the TXT record changed or if the
+    /// cached policy expired. A valid cached policy is still used if the TXT record or
+    /// the new policy are unavailable, and a failed fetch is not attempted again for the
+    /// same `id` before a short delay.
+    #[inline]
+    pub async fn policy(&self, resolver: &TokioAsyncResolver, domain: &Domain) -> Option<Policy> {
+        let id = match Self::lookup(resolver, domain).await {
+            Ok(id) => id,
+            Err(error) => {
+                tracing::warn!(%domain, %error, "Failed to lookup the MTA-STS record.");
+                None
+            }
+        };
+        self.policy_with_id(domain, id).await
+    }
+
+    async fn policy_with_id(&self, domain: &Domain, id: Option<String>) -> Option<Policy> {
+        let cached = self
+            .cache
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .get(domain)
+            .filter(|cached| !cached.is_expired())
+            .cloned();
+
+        let Some(id) = id else {
+            return cached.map(|cached| cached.policy);
+        };
+        if let Some(cached) = cached.as_ref().filter(|cached| cached.id == id) {
+            return Some(cached.policy.clone());
+        }
+        if self.is_backing_off(domain, &id) {
+            tracing::debug!(%domain, %id, "Skipping the fetch of the MTA-STS policy after a recent failure.");
+            return cached.map(|cached| cached.policy);
+        }
+
+        match self
+            .fetcher
+            .fetch(domain)
+            .await
+            .and_then(|body| body.parse::<Policy>())
+        {
+            Ok(policy) => {
+                tracing::debug!(%domain, %id, ?policy, "MTA-STS policy updated.");
+                self.failures
+                    .lock()
+                    .unwrap_or_else(std::sync::PoisonError::into_inner)
+                    .remove(domain);
+                self.cache
+                    .lock()
+                    .unwrap_or_else(std::sync::PoisonError::into_inner)
+                    .insert(
+                        domain.clone(),
+                        CachedPolicy {
+                            id,
+                            policy: policy.clone(),
+                            fetched_at: std::time::SystemTime::now(),
+                        },
+                    );
+                Some(policy)
+            }
+            Err(error) => {
+                tracing::warn!(%domain, %error, "Failed to fetch the MTA-STS policy.");
+                self.failures
+                    .lock()
+                    .unwrap_or_else(std::sync::PoisonError::into_inner)
+                    .insert(domain.clone(), (id, std::time::SystemTime::now()));
+                cached.map(|cached| cached.policy)
+            }
+        }
+    }
+
+    /// The fetch of the policy `id` of the domain failed less than [`FETCH_BACKOFF`] ago.
+    fn is_backing_off(&self, domain: &Domain, id: &str) -> bool {
+        self.failures
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .get(domain)
+            .filter(|(failed_id, _)| failed_id == id)
+            .map_or(false, |(_, failed_at)| {
+                failed_at
+                    .elapsed()
+                    .map_or(true, |elapsed| elapsed < FETCH_BACKOFF)
+            })
+    }
+
+    /// Lookup the `id` of the policy of the domain at `_mta-sts.<domain>`.
+    async fn lookup(
+        resolver: &TokioAsyncResolver,
+        domain: &Domain,
+    ) -> Result<Option<String>, Lookup> {
+        let query = format!("_mta-sts.{}", domain.to_string().trim_end_matches('.'));
+
+        let records = match resolver.txt_lookup(query).await {
+            Ok(lookup) => lookup
+                .iter()
+                .map(|txt| {
+                    txt.txt_data()
+                        .iter()
+                        .map(|data| String::from_utf8_lossy(data))
+                        .collect::<String>()
+                })
+                .filter(|record| record.starts_with("v=STSv1"))
+                .collect::<Vec<_>>(),
+            Err(error) => match Lookup::from(error) {
+                Lookup::NoRecords {} => vec![],
+                otherwise => return Err(otherwise),
+            },
+        };
+
+        // NOTE: several records are treated as if there was no record.
+        match records.as_slice() {
+            [record] => Ok(parse_record(record).map(str::to_owned)),
+            _ => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use vsmtp_test::get_tls_file::{
+        get_mta_sts_ca_certificate, get_mta_sts_certificate, get_mta_sts_pkcs8_key,
+    };
+
+    const POLICY: &str = "version: STSv1\r\nmode: enforce\r\nmx: mx.testserver.com\r\nmx: *.backup.testserver.com\r\nmax_age: 86400\r\n";
+
+    fn der(input: &str) -> Vec<u8> {
+        pem::parse(input).unwrap().into_contents()
+    }
+
+    #[rstest::rstest]
+    #[case("v=STSv1; id=20230101T000000;", Some("20230101T000000"))]
+    #[case("v=STSv1;id=abc", Some("abc"))]
+    #[case("v=STSv1; unknown=1; id=abc;", Some("abc"))]
+    #[case("id=abc; v=STSv1;", None)]
+    #[case("v=STSv2; id=abc;", None)]
+    #[case("v=STSv1; id=a-b;", None)]
+    #[case("v=STSv1; id=012345678901234567890123456789012;", None)]
+    #[case("v=STSv1;", None)]
+    fn record(#[case] input: &str, #[case] expected: Option<&str>) {
+        assert_eq!(parse_record(input), expected);
+    }
+
+    #[test]
+    fn policy() {
+        assert_eq!(
+            POLICY.parse::<Policy>().unwrap(),
+            Policy {
+                mode: Mode::Enforce,
+                mx: vec![
+                    "mx.testserver.com".to_owned(),
+                    "*.backup.testserver.com".to_owned()
+                ],
+                max_age: core::time::Duration::from_secs(86400),
+            }
+        );
+        assert_eq!(
+            "version: STSv1\nmode: none\nmax_age: 99999999999\n"
+                .parse::<Policy>()
+                .unwrap()
+                .max_age,
+            core::time::Duration::from_secs(MAX_AGE)
+        );
+    }
+
+    #[rstest::rstest]
+    #[case("mode: enforce\nmx: mx.testserver.com\nmax_age: 86400\n")]
+    #[case("version: STSv1\nmode: strict\nmx: mx.testserver.com\nmax_age: 86400\n")]
+    #[case("version: STSv1\nmode: enforce\nmax_age: 86400\n")]
+    #[case("version: STSv1\nmode: enforce\nmx: mx.testserver.com\n")]
+    fn invalid_policy(#[case] input: &str) {
+        assert!(matches!(input.parse::<Policy>(), Err(Error::Policy(_))));
+    }
+
+    #[rstest::rstest]
+    #[case("mx.testserver.com.", true)]
+    #[case("MX.testserver.com", true)]
+    #[case("a.backup.testserver.com", true)]
+    #[case("backup.testserver.com", false)]
+    #[case("a.b.backup.testserver.com", false)]
+    #[case("mx2.testserver.com", false)]
+    fn matches(#[case] mx: &str, #[case] expected: bool) {
+        let policy = POLICY.parse::<Policy>().unwrap();
+        assert_eq!(policy.matches(&mx.parse().unwrap()), expected);
+    }
+
+    /// HTTPS server for `mta-sts.testserver.com`, replying once with `response`.
+    async fn https_server(listener: tokio::net::TcpListener, response: String) {
+        let config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![rustls::Certificate(der(get_mta_sts_certificate()))],
+                rustls::PrivateKey(der(get_mta_sts_pkcs8_key())),
+            )
+            .unwrap();
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = tokio_rustls::TlsAcceptor::from(alloc::sync::Arc::new(config))
+            .accept(stream)
+            .await
+            .unwrap();
+
+        let mut request = vec![];
+        while !request.ends_with(b"\r\n\r\n") {
+            request.push(stream.read_u8().await.unwrap());
+        }
+        assert!(request.starts_with(b"GET /.well-known/mta-sts.txt HTTP/1.0\r\n"));
+
+        stream.write_all(response.as_bytes()).await.unwrap();
+        stream.shutdown().await.unwrap();
+    }
+
+    async fn fetch(response: String) -> Result<String, Error> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(https_server(listener, response));
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots
+            .add(&rustls::Certificate(der(get_mta_sts_ca_certificate())))
+            .unwrap();
+
+        let outcome = HttpsFetcher::new(roots)
+            .with_address(address)
+            .fetch(&"testserver.com".parse().unwrap())
+            .await;
+
+        server.await.unwrap();
+        outcome
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn https_fetch() {
+        assert_eq!(
+            fetch(format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{POLICY}"
+            ))
+            .await
+            .unwrap(),
+            POLICY
+        );
+    }
+
+    #[rstest::rstest]
+    #[case::redirect("HTTP/1.1 301 Moved Permanently\r\nLocation: https://example.com/\r\n\r\n")]
+    #[case::media_type("HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n\r\n")]
+    #[test_log::test(tokio::test)]
+    async fn https_fetch_refused(#[case] response: &str) {
+        assert!(matches!(
+            fetch(format!("{response}{POLICY}")).await,
+            Err(Error::Fetch(_))
+        ));
+    }
+
+    /// Fetcher serving a policy whose mode changes at each fetch, and failing after two.
+    struct Counting(alloc::sync::Arc<AtomicUsize>);
+
+    #[async_trait::async_trait]
+    impl PolicyFetcher for Counting {
+        async fn fetch(&self, _: &Domain) -> Result<String, Error> {
+            match self.0.fetch_add(1, Ordering::SeqCst) {
+                0 => Ok(POLICY.to_owned()),
+                1 => Ok(POLICY.replace("enforce", "testing")),
+                _ => Err(Error::Fetch("unavailable".to_owned())),
+            }
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn cache() {
+        let fetched = alloc::sync::Arc::new(AtomicUsize::new(0));
+        let mta_sts = MtaSts::new(Box::new(Counting(alloc::sync::Arc::clone(&fetched))));
+        let domain = "testserver.com".parse::<Domain>().unwrap();
+        let mode = |policy: Option<Policy>| policy.map(|policy| policy.mode);
+
+        assert_eq!(mode(mta_sts.policy_with_id(&domain, None).await), None);
+        assert_eq!(fetched.load(Ordering::SeqCst), 0);
+
+        for id in [Some("1"), Some("1"), None] {
+            assert_eq!(
+                mode(mta_sts.policy_with_id(&domain, id.map(str::to_owned)).await),
+                Some(Mode::Enforce)
+            );
+        }
+        assert_eq!(fetched.load(Ordering::SeqCst), 1);
+
+        // a new id refreshes the policy.
+        assert_eq!(
+            mode(mta_sts.policy_with_id(&domain, Some("2".to_owned())).await),
+            Some(Mode::Testing)
+        );
+        // the cached policy is used if the fetch fails.
+        assert_eq!(
+            mode(mta_sts.policy_with_id(&domain, Some("3".to_owned())).await),
+            Some(Mode::Testing)
+        );
+        assert_eq!(fetched.load(Ordering::SeqCst), 3);
+
+        // the failed fetch is not attempted again right away.
+        assert_eq!(
+            mode(mta_sts.policy_with_id(&domain, Some("3".to_owned())).await),
+            Some(Mode::Testing)
+        );
+        assert_eq!(fetched.load(Ordering::SeqCst), 3);
+
+        let cached = mta_sts.cached();
+        assert_eq!(cached.len(), 1);
+        assert_eq!(
+            cached.get(&domain).map(|cached| cached.id.as_str()),
+            Some("2")
+        );
+    }
+}
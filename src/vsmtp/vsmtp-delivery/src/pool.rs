@@ -0,0 +1,344 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+*/
+
+//! Reuse of the outbound SMTP connections.
+
+use crate::{
+    limits::{Limits, Permit},
+    mta_sts::{MtaSts, PolicyFetcher},
+    send::SenderParameters,
+};
+use lettre::transport::smtp::{client::AsyncSmtpConnection, commands::Rset};
+use vsmtp_common::{transfer::error::Throttle, Domain};
+use vsmtp_config::field::FieldQueueDelivery;
+extern crate alloc;
+
+tokio::task_local! {
+    static POOL: alloc::sync::Arc<ConnectionPool>;
+}
+
+/// The connections are reused only with the same parameters.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Destination {
+    params: SenderParameters,
+    hello_name: Domain,
+    dane: bool,
+}
+
+impl Destination {
+    pub(crate) fn new(params: &SenderParameters, hello_name: &Domain, dane: bool) -> Self {
+        Self {
+            params: params.clone(),
+            hello_name: hello_name.clone(),
+            dane,
+        }
+    }
+}
+
+/// A connection waiting for its next transaction.
+struct Idle {
+    conn: AsyncSmtpConnection,
+    messages: usize,
+    since: std::time::Instant,
+}
+
+/// Pool of the outbound SMTP connections, indexed by destination.
+///
+/// The pool is available to the `deliver` and `forward` transports for the futures
+/// run with [`ConnectionPool::scope`], the others open a new connection for each message.
+/// The limits of the destination domains and their MTA-STS policies are enforced by the
+/// `deliver` transport in this scope.
+pub struct ConnectionPool {
+    idle_timeout: std::time::Duration,
+    max_messages: usize,
+    max_idle: usize,
+    idle: tokio::sync::Mutex<std::collections::HashMap<Destination, Vec<Idle>>>,
+    limits: Limits,
+    mta_sts: MtaSts,
+}
+
+impl core::fmt::Debug for ConnectionPool {
+    #[inline]
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("ConnectionPool")
+            .field("idle_timeout", &self.idle_timeout)
+            .field("max_messages", &self.max_messages)
+            .field("max_idle", &self.max_idle)
+            .field("limits", &self.limits)
+            .field("mta_sts", &self.mta_sts)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ConnectionPool {
+    /// Create an empty pool.
+    #[must_use]
+    #[inline]
+    pub fn new(config: &FieldQueueDelivery) -> Self {
+        Self {
+            idle_timeout: config.connection_pool.idle_timeout,
+            max_messages: config.connection_pool.max_messages,
+            max_idle: config.connection_pool.max_idle,
+            idle: tokio::sync::Mutex::new(std::collections::HashMap::new()),
+            limits: Limits::new(&config.limits),
+            mta_sts: MtaSts::default(),
+        }
+    }
+
+    /// Obtain the MTA-STS policies with this fetcher instead of HTTPS.
+    #[must_use]
+    #[inline]
+    pub fn with_policy_fetcher(mut self, fetcher: Box<dyn PolicyFetcher>) -> Self {
+        self.mta_sts = MtaSts::new(fetcher);
+        self
+    }
+
+    /// The cache of the MTA-STS policies.
+    #[must_use]
+    #[inline]
+    pub const fn mta_sts(&self) -> &MtaSts {
+        &self.mta_sts
+    }
+
+    /// Run the future with the pool available to the SMTP transports.
+    #[inline]
+    pub async fn scope<F: core::future::Future + Send>(
+        self: alloc::sync::Arc<Self>,
+        future: F,
+    ) -> F::Output {
+        POOL.scope(self, future).await
+    }
+
+    /// The pool of the current scope, if any.
+    pub(crate) fn current() -> Option<alloc::sync::Arc<Self>> {
+        POOL.try_with(alloc::sync::Arc::clone).ok()
+    }
+
+    /// Reserve the delivery of one message to the domain, see [`Limits::acquire`].
+    pub(crate) async fn acquire(&self, domain: &Domain) -> Result<Permit, Throttle> {
+        self.limits.acquire(domain).await
+    }
+
+    /// Take an idle connection to the destination, and reset its session.
+    ///
+    /// Return the connection and the count of messages already sent with it.
+    pub(crate) async fn take(
+        &self,
+        destination: &Destination,
+    ) -> Option<(AsyncSmtpConnection, usize)> {
+        loop {
+            let Idle {
+                mut conn,
+                messages,
+                since,
+            } = self.idle.lock().await.get_mut(destination)?.pop()?;
+
+            if since.elapsed() >= self.idle_timeout {
+                conn.abort().await;
+                continue;
+            }
+
+            match conn.command(Rset).await {
+                Ok(_) => {
+                    tracing::debug!(?destination.params.host, messages, "Reusing a connection.");
+                    return Some((conn, messages));
+                }
+                Err(error) => {
+                    tracing::debug!(%error, "The idle connection is no longer usable.");
+                    conn.abort().await;
+                }
+            }
+        }
+    }
+
+    /// Give back a connection after a transaction, it is closed if it cannot be reused.
+    pub(crate) async fn release(
+        &self,
+        destination: Destination,
+        mut conn: AsyncSmtpConnection,
+        messages: usize,
+    ) {
+        if messages < self.max_messages {
+            let mut idle = self.idle.lock().await;
+            let connections = idle.entry(destination).or_default();
+
+            if connections.len() < self.max_idle {
+                connections.push(Idle {
+                    conn,
+                    messages,
+                    since: std::time::Instant::now(),
+                });
+                return;
+            }
+        }
+
+        conn.abort().await;
+    }
+
+    /// Close the connections unused since the idle timeout.
+    #[inline]
+    pub async fn close_expired(&self) {
+        let expired = {
+            let mut idle = self.idle.lock().await;
+            let mut expired = vec![];
+
+            for connections in idle.values_mut() {
+                let (alive, timed_out) = connections
+                    .drain(..)
+                    .partition(|i| i.since.elapsed() < self.idle_timeout);
+                *connections = alive;
+                expired.extend(timed_out);
+            }
+            idle.retain(|_, connections| !connections.is_empty());
+
+            expired
+        };
+
+        for mut i in expired {
+            i.conn.abort().await;
+        }
+    }
+
+    /// Close all the connections, the transactions in progress are not affected.
+    #[inline]
+    pub async fn close_all(&self) {
+        let idle = core::mem::take(&mut *self.idle.lock().await);
+
+        for mut i in idle.into_values().flatten() {
+            i.conn.abort().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Forward;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use vsmtp_common::{
+        transfer::Status,
+        transport::{AbstractTransport, DeliverTo},
+    };
+    use vsmtp_config::field::FieldQueueDeliveryConnectionPool;
+    use vsmtp_test::config::{local_ctx, local_msg};
+
+    /// SMTP server counting the connections opened and the `RSET` received.
+    async fn mock_server(
+        listener: tokio::net::TcpListener,
+        connections: alloc::sync::Arc<AtomicUsize>,
+        resets: alloc::sync::Arc<AtomicUsize>,
+    ) {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            connections.fetch_add(1, Ordering::SeqCst);
+            let resets = alloc::sync::Arc::clone(&resets);
+
+            tokio::spawn(async move {
+                let (read, mut write) = stream.into_split();
+                let mut lines = tokio::io::BufReader::new(read).lines();
+
+                write.write_all(b"220 mock ESMTP\r\n").await.unwrap();
+                while let Some(line) = lines.next_line().await.unwrap() {
+                    let reply = if line.starts_with("EHLO") {
+                        "250 mock\r\n"
+                    } else if line == "RSET" {
+                        resets.fetch_add(1, Ordering::SeqCst);
+                        "250 Ok\r\n"
+                    } else if line == "DATA" {
+                        write.write_all(b"354 Go ahead\r\n").await.unwrap();
+                        while lines.next_line().await.unwrap().unwrap() != "." {}
+                        "250 2.0.0 Queued\r\n"
+                    } else if line == "QUIT" {
+                        write.write_all(b"221 Bye\r\n").await.unwrap();
+                        return;
+                    } else {
+                        "250 Ok\r\n"
+                    };
+                    write.write_all(reply.as_bytes()).await.unwrap();
+                }
+            });
+        }
+    }
+
+    #[rstest::rstest]
+    #[case::reused(30, 100, 1, 2)]
+    #[case::max_messages(30, 1, 3, 0)]
+    #[case::idle_timeout(0, 100, 3, 0)]
+    #[test_log::test(tokio::test)]
+    async fn reuse(
+        #[case] idle_timeout: u64,
+        #[case] max_messages: usize,
+        #[case] expected_connections: usize,
+        #[case] expected_resets: usize,
+    ) {
+        let ctx = local_ctx();
+        let msg = local_msg().inner().to_string();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = format!("smtp://{}?tls=none", listener.local_addr().unwrap())
+            .parse::<SenderParameters>()
+            .unwrap();
+        let (connections, resets) = (
+            alloc::sync::Arc::new(AtomicUsize::new(0)),
+            alloc::sync::Arc::new(AtomicUsize::new(0)),
+        );
+        let server = tokio::spawn(mock_server(
+            listener,
+            alloc::sync::Arc::clone(&connections),
+            alloc::sync::Arc::clone(&resets),
+        ));
+
+        let pool = alloc::sync::Arc::new(ConnectionPool::new(&FieldQueueDelivery {
+            connection_pool: FieldQueueDeliveryConnectionPool {
+                idle_timeout: std::time::Duration::from_secs(idle_timeout),
+                max_messages,
+                max_idle: 1,
+            },
+            ..Default::default()
+        }));
+
+        let outcome = alloc::sync::Arc::clone(&pool)
+            .scope(async {
+                let mut outcome = DeliverTo::new();
+                for _ in 0..3 {
+                    outcome.extend(
+                        alloc::sync::Arc::new(Forward::new(target.clone()))
+                            .deliver(
+                                &ctx,
+                                vec![("john@localhost".parse().unwrap(), Status::default())],
+                                msg.as_bytes(),
+                            )
+                            .await,
+                    );
+                }
+                outcome
+            })
+            .await;
+        pool.close_expired().await;
+        pool.close_all().await;
+        server.abort();
+
+        assert!(outcome
+            .iter()
+            .all(|(_, status)| matches!(status, Status::Sent { .. })));
+        assert_eq!(connections.load(Ordering::SeqCst), expected_connections);
+        assert_eq!(resets.load(Ordering::SeqCst), expected_resets);
+        assert!(pool.idle.lock().await.is_empty());
+    }
+}
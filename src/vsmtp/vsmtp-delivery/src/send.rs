@@ -14,10 +14,14 @@
  * this program. If not, see https://www.gnu.org/licenses/.
  *
 */
+use crate::{
+    dane::Dane,
+    pool::{ConnectionPool, Destination},
+};
 use futures_util::FutureExt;
 use vsmtp_common::{
     transfer::{
-        error::{Delivery, Queuer},
+        error::{Delivery, Queuer, Variant},
         Status,
     },
     transport::WrapperSerde,
@@ -124,8 +128,12 @@ pub async fn split_and_sort_and_send(
 
     let mut out = None;
     for rcpt in &mut message_ctx.rcpt_to.delivery.values_mut().flatten() {
+        // NOTE: the postponements by the limits of the destination are not attempts.
         if matches!(&rcpt.1, Status::HeldBack{ errors }
-            if errors.len() >= config.server.queues.delivery.deferred_retry_max)
+            if errors
+                .iter()
+                .filter(|error| !matches!(error.variant(), Variant::Throttled(_)))
+                .count() >= config.server.queues.delivery.deferred_retry_max)
         {
             rcpt.1 = Status::failed(Queuer::MaxDeferredAttemptReached);
             tracing::warn!("Delivery error count maximum reached, moving to dead.");
@@ -378,32 +386,48 @@ impl From<Target> for SenderParameters {
     }
 }
 
+/// Timeout of the connection to the remote server.
+const CONNECTION_TIMEOUT: core::time::Duration = core::time::Duration::from_secs(60);
+
+/// Outcome of the transaction for each recipient of the envelop, in the same order.
+pub type RecipientsOutcome = Vec<Result<lettre::transport::smtp::response::Response, Delivery>>;
+
 impl SenderParameters {
-    #[allow(clippy::module_name_repetitions)]
-    pub(crate) async fn smtp_send(
+    async fn connect(
         &self,
         hello_name: &Domain,
-        envelop: &lettre::address::Envelope,
-        message: &[u8],
         certificate: Option<Vec<rustls::Certificate>>,
-    ) -> Result<lettre::transport::smtp::response::Response, Delivery> {
+        dane: Option<&Dane>,
+    ) -> Result<lettre::transport::smtp::client::AsyncSmtpConnection, Delivery> {
         use lettre::transport::smtp::{
-            client::{Certificate, Tls, TlsParameters},
+            authentication::DEFAULT_MECHANISMS,
+            client::{AsyncSmtpConnection, Certificate, TlsParameters},
             extension::ClientId,
         };
 
-        let mut builder = lettre::AsyncSmtpTransport::<lettre::Tokio1Executor>::builder_dangerous(
-            self.host.to_string(),
-        )
-        .port(self.port)
-        .hello_name(ClientId::Domain(
-            self.hello_name.as_ref().unwrap_or(hello_name).to_string(),
-        ));
-
-        if matches!(
-            &self.tls,
-            TlsPolicy::StarttlsOpportunistic | TlsPolicy::StarttlsRequired | TlsPolicy::Tunnel
-        ) {
+        let hello_name =
+            ClientId::Domain(self.hello_name.as_ref().unwrap_or(hello_name).to_string());
+
+        if let Some(dane) = dane {
+            let mut conn = dane
+                .connect(
+                    (self.host.to_string(), self.port),
+                    &hello_name,
+                    CONNECTION_TIMEOUT,
+                )
+                .await?;
+
+            if let Some(credentials) = &self.credentials {
+                conn.auth(DEFAULT_MECHANISMS, &credentials.clone().into())
+                    .await?;
+            }
+
+            return Ok(conn);
+        }
+
+        let tls_parameters = if self.tls == TlsPolicy::None {
+            None
+        } else {
             let mut tls_builder = TlsParameters::builder(self.host.to_string());
 
             // for self signed message
@@ -419,26 +443,161 @@ impl SenderParameters {
                 tls_builder = tls_builder.add_root_certificate(Certificate::from_pem(&certs)?);
             }
 
-            let params = tls_builder.build()?;
+            Some(tls_builder.build()?)
+        };
 
-            builder = builder.tls(match self.tls {
-                TlsPolicy::StarttlsOpportunistic => Tls::Opportunistic(params),
-                TlsPolicy::StarttlsRequired => Tls::Required(params),
-                TlsPolicy::Tunnel => Tls::Wrapper(params),
-                #[allow(clippy::unreachable)]
-                TlsPolicy::None => unreachable!(),
-            });
+        let mut conn = AsyncSmtpConnection::connect_tokio1(
+            (self.host.to_string(), self.port),
+            Some(CONNECTION_TIMEOUT),
+            &hello_name,
+            tls_parameters
+                .clone()
+                .filter(|_| self.tls == TlsPolicy::Tunnel),
+            None,
+        )
+        .await?;
+
+        match (self.tls, tls_parameters) {
+            (TlsPolicy::StarttlsOpportunistic, Some(params)) if conn.can_starttls() => {
+                conn.starttls(params, &hello_name).await?;
+            }
+            (TlsPolicy::StarttlsRequired, Some(params)) => {
+                conn.starttls(params, &hello_name).await?;
+            }
+            _ => (),
         }
 
         if let Some(credentials) = &self.credentials {
-            builder = builder.credentials(credentials.clone().into());
+            conn.auth(DEFAULT_MECHANISMS, &credentials.clone().into())
+                .await?;
+        }
+
+        Ok(conn)
+    }
+
+    /// Send the message with one `RCPT TO` command per recipient.
+    ///
+    /// An error is returned if the transaction failed before the recipients were submitted.
+    /// Otherwise, the reply of the server is recorded for each recipient, and a recipient
+    /// refused by the server does not prevent the message from being sent to the others.
+    /// The outcome comes with whether the connection was encrypted with TLS.
+    ///
+    /// If `dane` is provided, the connection must be secured with `STARTTLS`, and the
+    /// certificate of the server is authenticated with the TLSA records.
+    ///
+    /// In the scope of a [`ConnectionPool`], an idle connection to the same destination
+    /// is reused, and the connection is given back to the pool after the transaction.
+    #[allow(clippy::module_name_repetitions)]
+    pub(crate) async fn smtp_send(
+        &self,
+        hello_name: &Domain,
+        envelop: &lettre::address::Envelope,
+        message: &[u8],
+        certificate: Option<Vec<rustls::Certificate>>,
+        dane: Option<&Dane>,
+    ) -> Result<(RecipientsOutcome, bool), Delivery> {
+        let pool = ConnectionPool::current();
+        let destination = Destination::new(
+            self,
+            self.hello_name.as_ref().unwrap_or(hello_name),
+            dane.is_some(),
+        );
+
+        let pooled = match &pool {
+            Some(pool) => pool.take(&destination).await,
+            None => None,
+        };
+        let (mut conn, messages) = match pooled {
+            Some(pooled) => pooled,
+            None => (self.connect(hello_name, certificate, dane).await?, 0),
         };
 
-        let transport = builder.build();
+        let encrypted = conn.is_encrypted();
+        let outcome = Self::transaction(&mut conn, envelop, message).await;
+
+        match pool {
+            Some(pool) if outcome.is_ok() => {
+                pool.release(destination, conn, messages + 1).await;
+            }
+            // NOTE: send a `QUIT` and close the connection.
+            _ => conn.abort().await,
+        }
+
+        outcome.map(|outcome| (outcome, encrypted))
+    }
+
+    async fn transaction(
+        conn: &mut lettre::transport::smtp::client::AsyncSmtpConnection,
+        envelop: &lettre::address::Envelope,
+        message: &[u8],
+    ) -> Result<RecipientsOutcome, Delivery> {
+        use lettre::transport::smtp::{
+            commands::{Data, Mail, Rcpt},
+            extension::{Extension, MailBodyParameter, MailParameter},
+        };
+
+        let mut mail_options = vec![];
+
+        if envelop
+            .from()
+            .into_iter()
+            .chain(envelop.to())
+            .any(|addr| !AsRef::<str>::as_ref(addr).is_ascii())
+        {
+            if !conn.server_info().supports_feature(Extension::SmtpUtfEight) {
+                return Err(Delivery::Client {
+                    with_source: Some(
+                        "envelop contains non-ascii chars but server does not support SMTPUTF8"
+                            .to_owned(),
+                    ),
+                });
+            }
+            mail_options.push(MailParameter::SmtpUtfEight);
+        }
+
+        if !message.is_ascii() {
+            if !conn.server_info().supports_feature(Extension::EightBitMime) {
+                return Err(Delivery::Client {
+                    with_source: Some(
+                        "message contains non-ascii chars but server does not support 8BITMIME"
+                            .to_owned(),
+                    ),
+                });
+            }
+            mail_options.push(MailParameter::Body(MailBodyParameter::EightBitMime));
+        }
+
+        conn.command(Mail::new(envelop.from().cloned(), mail_options))
+            .await?;
+
+        let mut outcome = Vec::with_capacity(envelop.to().len());
+        for rcpt in envelop.to() {
+            match conn.command(Rcpt::new(rcpt.clone(), vec![])).await {
+                Ok(response) => outcome.push(Ok(response)),
+                Err(error) if error.status().is_some() => {
+                    tracing::warn!(%rcpt, %error, "Recipient refused by the server.");
+                    outcome.push(Err(error.into()));
+                }
+                Err(error) => return Err(error.into()),
+            }
+        }
+
+        if outcome.iter().all(Result::is_err) {
+            return Ok(outcome);
+        }
+
+        let data = async {
+            conn.command(Data).await?;
+            conn.message(message).await
+        }
+        .await
+        .map_err(Delivery::from);
 
-        lettre::AsyncTransport::send_raw(&transport, envelop, message)
-            .await
-            .map_err(Into::into)
+        // the reply to the message content applies to all the accepted recipients.
+        Ok(outcome
+            .into_iter()
+            .map(|rcpt| rcpt.and(data.clone()))
+            .collect())
     }
 }
 
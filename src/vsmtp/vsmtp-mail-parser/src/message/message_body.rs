@@ -155,6 +155,35 @@ impl MessageBody {
         self.raw.remove_header(name)
     }
 
+    /// Insert a header at the `index`-th position of the header section.
+    ///
+    /// The parsed part is dropped, and parsed again on demand.
+    pub fn insert_header(&mut self, index: usize, name: &str, value: &str) {
+        self.parsed = None;
+        self.raw.insert_header(index, name, &format!("{value}\r\n"));
+    }
+
+    /// Replace the `occurrence`-th header named `name` (starting from 0),
+    /// or remove it if `value` is `None`.
+    ///
+    /// The parsed part is dropped, and parsed again on demand.
+    pub fn replace_header(&mut self, name: &str, occurrence: usize, value: Option<&str>) -> bool {
+        self.parsed = None;
+        self.raw.replace_header(
+            name,
+            occurrence,
+            value.map(|value| format!("{value}\r\n")).as_deref(),
+        )
+    }
+
+    /// Replace the body of the message.
+    ///
+    /// The parsed part is dropped, and parsed again on demand.
+    pub fn set_body(&mut self, body: String) {
+        self.parsed = None;
+        self.raw.set_body(body);
+    }
+
     /// # Errors
     ///
     /// * the value produced by the [`MailParser`] was not a parsed [`Mail`]
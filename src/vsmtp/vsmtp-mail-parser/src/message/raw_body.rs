@@ -183,6 +183,63 @@ impl RawBody {
             false
         }
     }
+
+    /// Insert a header at the `index`-th position of the header fields,
+    /// or at the end of the list if there is less fields than `index`.
+    pub fn insert_header(&mut self, index: usize, name: &str, value: &str) {
+        let position = self
+            .fields()
+            .get(index)
+            .map_or(self.headers.len(), |field| field.start);
+        self.headers.insert(position, format!("{name}: {value}"));
+    }
+
+    /// Replace the `occurrence`-th header named `name` (starting from 0, using lowercase),
+    /// or remove it if `value` is `None`. Continuation lines are replaced too.
+    ///
+    /// Return `false` if no such header exist.
+    pub fn replace_header(&mut self, name: &str, occurrence: usize, value: Option<&str>) -> bool {
+        let field = self
+            .fields()
+            .into_iter()
+            .filter(|field| {
+                self.headers[field.start]
+                    .split_once(':')
+                    .map_or(false, |(key, _)| key.eq_ignore_ascii_case(name))
+            })
+            .nth(occurrence);
+
+        if let Some(field) = field {
+            let key = self.headers[field.start]
+                .split_once(':')
+                .map(|(key, _)| key.to_string())
+                .unwrap_or_default();
+            self.headers
+                .splice(field, value.map(|value| format!("{key}: {value}")));
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Replace the body of the message.
+    pub fn set_body(&mut self, body: String) {
+        self.body = Some(body);
+    }
+
+    /// Return the lines spanned by each header field, continuation lines included.
+    fn fields(&self) -> Vec<std::ops::Range<usize>> {
+        let mut fields = Vec::<std::ops::Range<usize>>::new();
+        for (idx, header) in self.headers.iter().enumerate() {
+            match fields.last_mut() {
+                Some(field) if header.starts_with(' ') || header.starts_with('\t') => {
+                    field.end = idx + 1;
+                }
+                _ => fields.push(idx..idx + 1),
+            }
+        }
+        fields
+    }
 }
 
 impl std::fmt::Display for RawBody {
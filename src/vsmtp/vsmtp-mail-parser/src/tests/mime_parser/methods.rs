@@ -89,3 +89,63 @@ fn test_set_and_append_header() {
         Some(new_header_message.to_string())
     );
 }
+
+#[test]
+fn test_insert_replace_header_and_body() {
+    use crate::tests::mime_parser::methods::generate_test_bodies;
+
+    let (_, mut parsed) = generate_test_bodies();
+
+    parsed.insert_header(0, "X-First", "first");
+    parsed.insert_header(100, "X-Last", "last");
+    assert!(parsed.replace_header("to", 0, Some("blue@example.com")));
+    assert!(parsed.replace_header("Content-Language", 0, None));
+    assert!(!parsed.replace_header("Content-Language", 0, None));
+    assert!(!parsed.replace_header("To", 1, None));
+    parsed.set_body("plain text\r\n".to_string());
+
+    assert_eq!(parsed.get_parsed(), &None);
+    assert_eq!(
+        parsed.inner().to_string(),
+        [
+            "X-First: first\r\n",
+            "From: john <john@example.com>\r\n",
+            "To: blue@example.com\r\n",
+            "Date: tue, 30 nov 2021 20:54:27 +0100\r\n",
+            "Subject: test message\r\n",
+            "Content-Type: text/html; charset=UTF-8\r\n",
+            "Content-Transfer-Encoding: 7bit\r\n",
+            "X-Last: last\r\n",
+            "\r\n",
+            "plain text\r\n",
+        ]
+        .concat()
+    );
+}
+
+#[test]
+fn test_replace_folded_header() {
+    let mut raw = MessageBody::new(
+        [
+            "Received: from foo\r\n",
+            "\tby bar\r\n",
+            "Received: from baz\r\n",
+            "Subject: test\r\n",
+        ]
+        .iter()
+        .map(ToString::to_string)
+        .collect(),
+        String::new(),
+    );
+
+    assert!(raw.replace_header("Received", 0, None));
+    raw.insert_header(1, "X-Between", "value");
+    assert_eq!(
+        raw.inner().raw_headers(),
+        &[
+            "Received: from baz\r\n",
+            "X-Between: value\r\n",
+            "Subject: test\r\n"
+        ]
+    );
+}
@@ -16,7 +16,7 @@
 */
 
 use crate::ConnectionKind;
-use vsmtp_common::{auth::Mechanism, ClientName, Domain};
+use vsmtp_common::{auth::Mechanism, dsn, ClientName, Domain, Priority};
 extern crate alloc;
 
 /// Buffer received from the client.
@@ -77,7 +77,8 @@ pub struct EhloArgs {
 
 /// See "SMTP Service Extension for 8-bit MIME Transport"
 /// <https://datatracker.ietf.org/doc/html/rfc6152>
-#[derive(strum::EnumVariantNames, strum::EnumString)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::EnumVariantNames, strum::EnumString)]
+#[non_exhaustive]
 pub enum MimeBodyType {
     ///
     #[strum(serialize = "7BIT")]
@@ -85,8 +86,10 @@ pub enum MimeBodyType {
     ///
     #[strum(serialize = "8BITMIME")]
     EightBitMime,
-    // TODO: https://datatracker.ietf.org/doc/html/rfc3030
-    // Binary,
+    /// See "SMTP Service Extensions for Transmission of Large and Binary MIME Messages"
+    /// <https://datatracker.ietf.org/doc/html/rfc3030>
+    #[strum(serialize = "BINARYMIME")]
+    BinaryMime,
 }
 
 /// Information received from the client at the MAIL FROM command.
@@ -97,9 +100,16 @@ pub struct MailFromArgs {
     pub reverse_path: Option<String>,
     /// (8BITMIME)
     pub mime_body_type: Option<MimeBodyType>,
+    /// Size of the message declared by the client (SIZE, RFC 1870).
+    pub message_size: Option<usize>,
+    /// Content of the message to return in a notification (RET, RFC 3461).
+    pub ret: Option<dsn::Ret>,
+    /// Identifier of the transaction given by the client (ENVID, RFC 3461).
+    pub envelope_id: Option<String>,
+    /// Priority of the message requested by the client (MT-PRIORITY, RFC 6710).
+    pub priority: Option<Priority>,
     // TODO:
     // Option<String>       (AUTH)
-    // Option<usize>        (SIZE)
     // use_smtputf8: bool,
 }
 
@@ -109,6 +119,19 @@ pub struct RcptToArgs {
     /// Recipient address.
     // TODO: wrap in a type mailbox
     pub forward_path: String,
+    /// Conditions of notification (NOTIFY, RFC 3461).
+    pub notify_on: Option<dsn::NotifyOn>,
+    /// Original recipient (ORCPT, RFC 3461).
+    pub original_forward_path: Option<dsn::OriginalRecipient>,
+}
+
+/// Information received from the client at the BDAT command.
+#[non_exhaustive]
+pub struct BdatArgs {
+    /// Size of the chunk following the command, in octets.
+    pub chunk_size: usize,
+    /// Is it the last chunk of the message ?
+    pub is_last: bool,
 }
 
 /// Information received from the client at the AUTH command.
@@ -283,29 +306,81 @@ impl TryFrom<UnparsedArgs> for MailFromArgs {
         };
 
         let mut mime_body_type = None;
+        let mut message_size = None;
+        let mut ret = None;
+        let mut envelope_id = None;
+        let mut priority = None;
 
         #[allow(clippy::expect_used)]
         for args in words {
-            match args.strip_prefix(b"BODY=") {
-                Some(args_mime_body_type) if mime_body_type.is_none() => {
-                    mime_body_type = <MimeBodyType as strum::VariantNames>::VARIANTS
-                        .iter()
-                        .find(|i| {
-                            args_mime_body_type.len() >= i.len()
-                                && args_mime_body_type
-                                    .get(..i.len())
-                                    .expect("range checked above")
-                                    .eq_ignore_ascii_case(i.as_bytes())
-                        })
-                        .map(|body| body.parse().expect("body found above"));
+            if let Some(args_mime_body_type) = args.strip_prefix(b"BODY=") {
+                if mime_body_type.is_some() {
+                    return Err(ParseArgsError::InvalidArgs);
+                }
+                mime_body_type = <MimeBodyType as strum::VariantNames>::VARIANTS
+                    .iter()
+                    .find(|i| {
+                        args_mime_body_type.len() >= i.len()
+                            && args_mime_body_type
+                                .get(..i.len())
+                                .expect("range checked above")
+                                .eq_ignore_ascii_case(i.as_bytes())
+                    })
+                    .map(|body| body.parse().expect("body found above"));
+            } else if let Some(args_message_size) = args.strip_prefix(b"SIZE=") {
+                if message_size.is_some()
+                    || args_message_size.is_empty()
+                    || !args_message_size.iter().all(u8::is_ascii_digit)
+                {
+                    return Err(ParseArgsError::InvalidArgs);
+                }
+                message_size = Some(
+                    core::str::from_utf8(args_message_size)
+                        .ok()
+                        .and_then(|size| size.parse::<usize>().ok())
+                        .ok_or(ParseArgsError::InvalidArgs)?,
+                );
+            } else if let Some(args_ret) = args.strip_prefix(b"RET=") {
+                if ret.is_some() {
+                    return Err(ParseArgsError::InvalidArgs);
                 }
-                _ => return Err(ParseArgsError::InvalidArgs),
+                ret = Some(
+                    core::str::from_utf8(args_ret)
+                        .ok()
+                        .and_then(|args_ret| args_ret.parse::<dsn::Ret>().ok())
+                        .ok_or(ParseArgsError::InvalidArgs)?,
+                );
+            } else if let Some(args_envelope_id) = args.strip_prefix(b"ENVID=") {
+                if envelope_id.is_some() {
+                    return Err(ParseArgsError::InvalidArgs);
+                }
+                envelope_id = Some(
+                    dsn::decode_xtext(args_envelope_id)
+                        .filter(|id| !id.is_empty() && id.len() <= dsn::ENVELOPE_ID_MAX_LENGTH)
+                        .ok_or(ParseArgsError::InvalidArgs)?,
+                );
+            } else if let Some(args_priority) = args.strip_prefix(b"MT-PRIORITY=") {
+                if priority.is_some() {
+                    return Err(ParseArgsError::InvalidArgs);
+                }
+                priority = Some(
+                    core::str::from_utf8(args_priority)
+                        .ok()
+                        .and_then(|args_priority| args_priority.parse::<Priority>().ok())
+                        .ok_or(ParseArgsError::InvalidArgs)?,
+                );
+            } else {
+                return Err(ParseArgsError::InvalidArgs);
             }
         }
 
         Ok(Self {
             reverse_path: mailbox,
             mime_body_type,
+            message_size,
+            ret,
+            envelope_id,
+            priority,
         })
     }
 }
@@ -337,8 +412,92 @@ impl TryFrom<UnparsedArgs> for RcptToArgs {
             return Err(ParseArgsError::InvalidArgs);
         };
 
+        let mut notify_on = None;
+        let mut original_forward_path = None;
+
+        for args in word {
+            if let Some(args_notify) = args.strip_prefix(b"NOTIFY=") {
+                if notify_on.is_some() {
+                    return Err(ParseArgsError::InvalidArgs);
+                }
+                notify_on = Some(
+                    core::str::from_utf8(args_notify)
+                        .ok()
+                        .and_then(|notify| notify.parse::<dsn::NotifyOn>().ok())
+                        .ok_or(ParseArgsError::InvalidArgs)?,
+                );
+            } else if let Some(args_orcpt) = args.strip_prefix(b"ORCPT=") {
+                if original_forward_path.is_some() {
+                    return Err(ParseArgsError::InvalidArgs);
+                }
+                let (addr_type, addr) = args_orcpt
+                    .iter()
+                    .position(|c| *c == b';')
+                    .map(|idx| args_orcpt.split_at(idx))
+                    .ok_or(ParseArgsError::InvalidArgs)?;
+                let addr_type = core::str::from_utf8(addr_type)
+                    .ok()
+                    .filter(|addr_type| {
+                        !addr_type.is_empty()
+                            && addr_type
+                                .bytes()
+                                .all(|c| c.is_ascii_alphanumeric() || c == b'-')
+                    })
+                    .ok_or(ParseArgsError::InvalidArgs)?;
+                original_forward_path = Some(dsn::OriginalRecipient {
+                    addr_type: addr_type.to_owned(),
+                    mailbox: addr
+                        .get(1..)
+                        .and_then(dsn::decode_xtext)
+                        .filter(|addr| !addr.is_empty())
+                        .ok_or(ParseArgsError::InvalidArgs)?,
+                });
+            } else {
+                return Err(ParseArgsError::InvalidArgs);
+            }
+        }
+
         Ok(Self {
             forward_path: mailbox,
+            notify_on,
+            original_forward_path,
+        })
+    }
+}
+
+impl TryFrom<UnparsedArgs> for BdatArgs {
+    type Error = ParseArgsError;
+
+    #[inline]
+    fn try_from(value: UnparsedArgs) -> Result<Self, Self::Error> {
+        let value = value
+            .0
+            .strip_suffix(b"\r\n")
+            .ok_or(ParseArgsError::InvalidArgs)?;
+
+        let mut words = value
+            .split(u8::is_ascii_whitespace)
+            .filter(|s| !s.is_empty());
+
+        let chunk_size = match words.next() {
+            Some(chunk_size) if chunk_size.iter().all(u8::is_ascii_digit) => {
+                core::str::from_utf8(chunk_size)
+                    .ok()
+                    .and_then(|chunk_size| chunk_size.parse::<usize>().ok())
+                    .ok_or(ParseArgsError::InvalidArgs)?
+            }
+            _ => return Err(ParseArgsError::InvalidArgs),
+        };
+
+        let is_last = match (words.next(), words.next()) {
+            (None, None) => false,
+            (Some(last), None) if last.eq_ignore_ascii_case(b"LAST") => true,
+            _ => return Err(ParseArgsError::InvalidArgs),
+        };
+
+        Ok(Self {
+            chunk_size,
+            is_last,
         })
     }
 }
@@ -368,6 +527,11 @@ pub enum Verb {
     /// This command causes the mail data to be appended to the mail data
     /// buffer.
     Data,
+    /// This command sends a chunk of the message, of the size given as argument.
+    /// See "SMTP Service Extensions for Transmission of Large and Binary MIME Messages"
+    /// <https://datatracker.ietf.org/doc/html/rfc3030>
+    #[strum(serialize = "BDAT ")]
+    Bdat,
     /// This command specifies that the receiver MUST send a "221 OK" reply,
     /// and then close the transmission channel.
     #[strum(serialize = "QUIT\r\n")]
@@ -54,8 +54,8 @@ mod smtp_sasl;
 mod writer;
 
 pub use command::{
-    AcceptArgs, AuthArgs, EhloArgs, HeloArgs, MailFromArgs, ParseArgsError, RcptToArgs,
-    UnparsedArgs, Verb,
+    AcceptArgs, AuthArgs, BdatArgs, EhloArgs, HeloArgs, MailFromArgs, MimeBodyType, ParseArgsError,
+    RcptToArgs, UnparsedArgs, Verb,
 };
 pub use connection_kind::ConnectionKind;
 pub use error::Error;
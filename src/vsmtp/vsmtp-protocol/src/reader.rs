@@ -26,26 +26,31 @@ fn find(bytes: &[u8], search: &[u8]) -> Option<usize> {
 }
 
 /// Stream for reading commands from the client.
+///
+/// The bytes read from the underlying reader are kept in an internal buffer
+/// shared by all the streams produced, so the commands sent in a group by a
+/// client using PIPELINING (RFC 2920) are not lost between two streams.
 pub struct Reader<R: tokio::io::AsyncRead + Unpin + Send> {
     inner: R,
-    initial_capacity: usize,
+    buffer: bytes::BytesMut,
     additional_reserve: usize,
 }
 
-// TODO: handle PIPELINING
 impl<R: tokio::io::AsyncRead + Unpin + Send> Reader<R> {
     /// Create a new stream.
     #[must_use]
     #[inline]
-    pub const fn new(tcp_stream: R) -> Self {
+    pub fn new(tcp_stream: R) -> Self {
         Self {
             inner: tcp_stream,
-            initial_capacity: 80,
+            buffer: bytes::BytesMut::with_capacity(80),
             additional_reserve: 100,
         }
     }
 
     /// Consume the instance and return the underlying reader.
+    ///
+    /// The bytes buffered but not consumed yet are discarded.
     #[must_use]
     #[inline]
     #[allow(clippy::missing_const_for_fn)]
@@ -53,6 +58,16 @@ impl<R: tokio::io::AsyncRead + Unpin + Send> Reader<R> {
         self.inner
     }
 
+    /// Is there a complete line already buffered, waiting to be consumed ?
+    ///
+    /// With PIPELINING, it means the client has sent the next command of the group
+    /// without waiting for the reply to the previous one.
+    #[must_use]
+    #[inline]
+    pub fn has_pending_line(&self) -> bool {
+        find(&self.buffer, b"\r\n").is_some()
+    }
+
     /// Produce a stream of "\r\n" terminated lines.
     #[inline]
     #[allow(clippy::todo, clippy::missing_panics_doc)]
@@ -60,28 +75,21 @@ impl<R: tokio::io::AsyncRead + Unpin + Send> Reader<R> {
         &mut self,
     ) -> impl tokio_stream::Stream<Item = std::io::Result<Vec<u8>>> + '_ {
         async_stream::try_stream! {
-            let mut buffer = bytes::BytesMut::with_capacity(self.initial_capacity);
-            let mut n = 0;
-
             loop {
-                if let Some(pos) = find(&buffer[..n], b"\r\n") {
-                    let out = buffer.split_to(pos + 2);
-                    n -= out.len();
-
-                    // PIPELINING: handle buffer here
-                    // TODO: should we return the extra bytes read?
-
+                if let Some(pos) = find(&self.buffer, b"\r\n") {
+                    // the extra bytes stay in the buffer for the next line,
+                    // even if they are read by another stream.
+                    let out = self.buffer.split_to(pos + 2);
                     yield Vec::<u8>::from(out);
                 } else {
-                    buffer.reserve(self.additional_reserve);
-                    let read_size = self.inner.read_buf(&mut buffer).await?;
+                    self.buffer.reserve(self.additional_reserve);
+                    let read_size = self.inner.read_buf(&mut self.buffer).await?;
                     if read_size == 0 {
-                        if !buffer.is_empty() {
-                            todo!("what about the remaining buffer? {:?}", buffer);
+                        if !self.buffer.is_empty() {
+                            todo!("what about the remaining buffer? {:?}", self.buffer);
                         }
                         return;
                     }
-                    n += read_size;
                 }
             }
         }
@@ -109,7 +117,7 @@ impl<R: tokio::io::AsyncRead + Unpin + Send> Reader<R> {
 
                 // TODO: handle line length max ?
                 size += line.len();
-                if size >= size_limit {
+                if size > size_limit {
                     yield Err(Error::BufferTooLong { expected: size_limit, got: size });
                     return;
                 }
@@ -119,6 +127,36 @@ impl<R: tokio::io::AsyncRead + Unpin + Send> Reader<R> {
         }
     }
 
+    /// Produce a stream of the `chunk_size` next bytes, as sent after a `BDAT` command.
+    ///
+    /// The bytes are yielded as they are received, without any dot-stuffing or
+    /// line handling (RFC 3030).
+    #[inline]
+    pub fn as_chunk_stream(
+        &mut self,
+        chunk_size: usize,
+    ) -> impl tokio_stream::Stream<Item = std::io::Result<Vec<u8>>> + '_ {
+        async_stream::try_stream! {
+            let mut remaining = chunk_size;
+
+            while remaining != 0 {
+                if self.buffer.is_empty() {
+                    self.buffer.reserve(core::cmp::min(remaining, 8192));
+                    let read_size = self.inner.read_buf(&mut self.buffer).await?;
+                    if read_size == 0 {
+                        Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
+                    }
+                }
+
+                // the extra bytes stay in the buffer for the next command.
+                let out = self.buffer.split_to(core::cmp::min(remaining, self.buffer.len()));
+                remaining -= out.len();
+                tracing::trace!("<< {} bytes of chunk", out.len());
+                yield Vec::<u8>::from(out);
+            }
+        }
+    }
+
     /// Produce a stream of ESMTP commands.
     #[inline]
     #[allow(clippy::expect_used)]
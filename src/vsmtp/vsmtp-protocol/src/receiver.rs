@@ -15,16 +15,17 @@
  *
 */
 use crate::{
-    reader::Reader, writer::Writer, AcceptArgs, AuthArgs, ConnectionKind, EhloArgs, Error,
-    HeloArgs, MailFromArgs, ParseArgsError, RcptToArgs, ReceiverHandler, Verb,
+    reader::Reader, writer::Writer, AcceptArgs, AuthArgs, BdatArgs, ConnectionKind, EhloArgs,
+    Error, HeloArgs, MailFromArgs, MimeBodyType, ParseArgsError, RcptToArgs, ReceiverHandler, Verb,
 };
 use tokio_rustls::rustls;
 use tokio_stream::StreamExt;
-use vsmtp_common::{auth::Mechanism, Stage};
+use vsmtp_common::{auth::Mechanism, Reply, Stage};
 extern crate alloc;
 
 enum HandshakeOutcome {
     Message,
+    ChunkedMessage,
     UpgradeTLS {
         config: alloc::sync::Arc<rustls::ServerConfig>,
         handshake_timeout: std::time::Duration,
@@ -36,6 +37,13 @@ enum HandshakeOutcome {
     Quit,
 }
 
+/// Message received with the BDAT command (RFC 3030), accumulated chunk by chunk.
+#[derive(Default)]
+struct ChunkedMessage {
+    body: Vec<u8>,
+    size: usize,
+}
+
 pub struct ErrorCounter {
     pub error_count: i64,
     pub threshold_soft_error: i64,
@@ -79,6 +87,15 @@ impl ReceiverContext {
     }
 }
 
+/// Signal making the [`Receiver`] close the connection once the current transaction is over.
+#[derive(Default)]
+enum Shutdown {
+    #[default]
+    Never,
+    Pending(core::pin::Pin<Box<dyn core::future::Future<Output = ()> + Send>>),
+    Requested,
+}
+
 /// A SMTP receiver.
 pub struct Receiver<
     T: ReceiverHandler + Send,
@@ -95,6 +112,9 @@ pub struct Receiver<
     context: ReceiverContext,
     kind: ConnectionKind,
     message_size_max: usize,
+    chunks: Option<ChunkedMessage>,
+    binary_mime: bool,
+    shutdown: Shutdown,
     v: std::marker::PhantomData<V>,
 }
 
@@ -151,6 +171,9 @@ where
                 error_counter: self.error_counter,
                 kind: self.kind,
                 message_size_max: self.message_size_max,
+                chunks: None,
+                binary_mime: false,
+                shutdown: self.shutdown,
                 v: self.v,
             }.into_secured_stream(
                 sni,
@@ -190,10 +213,25 @@ where
             context: ReceiverContext { outcome: None },
             kind,
             message_size_max,
+            chunks: None,
+            binary_mime: false,
+            shutdown: Shutdown::Never,
             v: std::marker::PhantomData,
         }
     }
 
+    /// Close the connection with a `421` reply when `signal` completes, the transaction
+    /// in progress (if any) is completed first.
+    #[inline]
+    #[must_use]
+    pub fn with_shutdown(
+        mut self,
+        signal: impl core::future::Future<Output = ()> + Send + 'static,
+    ) -> Self {
+        self.shutdown = Shutdown::Pending(Box::pin(signal));
+        self
+    }
+
     /// Handle the inner stream to produce a [`tokio_stream::Stream`], each item
     /// being a successful SMTP transaction.
     ///
@@ -224,7 +262,9 @@ where
             let produced_context_accept = std::mem::take(&mut self.context);
             if let Some(outcome) = produced_context_accept.outcome {
                 match outcome {
-                    HandshakeOutcome::Message | HandshakeOutcome::Authenticate { .. } =>
+                    HandshakeOutcome::Message
+                    | HandshakeOutcome::ChunkedMessage
+                    | HandshakeOutcome::Authenticate { .. } =>
                         todo!("implementation of Handler is incorrect"),
                     HandshakeOutcome::UpgradeTLS { config, handshake_timeout } => {
                         for await i in self.upgrade_tls(config, handshake_timeout) {
@@ -261,6 +301,10 @@ where
 
                         yield ();
                     },
+                    HandshakeOutcome::ChunkedMessage => {
+                        self.on_chunked_message().await?;
+                        yield ();
+                    },
                     HandshakeOutcome::UpgradeTLS { config, handshake_timeout } => {
                         for await i in self.upgrade_tls(config, handshake_timeout) {
                             yield i?;
@@ -346,6 +390,10 @@ where
 
                         yield ();
                     },
+                    HandshakeOutcome::ChunkedMessage => {
+                        self.on_chunked_message().await?;
+                        yield ();
+                    },
                     HandshakeOutcome::UpgradeTLS { .. } => todo!("smtp_handshake should not return UpgradeTLS"),
                     HandshakeOutcome::Authenticate { mechanism, initial_response } => {
                         let auth_result = self.authenticate(mechanism, initial_response).await;
@@ -368,12 +416,109 @@ where
         }
     }
 
+    /// Read a chunk sent after a `BDAT` command, and append it to the message
+    /// if the transaction is in a valid state.
+    async fn on_bdat(&mut self, args: BdatArgs, stage: Stage) -> std::io::Result<Option<Reply>> {
+        let is_accepted = stage == Stage::RcptTo;
+
+        // the chunk is always consumed, to stay synchronized with the client.
+        let chunk_stream = self.stream.as_chunk_stream(args.chunk_size);
+        tokio::pin!(chunk_stream);
+
+        let chunks = self.chunks.get_or_insert_with(ChunkedMessage::default);
+        while let Some(bytes) = chunk_stream.try_next().await? {
+            if !is_accepted {
+                continue;
+            }
+            chunks.size += bytes.len();
+            // the message is dropped, the error is produced with the last chunk.
+            if chunks.size > self.message_size_max {
+                chunks.body = vec![];
+            } else {
+                chunks.body.extend_from_slice(&bytes);
+            }
+        }
+
+        if !is_accepted {
+            self.chunks = None;
+            return Ok(Some(
+                self.handler.on_bad_sequence((Verb::Bdat, stage)).await,
+            ));
+        }
+
+        if args.is_last {
+            self.context.outcome = Some(HandshakeOutcome::ChunkedMessage);
+            Ok(None)
+        } else {
+            Ok(Some(self.handler.on_bdat(args.chunk_size).await))
+        }
+    }
+
+    /// Produce the message accumulated with the `BDAT` commands, following the
+    /// same path as a message received with `DATA`.
+    async fn on_chunked_message(&mut self) -> std::io::Result<()> {
+        let chunks = self.chunks.take().unwrap_or_default();
+
+        let lines = if chunks.size > self.message_size_max {
+            vec![Err(Error::BufferTooLong {
+                expected: self.message_size_max,
+                got: chunks.size,
+            })]
+        } else {
+            chunks
+                .body
+                .split_inclusive(|byte| *byte == b'\n')
+                .map(|line| Ok(line.to_vec()))
+                .collect::<Vec<_>>()
+        };
+        let message_stream = tokio_stream::iter(lines);
+
+        let (mut reply, completed) = self
+            .handler
+            .on_message(&mut self.context, message_stream)
+            .await;
+        if let Some(completed) = completed {
+            for (ctx, msg) in completed {
+                if let Some(error) = self.handler.on_message_completed(ctx, msg).await {
+                    reply = error;
+                    break;
+                }
+            }
+        }
+        self.sink
+            .send_reply(
+                &mut self.context,
+                &mut self.error_counter,
+                &mut self.handler,
+                reply,
+            )
+            .await
+    }
+
+    /// Answer `421` to the client, the connection is closed by the caller.
+    async fn close_on_shutdown(&mut self) -> std::io::Result<HandshakeOutcome> {
+        tracing::info!("Closing the connection, the server is shutting down.");
+        #[allow(clippy::expect_used)]
+        self.sink
+            .send_reply(
+                &mut self.context,
+                &mut self.error_counter,
+                &mut self.handler,
+                "421 Service shutting down, closing transmission channel\r\n"
+                    .parse()
+                    .expect("valid syntax"),
+            )
+            .await?;
+
+        Ok(HandshakeOutcome::Quit)
+    }
+
     /// SMTP handshake (generate the envelope and metadata).
     ///
     /// # Returns
     ///
     /// * the `Vec<u8>` is the bytes read with the SMTP verb "DATA\r\n"
-    #[allow(clippy::too_many_lines)]
+    #[allow(clippy::too_many_lines, clippy::cognitive_complexity)]
     async fn smtp_handshake(&mut self) -> std::io::Result<HandshakeOutcome> {
         macro_rules! handle_args {
             ($args_output:ty, $args:expr, $on_event:tt) => {
@@ -390,14 +535,39 @@ where
             };
         }
 
-        let command_stream = self
-            .stream
-            .as_command_stream()
-            .timeout(std::time::Duration::from_secs(30));
-        tokio::pin!(command_stream);
-
         loop {
-            let command = match command_stream.try_next().await {
+            // NOTE: the stream is produced for each command, the bytes already read
+            // are kept by the reader, so the pipelined commands are not lost.
+            let idle = matches!(self.handler.get_stage(), Stage::Connect | Stage::Helo);
+            if idle && matches!(self.shutdown, Shutdown::Requested) {
+                return self.close_on_shutdown().await;
+            }
+
+            let command = {
+                let command_stream = self
+                    .stream
+                    .as_command_stream()
+                    .timeout(std::time::Duration::from_secs(30));
+                tokio::pin!(command_stream);
+
+                match &mut self.shutdown {
+                    Shutdown::Pending(signal) if idle => tokio::select! {
+                        biased;
+                        () = signal.as_mut() => None,
+                        command = command_stream.try_next() => Some(command),
+                    },
+                    Shutdown::Never | Shutdown::Pending(_) | Shutdown::Requested => {
+                        Some(command_stream.try_next().await)
+                    }
+                }
+            };
+
+            let Some(command) = command else {
+                self.shutdown = Shutdown::Requested;
+                return self.close_on_shutdown().await;
+            };
+
+            let command = match command {
                 Ok(Some(command)) => command,
                 Ok(None) => return Ok(HandshakeOutcome::Quit),
                 Err(e) => {
@@ -450,12 +620,28 @@ where
             };
             tracing::trace!("<< {:?} ; {:?}", verb, std::str::from_utf8(&args.0));
 
+            // RFC 2920: these commands can appear anywhere in a group,
+            // others must be the last one.
+            let can_be_pipelined = matches!(
+                verb,
+                Verb::Rset | Verb::MailFrom | Verb::RcptTo | Verb::Bdat
+            );
+
             let stage = self.handler.get_stage();
             let reply = match (verb, stage) {
-                (Verb::Helo, _) => Some(handle_args!(HeloArgs, args, on_helo)),
-                (Verb::Ehlo, _) => Some(handle_args!(EhloArgs, args, on_ehlo)),
+                (Verb::Helo, _) => {
+                    self.chunks = None;
+                    Some(handle_args!(HeloArgs, args, on_helo))
+                }
+                (Verb::Ehlo, _) => {
+                    self.chunks = None;
+                    Some(handle_args!(EhloArgs, args, on_ehlo))
+                }
                 (Verb::Noop, _) => Some(self.handler.on_noop().await),
-                (Verb::Rset, _) => Some(self.handler.on_rset().await),
+                (Verb::Rset, _) => {
+                    self.chunks = None;
+                    Some(self.handler.on_rset().await)
+                }
                 (Verb::StartTls, Stage::Connect | Stage::Helo) => {
                     Some(self.handler.on_starttls(&mut self.context).await)
                 }
@@ -463,15 +649,33 @@ where
                     handle_args!(AuthArgs, args, Option: on_auth)
                 }
                 (Verb::MailFrom, Stage::Helo | Stage::MailFrom) => {
-                    Some(handle_args!(MailFromArgs, args, on_mail_from))
+                    match MailFromArgs::try_from(args) {
+                        Ok(args) => {
+                            // RFC 3030: a binary message can only be sent with BDAT.
+                            self.binary_mime =
+                                args.mime_body_type == Some(MimeBodyType::BinaryMime);
+                            Some(self.handler.on_mail_from(&mut self.context, args).await)
+                        }
+                        Err(e) => Some(self.handler.on_args_error(e).await),
+                    }
                 }
                 (Verb::RcptTo, Stage::MailFrom | Stage::RcptTo) => {
                     Some(handle_args!(RcptToArgs, args, on_rcpt_to))
                 }
-                (Verb::Data, Stage::RcptTo) => {
+                (Verb::Data, Stage::RcptTo) if self.chunks.is_none() && !self.binary_mime => {
                     self.context.outcome = Some(HandshakeOutcome::Message);
                     Some(self.handler.on_data().await)
                 }
+                (Verb::Bdat, stage) => match BdatArgs::try_from(args) {
+                    Ok(args) => self.on_bdat(args, stage).await?,
+                    // RFC 3030: the size of the chunk is unknown, the next bytes cannot
+                    // be told apart from the commands, so the connection is closed.
+                    Err(e) => {
+                        self.chunks = None;
+                        self.context.outcome = Some(HandshakeOutcome::Quit);
+                        Some(self.handler.on_args_error(e).await)
+                    }
+                },
                 (Verb::Quit, _) => {
                     self.context.outcome = Some(HandshakeOutcome::Quit);
                     Some(self.handler.on_quit().await)
@@ -482,18 +686,34 @@ where
             };
 
             if let Some(reply) = reply {
-                self.sink
-                    .send_reply(
-                        &mut self.context,
-                        &mut self.error_counter,
-                        &mut self.handler,
-                        reply,
-                    )
-                    .await?;
+                // the replies are sent in a single write once the whole group is consumed.
+                if can_be_pipelined
+                    && self.context.outcome.is_none()
+                    && self.stream.has_pending_line()
+                {
+                    self.sink
+                        .buffer_reply(
+                            &mut self.context,
+                            &mut self.error_counter,
+                            &mut self.handler,
+                            reply,
+                        )
+                        .await;
+                } else {
+                    self.sink
+                        .send_reply(
+                            &mut self.context,
+                            &mut self.error_counter,
+                            &mut self.handler,
+                            reply,
+                        )
+                        .await?;
+                }
             }
 
             let produced_context = std::mem::take(&mut self.context);
             if let Some(done) = produced_context.outcome {
+                self.sink.flush().await?;
                 return Ok(done);
             }
         }
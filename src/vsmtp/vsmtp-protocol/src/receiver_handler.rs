@@ -75,7 +75,7 @@ pub trait ReceiverHandler {
     /// Called after receiving a [`Verb::RcptTo`] command.
     async fn on_rcpt_to(&mut self, ctx: &mut ReceiverContext, args: RcptToArgs) -> Reply;
 
-    /// Called after receiving a [`Verb::Data`] command.
+    /// Called after receiving a [`Verb::Data`] command, or the last [`Verb::Bdat`] chunk.
     ///
     /// The stream is the body of the message, with dot-stuffing handled.
     /// The stream return `None` when the message is finished (`.<CRLF>`).
@@ -112,6 +112,15 @@ pub trait ReceiverHandler {
             .expect("valid syntax")
     }
 
+    /// Called after receiving a [`Verb::Bdat`] chunk which is not the last one.
+    #[inline]
+    async fn on_bdat(&mut self, chunk_size: usize) -> Reply {
+        #[allow(clippy::expect_used)]
+        format!("250 {chunk_size} octets received\r\n")
+            .parse()
+            .expect("valid syntax")
+    }
+
     /// Called after receiving a [`Verb::Quit`] command.
     #[inline]
     async fn on_quit(&mut self) -> Reply {
@@ -19,8 +19,12 @@ use tokio::io::AsyncWriteExt;
 use vsmtp_common::Reply;
 
 /// Sink for sending reply to the client
+///
+/// Replies can be buffered with [`Writer::buffer_reply`] while the client is
+/// pipelining commands (RFC 2920), they are flushed in order before any other write.
 pub struct Writer<W: tokio::io::AsyncWrite + Unpin + Send> {
     inner: W,
+    pending: Vec<u8>,
 }
 
 impl<W: tokio::io::AsyncWrite + Unpin + Send> AsMut<W> for Writer<W> {
@@ -35,10 +39,15 @@ impl<W: tokio::io::AsyncWrite + Unpin + Send> Writer<W> {
     #[inline]
     #[must_use]
     pub const fn new(inner: W) -> Self {
-        Self { inner }
+        Self {
+            inner,
+            pending: Vec::new(),
+        }
     }
 
     /// Consume the instance and return the underlying writer.
+    ///
+    /// The replies buffered but not flushed yet are discarded.
     #[inline]
     #[must_use]
     #[allow(clippy::missing_const_for_fn)]
@@ -64,22 +73,38 @@ impl<W: tokio::io::AsyncWrite + Unpin + Send> Writer<W> {
     /// * [`std::io::Error`] produced by the underlying writer
     #[inline]
     pub async fn write_all_bytes(&mut self, buffer: &[u8]) -> std::io::Result<()> {
-        self.inner.write_all(buffer).await
+        if self.pending.is_empty() {
+            return self.inner.write_all(buffer).await;
+        }
+
+        let mut pending = std::mem::take(&mut self.pending);
+        pending.extend_from_slice(buffer);
+        self.inner.write_all(&pending).await
     }
 
+    /// Send the buffered replies to the client.
+    ///
     /// # Errors
     ///
     /// * [`std::io::Error`] produced by the underlying writer
     #[inline]
-    pub async fn send_reply<T: ReceiverHandler + Send>(
-        &mut self,
+    pub async fn flush(&mut self) -> std::io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        let pending = std::mem::take(&mut self.pending);
+        self.inner.write_all(&pending).await
+    }
+
+    async fn handle_errors<T: ReceiverHandler + Send>(
         ctx: &mut ReceiverContext,
         error_counter: &mut ErrorCounter,
         handler: &mut T,
         reply: Reply,
-    ) -> std::io::Result<()> {
+    ) -> Reply {
         if !reply.code().is_error() {
-            return self.write_all(reply.as_ref()).await;
+            return reply;
         }
         error_counter.error_count += 1;
 
@@ -87,15 +112,43 @@ impl<W: tokio::io::AsyncWrite + Unpin + Send> Writer<W> {
         let soft_error = error_counter.threshold_soft_error;
 
         if hard_error != -1 && error_counter.error_count >= hard_error {
-            let reply = handler.on_hard_error(ctx, reply).await;
-            return self.write_all(reply.as_ref()).await;
+            return handler.on_hard_error(ctx, reply).await;
         }
 
         if soft_error != -1 && error_counter.error_count >= soft_error {
-            let reply = handler.on_soft_error(ctx, reply).await;
-            return self.write_all(reply.as_ref()).await;
+            return handler.on_soft_error(ctx, reply).await;
         }
 
+        reply
+    }
+
+    /// # Errors
+    ///
+    /// * [`std::io::Error`] produced by the underlying writer
+    #[inline]
+    pub async fn send_reply<T: ReceiverHandler + Send>(
+        &mut self,
+        ctx: &mut ReceiverContext,
+        error_counter: &mut ErrorCounter,
+        handler: &mut T,
+        reply: Reply,
+    ) -> std::io::Result<()> {
+        let reply = Self::handle_errors(ctx, error_counter, handler, reply).await;
         self.write_all(reply.as_ref()).await
     }
+
+    /// Same as [`Writer::send_reply`], but the reply is kept in the buffer
+    /// until the next write or [`Writer::flush`].
+    #[inline]
+    pub async fn buffer_reply<T: ReceiverHandler + Send>(
+        &mut self,
+        ctx: &mut ReceiverContext,
+        error_counter: &mut ErrorCounter,
+        handler: &mut T,
+        reply: Reply,
+    ) {
+        let reply = Self::handle_errors(ctx, error_counter, handler, reply).await;
+        tracing::trace!(">> (buffered) {:?}", reply.as_ref());
+        self.pending.extend_from_slice(reply.as_ref().as_bytes());
+    }
 }
@@ -310,9 +310,12 @@ fn execute_testsaslauthd(authid: &str, authpass: &str) -> EngineResult<Status> {
                 .map(std::borrow::ToOwned::to_owned)
                 .collect::<Vec<_>>(),
         ),
+        stdin: None,
+        output_limit: 0,
+        json: false,
     });
 
-    let result = vsl_generic_ok!(testsaslauthd.run());
+    let result = vsl_generic_ok!(block_on!(testsaslauthd.run(None))).status;
 
     if let Some(signal) = std::os::unix::prelude::ExitStatusExt::signal(&result) {
         tracing::warn!(
@@ -22,7 +22,8 @@ use crate::{
     get_global,
 };
 use rhai::plugin::{
-    Dynamic, FnAccess, FnNamespace, Module, NativeCallContext, PluginFunction, RhaiResult, TypeId,
+    mem, Dynamic, FnAccess, FnNamespace, Module, NativeCallContext, PluginFunction, RhaiResult,
+    TypeId,
 };
 use vsmtp_plugin_vsl::objects::Object;
 
@@ -549,4 +550,131 @@ mod mail_context {
             .map_err(Into::<crate::error::RuntimeError>::into)?
             .to_string())
     }
+
+    /// Get the size of the message declared by the client with the `SIZE`
+    /// parameter of the `MAIL FROM` command (RFC 1870).
+    ///
+    /// # Effective smtp stage
+    ///
+    /// `mail` and onwards.
+    ///
+    /// # Return
+    ///
+    /// * `int` - the declared size in bytes.
+    /// * `()` - the client did not declare a size.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # vsmtp_test::vsl::run(
+    /// # |builder| Ok(builder.add_root_filter_rules(r#"
+    /// #{
+    ///     mail: [
+    ///        rule "big messages" || {
+    ///          let size = ctx::message_size();
+    ///          if size != () && size > 1000000 {
+    ///            state::deny("552 5.3.4 Message too big for this policy")
+    ///          } else {
+    ///            state::next()
+    ///          }
+    ///        },
+    ///     ]
+    /// }
+    /// # "#)?.build()));
+    /// ```
+    ///
+    /// # rhai-autodocs:index:17
+    #[rhai_fn(name = "message_size", return_raw)]
+    pub fn message_size(ncc: NativeCallContext) -> EngineResult<rhai::Dynamic> {
+        Ok(vsl_guard_ok!(get_global!(ncc, ctx).read())
+            .message_size()
+            .map_err(Into::<crate::error::RuntimeError>::into)?
+            .map_or(rhai::Dynamic::UNIT, |size| {
+                rhai::INT::try_from(size).unwrap_or(rhai::INT::MAX).into()
+            }))
+    }
+
+    /// Get the priority of the message, from -9 (lowest) to 9 (highest).
+    ///
+    /// The priority is set by the `MT-PRIORITY` parameter of the `MAIL FROM`
+    /// command (RFC 6710) of an authenticated client, or by the rules,
+    /// and is 0 otherwise.
+    ///
+    /// # Effective smtp stage
+    ///
+    /// `mail` and onwards.
+    ///
+    /// # Return
+    ///
+    /// * `int` - the priority of the message.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # vsmtp_test::vsl::run(
+    /// # |builder| Ok(builder.add_root_filter_rules(r#"
+    /// #{
+    ///     rcpt: [
+    ///        rule "urgent messages only" || {
+    ///          if ctx::priority() > 0 { state::next() } else { state::deny() }
+    ///        },
+    ///     ]
+    /// }
+    /// # "#)?.build()));
+    /// ```
+    ///
+    /// # rhai-autodocs:index:18
+    #[rhai_fn(name = "priority", return_raw)]
+    pub fn priority(ncc: NativeCallContext) -> EngineResult<rhai::INT> {
+        Ok(vsl_guard_ok!(get_global!(ncc, ctx).read())
+            .priority()
+            .map_err(Into::<crate::error::RuntimeError>::into)?
+            .get()
+            .into())
+    }
+
+    /// Set the priority of the message, from -9 (lowest) to 9 (highest).
+    ///
+    /// The messages with a higher priority are processed and delivered first.
+    ///
+    /// # Args
+    ///
+    /// * `priority` - the new priority of the message.
+    ///
+    /// # Effective smtp stage
+    ///
+    /// `mail` and onwards.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # vsmtp_test::vsl::run(
+    /// # |builder| Ok(builder.add_root_filter_rules(r#"
+    /// #{
+    ///     mail: [
+    ///        action "password resets first" || {
+    ///          if ctx::mail_from() == "no-reply@example.com" { ctx::set_priority(5) }
+    ///        },
+    ///        action "newsletters last" || {
+    ///          if ctx::mail_from() == "news@example.com" { ctx::set_priority(-5) }
+    ///        },
+    ///     ]
+    /// }
+    /// # "#)?.build()));
+    /// ```
+    ///
+    /// # rhai-autodocs:index:19
+    #[rhai_fn(name = "set_priority", return_raw)]
+    pub fn set_priority(ncc: NativeCallContext, priority: rhai::INT) -> EngineResult<()> {
+        let priority = vsl_conversion_ok!(
+            "priority",
+            i8::try_from(priority)
+                .map_err(anyhow::Error::from)
+                .and_then(vsmtp_common::Priority::try_from)
+        );
+        vsl_guard_ok!(get_global!(ncc, ctx).write())
+            .set_priority(priority)
+            .map_err(Into::<crate::error::RuntimeError>::into)?;
+        Ok(())
+    }
 }
@@ -0,0 +1,134 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+*/
+
+use crate::api::{EngineResult, Server};
+use rhai::plugin::{
+    mem, Dynamic, FnAccess, FnNamespace, ImmutableString, Module, NativeCallContext,
+    PluginFunction, RhaiResult, TypeId,
+};
+use vqueue::control::{Client, Release, ReleaseAction, Request, Response};
+
+pub use quarantine::*;
+
+fn default_operator() -> String {
+    "vsl".to_owned()
+}
+
+#[derive(serde::Deserialize)]
+struct ReleaseParameters {
+    #[serde(default)]
+    quarantine: Option<String>,
+    #[serde(default = "default_operator")]
+    operator: String,
+    reason: String,
+    #[serde(flatten)]
+    action: ReleaseAction,
+}
+
+/// Administration of the quarantined messages.
+#[rhai::plugin::export_module]
+mod quarantine {
+    use crate::get_global;
+
+    /// Release a message from its quarantine, the release is recorded in the
+    /// context of the message with the operator and the reason.
+    ///
+    /// # Args
+    ///
+    /// * `msg_id` - the id of the quarantined message.
+    /// * a map composed of the following parameters:
+    ///     * `reason` - why the message is released.
+    ///     * `operator` - who releases the message. (default: "vsl")
+    ///     * `quarantine` - the name of the quarantine, all of them are searched if not set.
+    ///     * `action` - what is done with the message, one of:
+    ///         * "resume" - send the message to a process again, with the parameters:
+    ///             * `stage` - "working" to run the post-queue rules, or "delivery". (default: "delivery")
+    ///             * `rerun` - run the rules of the stages, instead of skipping them. (default: false)
+    ///             * `recipients` - the recipients the message is released to,
+    ///                              the others stay in quarantine. (default: all of them)
+    ///         * "reject" - fail all the recipients and send a bounce to the sender.
+    ///         * "forward" - send a copy of the message to the address `to`,
+    ///                       the message stays in quarantine.
+    ///
+    /// # Effective smtp stage
+    ///
+    /// All of them.
+    ///
+    /// # Errors
+    ///
+    /// * The parameters are not valid.
+    /// * The server cannot be reached on its control socket.
+    /// * The message is not in quarantine, or a recipient is not one of the message.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// #{
+    ///     preq: [
+    ///         action "release the false positive" || {
+    ///             quarantine::release("0b1c3f4a-3d8e-4b2b-9b1a-8d9a7e5c2f10", #{
+    ///                 reason: "reported by the recipient",
+    ///                 quarantine: "spam",
+    ///                 action: "resume",
+    ///                 recipients: ["john.doe@example.com"],
+    ///             });
+    ///         }
+    ///     ],
+    /// }
+    /// ```
+    ///
+    /// # rhai-autodocs:index:1
+    #[rhai_fn(return_raw)]
+    pub fn release(
+        ncc: NativeCallContext,
+        msg_id: &str,
+        parameters: rhai::Map,
+    ) -> EngineResult<()> {
+        let parameters = rhai::serde::from_dynamic::<super::ReleaseParameters>(&parameters.into())?;
+
+        super::Impl::release(&get_global!(ncc, srv), msg_id, parameters)
+    }
+}
+
+struct Impl;
+
+impl Impl {
+    fn release(srv: &Server, msg_id: &str, parameters: ReleaseParameters) -> EngineResult<()> {
+        let msg = uuid::Uuid::parse_str(msg_id)
+            .map_err::<Box<rhai::EvalAltResult>, _>(|err| err.to_string().into())?;
+
+        let request = Request::Release {
+            msg,
+            quarantine: parameters.quarantine,
+            release: Release::new(parameters.operator, parameters.reason, parameters.action),
+        };
+
+        let response = block_on!(async {
+            Client::connect(&vqueue::control::socket_path(&srv.config))
+                .await?
+                .send(&request)
+                .await
+        })
+        .map_err::<Box<rhai::EvalAltResult>, _>(|err| err.to_string().into())?;
+
+        match response {
+            Response::Done => Ok(()),
+            Response::Error(error) => Err(error.into()),
+            otherwise => Err(format!("unexpected response: {otherwise:?}").into()),
+        }
+    }
+}
@@ -0,0 +1,116 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+*/
+
+/// Address of a daemon used by a service (clamd, milter, rspamd, spamd),
+/// reached over TCP or a Unix socket.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub enum Address {
+    /// A `host:port` TCP address, written with an optional `inet:` prefix.
+    Tcp(String),
+    /// The path of a Unix socket, written with a `unix:` prefix or as an absolute path.
+    Unix(std::path::PathBuf),
+}
+
+impl TryFrom<String> for Address {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        if let Some(path) = value.strip_prefix("unix:") {
+            Ok(Self::Unix(path.into()))
+        } else if value.starts_with('/') {
+            Ok(Self::Unix(value.into()))
+        } else {
+            let address = value.strip_prefix("inet:").unwrap_or(&value);
+            anyhow::ensure!(
+                address.rsplit_once(':').map_or(false, |(host, port)| {
+                    !host.is_empty() && port.parse::<u16>().is_ok()
+                }),
+                "invalid address '{value}', expected '[inet:]host:port', 'unix:path' or an absolute path"
+            );
+            Ok(Self::Tcp(address.to_string()))
+        }
+    }
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(address) => write!(f, "{address}"),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// A connection to a daemon.
+pub trait Stream: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send {}
+
+impl<T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send> Stream for T {}
+
+impl Address {
+    /// Open a connection to the daemon.
+    pub async fn connect(&self) -> std::io::Result<Box<dyn Stream>> {
+        Ok(match self {
+            Self::Tcp(address) => Box::new(tokio::net::TcpStream::connect(address).await?),
+            Self::Unix(path) => Box::new(tokio::net::UnixStream::connect(path).await?),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Address;
+
+    #[test]
+    fn parse() {
+        for (input, expected) in [
+            ("localhost:3310", Address::Tcp("localhost:3310".to_string())),
+            (
+                "inet:localhost:8891",
+                Address::Tcp("localhost:8891".to_string()),
+            ),
+            ("[::1]:783", Address::Tcp("[::1]:783".to_string())),
+            ("unix:clamd.ctl", Address::Unix("clamd.ctl".into())),
+            (
+                "/run/opendkim/opendkim.sock",
+                Address::Unix("/run/opendkim/opendkim.sock".into()),
+            ),
+        ] {
+            assert_eq!(Address::try_from(input.to_string()).unwrap(), expected);
+        }
+
+        for input in ["localhost", ":3310", "inet:localhost", "localhost:port"] {
+            Address::try_from(input.to_string()).unwrap_err();
+        }
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(
+            Address::try_from("inet:localhost:8891".to_string())
+                .unwrap()
+                .to_string(),
+            "localhost:8891"
+        );
+        assert_eq!(
+            Address::try_from("/run/clamav/clamd.ctl".to_string())
+                .unwrap()
+                .to_string(),
+            "unix:/run/clamav/clamd.ctl"
+        );
+    }
+}
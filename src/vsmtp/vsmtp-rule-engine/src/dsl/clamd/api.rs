@@ -0,0 +1,180 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+*/
+
+use rhai::plugin::{
+    mem, Dynamic, FnAccess, FnNamespace, NativeCallContext, PluginFunction, RhaiResult, TypeId,
+};
+use rhai::Module;
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ClamdParameters {
+    /// Address of the daemon, `host:port` or the path of a Unix socket.
+    address: crate::dsl::address::Address,
+    /// Timeout of a scan.
+    #[serde(default = "default_timeout", with = "humantime_serde")]
+    timeout: std::time::Duration,
+    /// Size of the chunks the message is streamed in.
+    #[serde(default = "default_chunk_size")]
+    chunk_size: usize,
+}
+
+const fn default_timeout() -> std::time::Duration {
+    std::time::Duration::from_secs(30)
+}
+
+const fn default_chunk_size() -> usize {
+    64 * 1024
+}
+
+/// This module exposes the `clamd` service, scanning the messages with the `ClamAV` daemon.
+#[rhai::plugin::export_module]
+pub mod clamd {
+    use crate::api::EngineResult;
+    use crate::{get_global, vsl_guard_ok};
+
+    type Clamd = rhai::Shared<crate::dsl::clamd::service::Clamd>;
+
+    /// Create a service scanning the messages with a clamd daemon.
+    ///
+    /// The message is streamed to the daemon with the `INSTREAM` command,
+    /// no file is shared between vSMTP and the daemon.
+    ///
+    /// # Args
+    ///
+    /// * `parameters` - a map of the following parameters:
+    ///     * `address` - the address of the daemon, `host:port` for the `TCPSocket` of clamd
+    ///       or the path of its `LocalSocket`.
+    ///     * `timeout` - a duration after which the scan fails. (optional, default: 30s)
+    ///     * `chunk_size` - the size in bytes of the chunks the message is streamed in,
+    ///       it must not exceed the `StreamMaxLength` of the daemon. (optional, default: 65536)
+    ///
+    /// # Return
+    ///
+    /// A service used to scan the messages.
+    ///
+    /// # Error
+    ///
+    /// * The service failed to parse the parameters.
+    ///
+    /// # Example
+    ///
+    /// ```text
+    /// // declared in /etc/vsmtp/services/clamd.vsl
+    /// export const clamd = clamd::connect(#{
+    ///     address: "127.0.0.1:3310",
+    ///     timeout: "10s",
+    /// });
+    /// ```
+    ///
+    /// # rhai-autodocs:index:1
+    #[rhai_fn(return_raw)]
+    pub fn connect(parameters: rhai::Map) -> EngineResult<Clamd> {
+        let parameters = rhai::serde::from_dynamic::<super::ClamdParameters>(&parameters.into())?;
+
+        Ok(rhai::Shared::new(crate::dsl::clamd::service::Clamd {
+            address: parameters.address,
+            timeout: parameters.timeout,
+            chunk_size: parameters.chunk_size,
+        }))
+    }
+
+    /// Scan the message with the daemon.
+    ///
+    /// # Return
+    ///
+    /// A map of the following fields:
+    /// * `clean` - `true` if no virus has been found.
+    /// * `infected` - `true` if a virus has been found.
+    /// * `signature` - the name of the virus found, or `()`.
+    /// * `error` - the reason why the message could not be scanned (daemon unreachable,
+    ///   timeout, message too big ...), or `()`. Both `clean` and `infected` are then `false`.
+    ///
+    /// # Effective smtp stage
+    ///
+    /// `preq` and onwards.
+    ///
+    /// # Example
+    ///
+    /// ```text
+    /// import "services/clamd" as svc;
+    ///
+    /// #{
+    ///     postq: [
+    ///         rule "antivirus" || {
+    ///             let result = svc::clamd.scan();
+    ///
+    ///             if result.infected {
+    ///                 log("warn", `virus found: ${result.signature}`);
+    ///                 state::quarantine("virus")
+    ///             } else if result.error != () {
+    ///                 log("error", `message not scanned: ${result.error}`);
+    ///                 state::quarantine("not-scanned")
+    ///             } else {
+    ///                 state::next()
+    ///             }
+    ///         },
+    ///     ]
+    /// }
+    /// ```
+    ///
+    /// # rhai-autodocs:index:2
+    #[rhai_fn(global, name = "scan", pure)]
+    pub fn scan(ncc: NativeCallContext, clamd: &mut Clamd) -> rhai::Map {
+        let message = vsl_guard_ok!(get_global!(ncc, msg).read())
+            .inner()
+            .to_string();
+
+        let clamd = &**clamd;
+        let result = block_on!(clamd.scan(message.as_bytes()));
+        match &result {
+            Ok(verdict) => tracing::debug!(service = %clamd, ?verdict, "Message scanned."),
+            Err(error) => tracing::warn!(service = %clamd, %error, "Message not scanned."),
+        }
+
+        crate::dsl::clamd::service::Verdict::to_map(result)
+    }
+
+    /// Check that the daemon is alive.
+    ///
+    /// # Return
+    ///
+    /// `true` if the daemon replied to a `PING`.
+    ///
+    /// # Example
+    ///
+    /// ```text
+    /// import "services/clamd" as svc;
+    ///
+    /// #{
+    ///     connect: [
+    ///         action "check the antivirus" || {
+    ///             if !svc::clamd.ping() { log("error", "clamd is not available") }
+    ///         },
+    ///     ]
+    /// }
+    /// ```
+    ///
+    /// # rhai-autodocs:index:3
+    #[rhai_fn(global, name = "ping", pure)]
+    pub fn ping(clamd: &mut Clamd) -> bool {
+        let clamd = &**clamd;
+        block_on!(clamd.ping())
+            .map_err(|error| tracing::warn!(service = %clamd, %error, "Ping failed."))
+            .is_ok()
+    }
+}
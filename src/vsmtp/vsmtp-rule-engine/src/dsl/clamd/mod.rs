@@ -0,0 +1,221 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+*/
+
+pub mod api;
+pub mod service;
+
+/// Create a new clamd module.
+#[must_use]
+pub fn new_module() -> rhai::Module {
+    let mut module = rhai::exported_module!(api::clamd);
+
+    module.set_id("clamd");
+
+    module
+}
+
+#[cfg(test)]
+mod tests {
+    use super::service::{Clamd, Verdict};
+    use crate::dsl::address::Address;
+    use crate::tests::service::{
+        compile, run_connect, tcp_daemon, tcp_daemon_thread, unix_daemon, unreachable_address,
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const EICAR: &str = "X5O!P%@AP[4\\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*";
+
+    /// Answer one request as clamd would, detecting the EICAR test signature.
+    async fn fake_clamd<S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin>(
+        mut stream: S,
+        stream_max_length: usize,
+    ) {
+        let mut command = vec![];
+        while command.last() != Some(&b'\0') {
+            command.push(stream.read_u8().await.unwrap());
+        }
+
+        let reply = match command.as_slice() {
+            b"zPING\0" => "PONG".to_string(),
+            b"zINSTREAM\0" => {
+                let mut data = vec![];
+                loop {
+                    let length = stream.read_u32().await.unwrap() as usize;
+                    if length == 0 {
+                        break;
+                    }
+                    let mut chunk = vec![0; length];
+                    stream.read_exact(&mut chunk).await.unwrap();
+                    data.extend(chunk);
+                }
+                if data.len() > stream_max_length {
+                    "INSTREAM size limit exceeded. ERROR".to_string()
+                } else if String::from_utf8_lossy(&data).contains(EICAR) {
+                    "stream: Win.Test.EICAR_HDB-1 FOUND".to_string()
+                } else {
+                    "stream: OK".to_string()
+                }
+            }
+            _ => "UNKNOWN COMMAND".to_string(),
+        };
+        stream.write_all(reply.as_bytes()).await.unwrap();
+        stream.write_all(b"\0").await.unwrap();
+    }
+
+    fn clamd(address: Address, chunk_size: usize) -> Clamd {
+        Clamd {
+            address,
+            timeout: std::time::Duration::from_secs(5),
+            chunk_size,
+        }
+    }
+
+    #[tokio::test]
+    async fn scan_tcp() {
+        let address = tcp_daemon(|stream| fake_clamd(stream, 1024)).await;
+        let clamd = clamd(Address::Tcp(address.to_string()), 16);
+
+        clamd.ping().await.unwrap();
+        assert_eq!(
+            clamd
+                .scan(b"Subject: hello\r\n\r\nworld\r\n")
+                .await
+                .unwrap(),
+            Verdict::Clean
+        );
+        assert_eq!(
+            clamd
+                .scan(format!("Subject: hello\r\n\r\n{EICAR}\r\n").as_bytes())
+                .await
+                .unwrap(),
+            Verdict::Infected {
+                signature: "Win.Test.EICAR_HDB-1".to_string()
+            }
+        );
+        assert!(clamd
+            .scan(&[b'a'; 2048])
+            .await
+            .unwrap_err()
+            .to_string()
+            .contains("clamd"));
+    }
+
+    #[tokio::test]
+    async fn scan_unix() {
+        let (_dir, path) = unix_daemon(|stream| fake_clamd(stream, 1024));
+
+        let clamd = clamd(Address::Unix(path), 4096);
+        assert_eq!(
+            clamd.scan(EICAR.as_bytes()).await.unwrap(),
+            Verdict::Infected {
+                signature: "Win.Test.EICAR_HDB-1".to_string()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn error_while_streaming() {
+        // NOTE: the writes of the client fail once the daemon has closed the socket.
+        let (_dir, path) = unix_daemon(|mut stream| async move {
+            let mut command = [0; 10];
+            stream.read_exact(&mut command).await.unwrap();
+            stream
+                .write_all(b"INSTREAM size limit exceeded. ERROR\0")
+                .await
+                .unwrap();
+        });
+
+        let error = clamd(Address::Unix(path), 1024)
+            .scan(&vec![b'a'; 4 * 1024 * 1024])
+            .await
+            .unwrap_err();
+        assert_eq!(
+            format!("{error:#}"),
+            "clamd error: INSTREAM size limit exceeded."
+        );
+    }
+
+    #[tokio::test]
+    async fn reply_too_large() {
+        let address = tcp_daemon(|mut stream| async move {
+            stream.write_all(&[b'a'; 8192]).await.unwrap();
+            std::future::pending::<()>().await;
+        })
+        .await;
+
+        let error = clamd(Address::Tcp(address.to_string()), 16)
+            .ping()
+            .await
+            .unwrap_err();
+        assert!(
+            format!("{error:#}").contains("reply larger than"),
+            "{error:#}"
+        );
+    }
+
+    #[tokio::test]
+    async fn unreachable() {
+        let clamd = clamd(Address::Tcp(unreachable_address().to_string()), 16);
+
+        let map = Verdict::to_map(clamd.scan(b"").await);
+        assert!(!map["clean"].as_bool().unwrap());
+        assert!(!map["infected"].as_bool().unwrap());
+        assert!(map["error"].is_string());
+    }
+
+    #[test]
+    fn scan_in_rules() {
+        let address = tcp_daemon_thread(|stream| fake_clamd(stream, 1024));
+
+        // NOTE: the message of the test is available from the `connect` stage.
+        let (_, status) = run_connect(format!(
+            r#"
+#{{
+    connect: [
+        rule "antivirus" || {{
+            let result = clamd::connect(#{{ address: "{address}" }}).scan();
+            if result.clean && !result.infected && result.signature == () && result.error == () {{
+                state::accept()
+            }} else {{
+                state::deny()
+            }}
+        }},
+    ]
+}}
+"#
+        ));
+        assert!(
+            matches!(status, vsmtp_common::status::Status::Accept(_)),
+            "{status:?}"
+        );
+    }
+
+    #[test]
+    fn parse() {
+        compile(
+            r#"
+const clamd = clamd::connect(#{
+    address: "127.0.0.1:3310",
+    timeout: "10s",
+    chunk_size: 4096,
+});
+
+#{}
+"#,
+        );
+    }
+}
@@ -0,0 +1,196 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+*/
+
+use crate::dsl::address::{Address, Stream};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Maximum size of a reply of the daemon.
+const REPLY_SIZE_MAX: usize = 4096;
+
+/// Result of the scan of a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// No virus found.
+    Clean,
+    /// A virus has been found.
+    Infected {
+        /// Name of the signature matched.
+        signature: String,
+    },
+}
+
+/// A service scanning the messages with a clamd daemon, using the `INSTREAM` command.
+///
+/// See <https://docs.clamav.net/manual/Usage/Scanning.html#clamd>
+#[derive(Debug, Clone)]
+pub struct Clamd {
+    /// Address of the daemon.
+    pub address: Address,
+    /// Timeout of the whole scan, from the connection to the reply.
+    pub timeout: std::time::Duration,
+    /// Size of the chunks the message is streamed in,
+    /// must not exceed the `StreamMaxLength` of the daemon.
+    pub chunk_size: usize,
+}
+
+impl std::fmt::Display for Clamd {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "clamd:{}", self.address)
+    }
+}
+
+impl Clamd {
+    /// Stream the `message` to the daemon and return its verdict.
+    ///
+    /// # Errors
+    ///
+    /// * the daemon cannot be reached, or did not reply before the timeout.
+    /// * the daemon replied with an error, for instance if the message exceeds its size limit.
+    pub async fn scan(&self, message: &[u8]) -> anyhow::Result<Verdict> {
+        let reply = self
+            .exchange(async {
+                let mut stream = self.address.connect().await?;
+                let written = write_instream(&mut stream, message, self.chunk_size).await;
+
+                // NOTE: the daemon replies with an error and closes the connection as soon as
+                //       the stream exceeds its limit, the reply explains the failed writes.
+                match (written, read_reply(&mut stream).await) {
+                    (Ok(()), reply) => reply,
+                    (Err(_), Ok(reply)) if !reply.is_empty() => Ok(reply),
+                    (Err(error), _) => Err(error),
+                }
+            })
+            .await?;
+
+        parse_scan_reply(&reply)
+    }
+
+    /// Check that the daemon is alive.
+    ///
+    /// # Errors
+    ///
+    /// * the daemon cannot be reached, or did not reply `PONG` before the timeout.
+    pub async fn ping(&self) -> anyhow::Result<()> {
+        let reply = self
+            .exchange(async {
+                let mut stream = self.address.connect().await?;
+                stream.write_all(b"zPING\0").await?;
+                read_reply(&mut stream).await
+            })
+            .await?;
+
+        anyhow::ensure!(reply == "PONG", "unexpected reply to PING: '{reply}'");
+        Ok(())
+    }
+
+    /// Run the `exchange` with the daemon within the timeout.
+    async fn exchange(
+        &self,
+        exchange: impl std::future::Future<Output = anyhow::Result<String>> + Send,
+    ) -> anyhow::Result<String> {
+        tokio::time::timeout(self.timeout, exchange)
+            .await
+            .map_err(|_elapsed| anyhow::anyhow!("no reply within {:?}", self.timeout))
+            .and_then(|reply| reply)
+            .map_err(|error| error.context(format!("clamd at '{}'", self.address)))
+    }
+}
+
+/// Send the `INSTREAM` command, followed by the `message` in chunks.
+async fn write_instream(
+    stream: &mut Box<dyn Stream>,
+    message: &[u8],
+    chunk_size: usize,
+) -> anyhow::Result<()> {
+    stream.write_all(b"zINSTREAM\0").await?;
+    for chunk in message.chunks(chunk_size.max(1)) {
+        let length = u32::try_from(chunk.len())?;
+        stream.write_all(&length.to_be_bytes()).await?;
+        stream.write_all(chunk).await?;
+    }
+    stream.write_all(&0_u32.to_be_bytes()).await?;
+    Ok(())
+}
+
+/// Read a reply terminated by a null character (or by the end of the stream).
+async fn read_reply(stream: &mut Box<dyn Stream>) -> anyhow::Result<String> {
+    let mut reply = vec![];
+    let mut buffer = [0; 512];
+    loop {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        reply.extend_from_slice(&buffer[..read]);
+        if reply.contains(&b'\0') {
+            break;
+        }
+        anyhow::ensure!(
+            reply.len() <= REPLY_SIZE_MAX,
+            "reply larger than {REPLY_SIZE_MAX} bytes"
+        );
+    }
+    let reply = reply.split(|c| *c == b'\0').next().unwrap_or_default();
+
+    Ok(String::from_utf8_lossy(reply).trim_end().to_string())
+}
+
+/// Parse the reply to `INSTREAM`: `stream: OK`, `stream: <signature> FOUND`
+/// or `<reason> ERROR`.
+fn parse_scan_reply(reply: &str) -> anyhow::Result<Verdict> {
+    let result = reply.strip_prefix("stream: ").unwrap_or(reply);
+
+    if result == "OK" {
+        Ok(Verdict::Clean)
+    } else if let Some(signature) = result.strip_suffix(" FOUND") {
+        Ok(Verdict::Infected {
+            signature: signature.to_string(),
+        })
+    } else if let Some(reason) = result.strip_suffix(" ERROR") {
+        anyhow::bail!("clamd error: {reason}")
+    } else {
+        anyhow::bail!("unexpected clamd reply: '{reply}'")
+    }
+}
+
+impl Verdict {
+    /// Map the result of a scan to a rhai map.
+    #[must_use]
+    pub fn to_map(result: anyhow::Result<Self>) -> rhai::Map {
+        let (clean, signature, error) = match result {
+            Ok(Self::Clean) => (true, None, None),
+            Ok(Self::Infected { signature }) => (false, Some(signature), None),
+            Err(error) => (false, None, Some(format!("{error:#}"))),
+        };
+
+        rhai::Map::from_iter([
+            ("clean".into(), rhai::Dynamic::from_bool(clean)),
+            (
+                "infected".into(),
+                rhai::Dynamic::from_bool(signature.is_some()),
+            ),
+            (
+                "signature".into(),
+                signature.map_or(rhai::Dynamic::UNIT, rhai::Dynamic::from),
+            ),
+            (
+                "error".into(),
+                error.map_or(rhai::Dynamic::UNIT, rhai::Dynamic::from),
+            ),
+        ])
+    }
+}
@@ -34,12 +34,49 @@ pub struct CmdParameters {
     pub user: Option<String>,
     /// Optional: a group to run the subprocess under
     pub group: Option<String>,
+    /// Optional: data written on the standard input of the subprocess
+    pub stdin: Option<crate::dsl::cmd::service::Stdin>,
+    /// Maximum number of bytes kept from the standard output and from the standard error
+    #[serde(default = "default_output_limit")]
+    pub output_limit: usize,
+    /// Parse the standard output as JSON
+    #[serde(default)]
+    pub json: bool,
 }
 
 const fn default_timeout() -> std::time::Duration {
     std::time::Duration::from_secs(30)
 }
 
+const fn default_output_limit() -> usize {
+    64 * 1024
+}
+
+/// Build the data written on the standard input of the subprocess.
+fn stdin(
+    ncc: &NativeCallContext,
+    cmd: &crate::dsl::cmd::service::Cmd,
+) -> crate::api::EngineResult<Option<Vec<u8>>> {
+    use crate::{get_global, vsl_guard_ok};
+
+    Ok(match cmd.stdin {
+        None => None,
+        Some(crate::dsl::cmd::service::Stdin::Message) => Some(
+            vsl_guard_ok!(get_global!(ncc, msg).read())
+                .inner()
+                .to_string()
+                .into_bytes(),
+        ),
+        Some(crate::dsl::cmd::service::Stdin::Context) => Some(
+            serde_json::to_vec(&*vsl_guard_ok!(get_global!(ncc, ctx).read())).map_err::<Box<
+                rhai::EvalAltResult,
+            >, _>(
+                |e| e.to_string().into(),
+            )?,
+        ),
+    })
+}
+
 /// This module exposes the `cmd` function, allowing vSMTP to execute system commands.
 #[rhai::plugin::export_module]
 pub mod cmd {
@@ -58,6 +95,11 @@ pub mod cmd {
     ///     * `args` - an array of parameters passed to the executed program. (optional)
     ///     * `user` - a user to run the command with. (optional)
     ///     * `group` - a group to run the command with. (optional)
+    ///     * `stdin` - the data written on the standard input of the command, "message" for
+    ///       the message of the transaction or "context" for its context in JSON. (optional)
+    ///     * `output_limit` - the maximum number of bytes kept from the standard output and
+    ///       from the standard error of the command. (optional, default: 65536)
+    ///     * `json` - parse the standard output of the command as JSON. (optional, default: false)
     ///
     /// # Return
     ///
@@ -75,6 +117,14 @@ pub mod cmd {
     ///     args: ["-e", "'Hello World. \c This is vSMTP.'"],
     ///     timeout: "10s",
     /// });
+    ///
+    /// // a classifier reading the message on its standard input,
+    /// // and writing its verdict in JSON on its standard output.
+    /// export const classifier = cmd::build(#{
+    ///     command: "/usr/local/bin/classify",
+    ///     stdin: "message",
+    ///     json: true,
+    /// });
     /// ```
     ///
     /// # rhai-autodocs:index:1
@@ -88,19 +138,38 @@ pub mod cmd {
             group: parameters.group,
             command: parameters.command,
             args: parameters.args,
+            stdin: parameters.stdin,
+            output_limit: parameters.output_limit,
+            json: parameters.json,
         }))
     }
 
     /// Execute the given command.
     ///
+    /// The command is executed on the async runtime of vSMTP, and killed after the timeout
+    /// of the service.
+    ///
     /// # Return
     ///
-    /// The command output.
+    /// A map of the following fields:
+    /// * `has_code` - `true` if the command exited with a code.
+    /// * `code` - the exit code of the command, or `()` if it was killed by a signal.
+    /// * `has_signal` - `true` if the command was killed by a signal.
+    /// * `signal` - the signal that killed the command, or `()`.
+    /// * `stdout` - the standard output of the command, up to `output_limit` bytes.
+    /// * `stderr` - the standard error of the command, up to `output_limit` bytes.
+    /// * `truncated` - `true` if the standard output or the standard error exceeded `output_limit`.
+    /// * `json` - the standard output parsed as JSON if the `json` parameter is set,
+    ///   or `()` if it is not set or if the output is not valid JSON.
     ///
     /// # Error
     ///
     /// * The service failed to execute the command.
     ///
+    /// # Effective smtp stage
+    ///
+    /// All of them, `preq` and onwards if `stdin` is "message".
+    ///
     /// # Example
     ///
     /// ```text
@@ -117,19 +186,38 @@ pub mod cmd {
     /// // run the command with custom arguments (based one are replaced).
     /// // echo -n 'Hello World.'
     /// echo.run([ "-n", "'Hello World.'" ]);
+    ///
+    /// const classifier = cmd::build(#{
+    ///     command: "/usr/local/bin/classify",
+    ///     stdin: "message",
+    ///     json: true,
+    /// });
+    ///
+    /// // the classifier prints `{ "spam": true, "reason": "..." }`.
+    /// let result = classifier.run();
+    /// if result.json != () && result.json.spam {
+    ///     log("info", `spam: ${result.json.reason}`);
+    /// }
     /// ```
     ///
     /// # rhai-autodocs:index:2
     #[rhai_fn(global, name = "run", return_raw, pure)]
-    pub fn run(cmd: &mut Cmd) -> EngineResult<rhai::Map> {
-        cmd.run()
-            .map(crate::dsl::cmd::service::Cmd::status_to_map)
+    pub fn run(ncc: NativeCallContext, cmd: &mut Cmd) -> EngineResult<rhai::Map> {
+        let stdin = super::stdin(&ncc, cmd)?;
+        let cmd = &**cmd;
+
+        block_on!(cmd.run(stdin))
+            .map(|output| cmd.output_to_map(&output))
             .map_err::<Box<rhai::EvalAltResult>, _>(|e| e.to_string().into())
     }
 
     #[doc(hidden)]
     #[rhai_fn(global, name = "run", return_raw, pure)]
-    pub fn run_with_args(cmd: &mut Cmd, args: rhai::Array) -> EngineResult<rhai::Map> {
+    pub fn run_with_args(
+        ncc: NativeCallContext,
+        cmd: &mut Cmd,
+        args: rhai::Array,
+    ) -> EngineResult<rhai::Map> {
         let args = args
             .into_iter()
             .map(rhai::Dynamic::try_cast)
@@ -137,9 +225,11 @@ pub mod cmd {
             .ok_or_else::<Box<rhai::EvalAltResult>, _>(|| {
                 "all cmd arguments must be strings".into()
             })?;
+        let stdin = super::stdin(&ncc, cmd)?;
+        let cmd = &**cmd;
 
-        cmd.run_with_args(&args)
-            .map(crate::dsl::cmd::service::Cmd::status_to_map)
+        block_on!(cmd.run_with_args(&args, stdin))
+            .map(|output| cmd.output_to_map(&output))
             .map_err::<Box<rhai::EvalAltResult>, _>(|e| e.to_string().into())
     }
 }
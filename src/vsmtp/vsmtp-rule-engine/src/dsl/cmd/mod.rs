@@ -30,6 +30,7 @@ pub fn new_module() -> rhai::Module {
 
 #[cfg(test)]
 mod tests {
+    use super::service;
     use vsmtp_config::DnsResolvers;
     use vsmtp_test::config::local_test;
 
@@ -88,4 +89,115 @@ const my_command = cmd::build(#{
         )
         .unwrap();
     }
+
+    fn cmd(command: &str, args: &[&str], stdin: Option<service::Stdin>) -> service::Cmd {
+        service::Cmd {
+            timeout: std::time::Duration::from_secs(5),
+            user: None,
+            group: None,
+            command: command.to_string(),
+            args: Some(args.iter().map(ToString::to_string).collect()),
+            stdin,
+            output_limit: 16,
+            json: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn capture() {
+        let output = cmd("sh", &["-c", "printf out; printf err >&2; exit 3"], None)
+            .run(None)
+            .await
+            .unwrap();
+
+        assert_eq!(output.status.code(), Some(3));
+        assert_eq!(output.stdout, b"out");
+        assert_eq!(output.stderr, b"err");
+        assert!(!output.truncated);
+    }
+
+    #[tokio::test]
+    async fn output_limit() {
+        let cat = cmd("cat", &[], Some(service::Stdin::Message));
+        let output = cat.run(Some(vec![b'a'; 1024 * 1024])).await.unwrap();
+
+        assert!(output.status.success());
+        assert_eq!(output.stdout, [b'a'; 16]);
+        assert!(output.truncated);
+
+        let map = cat.output_to_map(&output);
+        assert_eq!(map.get("stdout").unwrap().to_string(), "a".repeat(16));
+        assert!(map.get("truncated").unwrap().as_bool().unwrap());
+    }
+
+    #[tokio::test]
+    async fn timeout() {
+        let mut sleep = cmd("sleep", &["10"], None);
+        sleep.timeout = std::time::Duration::from_millis(100);
+
+        let output = sleep.run(None).await.unwrap();
+        assert_eq!(
+            std::os::unix::prelude::ExitStatusExt::signal(&output.status),
+            Some(9)
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_json() {
+        let mut echo = cmd("echo", &["not json"], None);
+        echo.json = true;
+
+        let map = echo.output_to_map(&echo.run(None).await.unwrap());
+        assert!(map.get("json").unwrap().is_unit());
+        assert_eq!(map.get("stdout").unwrap().to_string(), "not json\n");
+    }
+
+    #[test]
+    fn stdin_and_json_in_rules() {
+        let results = vsmtp_test::vsl::run(|builder| {
+            Ok(builder
+                .add_root_filter_rules(
+                    r#"
+#{
+    connect: [
+        rule "classify" || {
+            let count = cmd::build(#{
+                command: "sh",
+                args: ["-c", "printf '{\"lines\": %d, \"tags\": [\"a\", \"b\"]}' $(wc -l)"],
+                stdin: "message",
+                json: true,
+            }).run();
+            let context = cmd::build(#{
+                command: "cat",
+                stdin: "context",
+                json: true,
+            }).run();
+
+            if count.code == 0
+                && count.json.lines > 0
+                && count.json.tags == ["a", "b"]
+                && context.json != ()
+                && context.stdout.contains("client_addr") {
+                state::accept()
+            } else {
+                state::deny()
+            }
+        },
+    ]
+}
+"#,
+                )?
+                .build())
+        });
+
+        // NOTE: the rule engine of `vsmtp_test` is another instance of this crate.
+        let (_, (_, _, status)) = results
+            .iter()
+            .find(|(stage, _)| stage.to_string() == "connect")
+            .unwrap();
+        assert!(
+            matches!(status, vsmtp_common::status::Status::Accept(_)),
+            "{status:?}"
+        );
+    }
 }
@@ -15,6 +15,18 @@
  *
 */
 
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Data written on the standard input of the subprocess.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stdin {
+    /// The message of the transaction.
+    Message,
+    /// The context of the transaction, serialized in JSON.
+    Context,
+}
+
 #[derive(Debug, Clone)]
 /// A service that executes commands.
 pub struct Cmd {
@@ -28,6 +40,25 @@ pub struct Cmd {
     pub command: String,
     /// Optional: parameters directly given to the executed program (argc, argv)
     pub args: Option<Vec<String>>,
+    /// Optional: data written on the standard input of the subprocess
+    pub stdin: Option<Stdin>,
+    /// Maximum number of bytes kept from the standard output and from the standard error
+    pub output_limit: usize,
+    /// Parse the standard output as JSON
+    pub json: bool,
+}
+
+/// The result of a command.
+#[derive(Debug)]
+pub struct Output {
+    /// The exit status of the subprocess.
+    pub status: std::process::ExitStatus,
+    /// The standard output, up to the output limit.
+    pub stdout: Vec<u8>,
+    /// The standard error, up to the output limit.
+    pub stderr: Vec<u8>,
+    /// `true` if the standard output or the standard error exceeded the output limit.
+    pub truncated: bool,
 }
 
 impl std::fmt::Display for Cmd {
@@ -36,6 +67,40 @@ impl std::fmt::Display for Cmd {
     }
 }
 
+/// Read a pipe to its end, keeping the first `limit` bytes.
+async fn read_capped(
+    pipe: Option<impl tokio::io::AsyncRead + Unpin>,
+    limit: usize,
+) -> std::io::Result<(Vec<u8>, bool)> {
+    let mut buffer = vec![];
+    let Some(pipe) = pipe else {
+        return Ok((buffer, false));
+    };
+
+    let mut head = pipe.take(limit as u64);
+    head.read_to_end(&mut buffer).await?;
+    // the rest is drained so the subprocess is not blocked on a full pipe.
+    let discarded = tokio::io::copy(&mut head.into_inner(), &mut tokio::io::sink()).await?;
+
+    Ok((buffer, discarded != 0))
+}
+
+/// Write the input on the standard input of the subprocess, then close it.
+async fn write_stdin(
+    pipe: Option<tokio::process::ChildStdin>,
+    input: &[u8],
+) -> std::io::Result<()> {
+    let Some(mut pipe) = pipe else {
+        return Ok(());
+    };
+
+    match pipe.write_all(input).await {
+        // the subprocess does not have to read its whole input.
+        Err(error) if error.kind() == std::io::ErrorKind::BrokenPipe => Ok(()),
+        result => result,
+    }
+}
+
 impl Cmd {
     /// run a cmd service with it's internal arguments.
     ///
@@ -45,8 +110,9 @@ impl Cmd {
     /// * if the group used to launch commands is not found.
     /// * if the cmd service failed to spawn.
     /// * if the cmd returned an error.
-    pub fn run(&self) -> anyhow::Result<std::process::ExitStatus> {
-        self.run_inner(self.args.as_ref().unwrap_or(&vec![]))
+    pub async fn run(&self, stdin: Option<Vec<u8>>) -> anyhow::Result<Output> {
+        self.run_inner(self.args.as_deref().unwrap_or_default(), stdin)
+            .await
     }
 
     /// run a cmd service using the provided arguments.
@@ -57,31 +123,51 @@ impl Cmd {
     /// * if the group used to launch commands is not found.
     /// * if the cmd service failed to spawn.
     /// * if the cmd returned an error.
-    pub fn run_with_args(&self, args: &Vec<String>) -> anyhow::Result<std::process::ExitStatus> {
-        self.run_inner(args)
+    pub async fn run_with_args(
+        &self,
+        args: &[String],
+        stdin: Option<Vec<u8>>,
+    ) -> anyhow::Result<Output> {
+        self.run_inner(args, stdin).await
     }
 
+    /// Spawn the subprocess, write `stdin` on its standard input and capture its outputs.
+    /// The subprocess is killed if it has not exited after the timeout.
+    ///
     /// # Errors
     ///
     /// * if the user used to launch commands is not found.
     /// * if the group used to launch commands is not found.
     /// * if the cmd service failed to spawn.
     /// * if the cmd returned an error.
-    pub fn run_inner(&self, args: &Vec<String>) -> anyhow::Result<std::process::ExitStatus> {
-        let mut child = std::process::Command::new(&self.command);
+    pub async fn run_inner(
+        &self,
+        args: &[String],
+        stdin: Option<Vec<u8>>,
+    ) -> anyhow::Result<Output> {
+        let mut child = tokio::process::Command::new(&self.command);
 
-        child.args(args);
+        child
+            .args(args)
+            .stdin(if stdin.is_some() {
+                std::process::Stdio::piped()
+            } else {
+                std::process::Stdio::null()
+            })
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .kill_on_drop(true);
 
         if let Some(user_name) = &self.user {
             if let Some(user) = users::get_user_by_name(&user_name) {
-                std::os::unix::prelude::CommandExt::uid(&mut child, user.uid());
+                child.uid(user.uid());
             } else {
                 anyhow::bail!("user not found: '{user_name}'")
             }
         }
         if let Some(group_name) = &self.group {
             if let Some(group) = users::get_group_by_name(group_name) {
-                std::os::unix::prelude::CommandExt::gid(&mut child, group.gid());
+                child.gid(group.gid());
             } else {
                 anyhow::bail!("group not found: '{group_name}'")
             }
@@ -94,16 +180,79 @@ impl Cmd {
             Err(err) => anyhow::bail!("cmd process failed to spawn: {err:?}"),
         };
 
-        match wait_timeout::ChildExt::wait_timeout(&mut child, self.timeout) {
-            Ok(status) => Ok(status.unwrap_or_else(|| {
-                child.kill().expect("child has already exited");
-                child.wait().expect("command wasn't running")
-            })),
+        let input = stdin.unwrap_or_default();
+        let (stdin, stdout, stderr) =
+            (child.stdin.take(), child.stdout.take(), child.stderr.take());
+
+        let outputs = tokio::time::timeout(self.timeout, async {
+            tokio::try_join!(
+                write_stdin(stdin, &input),
+                read_capped(stdout, self.output_limit),
+                read_capped(stderr, self.output_limit),
+                child.wait(),
+            )
+        })
+        .await;
+
+        match outputs {
+            Ok(Ok(((), (stdout, stdout_truncated), (stderr, stderr_truncated), status))) => {
+                Ok(Output {
+                    status,
+                    stdout,
+                    stderr,
+                    truncated: stdout_truncated || stderr_truncated,
+                })
+            }
+            Ok(Err(err)) => anyhow::bail!("cmd unexpected error: {err:?}"),
+            Err(_) => {
+                tracing::warn!(command = %self, timeout = ?self.timeout, "Command timed out, killing it.");
+                child.start_kill()?;
 
-            Err(err) => anyhow::bail!("cmd unexpected error: {err:?}"),
+                Ok(Output {
+                    status: child.wait().await?,
+                    stdout: vec![],
+                    stderr: vec![],
+                    truncated: false,
+                })
+            }
         }
     }
 
+    /// Map the output of a command to a rhai map.
+    #[must_use]
+    pub fn output_to_map(&self, output: &Output) -> rhai::Map {
+        let json = if self.json {
+            match serde_json::from_slice::<serde_json::Value>(&output.stdout)
+                .map_err(anyhow::Error::from)
+                .and_then(|value| {
+                    rhai::serde::to_dynamic(value).map_err(|e| anyhow::anyhow!("{e}"))
+                }) {
+                Ok(value) => value,
+                Err(error) => {
+                    tracing::warn!(command = %self, %error, "Output is not valid JSON.");
+                    rhai::Dynamic::UNIT
+                }
+            }
+        } else {
+            rhai::Dynamic::UNIT
+        };
+
+        let mut map = Self::status_to_map(output.status);
+        map.extend([
+            (
+                "stdout".into(),
+                String::from_utf8_lossy(&output.stdout).into_owned().into(),
+            ),
+            (
+                "stderr".into(),
+                String::from_utf8_lossy(&output.stderr).into_owned().into(),
+            ),
+            ("truncated".into(), output.truncated.into()),
+            ("json".into(), json),
+        ]);
+        map
+    }
+
     /// Map an exit status code to a rhai map.
     pub fn status_to_map(status: std::process::ExitStatus) -> rhai::Map {
         let code = status.code().map(i64::from).map(rhai::Dynamic::from);
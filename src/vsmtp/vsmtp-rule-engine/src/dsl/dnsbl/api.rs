@@ -0,0 +1,253 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+*/
+
+use rhai::plugin::{
+    mem, Dynamic, FnAccess, FnNamespace, NativeCallContext, PluginFunction, RhaiResult, TypeId,
+};
+use rhai::Module;
+
+/// A weight, written as an integer or a float in vSL.
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+#[serde(untagged)]
+enum Weight {
+    Integer(i64),
+    Float(f64),
+}
+
+impl From<Weight> for f64 {
+    #[allow(clippy::cast_precision_loss)]
+    fn from(value: Weight) -> Self {
+        match value {
+            Weight::Integer(integer) => integer as Self,
+            Weight::Float(float) => float,
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ZoneParameters {
+    /// Name of the zone.
+    zone: String,
+    /// Data listed by the zone.
+    r#type: super::service::ZoneType,
+    /// Weights of the return codes.
+    #[serde(default)]
+    weights: std::collections::HashMap<std::net::Ipv4Addr, Weight>,
+    /// Weight of the return codes which are not in `weights`.
+    #[serde(default = "default_weight")]
+    weight: Weight,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DnsblParameters {
+    /// Zones queried.
+    zones: Vec<ZoneParameters>,
+    /// Timeout of a query.
+    #[serde(default = "default_timeout", with = "humantime_serde")]
+    timeout: std::time::Duration,
+    /// Maximum number of URI hosts checked per message.
+    #[serde(default = "default_max_uris")]
+    max_uris: usize,
+}
+
+const fn default_weight() -> Weight {
+    Weight::Integer(1)
+}
+
+const fn default_timeout() -> std::time::Duration {
+    std::time::Duration::from_secs(5)
+}
+
+const fn default_max_uris() -> usize {
+    20
+}
+
+/// This module exposes the `dnsbl` service, checking the transactions against DNS block
+/// and allow lists such as Spamhaus, DNSWL or URIBL.
+#[rhai::plugin::export_module]
+pub mod dnsbl {
+    use crate::api::EngineResult;
+    use crate::{get_global, vsl_guard_ok};
+
+    type Dnsbl = rhai::Shared<crate::dsl::dnsbl::service::Dnsbl>;
+
+    /// Create a service checking the transactions against DNS lists.
+    ///
+    /// # Args
+    ///
+    /// * `parameters` - a map of the following parameters:
+    ///     * `zones` - an array of the lists queried, maps of the following parameters:
+    ///         * `zone` - the name of the list, for example "zen.spamhaus.org".
+    ///         * `type` - the data listed by the zone:
+    ///             * "ip" - the address of the client, and the address given in `HELO` / `EHLO`
+    ///               if any, queried with their octets (IPv4) or nibbles (IPv6) reversed.
+    ///             * "domain" - the domain of the `HELO` / `EHLO` command and of the sender.
+    ///             * "uri" - the hosts of the URIs found in the text parts of the message,
+    ///               reversed for the addresses.
+    ///
+    ///           The domains are queried with their parents of two to four labels,
+    ///           `mail.example.co.uk` is queried as `co.uk`, `example.co.uk` and
+    ///           `mail.example.co.uk`.
+    ///         * `weights` - a map of the weights of the return codes of the zone,
+    ///           by code. (optional)
+    ///         * `weight` - the weight of the return codes which are not in `weights`,
+    ///           negative for an allow list. (optional, default: 1)
+    ///     * `timeout` - a duration after which a query fails. (optional, default: 5s)
+    ///     * `max_uris` - the maximum number of URI hosts checked per message.
+    ///       (optional, default: 20)
+    ///
+    /// # Return
+    ///
+    /// A service used to check the transactions.
+    ///
+    /// # Error
+    ///
+    /// * The service failed to parse the parameters.
+    ///
+    /// # Example
+    ///
+    /// ```text
+    /// // declared in /etc/vsmtp/services/dnsbl.vsl
+    /// export const dnsbl = dnsbl::build(#{
+    ///     zones: [
+    ///         #{
+    ///             zone: "zen.spamhaus.org",
+    ///             type: "ip",
+    ///             weights: #{ "127.0.0.2": 10, "127.0.0.3": 10, "127.0.0.10": 3, "127.0.0.11": 3 },
+    ///             weight: 5,
+    ///         },
+    ///         #{ zone: "list.dnswl.org", type: "ip", weight: -5 },
+    ///         #{ zone: "dbl.spamhaus.org", type: "domain", weight: 5 },
+    ///         #{ zone: "multi.uribl.com", type: "uri", weight: 5 },
+    ///     ],
+    ///     timeout: "2s",
+    /// });
+    /// ```
+    ///
+    /// # rhai-autodocs:index:1
+    #[rhai_fn(return_raw)]
+    pub fn build(parameters: rhai::Map) -> EngineResult<Dnsbl> {
+        let parameters = rhai::serde::from_dynamic::<super::DnsblParameters>(&parameters.into())?;
+
+        Ok(rhai::Shared::new(crate::dsl::dnsbl::service::Dnsbl {
+            zones: parameters
+                .zones
+                .into_iter()
+                .map(|zone| crate::dsl::dnsbl::service::Zone {
+                    name: zone.zone,
+                    r#type: zone.r#type,
+                    weights: zone
+                        .weights
+                        .into_iter()
+                        .map(|(code, weight)| (code, weight.into()))
+                        .collect(),
+                    weight: zone.weight.into(),
+                })
+                .collect(),
+            timeout: parameters.timeout,
+            max_uris: parameters.max_uris,
+        }))
+    }
+
+    /// Query the zones for the values of the transaction known at the current stage,
+    /// in parallel, with the root resolver of the server.
+    ///
+    /// A query which fails or times out is counted as not listed.
+    ///
+    /// # Return
+    ///
+    /// A map of the following fields:
+    /// * `score` - the sum of the weights of the listings.
+    /// * `listed` - `true` if a value was found in a zone.
+    /// * `listings` - an array of the values found, maps with the `zone`, its `type`,
+    ///   the `target` found (address, domain or host), the `query` sent, the `code`
+    ///   returned by the zone and its `weight`.
+    ///
+    /// # Effective smtp stage
+    ///
+    /// `connect` and onwards. The domains are checked from the `helo` and `mail` stages,
+    /// the URIs from the `preq` stage.
+    ///
+    /// # Example
+    ///
+    /// ```text
+    /// import "services/dnsbl" as svc;
+    ///
+    /// #{
+    ///     preq: [
+    ///         rule "dnsbl" || {
+    ///             let result = svc::dnsbl.check();
+    ///
+    ///             for listing in result.listings {
+    ///                 log("info", `${listing.target} listed by ${listing.zone} (${listing.code})`);
+    ///             }
+    ///
+    ///             if result.score >= 10 {
+    ///                 state::deny(code::c554_7_1())
+    ///             } else {
+    ///                 state::next()
+    ///             }
+    ///         },
+    ///     ]
+    /// }
+    /// ```
+    ///
+    /// # rhai-autodocs:index:2
+    #[rhai_fn(global, name = "check", pure)]
+    pub fn check(ncc: NativeCallContext, dnsbl: &mut Dnsbl) -> rhai::Map {
+        let ctx = get_global!(ncc, ctx);
+        let msg = get_global!(ncc, msg);
+        let srv = get_global!(ncc, srv);
+
+        let uses_uris = dnsbl
+            .zones
+            .iter()
+            .any(|zone| zone.r#type == crate::dsl::dnsbl::service::ZoneType::Uri);
+        let finished = matches!(
+            *vsl_guard_ok!(ctx.read()),
+            vsmtp_common::Context::Finished(_)
+        );
+
+        let uri_hosts = if uses_uris && finished {
+            vsl_guard_ok!(msg.write())
+                .parsed::<vsmtp_mail_parser::MailMimeParser>()
+                .map_or_else(
+                    |error| {
+                        tracing::warn!(service = %**dnsbl, %error, "Message not parsed.");
+                        vec![]
+                    },
+                    |mail| crate::dsl::dnsbl::service::uri_hosts(mail),
+                )
+        } else {
+            vec![]
+        };
+
+        let targets = crate::dsl::dnsbl::service::Targets::new(
+            &vsl_guard_ok!(ctx.read()),
+            &uri_hosts,
+            dnsbl.max_uris,
+        );
+
+        let dnsbl = &**dnsbl;
+        let listings = block_on!(dnsbl.check(&srv.resolvers.get_resolver_root(), &targets));
+        tracing::debug!(service = %dnsbl, ?listings, "Transaction checked.");
+
+        crate::dsl::dnsbl::service::Listing::to_map(listings)
+    }
+}
@@ -0,0 +1,334 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+*/
+
+pub mod api;
+pub mod service;
+
+/// Create a new dnsbl module.
+#[must_use]
+pub fn new_module() -> rhai::Module {
+    let mut module = rhai::exported_module!(api::dnsbl);
+
+    module.set_id("dnsbl");
+
+    module
+}
+
+#[cfg(test)]
+mod tests {
+    use super::service::{
+        parent_domains, reverse_ip, uri_hosts, Dnsbl, Listing, Target, Targets, Zone, ZoneType,
+    };
+    use crate::tests::service::run_connect;
+    use trust_dns_resolver::proto::{
+        op::{Message, MessageType, ResponseCode},
+        rr::{RData, Record},
+    };
+
+    /// Answer the `A` queries of the names in `records`, and `NXDOMAIN` for the others.
+    async fn fake_dns(records: &'static [(&'static str, [u8; 4])]) -> std::net::SocketAddr {
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut buffer = [0; 512];
+            loop {
+                let (size, peer) = socket.recv_from(&mut buffer).await.unwrap();
+                let request = Message::from_vec(&buffer[..size]).unwrap();
+                let query = request.queries()[0].clone();
+                let name = query.name().to_string();
+
+                let mut response = Message::new();
+                response
+                    .set_id(request.id())
+                    .set_message_type(MessageType::Response)
+                    .set_recursion_desired(true)
+                    .set_recursion_available(true)
+                    .add_query(query.clone());
+
+                let answers = records
+                    .iter()
+                    .filter(|(record, _)| *record == name)
+                    .map(|(_, ip)| {
+                        Record::from_rdata(query.name().clone(), 60, RData::A((*ip).into()))
+                    })
+                    .collect::<Vec<_>>();
+                if answers.is_empty() {
+                    response.set_response_code(ResponseCode::NXDomain);
+                } else {
+                    response.add_answers(answers);
+                }
+
+                socket
+                    .send_to(&response.to_vec().unwrap(), peer)
+                    .await
+                    .unwrap();
+            }
+        });
+
+        address
+    }
+
+    fn resolver(address: std::net::SocketAddr) -> trust_dns_resolver::TokioAsyncResolver {
+        trust_dns_resolver::TokioAsyncResolver::tokio(
+            trust_dns_resolver::config::ResolverConfig::from_parts(
+                None,
+                vec![],
+                trust_dns_resolver::config::NameServerConfigGroup::from_ips_clear(
+                    &[address.ip()],
+                    address.port(),
+                    true,
+                ),
+            ),
+            trust_dns_resolver::config::ResolverOpts::default(),
+        )
+        .unwrap()
+    }
+
+    fn zone(name: &str, r#type: ZoneType, weights: &[([u8; 4], f64)], weight: f64) -> Zone {
+        Zone {
+            name: name.to_string(),
+            r#type,
+            weights: weights
+                .iter()
+                .map(|(code, weight)| ((*code).into(), *weight))
+                .collect(),
+            weight,
+        }
+    }
+
+    #[test]
+    fn reverse() {
+        assert_eq!(reverse_ip("192.0.2.1".parse().unwrap()), "1.2.0.192");
+        assert_eq!(
+            reverse_ip("2001:db8::1:2".parse().unwrap()),
+            "2.0.0.0.1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2"
+        );
+    }
+
+    #[test]
+    fn parents() {
+        assert_eq!(
+            parent_domains("A.Mail.Example.co.uk."),
+            ["co.uk", "example.co.uk", "mail.example.co.uk"]
+        );
+        assert_eq!(parent_domains("example.com"), ["example.com"]);
+        assert!(parent_domains("localhost").is_empty());
+        assert!(parent_domains("bad..example.com").is_empty());
+    }
+
+    #[test]
+    fn uris() {
+        let mut message = vsmtp_mail_parser::MessageBody::try_from(
+            [
+                "From: john@example.com",
+                "To: jane@example.com",
+                "Subject: links",
+                "Date: Mon, 1 Jan 2024 00:00:00 +0000",
+                "MIME-Version: 1.0",
+                "Content-Type: multipart/mixed; boundary=\"bound\"",
+                "",
+                "--bound",
+                "Content-Type: text/plain; charset=utf-8",
+                "Content-Transfer-Encoding: quoted-printable",
+                "",
+                "see http://www.spam-=",
+                "domain.com/path?a=3Db and https://user@[192.0.2.1]:8080/",
+                "--bound",
+                "Content-Type: text/html",
+                "Content-Transfer-Encoding: base64",
+                "",
+                "PGEgaHJlZj0iSFRUUFM6Ly9ldmlsLmV4YW1wbGUubmV0LyI+Y2xpY2s8L2E+",
+                "--bound",
+                "Content-Type: image/png",
+                "",
+                "http://not-a-link.example.org/",
+                "--bound--",
+                "",
+            ]
+            .join("\r\n")
+            .as_str(),
+        )
+        .unwrap();
+        let mail = message
+            .parsed::<vsmtp_mail_parser::MailMimeParser>()
+            .unwrap();
+
+        assert_eq!(
+            uri_hosts(mail),
+            ["www.spam-domain.com", "192.0.2.1", "evil.example.net"]
+        );
+
+        let mut regular = vsmtp_mail_parser::MessageBody::try_from(
+            [
+                "From: john@example.com",
+                "Subject: links",
+                "Date: Mon, 1 Jan 2024 00:00:00 +0000",
+                "",
+                "ftp://files.example.com/a and http://files.example.com/b",
+                "",
+            ]
+            .join("\r\n")
+            .as_str(),
+        )
+        .unwrap();
+        let regular = regular
+            .parsed::<vsmtp_mail_parser::MailMimeParser>()
+            .unwrap();
+        assert_eq!(uri_hosts(regular), ["files.example.com"]);
+    }
+
+    #[tokio::test]
+    async fn check() {
+        let address = fake_dns(&[
+            ("2.0.0.127.bl.test.", [127, 0, 0, 2]),
+            ("2.0.0.127.bl.test.", [127, 0, 0, 4]),
+            ("2.0.0.127.wl.test.", [127, 0, 10, 3]),
+            ("example.net.dbl.test.", [127, 0, 1, 2]),
+        ])
+        .await;
+
+        let dnsbl = Dnsbl {
+            zones: vec![
+                zone("bl.test", ZoneType::Ip, &[([127, 0, 0, 2], 10.0)], 3.0),
+                zone("wl.test", ZoneType::Ip, &[], -5.0),
+                zone("dbl.test", ZoneType::Domain, &[], 2.5),
+                zone("dbl.test", ZoneType::Uri, &[], 2.5),
+            ],
+            timeout: std::time::Duration::from_secs(5),
+            max_uris: 20,
+        };
+        let targets = Targets {
+            ip: vec![Target {
+                value: "127.0.0.2".to_string(),
+                query: reverse_ip("127.0.0.2".parse().unwrap()),
+            }],
+            domain: vec![Target {
+                value: "example.net".to_string(),
+                query: "example.net".to_string(),
+            }],
+            uri: vec![Target {
+                value: "example.org".to_string(),
+                query: "example.org".to_string(),
+            }],
+        };
+
+        let mut listings = dnsbl.check(&resolver(address), &targets).await;
+        listings.sort_by(|a, b| (&a.zone, a.code).cmp(&(&b.zone, b.code)));
+
+        assert_eq!(
+            listings,
+            [
+                Listing {
+                    zone: "bl.test".to_string(),
+                    r#type: ZoneType::Ip,
+                    target: "127.0.0.2".to_string(),
+                    query: "2.0.0.127.bl.test.".to_string(),
+                    code: [127, 0, 0, 2].into(),
+                    weight: 10.0,
+                },
+                Listing {
+                    zone: "bl.test".to_string(),
+                    r#type: ZoneType::Ip,
+                    target: "127.0.0.2".to_string(),
+                    query: "2.0.0.127.bl.test.".to_string(),
+                    code: [127, 0, 0, 4].into(),
+                    weight: 3.0,
+                },
+                Listing {
+                    zone: "dbl.test".to_string(),
+                    r#type: ZoneType::Domain,
+                    target: "example.net".to_string(),
+                    query: "example.net.dbl.test.".to_string(),
+                    code: [127, 0, 1, 2].into(),
+                    weight: 2.5,
+                },
+                Listing {
+                    zone: "wl.test".to_string(),
+                    r#type: ZoneType::Ip,
+                    target: "127.0.0.2".to_string(),
+                    query: "2.0.0.127.wl.test.".to_string(),
+                    code: [127, 0, 10, 3].into(),
+                    weight: -5.0,
+                },
+            ]
+        );
+
+        let map = Listing::to_map(listings);
+        assert!((map.get("score").unwrap().as_float().unwrap() - 10.5).abs() < f64::EPSILON);
+        assert!(map.get("listed").unwrap().as_bool().unwrap());
+    }
+
+    #[tokio::test]
+    async fn unreachable() {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let dnsbl = Dnsbl {
+            zones: vec![zone("bl.test", ZoneType::Ip, &[], 1.0)],
+            timeout: std::time::Duration::from_millis(200),
+            max_uris: 20,
+        };
+        let targets = Targets {
+            ip: vec![Target {
+                value: "127.0.0.2".to_string(),
+                query: "2.0.0.127".to_string(),
+            }],
+            ..Targets::default()
+        };
+
+        let listings = dnsbl
+            .check(&resolver(socket.local_addr().unwrap()), &targets)
+            .await;
+        assert!(listings.is_empty());
+
+        let map = Listing::to_map(listings);
+        assert!(map.get("score").unwrap().as_float().unwrap().abs() < f64::EPSILON);
+        assert!(!map.get("listed").unwrap().as_bool().unwrap());
+    }
+
+    #[test]
+    fn check_in_rules() {
+        let (_, status) = run_connect(
+            r#"
+#{
+    connect: [
+        rule "dnsbl" || {
+            let result = dnsbl::build(#{
+                zones: [
+                    #{ zone: "bl.invalid", type: "ip", weights: #{ "127.0.0.2": 10 }, weight: 2.5 },
+                    #{ zone: "uribl.invalid", type: "uri" },
+                ],
+                timeout: "200ms",
+                max_uris: 5,
+            }).check();
+
+            if result.score == 0.0 && !result.listed && result.listings == [] {
+                state::accept()
+            } else {
+                state::deny()
+            }
+        },
+    ]
+}
+"#
+            .to_string(),
+        );
+        assert!(
+            matches!(status, vsmtp_common::status::Status::Accept(_)),
+            "{status:?}"
+        );
+    }
+}
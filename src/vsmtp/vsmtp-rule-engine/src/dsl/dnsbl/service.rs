@@ -0,0 +1,443 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+*/
+
+use trust_dns_resolver::{error::ResolveErrorKind, TokioAsyncResolver};
+use vsmtp_common::{ClientName, Context};
+use vsmtp_mail_parser::{BodyType, Mail, Mime, MimeBodyType};
+
+/// Data listed by a zone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ZoneType {
+    /// Addresses of the client, queried with their octets (IPv4) or nibbles (IPv6) reversed.
+    Ip,
+    /// Domains of the `HELO` / `EHLO` command and of the sender.
+    Domain,
+    /// Hosts of the URIs found in the body of the message.
+    Uri,
+}
+
+impl std::fmt::Display for ZoneType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Ip => "ip",
+            Self::Domain => "domain",
+            Self::Uri => "uri",
+        })
+    }
+}
+
+/// A DNS list queried by the service.
+#[derive(Debug, Clone)]
+pub struct Zone {
+    /// Name of the zone, such as `zen.spamhaus.org`.
+    pub name: String,
+    /// Data listed by the zone.
+    pub r#type: ZoneType,
+    /// Weights of the return codes of the zone.
+    pub weights: std::collections::HashMap<std::net::Ipv4Addr, f64>,
+    /// Weight of the return codes which are not in `weights`.
+    pub weight: f64,
+}
+
+/// A value checked against the zones.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    /// The address, domain or host checked.
+    pub value: String,
+    /// The label prepended to the name of the zone.
+    pub query: String,
+}
+
+impl Target {
+    fn ip(ip: std::net::IpAddr) -> Self {
+        Self {
+            value: ip.to_string(),
+            query: reverse_ip(ip),
+        }
+    }
+
+    fn domain(domain: String) -> Self {
+        Self {
+            value: domain.clone(),
+            query: domain,
+        }
+    }
+}
+
+/// Values of a transaction checked against the zones, by type of zone.
+#[derive(Debug, Default, Clone)]
+pub struct Targets {
+    /// Addresses of the client.
+    pub ip: Vec<Target>,
+    /// Domains of the `HELO` / `EHLO` command and of the sender.
+    pub domain: Vec<Target>,
+    /// Hosts of the URIs of the message.
+    pub uri: Vec<Target>,
+}
+
+fn push_unique(targets: &mut Vec<Target>, target: Target) {
+    if !targets.contains(&target) {
+        targets.push(target);
+    }
+}
+
+impl Targets {
+    /// Capture the values to check from the context and the hosts of the URIs of the message.
+    ///
+    /// The domains are checked with their parents of two to four labels,
+    /// and at most `max_uris` distinct URI hosts are kept.
+    #[must_use]
+    pub fn new(ctx: &Context, uri_hosts: &[String], max_uris: usize) -> Self {
+        let mut targets = Self::default();
+
+        push_unique(
+            &mut targets.ip,
+            Target::ip(canonical_ip(ctx.client_addr().ip())),
+        );
+
+        match ctx.client_name() {
+            Ok(ClientName::Ip4(ip)) => push_unique(&mut targets.ip, Target::ip((*ip).into())),
+            Ok(ClientName::Ip6(ip)) => {
+                push_unique(&mut targets.ip, Target::ip(canonical_ip((*ip).into())));
+            }
+            Ok(ClientName::Domain(domain)) => {
+                for domain in parent_domains(&domain.to_string()) {
+                    push_unique(&mut targets.domain, Target::domain(domain));
+                }
+            }
+            Err(_) => {}
+        }
+
+        if let Ok(Some(reverse_path)) = ctx.reverse_path() {
+            for domain in parent_domains(&reverse_path.domain().to_string()) {
+                push_unique(&mut targets.domain, Target::domain(domain));
+            }
+        }
+
+        for host in uri_hosts.iter().take(max_uris) {
+            if let Ok(ip) = host.parse::<std::net::IpAddr>() {
+                push_unique(&mut targets.uri, Target::ip(canonical_ip(ip)));
+            } else {
+                for domain in parent_domains(host) {
+                    push_unique(&mut targets.uri, Target::domain(domain));
+                }
+            }
+        }
+
+        targets
+    }
+
+    const fn of(&self, r#type: ZoneType) -> &Vec<Target> {
+        match r#type {
+            ZoneType::Ip => &self.ip,
+            ZoneType::Domain => &self.domain,
+            ZoneType::Uri => &self.uri,
+        }
+    }
+}
+
+/// A value found in a zone.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Listing {
+    /// Name of the zone.
+    pub zone: String,
+    /// Data listed by the zone.
+    pub r#type: ZoneType,
+    /// The address, domain or host listed.
+    pub target: String,
+    /// The name queried.
+    pub query: String,
+    /// The code returned by the zone.
+    pub code: std::net::Ipv4Addr,
+    /// The weight of the code.
+    pub weight: f64,
+}
+
+/// A service checking the transactions against DNS block and allow lists.
+#[derive(Debug, Clone)]
+pub struct Dnsbl {
+    /// Zones queried.
+    pub zones: Vec<Zone>,
+    /// Timeout of a query.
+    pub timeout: std::time::Duration,
+    /// Maximum number of URI hosts checked per message.
+    pub max_uris: usize,
+}
+
+impl std::fmt::Display for Dnsbl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "dnsbl:{}",
+            self.zones
+                .iter()
+                .map(|zone| zone.name.as_str())
+                .collect::<Vec<_>>()
+                .join(",")
+        )
+    }
+}
+
+impl Dnsbl {
+    /// Query all the zones for their targets in parallel, and return the listings found.
+    ///
+    /// A query which fails or times out is logged and counted as not listed.
+    pub async fn check(&self, resolver: &TokioAsyncResolver, targets: &Targets) -> Vec<Listing> {
+        futures_util::future::join_all(self.zones.iter().flat_map(|zone| {
+            targets
+                .of(zone.r#type)
+                .iter()
+                .map(move |target| self.lookup(resolver, zone, target))
+        }))
+        .await
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    async fn lookup(
+        &self,
+        resolver: &TokioAsyncResolver,
+        zone: &Zone,
+        target: &Target,
+    ) -> Vec<Listing> {
+        let query = format!("{}.{}.", target.query, zone.name.trim_end_matches('.'));
+
+        let records = match tokio::time::timeout(self.timeout, resolver.ipv4_lookup(query.as_str()))
+            .await
+        {
+            Ok(Ok(records)) => records,
+            Ok(Err(error)) if matches!(error.kind(), ResolveErrorKind::NoRecordsFound { .. }) => {
+                return vec![];
+            }
+            Ok(Err(error)) => {
+                tracing::warn!(service = %self, %query, %error, "Lookup failed.");
+                return vec![];
+            }
+            Err(_) => {
+                tracing::warn!(service = %self, %query, "Lookup timed out.");
+                return vec![];
+            }
+        };
+
+        records
+            .iter()
+            .map(|code| Listing {
+                zone: zone.name.clone(),
+                r#type: zone.r#type,
+                target: target.value.clone(),
+                query: query.clone(),
+                code: *code,
+                weight: zone.weights.get(code).copied().unwrap_or(zone.weight),
+            })
+            .collect()
+    }
+}
+
+impl Listing {
+    /// Aggregate the listings in a rhai map.
+    #[must_use]
+    pub fn to_map(listings: Vec<Self>) -> rhai::Map {
+        let score = listings.iter().map(|listing| listing.weight).sum::<f64>();
+
+        rhai::Map::from_iter([
+            ("score".into(), score.into()),
+            ("listed".into(), (!listings.is_empty()).into()),
+            (
+                "listings".into(),
+                listings
+                    .into_iter()
+                    .map(|listing| {
+                        rhai::Dynamic::from_map(rhai::Map::from_iter([
+                            ("zone".into(), listing.zone.into()),
+                            ("type".into(), listing.r#type.to_string().into()),
+                            ("target".into(), listing.target.into()),
+                            ("query".into(), listing.query.into()),
+                            ("code".into(), listing.code.to_string().into()),
+                            ("weight".into(), listing.weight.into()),
+                        ]))
+                    })
+                    .collect::<rhai::Array>()
+                    .into(),
+            ),
+        ])
+    }
+}
+
+/// Map an IPv4-mapped IPv6 address to its IPv4 address.
+fn canonical_ip(ip: std::net::IpAddr) -> std::net::IpAddr {
+    match ip {
+        std::net::IpAddr::V6(v6) => v6
+            .to_ipv4_mapped()
+            .map_or(std::net::IpAddr::V6(v6), std::net::IpAddr::V4),
+        std::net::IpAddr::V4(_) => ip,
+    }
+}
+
+/// Reverse the octets of an IPv4 address, or the nibbles of an IPv6 address,
+/// as expected by the DNS lists.
+#[must_use]
+pub fn reverse_ip(ip: std::net::IpAddr) -> String {
+    match ip {
+        std::net::IpAddr::V4(v4) => v4
+            .octets()
+            .iter()
+            .rev()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join("."),
+        std::net::IpAddr::V6(v6) => v6
+            .octets()
+            .iter()
+            .rev()
+            .flat_map(|octet| [octet & 0x0f, octet >> 4])
+            .map(|nibble| format!("{nibble:x}"))
+            .collect::<Vec<_>>()
+            .join("."),
+    }
+}
+
+/// The parents of a domain with two to four labels, the domain included,
+/// as the lists register domains (`example.com`, `example.co.uk`) and some of their hosts.
+///
+/// `mail.example.co.uk` -> `co.uk`, `example.co.uk`, `mail.example.co.uk`
+#[must_use]
+pub fn parent_domains(domain: &str) -> Vec<String> {
+    let domain = domain.trim_end_matches('.').to_lowercase();
+    let labels = domain.split('.').collect::<Vec<_>>();
+    if labels.iter().any(|label| label.is_empty()) {
+        return vec![];
+    }
+
+    (2..=std::cmp::min(4, labels.len()))
+        .map(|count| labels[labels.len() - count..].join("."))
+        .collect()
+}
+
+/// Hosts of the URIs found in the text parts of a message, in order of appearance.
+#[must_use]
+pub fn uri_hosts(mail: &Mail) -> Vec<String> {
+    let uri = regex::Regex::new(
+        r#"(?i)\b(?:https?|ftp)://(?:[^\s/?#@<>"']*@)?(\[[0-9a-f:.]+\]|[^\s/?#:<>"'\\]+)"#,
+    )
+    .expect("valid regex");
+
+    let mut texts = vec![];
+    mail_texts(mail, &mut texts);
+
+    let mut hosts = Vec::<String>::new();
+    for text in &texts {
+        for captures in uri.captures_iter(text) {
+            let host = captures[1]
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .trim_end_matches('.')
+                .to_lowercase();
+            if !host.is_empty() && !hosts.contains(&host) {
+                hosts.push(host);
+            }
+        }
+    }
+    hosts
+}
+
+fn mail_texts(mail: &Mail, texts: &mut Vec<String>) {
+    match &mail.body {
+        BodyType::Regular(lines) => {
+            texts.push(decode(lines, mail.get_header("Content-Transfer-Encoding")));
+        }
+        BodyType::Mime(mime) => mime_texts(mime, texts),
+        BodyType::Undefined => {}
+    }
+}
+
+fn mime_texts(mime: &Mime, texts: &mut Vec<String>) {
+    let header = |name: &str| {
+        mime.headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case(name))
+            .map(|header| header.value.as_str())
+    };
+
+    match &mime.content {
+        MimeBodyType::Regular(lines) => {
+            if header("Content-Type").map_or(true, |value| {
+                value.trim().to_lowercase().starts_with("text/")
+            }) {
+                texts.push(decode(lines, header("Content-Transfer-Encoding")));
+            }
+        }
+        MimeBodyType::Multipart(multipart) => {
+            for part in &multipart.parts {
+                mime_texts(part, texts);
+            }
+        }
+        MimeBodyType::Embedded(mail) => mail_texts(mail, texts),
+    }
+}
+
+/// Decode the lines of a body part according to its `Content-Transfer-Encoding`.
+fn decode(lines: &[String], encoding: Option<&str>) -> String {
+    match encoding
+        .map(|encoding| encoding.trim().to_lowercase())
+        .as_deref()
+    {
+        Some("base64") => base64::Engine::decode(
+            &base64::engine::general_purpose::STANDARD,
+            lines.concat().trim(),
+        )
+        .map_or_else(
+            |_| lines.join("\n"),
+            |bytes| String::from_utf8_lossy(&bytes).into_owned(),
+        ),
+        Some("quoted-printable") => decode_quoted_printable(lines),
+        _ => lines.join("\n"),
+    }
+}
+
+fn decode_quoted_printable(lines: &[String]) -> String {
+    let mut bytes = vec![];
+    for line in lines {
+        let line = line.trim_end();
+        let (line, soft_break) = line
+            .strip_suffix('=')
+            .map_or((line, false), |line| (line, true));
+
+        let mut rest = line.as_bytes();
+        while let Some((&byte, tail)) = rest.split_first() {
+            let escaped = (byte == b'=')
+                .then(|| tail.get(..2))
+                .flatten()
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+            if let Some(escaped) = escaped {
+                bytes.push(escaped);
+                rest = &tail[2..];
+            } else {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+
+        if !soft_break {
+            bytes.push(b'\n');
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
@@ -0,0 +1,309 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+*/
+
+use super::service::{Decision, Modification};
+use rhai::plugin::{
+    mem, Dynamic, FnAccess, FnNamespace, NativeCallContext, PluginFunction, RhaiResult, TypeId,
+};
+use rhai::Module;
+use vsmtp_common::{status::Status, Address, Context, Reply};
+use vsmtp_delivery::Deliver;
+use vsmtp_mail_parser::MessageBody;
+
+/// Status returned when the milter cannot be reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum DefaultAction {
+    Accept,
+    Tempfail,
+    Reject,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MilterParameters {
+    /// Address of the milter, `inet:host:port` or `unix:path`.
+    address: crate::dsl::address::Address,
+    /// Timeout of an exchange with the milter.
+    #[serde(default = "default_timeout", with = "humantime_serde")]
+    timeout: std::time::Duration,
+    /// Duration after which an unused session is closed.
+    #[serde(default = "default_idle_timeout", with = "humantime_serde")]
+    idle_timeout: std::time::Duration,
+    /// Quarantine of the messages quarantined or discarded by the milter.
+    #[serde(default = "default_quarantine")]
+    quarantine: String,
+    /// Status returned when the milter cannot be reached.
+    #[serde(default = "default_action")]
+    default_action: DefaultAction,
+}
+
+const fn default_timeout() -> std::time::Duration {
+    std::time::Duration::from_secs(30)
+}
+
+const fn default_idle_timeout() -> std::time::Duration {
+    std::time::Duration::from_secs(5 * 60)
+}
+
+fn default_quarantine() -> String {
+    "milter".to_string()
+}
+
+const fn default_action() -> DefaultAction {
+    DefaultAction::Tempfail
+}
+
+/// The milter service, with the way its decisions are mapped to a status.
+#[derive(Debug)]
+pub struct Milter {
+    service: super::service::Milter,
+    quarantine: String,
+    default_action: DefaultAction,
+}
+
+fn reject() -> Reply {
+    "550 5.7.1 Command rejected\r\n"
+        .parse::<Reply>()
+        .expect("valid reply")
+}
+
+fn tempfail() -> Reply {
+    "451 4.7.1 Service unavailable - try again later\r\n"
+        .parse::<Reply>()
+        .expect("valid reply")
+}
+
+impl Milter {
+    fn status(&self, decision: Decision) -> Status {
+        match decision {
+            Decision::Continue => Status::Next,
+            Decision::Reject => Status::Deny(reject()),
+            Decision::Tempfail => Status::Deny(tempfail()),
+            Decision::Reply(reply) => Status::Deny(
+                format!("{}\r\n", reply.trim_end())
+                    .parse::<Reply>()
+                    .unwrap_or_else(|_| reject()),
+            ),
+            Decision::Discard | Decision::Quarantine(_) => {
+                Status::Quarantine(self.quarantine.clone())
+            }
+        }
+    }
+
+    fn default_status(&self) -> Status {
+        match self.default_action {
+            DefaultAction::Accept => Status::Next,
+            DefaultAction::Tempfail => Status::Deny(tempfail()),
+            DefaultAction::Reject => Status::Deny(reject()),
+        }
+    }
+}
+
+/// Apply a modification requested by the milter to the envelop or the message.
+fn apply(
+    ctx: &mut Context,
+    msg: &mut MessageBody,
+    srv: &crate::api::Server,
+    modification: Modification,
+) -> anyhow::Result<()> {
+    fn address(address: &str) -> anyhow::Result<Option<Address>> {
+        let address = address.trim_start_matches('<').trim_end_matches('>');
+        if address.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(address.parse::<Address>()?))
+        }
+    }
+
+    match modification {
+        Modification::AddHeader { name, value } => {
+            msg.append_header(&name, &value.replace('\n', "\r\n"));
+        }
+        Modification::InsertHeader { index, name, value } => {
+            msg.insert_header(index, &name, &value.replace('\n', "\r\n"));
+        }
+        Modification::ChangeHeader { index, name, value } => {
+            let value = value.replace('\n', "\r\n");
+            msg.replace_header(
+                &name,
+                index.saturating_sub(1),
+                (!value.is_empty()).then_some(value.as_str()),
+            );
+        }
+        Modification::AddRcpt(rcpt) => {
+            let rcpt = address(&rcpt)?.ok_or_else(|| anyhow::anyhow!("empty recipient"))?;
+            ctx.add_forward_path(
+                rcpt,
+                std::sync::Arc::new(Deliver::new(
+                    srv.resolvers.get_resolver_root(),
+                    srv.config.clone(),
+                )),
+            )?;
+        }
+        Modification::DeleteRcpt(rcpt) => {
+            if let Some(rcpt) = address(&rcpt)? {
+                ctx.remove_forward_path(&rcpt)?;
+            }
+        }
+        Modification::ChangeFrom(reverse_path) => {
+            ctx.set_reverse_path(address(&reverse_path)?)?;
+        }
+        Modification::ReplaceBody(body) => msg.set_body(body),
+    }
+
+    Ok(())
+}
+
+/// This module exposes the `milter` service, forwarding the transactions to a milter
+/// such as `OpenDKIM`, `OpenDMARC` or any filter written for Sendmail or Postfix.
+#[rhai::plugin::export_module]
+pub mod milter {
+    use crate::api::EngineResult;
+    use crate::{get_global, vsl_guard_ok};
+
+    type Milter = rhai::Shared<crate::dsl::milter::api::Milter>;
+
+    /// Create a service forwarding the transactions to a milter.
+    ///
+    /// A session is opened with the milter for each SMTP connection the first time
+    /// the service is used, and closed when the transaction is rejected
+    /// or when it has not been used for `idle_timeout`.
+    ///
+    /// # Args
+    ///
+    /// * `parameters` - a map of the following parameters:
+    ///     * `address` - the address of the milter, `inet:host:port` or `unix:/path/to/socket`.
+    ///     * `timeout` - a duration after which an exchange with the milter fails. (optional, default: 30s)
+    ///     * `idle_timeout` - a duration after which an unused session is closed. (optional, default: 5m)
+    ///     * `quarantine` - the quarantine of the messages quarantined or discarded by the milter.
+    ///       (optional, default: "milter")
+    ///     * `default_action` - the status returned when the milter cannot be reached,
+    ///       "accept", "tempfail" or "reject". (optional, default: "tempfail")
+    ///
+    /// # Return
+    ///
+    /// A service used to check the transactions.
+    ///
+    /// # Error
+    ///
+    /// * The service failed to parse the parameters.
+    ///
+    /// # Example
+    ///
+    /// ```text
+    /// // declared in /etc/vsmtp/services/opendkim.vsl
+    /// export const opendkim = milter::connect(#{
+    ///     address: "inet:127.0.0.1:8891",
+    ///     default_action: "accept",
+    /// });
+    /// ```
+    ///
+    /// # rhai-autodocs:index:1
+    #[rhai_fn(return_raw)]
+    pub fn connect(parameters: rhai::Map) -> EngineResult<Milter> {
+        let parameters = rhai::serde::from_dynamic::<super::MilterParameters>(&parameters.into())?;
+
+        Ok(rhai::Shared::new(super::Milter {
+            service: crate::dsl::milter::service::Milter::new(
+                parameters.address,
+                parameters.timeout,
+                parameters.idle_timeout,
+            ),
+            quarantine: parameters.quarantine,
+            default_action: parameters.default_action,
+        }))
+    }
+
+    /// Send the stages of the transaction not yet seen by the milter, and return its decision.
+    ///
+    /// The milter receives the connection, `HELO`, `MAIL FROM` and each `RCPT TO`,
+    /// and the message once it is received. Calling this function in the `preq` stage only
+    /// sends the whole transaction at once.
+    ///
+    /// At the end of the message, the headers added, inserted, changed or removed by the milter
+    /// are applied on the message, and the recipients added or removed, the sender changed
+    /// and the body replaced are applied on the envelop and the message.
+    ///
+    /// # Return
+    ///
+    /// * `next()` - the milter accepted the stage.
+    /// * `deny()` - the milter rejected the stage, with a `550` code, a `451` code for a
+    ///   temporary failure, or the reply of the milter.
+    /// * `quarantine(queue)` - the milter asked to quarantine or to discard the message,
+    ///   the message is put in the quarantine of the service.
+    /// * the `default_action` of the service if the milter could not be reached.
+    ///
+    /// # Effective smtp stage
+    ///
+    /// `connect` and onwards.
+    ///
+    /// # Example
+    ///
+    /// ```text
+    /// import "services/opendkim" as svc;
+    ///
+    /// #{
+    ///     connect: [
+    ///         rule "milter connect" || svc::opendkim.check(),
+    ///     ],
+    ///
+    ///     rcpt: [
+    ///         rule "milter rcpt" || svc::opendkim.check(),
+    ///     ],
+    ///
+    ///     preq: [
+    ///         rule "milter message" || svc::opendkim.check(),
+    ///     ]
+    /// }
+    /// ```
+    ///
+    /// # rhai-autodocs:index:2
+    #[rhai_fn(global, name = "check", pure)]
+    pub fn check(ncc: NativeCallContext, milter: &mut Milter) -> Status {
+        let ctx = get_global!(ncc, ctx);
+        let msg = get_global!(ncc, msg);
+        let srv = get_global!(ncc, srv);
+
+        let transaction = crate::dsl::milter::service::Transaction::new(
+            &vsl_guard_ok!(ctx.read()),
+            &vsl_guard_ok!(msg.read()),
+        );
+
+        let milter = &**milter;
+        let outcome = match block_on!(milter.service.check(&transaction)) {
+            Ok(outcome) => outcome,
+            Err(error) => {
+                tracing::warn!(service = %milter.service, %error, "Transaction not checked.");
+                return milter.default_status();
+            }
+        };
+        tracing::debug!(service = %milter.service, ?outcome, "Transaction checked.");
+
+        if !outcome.modifications.is_empty() {
+            let mut ctx = vsl_guard_ok!(ctx.write());
+            let mut msg = vsl_guard_ok!(msg.write());
+            for modification in outcome.modifications {
+                if let Err(error) = super::apply(&mut ctx, &mut msg, &srv, modification) {
+                    tracing::warn!(service = %milter.service, %error, "Modification not applied.");
+                }
+            }
+        }
+
+        milter.status(outcome.decision)
+    }
+}
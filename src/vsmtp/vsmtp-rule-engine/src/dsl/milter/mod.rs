@@ -0,0 +1,350 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+*/
+
+pub mod api;
+pub mod service;
+
+/// Create a new milter module.
+#[must_use]
+pub fn new_module() -> rhai::Module {
+    let mut module = rhai::exported_module!(api::milter);
+
+    module.set_id("milter");
+
+    module
+}
+
+#[cfg(test)]
+mod tests {
+    use super::service::{Decision, Milter, Modification, Outcome, Transaction};
+    use crate::dsl::address::Address;
+    use crate::tests::service::{
+        compile, run_connect, tcp_daemon, tcp_daemon_thread, unix_daemon, unreachable_address,
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    type Packets = std::sync::Arc<std::sync::Mutex<Vec<(u8, Vec<u8>)>>>;
+
+    /// Replies of the fake milter to a command.
+    type Script = fn(u8, &[u8]) -> Vec<(u8, Vec<u8>)>;
+
+    /// Answer the commands of a session as a milter would,
+    /// negotiating the `protocol` flags and replying with the `script`.
+    async fn fake_milter<S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin>(
+        mut stream: S,
+        protocol: u32,
+        script: Script,
+        received: Packets,
+    ) {
+        while let Ok(length) = stream.read_u32().await {
+            let mut packet = vec![0; length as usize];
+            stream.read_exact(&mut packet).await.unwrap();
+            let data = packet.split_off(1);
+            let command = packet[0];
+            received.lock().unwrap().push((command, data.clone()));
+
+            let replies = match command {
+                b'O' => vec![(
+                    b'O',
+                    [6_u32, 0xFF, protocol]
+                        .iter()
+                        .flat_map(|option| option.to_be_bytes())
+                        .collect(),
+                )],
+                b'D' | b'A' => vec![],
+                _ => script(command, &data),
+            };
+            for (reply, data) in replies {
+                stream
+                    .write_all(&u32::try_from(data.len() + 1).unwrap().to_be_bytes())
+                    .await
+                    .unwrap();
+                stream.write_all(&[reply]).await.unwrap();
+                stream.write_all(&data).await.unwrap();
+            }
+        }
+    }
+
+    fn milter(address: Address) -> Milter {
+        Milter::new(
+            address,
+            std::time::Duration::from_secs(5),
+            std::time::Duration::from_secs(60),
+        )
+    }
+
+    async fn tcp_milter(protocol: u32, script: Script) -> (Milter, Packets) {
+        let received = Packets::default();
+        let packets = received.clone();
+        let address =
+            tcp_daemon(move |stream| fake_milter(stream, protocol, script, packets.clone())).await;
+
+        (milter(Address::Tcp(address.to_string())), received)
+    }
+
+    fn transaction() -> Transaction {
+        Transaction {
+            connection_uuid: uuid::Uuid::new_v4(),
+            client_addr: "192.168.1.1:50000".parse().unwrap(),
+            server_name: "testserver.com".to_string(),
+            helo: Some("client.com".to_string()),
+            mail_from: Some((uuid::Uuid::new_v4(), Some("john@doe.com".to_string()))),
+            rcpt_to: vec!["jane@doe.com".to_string(), "jim@doe.com".to_string()],
+            message: Some((
+                vec![
+                    ("From".to_string(), "john@doe.com".to_string()),
+                    ("Subject".to_string(), "hello".to_string()),
+                ],
+                "world\r\n".to_string(),
+            )),
+        }
+    }
+
+    fn commands(received: &Packets) -> String {
+        received
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(command, _)| char::from(*command))
+            .filter(|command| *command != 'D')
+            .collect()
+    }
+
+    fn continue_(_: u8, _: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        vec![(b'c', vec![])]
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stages() {
+        let (milter, received) = tcp_milter(0, continue_).await;
+        let transaction = transaction();
+
+        let mut stage = Transaction {
+            helo: None,
+            mail_from: None,
+            rcpt_to: vec![],
+            message: None,
+            ..transaction.clone()
+        };
+        assert_eq!(
+            milter.check(&stage).await.unwrap().decision,
+            Decision::Continue
+        );
+        assert_eq!(commands(&received), "OC");
+
+        stage.helo = transaction.helo.clone();
+        stage.mail_from = transaction.mail_from.clone();
+        stage.rcpt_to = vec![transaction.rcpt_to[0].clone()];
+        milter.check(&stage).await.unwrap();
+        assert_eq!(commands(&received), "OCHMR");
+
+        stage.rcpt_to = transaction.rcpt_to.clone();
+        milter.check(&stage).await.unwrap();
+        milter.check(&transaction).await.unwrap();
+        assert_eq!(commands(&received), "OCHMRRTLLNBE");
+
+        // A new message on the same connection.
+        let next = Transaction {
+            mail_from: Some((uuid::Uuid::new_v4(), None)),
+            ..transaction.clone()
+        };
+        milter.check(&next).await.unwrap();
+        assert_eq!(commands(&received), "OCHMRRTLLNBEMRRTLLNBE");
+
+        let packets = received.lock().unwrap().clone();
+        assert_eq!(
+            packets[2],
+            (
+                b'C',
+                [
+                    b"[192.168.1.1]\x004".as_slice(),
+                    &50000_u16.to_be_bytes(),
+                    b"192.168.1.1\0",
+                ]
+                .concat()
+            )
+        );
+        assert!(packets.contains(&(b'M', b"<>\0".to_vec())));
+        assert!(packets.contains(&(b'L', b"Subject\0hello\0".to_vec())));
+        assert!(packets.contains(&(b'B', b"world\r\n".to_vec())));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn protocol_flags() {
+        // SMFIP_NOHELO | SMFIP_NOBODY | SMFIP_NR_HDR
+        let (milter, received) = tcp_milter(0x2 | 0x10 | 0x80, |command, _| match command {
+            b'L' => vec![],
+            _ => vec![(b'c', vec![])],
+        })
+        .await;
+
+        milter.check(&transaction()).await.unwrap();
+        assert_eq!(commands(&received), "OCMRRTLLNE");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn modifications() {
+        let (milter, _) = tcp_milter(0, |command, _| match command {
+            b'E' => vec![
+                (b'p', vec![]),
+                (b'h', b"X-Milter\0checked\0".to_vec()),
+                (b'i', [&1_u32.to_be_bytes()[..], b"X-First\0one\0"].concat()),
+                (b'm', [&1_u32.to_be_bytes()[..], b"Subject\0\0"].concat()),
+                (b'+', b"<added@doe.com>\0".to_vec()),
+                (b'-', b"<jim@doe.com>\0".to_vec()),
+                (b'e', b"<bounce@doe.com>\0".to_vec()),
+                (b'b', b"new ".to_vec()),
+                (b'b', b"body\r\n".to_vec()),
+                (b'a', vec![]),
+            ],
+            _ => vec![(b'c', vec![])],
+        })
+        .await;
+
+        assert_eq!(
+            milter.check(&transaction()).await.unwrap(),
+            Outcome {
+                decision: Decision::Continue,
+                modifications: vec![
+                    Modification::AddHeader {
+                        name: "X-Milter".to_string(),
+                        value: "checked".to_string()
+                    },
+                    Modification::InsertHeader {
+                        index: 1,
+                        name: "X-First".to_string(),
+                        value: "one".to_string()
+                    },
+                    Modification::ChangeHeader {
+                        index: 1,
+                        name: "Subject".to_string(),
+                        value: String::new()
+                    },
+                    Modification::AddRcpt("<added@doe.com>".to_string()),
+                    Modification::DeleteRcpt("<jim@doe.com>".to_string()),
+                    Modification::ChangeFrom("<bounce@doe.com>".to_string()),
+                    Modification::ReplaceBody("new body\r\n".to_string()),
+                ]
+            }
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn decisions() {
+        let (milter, received) = tcp_milter(0, |command, data| match (command, data) {
+            (b'R', b"<jim@doe.com>\0") => vec![(b'y', b"550 5.7.1 jim is not welcome\0".to_vec())],
+            (b'R', b"<joe@doe.com>\0") => vec![(b't', vec![])],
+            (b'R', b"<jack@doe.com>\0") => vec![(b'd', vec![])],
+            (b'E', _) => vec![(b'q', b"suspicious\0".to_vec()), (b'c', vec![])],
+            _ => vec![(b'c', vec![])],
+        })
+        .await;
+
+        let transaction = transaction();
+        assert_eq!(
+            milter.check(&transaction).await.unwrap().decision,
+            Decision::Reply("550 5.7.1 jim is not welcome".to_string())
+        );
+        // The session is closed once rejected, a new one is opened.
+        assert_eq!(
+            milter.check(&transaction).await.unwrap().decision,
+            Decision::Reply("550 5.7.1 jim is not welcome".to_string())
+        );
+        assert_eq!(commands(&received), "OCHMRROCHMRR");
+
+        let with = |rcpt: &str| Transaction {
+            mail_from: Some((uuid::Uuid::new_v4(), Some("john@doe.com".to_string()))),
+            rcpt_to: vec![rcpt.to_string()],
+            ..transaction.clone()
+        };
+        assert_eq!(
+            milter.check(&with("joe@doe.com")).await.unwrap().decision,
+            Decision::Tempfail
+        );
+        assert_eq!(
+            milter.check(&with("jack@doe.com")).await.unwrap().decision,
+            Decision::Discard
+        );
+        assert_eq!(
+            milter.check(&with("jane@doe.com")).await.unwrap().decision,
+            Decision::Quarantine("suspicious".to_string())
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn unix() {
+        let (_dir, path) =
+            unix_daemon(|stream| fake_milter(stream, 0, continue_, Packets::default()));
+
+        assert_eq!(
+            milter(Address::Unix(path))
+                .check(&transaction())
+                .await
+                .unwrap()
+                .decision,
+            Decision::Continue
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn unreachable() {
+        assert!(milter(Address::Tcp(unreachable_address().to_string()))
+            .check(&transaction())
+            .await
+            .unwrap_err()
+            .to_string()
+            .contains("milter"));
+    }
+
+    #[test]
+    fn check_in_rules() {
+        let address = tcp_daemon_thread(|stream| {
+            fake_milter(stream, 0, |_, _| vec![(b'r', vec![])], Packets::default())
+        });
+
+        let (_, status) = run_connect(format!(
+            r#"
+#{{
+    connect: [
+        rule "milter" || milter::connect(#{{ address: "inet:{address}" }}).check(),
+    ]
+}}
+"#
+        ));
+        assert_eq!(
+            status,
+            vsmtp_common::status::Status::Deny("550 5.7.1 Command rejected\r\n".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn parse() {
+        compile(
+            r#"
+const opendkim = milter::connect(#{
+    address: "inet:127.0.0.1:8891",
+    timeout: "10s",
+    idle_timeout: "1m",
+    quarantine: "dkim",
+    default_action: "accept",
+});
+
+#{}
+"#,
+        );
+    }
+}
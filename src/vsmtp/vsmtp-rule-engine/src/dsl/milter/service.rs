@@ -0,0 +1,674 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+*/
+
+use crate::dsl::address::{Address, Stream};
+use std::ops::ControlFlow;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use vsmtp_common::{Context, Stage};
+use vsmtp_mail_parser::MessageBody;
+
+/// Version of the protocol spoken by the client.
+const VERSION: u32 = 6;
+
+/// Actions the milter is allowed to perform at the end of a message.
+///
+/// `SMFIF_ADDHDRS | SMFIF_CHGBODY | SMFIF_ADDRCPT | SMFIF_DELRCPT | SMFIF_CHGHDRS
+/// | SMFIF_QUARANTINE | SMFIF_CHGFROM | SMFIF_ADDRCPT_PAR`
+const ACTIONS: u32 = 0xFF;
+
+const NO_CONNECT: u32 = 0x1;
+const NO_HELO: u32 = 0x2;
+const NO_MAIL: u32 = 0x4;
+const NO_RCPT: u32 = 0x8;
+const NO_BODY: u32 = 0x10;
+const NO_HEADERS: u32 = 0x20;
+const NO_EOH: u32 = 0x40;
+const NR_HEADER: u32 = 0x80;
+const NO_DATA: u32 = 0x200;
+const NR_CONNECT: u32 = 0x1000;
+const NR_HELO: u32 = 0x2000;
+const NR_MAIL: u32 = 0x4000;
+const NR_RCPT: u32 = 0x8000;
+const NR_DATA: u32 = 0x1_0000;
+const NR_EOH: u32 = 0x4_0000;
+const NR_BODY: u32 = 0x8_0000;
+
+/// Steps the milter can ask to skip or not to reply to, `SMFIP_SKIP` and `SMFIP_NOUNKNOWN` included.
+const PROTOCOL: u32 = 0x000F_F7FF;
+
+/// Maximum size of a body chunk.
+const CHUNK_SIZE: usize = 65535;
+
+/// Maximum size of a packet sent by the milter.
+const PACKET_SIZE_MAX: usize = 64 * 1024 * 1024;
+
+/// Decision of the milter for the current stage.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+    /// Go on with the transaction.
+    Continue,
+    /// Reject the command (`SMFIR_REJECT`).
+    Reject,
+    /// Reject the command with a temporary failure (`SMFIR_TEMPFAIL`).
+    Tempfail,
+    /// Reject the command with the given SMTP reply (`SMFIR_REPLYCODE`).
+    Reply(String),
+    /// Accept the message but do not deliver it (`SMFIR_DISCARD`).
+    Discard,
+    /// Put the message in quarantine for the given reason (`SMFIR_QUARANTINE`).
+    Quarantine(String),
+}
+
+/// Modification of the message requested by the milter at the end of the message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Modification {
+    /// Append a header (`SMFIR_ADDHEADER`).
+    AddHeader {
+        /// Name of the header.
+        name: String,
+        /// Value of the header.
+        value: String,
+    },
+    /// Insert a header at a position, starting from 0 (`SMFIR_INSHEADER`).
+    InsertHeader {
+        /// Position of the header.
+        index: usize,
+        /// Name of the header.
+        name: String,
+        /// Value of the header.
+        value: String,
+    },
+    /// Change the `index`-th occurrence of a header, starting from 1,
+    /// or remove it if the value is empty (`SMFIR_CHGHEADER`).
+    ChangeHeader {
+        /// Occurrence of the header.
+        index: usize,
+        /// Name of the header.
+        name: String,
+        /// New value of the header.
+        value: String,
+    },
+    /// Add a recipient (`SMFIR_ADDRCPT`, `SMFIR_ADDRCPT_PAR`).
+    AddRcpt(String),
+    /// Remove a recipient (`SMFIR_DELRCPT`).
+    DeleteRcpt(String),
+    /// Change the sender (`SMFIR_CHGFROM`).
+    ChangeFrom(String),
+    /// Replace the body of the message (`SMFIR_REPLBODY`).
+    ReplaceBody(String),
+}
+
+/// Result of the exchange with the milter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Outcome {
+    /// Decision of the milter.
+    pub decision: Decision,
+    /// Modifications of the message to apply.
+    pub modifications: Vec<Modification>,
+}
+
+/// The state of the transaction, sent to the milter.
+#[derive(Debug, Clone)]
+pub struct Transaction {
+    /// Identifier of the SMTP connection.
+    pub connection_uuid: uuid::Uuid,
+    /// Address of the client.
+    pub client_addr: std::net::SocketAddr,
+    /// Name of the server.
+    pub server_name: String,
+    /// Argument of the `HELO` / `EHLO` command.
+    pub helo: Option<String>,
+    /// Identifier of the message and sender.
+    pub mail_from: Option<(uuid::Uuid, Option<String>)>,
+    /// Recipients of the message.
+    pub rcpt_to: Vec<String>,
+    /// Headers and body of the message, once received.
+    pub message: Option<(Vec<(String, String)>, String)>,
+}
+
+impl Transaction {
+    /// Capture the state of the transaction from the context and the message.
+    #[must_use]
+    pub fn new(ctx: &Context, message: &MessageBody) -> Self {
+        Self {
+            connection_uuid: *ctx.connection_uuid(),
+            client_addr: *ctx.client_addr(),
+            server_name: ctx.server_name().to_string(),
+            helo: ctx.client_name().ok().map(ToString::to_string),
+            mail_from: ctx.message_uuid().ok().zip(ctx.reverse_path().ok()).map(
+                |(uuid, reverse_path)| (*uuid, reverse_path.as_ref().map(ToString::to_string)),
+            ),
+            rcpt_to: ctx
+                .forward_paths()
+                .map(|forward_paths| forward_paths.iter().map(ToString::to_string).collect())
+                .unwrap_or_default(),
+            message: (ctx.stage() == Stage::Finished).then(|| {
+                let headers = message
+                    .inner()
+                    .headers()
+                    .into_iter()
+                    .map(|(name, value)| {
+                        let value = value.strip_prefix(' ').unwrap_or(&value);
+                        let value = value.trim_end_matches(['\r', '\n']);
+                        (name, value.replace("\r\n", "\n"))
+                    })
+                    .collect();
+                let body = message.inner().body().clone().unwrap_or_default();
+                (headers, body)
+            }),
+        }
+    }
+}
+
+/// A service forwarding the transactions to a milter, using the version 6 of the protocol.
+///
+/// A session is opened with the milter for each SMTP connection, and carried
+/// over the stages of the transaction.
+///
+/// See <https://github.com/emersion/go-milter/blob/master/milter-protocol.txt>
+#[derive(Debug)]
+pub struct Milter {
+    /// Address of the milter.
+    pub address: Address,
+    /// Timeout of an exchange with the milter.
+    pub timeout: std::time::Duration,
+    /// Duration after which an unused session is closed.
+    pub idle_timeout: std::time::Duration,
+    /// Sessions opened with the milter, by SMTP connection.
+    sessions: std::sync::Mutex<std::collections::HashMap<uuid::Uuid, Session>>,
+}
+
+impl std::fmt::Display for Milter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "milter:{}", self.address)
+    }
+}
+
+impl Milter {
+    /// Create a service for the milter at `address`.
+    #[must_use]
+    pub fn new(
+        address: Address,
+        timeout: std::time::Duration,
+        idle_timeout: std::time::Duration,
+    ) -> Self {
+        Self {
+            address,
+            timeout,
+            idle_timeout,
+            sessions: std::sync::Mutex::default(),
+        }
+    }
+
+    /// Send the stages of the `transaction` not yet sent to the milter and return its decision.
+    ///
+    /// The session is closed once the transaction is rejected.
+    ///
+    /// # Errors
+    ///
+    /// * the milter cannot be reached, or did not reply before the timeout.
+    /// * the milter does not follow the protocol.
+    pub async fn check(&self, transaction: &Transaction) -> anyhow::Result<Outcome> {
+        let session = {
+            let mut sessions = self.sessions.lock().expect("mutex poisoned");
+            sessions.retain(|_, session| session.last_used.elapsed() < self.idle_timeout);
+            sessions.remove(&transaction.connection_uuid)
+        };
+
+        let result = tokio::time::timeout(self.timeout, async {
+            let mut session = match session {
+                Some(session) => session,
+                None => Session::open(&self.address).await?,
+            };
+            let outcome = session.advance(transaction).await?;
+            anyhow::Ok((session, outcome))
+        })
+        .await
+        .map_err(|_elapsed| anyhow::anyhow!("no reply within {:?}", self.timeout))
+        .and_then(|result| result)
+        .map_err(|error| error.context(format!("milter at '{}'", self.address)))?;
+
+        let (mut session, outcome) = result;
+        if matches!(
+            outcome.decision,
+            Decision::Continue | Decision::Discard | Decision::Quarantine(_)
+        ) {
+            session.last_used = std::time::Instant::now();
+            self.sessions
+                .lock()
+                .expect("mutex poisoned")
+                .insert(transaction.connection_uuid, session);
+        }
+
+        Ok(outcome)
+    }
+}
+
+/// The stages of the transaction already sent to the milter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Step {
+    Negotiated,
+    Connect,
+    Helo,
+    MailFrom,
+    Message,
+}
+
+/// The scope of an `accept` or `discard` reply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Scope {
+    Connection,
+    Message,
+}
+
+/// Reply of the milter to a command.
+#[derive(Debug)]
+enum Response {
+    Accept,
+    Continue,
+    Discard,
+    Reject,
+    Tempfail,
+    ReplyCode(String),
+    Skip,
+}
+
+/// A session with the milter, following an SMTP connection.
+struct Session {
+    stream: Box<dyn Stream>,
+    /// Protocol flags negotiated with the milter.
+    protocol: u32,
+    step: Step,
+    last_used: std::time::Instant,
+    /// Message currently sent to the milter.
+    message_uuid: Option<uuid::Uuid>,
+    rcpt_sent: usize,
+    skip: Option<Scope>,
+    discard: Option<Scope>,
+    quarantine: Option<String>,
+    body: Option<String>,
+    modifications: Vec<Modification>,
+}
+
+impl std::fmt::Debug for Session {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Session")
+            .field("protocol", &self.protocol)
+            .field("step", &self.step)
+            .field("message_uuid", &self.message_uuid)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Session {
+    /// Connect to the milter and negotiate the options of the session.
+    async fn open(address: &Address) -> anyhow::Result<Self> {
+        let stream = address.connect().await?;
+        let mut session = Self {
+            stream,
+            protocol: 0,
+            step: Step::Negotiated,
+            last_used: std::time::Instant::now(),
+            message_uuid: None,
+            rcpt_sent: 0,
+            skip: None,
+            discard: None,
+            quarantine: None,
+            body: None,
+            modifications: vec![],
+        };
+
+        let options = [VERSION, ACTIONS, PROTOCOL]
+            .iter()
+            .flat_map(|option| option.to_be_bytes())
+            .collect::<Vec<_>>();
+        session.write(b'O', &options).await?;
+
+        let (command, data) = session.read().await?;
+        anyhow::ensure!(
+            command == b'O' && data.len() >= 12,
+            "unexpected reply to the negotiation '{}'",
+            char::from(command)
+        );
+        let option = |index: usize| {
+            u32::from_be_bytes([
+                data[index * 4],
+                data[index * 4 + 1],
+                data[index * 4 + 2],
+                data[index * 4 + 3],
+            ])
+        };
+        let (version, actions, protocol) = (option(0), option(1), option(2));
+        anyhow::ensure!(
+            (2..=VERSION).contains(&version),
+            "unsupported protocol version {version}"
+        );
+        anyhow::ensure!(
+            actions & !ACTIONS == 0,
+            "unsupported actions requested {actions:#x}"
+        );
+        anyhow::ensure!(
+            protocol & !PROTOCOL == 0,
+            "unsupported protocol flags requested {protocol:#x}"
+        );
+        session.protocol = protocol;
+
+        Ok(session)
+    }
+
+    /// Send the stages of the transaction not yet sent, and return the decision of the milter.
+    async fn advance(&mut self, transaction: &Transaction) -> anyhow::Result<Outcome> {
+        let message_uuid = transaction.mail_from.as_ref().map(|(uuid, _)| *uuid);
+        if self.step >= Step::MailFrom && self.message_uuid != message_uuid {
+            if self.step < Step::Message || self.skip == Some(Scope::Message) {
+                self.write(b'A', &[]).await?;
+            }
+            self.reset_message();
+        }
+
+        let decision = match self.exchange(transaction).await? {
+            Some(decision) => decision,
+            None if self.discard.is_some() && transaction.message.is_some() => Decision::Discard,
+            None => Decision::Continue,
+        };
+
+        Ok(Outcome {
+            decision,
+            modifications: std::mem::take(&mut self.modifications),
+        })
+    }
+
+    /// Send the commands of each stage not yet sent, stopping at the first final decision.
+    #[allow(clippy::too_many_lines)]
+    async fn exchange(&mut self, transaction: &Transaction) -> anyhow::Result<Option<Decision>> {
+        macro_rules! handle {
+            ($response:expr, $scope:expr) => {
+                if let ControlFlow::Break(decision) = self.handle($response, $scope) {
+                    return Ok(decision);
+                }
+            };
+        }
+
+        if self.skip == Some(Scope::Connection) {
+            return Ok(None);
+        }
+
+        if self.step < Step::Connect {
+            self.step = Step::Connect;
+            let client = transaction.client_addr;
+            let mut data = format!("[{}]\0", client.ip()).into_bytes();
+            data.push(if client.is_ipv4() { b'4' } else { b'6' });
+            data.extend(client.port().to_be_bytes());
+            data.extend(format!("{}\0", client.ip()).into_bytes());
+
+            self.macros(
+                b'C',
+                NO_CONNECT,
+                &[
+                    ("j", &transaction.server_name),
+                    ("{daemon_name}", "vsmtp"),
+                    ("{client_addr}", &client.ip().to_string()),
+                ],
+            )
+            .await?;
+            let response = self.command(b'C', &data, NO_CONNECT, NR_CONNECT).await?;
+            handle!(response, Scope::Connection);
+        }
+
+        let Some(helo) = &transaction.helo else {
+            return Ok(None);
+        };
+        if self.step < Step::Helo {
+            self.step = Step::Helo;
+            let response = self
+                .command(b'H', format!("{helo}\0").as_bytes(), NO_HELO, NR_HELO)
+                .await?;
+            handle!(response, Scope::Connection);
+        }
+
+        let Some((message_uuid, reverse_path)) = &transaction.mail_from else {
+            return Ok(None);
+        };
+        if self.skip == Some(Scope::Message) {
+            return Ok(None);
+        }
+        if self.step < Step::MailFrom {
+            self.step = Step::MailFrom;
+            self.message_uuid = Some(*message_uuid);
+            let reverse_path = reverse_path.as_deref().unwrap_or_default();
+
+            self.macros(
+                b'M',
+                NO_MAIL,
+                &[
+                    ("i", &message_uuid.to_string()),
+                    ("{mail_addr}", reverse_path),
+                ],
+            )
+            .await?;
+            let response = self
+                .command(
+                    b'M',
+                    format!("<{reverse_path}>\0").as_bytes(),
+                    NO_MAIL,
+                    NR_MAIL,
+                )
+                .await?;
+            handle!(response, Scope::Message);
+        }
+
+        while let Some(forward_path) = transaction.rcpt_to.get(self.rcpt_sent) {
+            self.rcpt_sent += 1;
+            self.macros(b'R', NO_RCPT, &[("{rcpt_addr}", forward_path)])
+                .await?;
+            let response = self
+                .command(
+                    b'R',
+                    format!("<{forward_path}>\0").as_bytes(),
+                    NO_RCPT,
+                    NR_RCPT,
+                )
+                .await?;
+            handle!(response, Scope::Message);
+        }
+
+        let Some((headers, body)) = &transaction.message else {
+            return Ok(None);
+        };
+        if self.step < Step::Message {
+            self.step = Step::Message;
+            let response = self.command(b'T', &[], NO_DATA, NR_DATA).await?;
+            handle!(response, Scope::Message);
+
+            for (name, value) in headers {
+                let response = self
+                    .command(
+                        b'L',
+                        format!("{name}\0{value}\0").as_bytes(),
+                        NO_HEADERS,
+                        NR_HEADER,
+                    )
+                    .await?;
+                handle!(response, Scope::Message);
+            }
+            let response = self.command(b'N', &[], NO_EOH, NR_EOH).await?;
+            handle!(response, Scope::Message);
+
+            for chunk in body.as_bytes().chunks(CHUNK_SIZE) {
+                let response = self.command(b'B', chunk, NO_BODY, NR_BODY).await?;
+                if matches!(response, Response::Skip) {
+                    break;
+                }
+                handle!(response, Scope::Message);
+            }
+            let response = self.command(b'E', &[], 0, 0).await?;
+            if let Some(body) = self.body.take() {
+                self.modifications.push(Modification::ReplaceBody(body));
+            }
+            handle!(response, Scope::Message);
+
+            if let Some(reason) = self.quarantine.take() {
+                return Ok(Some(Decision::Quarantine(reason)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Map the reply of the milter, breaking if no more command must be sent.
+    fn handle(&mut self, response: Response, scope: Scope) -> ControlFlow<Option<Decision>> {
+        match response {
+            Response::Continue | Response::Skip => ControlFlow::Continue(()),
+            Response::Accept => {
+                self.skip = Some(scope);
+                ControlFlow::Break(None)
+            }
+            Response::Discard => {
+                self.skip = Some(scope);
+                self.discard = Some(scope);
+                ControlFlow::Break(None)
+            }
+            Response::Reject => ControlFlow::Break(Some(Decision::Reject)),
+            Response::Tempfail => ControlFlow::Break(Some(Decision::Tempfail)),
+            Response::ReplyCode(reply) => ControlFlow::Break(Some(Decision::Reply(reply))),
+        }
+    }
+
+    /// Forget the state of the previous message.
+    fn reset_message(&mut self) {
+        self.step = Step::Helo;
+        self.message_uuid = None;
+        self.rcpt_sent = 0;
+        if self.skip == Some(Scope::Message) {
+            self.skip = None;
+        }
+        if self.discard == Some(Scope::Message) {
+            self.discard = None;
+        }
+        self.quarantine = None;
+        self.body = None;
+        self.modifications.clear();
+    }
+
+    /// Send the macros defined for the command `command`, unless the milter asked to skip it.
+    async fn macros(
+        &mut self,
+        command: u8,
+        no: u32,
+        macros: &[(&str, &str)],
+    ) -> anyhow::Result<()> {
+        if self.protocol & no != 0 {
+            return Ok(());
+        }
+        let mut data = vec![command];
+        for (name, value) in macros {
+            data.extend(format!("{name}\0{value}\0").into_bytes());
+        }
+        self.write(b'D', &data).await
+    }
+
+    /// Send a command, and wait for the reply unless the milter asked not to.
+    async fn command(
+        &mut self,
+        command: u8,
+        data: &[u8],
+        no: u32,
+        no_reply: u32,
+    ) -> anyhow::Result<Response> {
+        if self.protocol & no != 0 {
+            return Ok(Response::Continue);
+        }
+        self.write(command, data).await?;
+        if self.protocol & no_reply != 0 {
+            return Ok(Response::Continue);
+        }
+
+        loop {
+            let (response, data) = self.read().await?;
+            let mut arguments = data
+                .split(|c| *c == b'\0')
+                .map(|argument| String::from_utf8_lossy(argument).to_string());
+            let mut argument = || arguments.next().unwrap_or_default();
+
+            match response {
+                b'a' => return Ok(Response::Accept),
+                b'c' => return Ok(Response::Continue),
+                b'd' => return Ok(Response::Discard),
+                b'r' => return Ok(Response::Reject),
+                b't' => return Ok(Response::Tempfail),
+                b's' => return Ok(Response::Skip),
+                b'y' => return Ok(Response::ReplyCode(argument())),
+                b'p' => {}
+                b'q' => self.quarantine = Some(argument()),
+                b'h' => self.modifications.push(Modification::AddHeader {
+                    name: argument(),
+                    value: argument(),
+                }),
+                b'i' | b'm' => {
+                    anyhow::ensure!(data.len() >= 4, "invalid header modification");
+                    let index = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+                    let mut arguments = data[4..]
+                        .split(|c| *c == b'\0')
+                        .map(|argument| String::from_utf8_lossy(argument).to_string());
+                    let name = arguments.next().unwrap_or_default();
+                    let value = arguments.next().unwrap_or_default();
+                    self.modifications.push(if response == b'i' {
+                        Modification::InsertHeader { index, name, value }
+                    } else {
+                        Modification::ChangeHeader { index, name, value }
+                    });
+                }
+                b'+' | b'2' => self.modifications.push(Modification::AddRcpt(argument())),
+                b'-' => self
+                    .modifications
+                    .push(Modification::DeleteRcpt(argument())),
+                b'e' => self
+                    .modifications
+                    .push(Modification::ChangeFrom(argument())),
+                b'b' => self
+                    .body
+                    .get_or_insert_with(String::new)
+                    .push_str(&String::from_utf8_lossy(&data)),
+                otherwise => {
+                    anyhow::bail!("unexpected reply '{}'", char::from(otherwise))
+                }
+            }
+        }
+    }
+
+    async fn write(&mut self, command: u8, data: &[u8]) -> anyhow::Result<()> {
+        let length = u32::try_from(data.len() + 1)?;
+        let mut packet = Vec::with_capacity(data.len() + 5);
+        packet.extend(length.to_be_bytes());
+        packet.push(command);
+        packet.extend(data);
+        self.stream.write_all(&packet).await?;
+        Ok(())
+    }
+
+    async fn read(&mut self) -> anyhow::Result<(u8, Vec<u8>)> {
+        let length = self.stream.read_u32().await? as usize;
+        anyhow::ensure!(
+            (1..=PACKET_SIZE_MAX).contains(&length),
+            "invalid packet length {length}"
+        );
+        let mut packet = vec![0; length];
+        self.stream.read_exact(&mut packet).await?;
+        let data = packet.split_off(1);
+        Ok((packet[0], data))
+    }
+}
@@ -0,0 +1,344 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+*/
+
+use rhai::plugin::{
+    mem, Dynamic, FnAccess, FnNamespace, NativeCallContext, PluginFunction, RhaiResult, TypeId,
+};
+use rhai::Module;
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RspamdParameters {
+    /// Address of the normal worker, `host:port` or the path of a Unix socket.
+    address: crate::dsl::address::Address,
+    /// Timeout of a scan.
+    #[serde(default = "default_timeout", with = "humantime_serde")]
+    timeout: std::time::Duration,
+    /// Password of the controller.
+    password: Option<String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SpamdParameters {
+    /// Address of the daemon, `host:port` or the path of a Unix socket.
+    address: crate::dsl::address::Address,
+    /// Timeout of a scan.
+    #[serde(default = "default_timeout", with = "humantime_serde")]
+    timeout: std::time::Duration,
+    /// User whose preferences are used.
+    user: Option<String>,
+    /// Command sent to the daemon.
+    #[serde(default = "default_command")]
+    command: super::service::SpamdCommand,
+}
+
+const fn default_timeout() -> std::time::Duration {
+    std::time::Duration::from_secs(30)
+}
+
+const fn default_command() -> super::service::SpamdCommand {
+    super::service::SpamdCommand::Report
+}
+
+/// Build the `X-Spam-*` headers from the result of a scan,
+/// or `None` if the message has not been scanned.
+fn x_spam_headers(result: &rhai::Map) -> Option<[(&'static str, String); 5]> {
+    let score = result.get("score")?.as_float().ok()?;
+    let required_score = result.get("required_score")?.as_float().ok()?;
+    let is_spam = result.get("is_spam")?.as_bool().ok()?;
+    let action = result.get("action")?.clone().into_string().ok()?;
+    let tests = result
+        .get("symbols")?
+        .read_lock::<rhai::Map>()?
+        .keys()
+        .map(rhai::Identifier::as_str)
+        .collect::<Vec<_>>()
+        .join(",");
+
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    let level = "*".repeat(score.clamp(0.0, 50.0) as usize);
+
+    Some([
+        (
+            "X-Spam-Flag",
+            if is_spam { "YES" } else { "NO" }.to_string(),
+        ),
+        ("X-Spam-Score", format!("{score:.2}")),
+        ("X-Spam-Level", level),
+        (
+            "X-Spam-Status",
+            format!(
+                "{}, score={score:.2} required={required_score:.2} tests={tests}",
+                if is_spam { "Yes" } else { "No" }
+            ),
+        ),
+        ("X-Spam-Action", action),
+    ])
+}
+
+/// This module exposes the `rspamd` and `spamd` services, scanning the messages for spam.
+#[rhai::plugin::export_module]
+pub mod spam {
+    use crate::api::EngineResult;
+    use crate::{get_global, vsl_guard_ok};
+
+    type Scanner = rhai::Shared<crate::dsl::spam::service::Scanner>;
+
+    /// Create a service scanning the messages with rspamd.
+    ///
+    /// The message is sent to the `/checkv2` endpoint of the normal worker,
+    /// with the envelop (client address, helo, sender, recipients, authenticated user).
+    ///
+    /// # Args
+    ///
+    /// * `parameters` - a map of the following parameters:
+    ///     * `address` - the address of the normal worker, `host:port` (port `11333` by default
+    ///       in rspamd) or the path of a Unix socket.
+    ///     * `timeout` - a duration after which the scan fails. (optional, default: 30s)
+    ///     * `password` - the password of the controller, if any. (optional)
+    ///
+    /// # Return
+    ///
+    /// A service used to scan the messages.
+    ///
+    /// # Error
+    ///
+    /// * The service failed to parse the parameters.
+    ///
+    /// # Example
+    ///
+    /// ```text
+    /// // declared in /etc/vsmtp/services/spam.vsl
+    /// export const rspamd = spam::rspamd(#{
+    ///     address: "127.0.0.1:11333",
+    ///     timeout: "10s",
+    /// });
+    /// ```
+    ///
+    /// # rhai-autodocs:index:1
+    #[rhai_fn(return_raw)]
+    pub fn rspamd(parameters: rhai::Map) -> EngineResult<Scanner> {
+        let parameters = rhai::serde::from_dynamic::<super::RspamdParameters>(&parameters.into())?;
+
+        Ok(rhai::Shared::new(crate::dsl::spam::service::Scanner {
+            protocol: crate::dsl::spam::service::Protocol::Rspamd {
+                password: parameters.password,
+            },
+            address: parameters.address,
+            timeout: parameters.timeout,
+        }))
+    }
+
+    /// Create a service scanning the messages with `SpamAssassin`'s spamd.
+    ///
+    /// The message is sent with the `REPORT` or `SYMBOLS` command of the `SPAMC/1.5` protocol,
+    /// the envelop is not sent to spamd.
+    ///
+    /// # Args
+    ///
+    /// * `parameters` - a map of the following parameters:
+    ///     * `address` - the address of the daemon, `host:port` (port `783` by default in spamd)
+    ///       or the path of a Unix socket.
+    ///     * `timeout` - a duration after which the scan fails. (optional, default: 30s)
+    ///     * `user` - the user whose preferences are used. (optional)
+    ///     * `command` - "report" to get the score and the description of the symbols,
+    ///       or "symbols" to get their name only. (optional, default: "report")
+    ///
+    /// # Return
+    ///
+    /// A service used to scan the messages.
+    ///
+    /// # Error
+    ///
+    /// * The service failed to parse the parameters.
+    ///
+    /// # Example
+    ///
+    /// ```text
+    /// // declared in /etc/vsmtp/services/spam.vsl
+    /// export const spamd = spam::spamd(#{
+    ///     address: "127.0.0.1:783",
+    ///     user: "vsmtp",
+    /// });
+    /// ```
+    ///
+    /// # rhai-autodocs:index:2
+    #[rhai_fn(return_raw)]
+    pub fn spamd(parameters: rhai::Map) -> EngineResult<Scanner> {
+        let parameters = rhai::serde::from_dynamic::<super::SpamdParameters>(&parameters.into())?;
+
+        Ok(rhai::Shared::new(crate::dsl::spam::service::Scanner {
+            protocol: crate::dsl::spam::service::Protocol::Spamd {
+                user: parameters.user,
+                command: parameters.command,
+            },
+            address: parameters.address,
+            timeout: parameters.timeout,
+        }))
+    }
+
+    /// Scan the message with the daemon.
+    ///
+    /// # Return
+    ///
+    /// A map of the following fields:
+    /// * `score` - the score of the message.
+    /// * `required_score` - the score from which the message is a spam.
+    /// * `action` - the action suggested by the scanner: "no action", "greylist", "add header",
+    ///   "rewrite subject", "soft reject" or "reject". spamd only suggests "no action" or "add header".
+    /// * `is_spam` - `true` if the action is not "no action" or "greylist".
+    /// * `symbols` - a map of the rules matched, by name, with their `score`, `description`
+    ///   and `options`. The `score` and the `description` are `()` with the spamd `symbols` command.
+    /// * `add_headers` - a map of the headers the scanner suggests to add.
+    /// * `remove_headers` - an array of the headers the scanner suggests to remove.
+    /// * `subject` - the subject the scanner suggests to use, or `()`.
+    /// * `report` - the report of spamd with the `report` command, or `()`.
+    /// * `error` - the reason why the message could not be scanned, or `()`.
+    ///   The other fields are then `()`, and `is_spam` is `false`.
+    ///
+    /// # Effective smtp stage
+    ///
+    /// `preq` and onwards.
+    ///
+    /// # Example
+    ///
+    /// ```text
+    /// import "services/spam" as svc;
+    ///
+    /// #{
+    ///     preq: [
+    ///         rule "antispam" || {
+    ///             let result = svc::rspamd.scan();
+    ///
+    ///             if result.action == "reject" {
+    ///                 state::deny(code::c554_7_1())
+    ///             } else {
+    ///                 spam::add_headers(result);
+    ///                 spam::apply_headers(result);
+    ///                 state::next()
+    ///             }
+    ///         },
+    ///     ]
+    /// }
+    /// ```
+    ///
+    /// # rhai-autodocs:index:3
+    #[rhai_fn(global, name = "scan", pure)]
+    pub fn scan(ncc: NativeCallContext, scanner: &mut Scanner) -> rhai::Map {
+        let envelop =
+            crate::dsl::spam::service::Envelop::new(&vsl_guard_ok!(get_global!(ncc, ctx).read()));
+        let message = vsl_guard_ok!(get_global!(ncc, msg).read())
+            .inner()
+            .to_string();
+
+        let scanner = &**scanner;
+        let result = block_on!(scanner.scan(&envelop, message.as_bytes()));
+        match &result {
+            Ok(verdict) => tracing::debug!(
+                service = %scanner,
+                score = verdict.score,
+                action = verdict.action,
+                "Message scanned."
+            ),
+            Err(error) => tracing::warn!(service = %scanner, %error, "Message not scanned."),
+        }
+
+        crate::dsl::spam::service::Verdict::to_map(result)
+    }
+
+    /// Add the `X-Spam-Flag`, `X-Spam-Score`, `X-Spam-Level`, `X-Spam-Status` and `X-Spam-Action`
+    /// headers on top of the message, from the result of a scan.
+    ///
+    /// The headers of the same name already in the message are removed,
+    /// and nothing is done if the message has not been scanned.
+    ///
+    /// ```text
+    /// X-Spam-Flag: YES
+    /// X-Spam-Score: 7.10
+    /// X-Spam-Level: *******
+    /// X-Spam-Status: Yes, score=7.10 required=5.00 tests=MISSING_MID,URIBL_BLOCKED
+    /// X-Spam-Action: add header
+    /// ```
+    ///
+    /// # Args
+    ///
+    /// * `result` - the result of `scan()`.
+    ///
+    /// # Effective smtp stage
+    ///
+    /// `preq` and onwards.
+    ///
+    /// # rhai-autodocs:index:4
+    #[rhai_fn(name = "add_headers")]
+    pub fn add_headers(ncc: NativeCallContext, result: rhai::Map) {
+        let Some(headers) = super::x_spam_headers(&result) else {
+            return;
+        };
+
+        let msg = get_global!(ncc, msg);
+        let mut msg = vsl_guard_ok!(msg.write());
+        for (name, value) in headers.iter().rev() {
+            while msg.remove_header(name) {}
+            msg.prepend_header(name, value);
+        }
+    }
+
+    /// Apply the headers edits suggested by the scanner: remove the headers of `remove_headers`,
+    /// add the headers of `add_headers` on top of the message and replace the subject.
+    ///
+    /// # Args
+    ///
+    /// * `result` - the result of `scan()`.
+    ///
+    /// # Effective smtp stage
+    ///
+    /// `preq` and onwards.
+    ///
+    /// # rhai-autodocs:index:5
+    #[rhai_fn(name = "apply_headers")]
+    pub fn apply_headers(ncc: NativeCallContext, result: rhai::Map) {
+        let msg = get_global!(ncc, msg);
+        let mut msg = vsl_guard_ok!(msg.write());
+
+        if let Some(remove) = result
+            .get("remove_headers")
+            .and_then(rhai::Dynamic::read_lock::<rhai::Array>)
+        {
+            for name in remove
+                .iter()
+                .filter_map(|name| name.clone().into_string().ok())
+            {
+                while msg.remove_header(&name) {}
+            }
+        }
+        if let Some(add) = result
+            .get("add_headers")
+            .and_then(rhai::Dynamic::read_lock::<rhai::Map>)
+        {
+            for (name, value) in add.iter() {
+                msg.prepend_header(name, &value.to_string());
+            }
+        }
+        if let Some(subject) = result
+            .get("subject")
+            .and_then(|subject| subject.clone().into_string().ok())
+        {
+            msg.set_header("Subject", &subject);
+        }
+    }
+}
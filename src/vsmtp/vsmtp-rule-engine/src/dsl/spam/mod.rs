@@ -0,0 +1,391 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+*/
+
+pub mod api;
+pub mod service;
+
+/// Create a new spam module.
+#[must_use]
+pub fn new_module() -> rhai::Module {
+    let mut module = rhai::exported_module!(api::spam);
+
+    module.set_id("spam");
+
+    module
+}
+
+#[cfg(test)]
+mod tests {
+    use super::service::{Envelop, Protocol, Scanner, SpamdCommand, Symbol, Verdict};
+    use crate::dsl::address::Address;
+    use crate::tests::service::{
+        compile, run_connect, tcp_daemon, tcp_daemon_thread, unix_daemon, unreachable_address,
+    };
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+
+    const RSPAMD_REPLY: &str = r#"{
+    "is_skipped": false,
+    "score": 7.1,
+    "required_score": 15.0,
+    "action": "add header",
+    "symbols": {
+        "BAYES_SPAM": { "name": "BAYES_SPAM", "score": 5.1, "description": "Message probably spam", "options": ["99.9%"] },
+        "MISSING_MID": { "name": "MISSING_MID", "score": 2.0 }
+    },
+    "milter": {
+        "add_headers": {
+            "X-Spamd-Bar": { "value": "+++++++", "order": 0 },
+            "X-Rspamd-Server": "localhost"
+        },
+        "remove_headers": { "X-Spam": 0 }
+    },
+    "subject": "[SPAM] hello"
+}"#;
+
+    const SPAMD_REPORT: &str = "Spam detection software, running on the system \"localhost\",
+has identified this incoming email as possible spam.
+
+Content analysis details:   (7.1 points, 5.0 required)
+
+ pts rule name              description
+---- ---------------------- --------------------------------------------------
+ 5.1 BAYES_99               BODY: Bayes spam probability is 99 to 100%
+                            [score: 1.0000]
+ 2.0 MISSING_MID            Missing Message-Id: header
+";
+
+    /// Read a request made of a first line, headers and a body of `Content-Length` bytes.
+    async fn read_request<S: tokio::io::AsyncRead + Unpin>(
+        stream: &mut tokio::io::BufReader<S>,
+    ) -> (Vec<String>, Vec<u8>) {
+        let mut head = vec![];
+        loop {
+            let mut line = String::new();
+            stream.read_line(&mut line).await.unwrap();
+            let line = line.trim_end().to_string();
+            if line.is_empty() {
+                break;
+            }
+            head.push(line);
+        }
+        let length = head
+            .iter()
+            .find_map(|line| {
+                line.to_lowercase()
+                    .strip_prefix("content-length: ")
+                    .map(|length| length.parse::<usize>().unwrap())
+            })
+            .unwrap();
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).await.unwrap();
+        (head, body)
+    }
+
+    /// Answer one request as rspamd would, returning the request.
+    async fn fake_rspamd(stream: tokio::net::TcpStream, status: &str) -> (Vec<String>, Vec<u8>) {
+        let mut stream = tokio::io::BufReader::new(stream);
+        let request = read_request(&mut stream).await;
+        let body = if status == "200 OK" { RSPAMD_REPLY } else { "" };
+        stream
+            .write_all(
+                format!(
+                    "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}",
+                    body.len()
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        request
+    }
+
+    /// Answer one request as spamd would.
+    async fn fake_spamd<S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin>(stream: S) {
+        let mut stream = tokio::io::BufReader::new(stream);
+        let (head, _) = read_request(&mut stream).await;
+        let body = match head[0].as_str() {
+            "REPORT SPAMC/1.5" => SPAMD_REPORT,
+            "SYMBOLS SPAMC/1.5" => "BAYES_99,MISSING_MID",
+            _ => unreachable!(),
+        };
+        stream
+            .write_all(
+                format!(
+                    "SPAMD/1.1 0 EX_OK\r\nContent-length: {}\r\nSpam: True ; 7.1 / 5.0\r\n\r\n{body}",
+                    body.len()
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+    }
+
+    fn scanner(protocol: Protocol, address: Address) -> Scanner {
+        Scanner {
+            protocol,
+            address,
+            timeout: std::time::Duration::from_secs(5),
+        }
+    }
+
+    #[tokio::test]
+    async fn rspamd() {
+        let (requests, mut received) = tokio::sync::mpsc::unbounded_channel();
+        let address = tcp_daemon(move |stream| {
+            let requests = requests.clone();
+            async move {
+                requests.send(fake_rspamd(stream, "200 OK").await).unwrap();
+            }
+        })
+        .await;
+
+        let scanner = scanner(
+            Protocol::Rspamd {
+                password: Some("secret".to_string()),
+            },
+            Address::Tcp(address.to_string()),
+        );
+        let envelop = Envelop {
+            client_ip: Some("192.168.1.1".parse().unwrap()),
+            helo: Some("client.com".to_string()),
+            mail_from: Some(String::new()),
+            rcpt_to: vec!["jane@doe.com".to_string(), "jim@doe.com".to_string()],
+            message_uuid: None,
+            server_name: Some("testserver.com".to_string()),
+            user: None,
+        };
+        let verdict = scanner
+            .scan(&envelop, b"Subject: hello\r\n\r\nworld\r\n")
+            .await
+            .unwrap();
+
+        let (head, body) = received.recv().await.unwrap();
+        assert_eq!(
+            head,
+            [
+                "POST /checkv2 HTTP/1.0".to_string(),
+                format!("Host: {address}"),
+                "Content-Length: 25".to_string(),
+                "IP: 192.168.1.1".to_string(),
+                "Helo: client.com".to_string(),
+                "From: <>".to_string(),
+                "Rcpt: <jane@doe.com>".to_string(),
+                "Rcpt: <jim@doe.com>".to_string(),
+                "MTA-Name: testserver.com".to_string(),
+                "Password: secret".to_string(),
+            ]
+        );
+        assert_eq!(body, b"Subject: hello\r\n\r\nworld\r\n");
+
+        assert_eq!(
+            verdict,
+            Verdict {
+                score: 7.1,
+                required_score: 15.0,
+                action: "add header".to_string(),
+                is_spam: true,
+                symbols: vec![
+                    Symbol {
+                        name: "BAYES_SPAM".to_string(),
+                        score: Some(5.1),
+                        description: Some("Message probably spam".to_string()),
+                        options: vec!["99.9%".to_string()],
+                    },
+                    Symbol {
+                        name: "MISSING_MID".to_string(),
+                        score: Some(2.0),
+                        description: None,
+                        options: vec![],
+                    },
+                ],
+                add_headers: vec![
+                    ("X-Rspamd-Server".to_string(), "localhost".to_string()),
+                    ("X-Spamd-Bar".to_string(), "+++++++".to_string()),
+                ],
+                remove_headers: vec!["X-Spam".to_string()],
+                subject: Some("[SPAM] hello".to_string()),
+                report: None,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn rspamd_error() {
+        let address = tcp_daemon(|stream| async move {
+            fake_rspamd(stream, "403 Forbidden").await;
+        })
+        .await;
+
+        let scanner = scanner(
+            Protocol::Rspamd { password: None },
+            Address::Tcp(address.to_string()),
+        );
+        let map = Verdict::to_map(scanner.scan(&Envelop::default(), b"").await);
+        assert!(!map["is_spam"].as_bool().unwrap());
+        assert!(map["score"].is_unit());
+        assert!(map["error"]
+            .clone()
+            .into_string()
+            .unwrap()
+            .contains("403 Forbidden"));
+    }
+
+    #[tokio::test]
+    async fn spamd() {
+        let (_dir, path) = unix_daemon(fake_spamd);
+
+        let address = Address::Unix(path);
+        let report = scanner(
+            Protocol::Spamd {
+                user: Some("vsmtp".to_string()),
+                command: SpamdCommand::Report,
+            },
+            address.clone(),
+        )
+        .scan(&Envelop::default(), b"Subject: hello\r\n\r\nworld\r\n")
+        .await
+        .unwrap();
+        assert_eq!(
+            report,
+            Verdict {
+                score: 7.1,
+                required_score: 5.0,
+                action: "add header".to_string(),
+                is_spam: true,
+                symbols: vec![
+                    Symbol {
+                        name: "BAYES_99".to_string(),
+                        score: Some(5.1),
+                        description: Some(
+                            "BODY: Bayes spam probability is 99 to 100% [score: 1.0000]"
+                                .to_string()
+                        ),
+                        options: vec![],
+                    },
+                    Symbol {
+                        name: "MISSING_MID".to_string(),
+                        score: Some(2.0),
+                        description: Some("Missing Message-Id: header".to_string()),
+                        options: vec![],
+                    },
+                ],
+                add_headers: vec![],
+                remove_headers: vec![],
+                subject: None,
+                report: Some(SPAMD_REPORT.trim_end().to_string()),
+            }
+        );
+
+        let symbols = scanner(
+            Protocol::Spamd {
+                user: None,
+                command: SpamdCommand::Symbols,
+            },
+            address,
+        )
+        .scan(&Envelop::default(), b"Subject: hello\r\n\r\nworld\r\n")
+        .await
+        .unwrap();
+        assert_eq!(
+            symbols
+                .symbols
+                .iter()
+                .map(|symbol| (symbol.name.as_str(), symbol.score))
+                .collect::<Vec<_>>(),
+            [("BAYES_99", None), ("MISSING_MID", None)]
+        );
+        assert_eq!(symbols.report, None);
+    }
+
+    #[tokio::test]
+    async fn unreachable() {
+        let scanner = scanner(
+            Protocol::Spamd {
+                user: None,
+                command: SpamdCommand::Report,
+            },
+            Address::Tcp(unreachable_address().to_string()),
+        );
+        let error = scanner.scan(&Envelop::default(), b"").await.unwrap_err();
+        assert!(error.to_string().contains("spamd:"));
+    }
+
+    #[test]
+    fn scan_in_rules() {
+        let address = tcp_daemon_thread(|stream| async move {
+            fake_rspamd(stream, "200 OK").await;
+        });
+
+        let (msg, status) = run_connect(format!(
+            r#"
+#{{
+    connect: [
+        rule "antispam" || {{
+            let result = spam::rspamd(#{{ address: "{address}" }}).scan();
+            spam::add_headers(result);
+            spam::apply_headers(result);
+
+            if result.is_spam && result.symbols.BAYES_SPAM.options == ["99.9%"] {{
+                state::accept()
+            }} else {{
+                state::deny()
+            }}
+        }},
+    ]
+}}
+"#
+        ));
+        assert!(
+            matches!(status, vsmtp_common::status::Status::Accept(_)),
+            "{status:?}"
+        );
+        assert_eq!(msg.get_header("X-Spam-Flag").as_deref(), Some("YES"));
+        assert_eq!(msg.get_header("X-Spam-Score").as_deref(), Some("7.10"));
+        assert_eq!(msg.get_header("X-Spam-Level").as_deref(), Some("*******"));
+        assert_eq!(
+            msg.get_header("X-Spam-Status").as_deref(),
+            Some("Yes, score=7.10 required=15.00 tests=BAYES_SPAM,MISSING_MID")
+        );
+        assert_eq!(
+            msg.get_header("X-Spam-Action").as_deref(),
+            Some("add header")
+        );
+        assert_eq!(msg.get_header("X-Spamd-Bar").as_deref(), Some("+++++++"));
+        assert_eq!(msg.get_header("Subject").as_deref(), Some("[SPAM] hello"));
+    }
+
+    #[test]
+    fn parse() {
+        compile(
+            r#"
+const rspamd = spam::rspamd(#{
+    address: "127.0.0.1:11333",
+    timeout: "10s",
+    password: "secret",
+});
+
+const spamd = spam::spamd(#{
+    address: "/run/spamd.sock",
+    user: "vsmtp",
+    command: "symbols",
+});
+
+#{}
+"#,
+        );
+    }
+}
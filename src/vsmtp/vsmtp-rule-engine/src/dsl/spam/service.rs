@@ -0,0 +1,533 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+*/
+
+use crate::dsl::address::Address;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use vsmtp_common::Context;
+
+/// Command sent to spamd.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpamdCommand {
+    /// `REPORT`: the symbols with their score and description.
+    Report,
+    /// `SYMBOLS`: the name of the symbols only.
+    Symbols,
+}
+
+/// The protocol spoken by the scanner.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Protocol {
+    /// The HTTP `/checkv2` endpoint of rspamd.
+    Rspamd {
+        /// Password of the controller, if any.
+        password: Option<String>,
+    },
+    /// The `SPAMC/1.5` protocol of `SpamAssassin`'s spamd.
+    Spamd {
+        /// User whose preferences are used.
+        user: Option<String>,
+        /// Command sent to the daemon.
+        command: SpamdCommand,
+    },
+}
+
+/// A rule matched by the scanner.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    /// Name of the symbol.
+    pub name: String,
+    /// Score of the symbol, unknown with the spamd `SYMBOLS` command.
+    pub score: Option<f64>,
+    /// Description of the symbol.
+    pub description: Option<String>,
+    /// Options of the symbol (the URLs or the DNSBL matched for instance).
+    pub options: Vec<String>,
+}
+
+/// Result of the scan of a message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Verdict {
+    /// Score of the message.
+    pub score: f64,
+    /// Score from which the message is a spam.
+    pub required_score: f64,
+    /// Action suggested by the scanner: "no action", "greylist", "add header",
+    /// "rewrite subject", "soft reject" or "reject".
+    pub action: String,
+    /// Is the message a spam.
+    pub is_spam: bool,
+    /// Rules matched.
+    pub symbols: Vec<Symbol>,
+    /// Headers the scanner suggests to add.
+    pub add_headers: Vec<(String, String)>,
+    /// Headers the scanner suggests to remove.
+    pub remove_headers: Vec<String>,
+    /// Subject the scanner suggests to use.
+    pub subject: Option<String>,
+    /// Report of the scanner, with the spamd `REPORT` command.
+    pub report: Option<String>,
+}
+
+/// The envelop of the message, sent to rspamd.
+#[derive(Debug, Clone, Default)]
+pub struct Envelop {
+    /// Address of the client.
+    pub client_ip: Option<std::net::IpAddr>,
+    /// Argument of the `HELO` / `EHLO` command.
+    pub helo: Option<String>,
+    /// Sender of the message, empty for the null sender.
+    pub mail_from: Option<String>,
+    /// Recipients of the message.
+    pub rcpt_to: Vec<String>,
+    /// Identifier of the message.
+    pub message_uuid: Option<uuid::Uuid>,
+    /// Name of the server.
+    pub server_name: Option<String>,
+    /// Name of the authenticated user.
+    pub user: Option<String>,
+}
+
+impl Envelop {
+    /// Capture the envelop from the context.
+    #[must_use]
+    pub fn new(ctx: &Context) -> Self {
+        Self {
+            client_ip: Some(ctx.client_addr().ip()),
+            helo: ctx.client_name().ok().map(ToString::to_string),
+            mail_from: ctx.reverse_path().ok().map(|reverse_path| {
+                reverse_path
+                    .as_ref()
+                    .map(ToString::to_string)
+                    .unwrap_or_default()
+            }),
+            rcpt_to: ctx
+                .forward_paths()
+                .map(|forward_paths| forward_paths.iter().map(ToString::to_string).collect())
+                .unwrap_or_default(),
+            message_uuid: ctx.message_uuid().ok().copied(),
+            server_name: Some(ctx.server_name().to_string()),
+            user: ctx.auth().as_ref().and_then(|auth| {
+                match (auth.authenticated, &auth.credentials) {
+                    (true, Some(vsmtp_common::auth::Credentials::Verify { authid, .. })) => {
+                        Some(authid.clone())
+                    }
+                    _ => None,
+                }
+            }),
+        }
+    }
+}
+
+/// A service scanning the messages with rspamd or spamd.
+///
+/// See <https://rspamd.com/doc/architecture/protocol.html>
+/// and <https://spamassassin.apache.org/full/3.4.x/doc/spamd.html>
+#[derive(Debug, Clone)]
+pub struct Scanner {
+    /// Protocol spoken by the daemon.
+    pub protocol: Protocol,
+    /// Address of the daemon.
+    pub address: Address,
+    /// Timeout of the whole scan, from the connection to the reply.
+    pub timeout: std::time::Duration,
+}
+
+impl std::fmt::Display for Scanner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.protocol {
+            Protocol::Rspamd { .. } => write!(f, "rspamd:{}", self.address),
+            Protocol::Spamd { .. } => write!(f, "spamd:{}", self.address),
+        }
+    }
+}
+
+impl Scanner {
+    /// Send the `message` to the daemon and return its verdict.
+    ///
+    /// spamd only receives the message, rspamd receives the envelop too.
+    ///
+    /// # Errors
+    ///
+    /// * the daemon cannot be reached, or did not reply before the timeout.
+    /// * the daemon replied with an error.
+    pub async fn scan(&self, envelop: &Envelop, message: &[u8]) -> anyhow::Result<Verdict> {
+        tokio::time::timeout(self.timeout, async {
+            let mut stream = self.address.connect().await?;
+            let request = match &self.protocol {
+                Protocol::Rspamd { password } => {
+                    rspamd_request(&self.address, password.as_deref(), envelop, message)
+                }
+                Protocol::Spamd { user, command } => {
+                    spamd_request(*command, user.as_deref(), message)
+                }
+            };
+            stream.write_all(&request).await?;
+
+            let mut reply = vec![];
+            stream.read_to_end(&mut reply).await?;
+
+            match &self.protocol {
+                Protocol::Rspamd { .. } => parse_rspamd_reply(&reply),
+                Protocol::Spamd { .. } => parse_spamd_reply(&reply),
+            }
+        })
+        .await
+        .map_err(|_elapsed| anyhow::anyhow!("no reply within {:?}", self.timeout))
+        .and_then(|verdict| verdict)
+        .map_err(|error| error.context(format!("{self}")))
+    }
+}
+
+/// Build the `/checkv2` request, the envelop being passed in the HTTP headers.
+fn rspamd_request(
+    address: &Address,
+    password: Option<&str>,
+    envelop: &Envelop,
+    message: &[u8],
+) -> Vec<u8> {
+    let mut headers = vec![
+        ("Host".to_string(), address.to_string()),
+        ("Content-Length".to_string(), message.len().to_string()),
+    ];
+    headers.extend(
+        envelop
+            .client_ip
+            .map(|ip| ("IP".to_string(), ip.to_string())),
+    );
+    headers.extend(envelop.helo.clone().map(|helo| ("Helo".to_string(), helo)));
+    headers.extend(
+        envelop
+            .mail_from
+            .as_ref()
+            .map(|from| ("From".to_string(), format!("<{from}>"))),
+    );
+    headers.extend(
+        envelop
+            .rcpt_to
+            .iter()
+            .map(|rcpt| ("Rcpt".to_string(), format!("<{rcpt}>"))),
+    );
+    headers.extend(
+        envelop
+            .message_uuid
+            .map(|uuid| ("Queue-Id".to_string(), uuid.to_string())),
+    );
+    headers.extend(
+        envelop
+            .server_name
+            .clone()
+            .map(|name| ("MTA-Name".to_string(), name)),
+    );
+    headers.extend(envelop.user.clone().map(|user| ("User".to_string(), user)));
+    headers.extend(password.map(|password| ("Password".to_string(), password.to_string())));
+
+    let mut request = b"POST /checkv2 HTTP/1.0\r\n".to_vec();
+    for (name, value) in headers {
+        // NOTE: the values come from the client, line breaks would inject headers.
+        let value = value.replace(['\r', '\n'], "");
+        request.extend(format!("{name}: {value}\r\n").into_bytes());
+    }
+    request.extend(b"\r\n");
+    request.extend(message);
+    request
+}
+
+fn spamd_request(command: SpamdCommand, user: Option<&str>, message: &[u8]) -> Vec<u8> {
+    let command = match command {
+        SpamdCommand::Report => "REPORT",
+        SpamdCommand::Symbols => "SYMBOLS",
+    };
+    let mut request = format!(
+        "{command} SPAMC/1.5\r\nContent-length: {}\r\n",
+        message.len()
+    )
+    .into_bytes();
+    if let Some(user) = user {
+        request.extend(format!("User: {user}\r\n").into_bytes());
+    }
+    request.extend(b"\r\n");
+    request.extend(message);
+    request
+}
+
+/// First line, headers and body of a reply.
+type ReplyParts<'a> = (String, Vec<(String, String)>, &'a [u8]);
+
+/// Split a reply in its first line, headers and body.
+fn split_reply(reply: &[u8]) -> anyhow::Result<ReplyParts<'_>> {
+    let end = reply
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or_else(|| anyhow::anyhow!("incomplete reply"))?;
+    let head = String::from_utf8_lossy(&reply[..end]);
+    let mut lines = head.split("\r\n");
+    let first = lines.next().unwrap_or_default().to_string();
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+    Ok((first, headers, &reply[end + 4..]))
+}
+
+#[derive(serde::Deserialize)]
+struct RspamdReply {
+    score: f64,
+    required_score: f64,
+    action: String,
+    #[serde(default)]
+    symbols: std::collections::BTreeMap<String, RspamdSymbol>,
+    #[serde(default)]
+    milter: RspamdMilter,
+    subject: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct RspamdSymbol {
+    #[serde(default)]
+    score: f64,
+    description: Option<String>,
+    #[serde(default)]
+    options: Vec<String>,
+}
+
+#[derive(Default, serde::Deserialize)]
+struct RspamdMilter {
+    #[serde(default)]
+    add_headers: std::collections::BTreeMap<String, RspamdHeader>,
+    #[serde(default)]
+    remove_headers: std::collections::BTreeMap<String, serde_json::Value>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum RspamdHeader {
+    Value(String),
+    Object { value: String },
+}
+
+fn parse_rspamd_reply(reply: &[u8]) -> anyhow::Result<Verdict> {
+    let (status, _, body) = split_reply(reply)?;
+    anyhow::ensure!(
+        status.split_whitespace().nth(1) == Some("200"),
+        "unexpected reply '{status}': {}",
+        String::from_utf8_lossy(body).trim()
+    );
+    let reply = serde_json::from_slice::<RspamdReply>(body)?;
+
+    Ok(Verdict {
+        score: reply.score,
+        required_score: reply.required_score,
+        is_spam: !matches!(reply.action.as_str(), "no action" | "greylist"),
+        action: reply.action,
+        symbols: reply
+            .symbols
+            .into_iter()
+            .map(|(name, symbol)| Symbol {
+                name,
+                score: Some(symbol.score),
+                description: symbol.description,
+                options: symbol.options,
+            })
+            .collect(),
+        add_headers: reply
+            .milter
+            .add_headers
+            .into_iter()
+            .map(|(name, header)| match header {
+                RspamdHeader::Value(value) | RspamdHeader::Object { value } => (name, value),
+            })
+            .collect(),
+        remove_headers: reply.milter.remove_headers.into_keys().collect(),
+        subject: reply.subject,
+        report: None,
+    })
+}
+
+fn parse_spamd_reply(reply: &[u8]) -> anyhow::Result<Verdict> {
+    let (status, headers, body) = split_reply(reply)?;
+    anyhow::ensure!(
+        status.starts_with("SPAMD/") && status.split_whitespace().nth(1) == Some("0"),
+        "unexpected reply '{status}'"
+    );
+
+    // Spam: True ; 15.0 / 5.0
+    let spam = headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("Spam"))
+        .map(|(_, value)| value.as_str())
+        .ok_or_else(|| anyhow::anyhow!("no 'Spam' header in the reply"))?;
+    let (flag, scores) = spam
+        .split_once(';')
+        .ok_or_else(|| anyhow::anyhow!("invalid 'Spam' header '{spam}'"))?;
+    let (score, required_score) = scores
+        .split_once('/')
+        .ok_or_else(|| anyhow::anyhow!("invalid 'Spam' header '{spam}'"))?;
+    let is_spam = matches!(flag.trim().to_lowercase().as_str(), "true" | "yes");
+
+    let body = String::from_utf8_lossy(body);
+    let (symbols, report) = if body.contains("\n---- ") {
+        (parse_spamd_report(&body), Some(body.trim_end().to_string()))
+    } else {
+        let symbols = body
+            .trim()
+            .split(',')
+            .filter(|name| !name.is_empty())
+            .map(|name| Symbol {
+                name: name.trim().to_string(),
+                score: None,
+                description: None,
+                options: vec![],
+            })
+            .collect();
+        (symbols, None)
+    };
+
+    Ok(Verdict {
+        score: score.trim().parse()?,
+        required_score: required_score.trim().parse()?,
+        action: if is_spam { "add header" } else { "no action" }.to_string(),
+        is_spam,
+        symbols,
+        add_headers: vec![],
+        remove_headers: vec![],
+        subject: None,
+        report,
+    })
+}
+
+/// Parse the table of the report, following the `---- ----` line:
+///
+/// ```text
+///  pts rule name              description
+/// ---- ---------------------- --------------------------------------------------
+///  0.1 MISSING_MID            Missing Message-Id: header
+/// -0.0 NO_RELAYS              Informational: message was not relayed via SMTP
+/// ```
+fn parse_spamd_report(report: &str) -> Vec<Symbol> {
+    let mut symbols = Vec::<Symbol>::new();
+    for line in report
+        .lines()
+        .skip_while(|line| !line.starts_with("---- "))
+        .skip(1)
+    {
+        let mut columns = line.split_whitespace();
+        match (
+            columns.next().map(str::parse::<f64>),
+            columns.next(),
+            symbols.last_mut(),
+        ) {
+            (Some(Ok(score)), Some(name), _) => symbols.push(Symbol {
+                name: name.to_string(),
+                score: Some(score),
+                description: Some(columns.collect::<Vec<_>>().join(" ")),
+                options: vec![],
+            }),
+            (Some(Err(_)), _, Some(symbol)) if line.starts_with(char::is_whitespace) => {
+                let description = symbol.description.get_or_insert_with(String::new);
+                description.push(' ');
+                description.push_str(line.trim());
+            }
+            _ => {}
+        }
+    }
+    symbols
+}
+
+impl Verdict {
+    /// Map the result of a scan to a rhai map.
+    #[must_use]
+    pub fn to_map(result: anyhow::Result<Self>) -> rhai::Map {
+        fn or_unit<T: Into<rhai::Dynamic>>(value: Option<T>) -> rhai::Dynamic {
+            value.map_or(rhai::Dynamic::UNIT, Into::into)
+        }
+
+        match result {
+            Ok(verdict) => rhai::Map::from_iter([
+                ("score".into(), verdict.score.into()),
+                ("required_score".into(), verdict.required_score.into()),
+                ("action".into(), verdict.action.into()),
+                ("is_spam".into(), verdict.is_spam.into()),
+                (
+                    "symbols".into(),
+                    verdict
+                        .symbols
+                        .into_iter()
+                        .map(|symbol| {
+                            (
+                                symbol.name.into(),
+                                rhai::Map::from_iter([
+                                    ("score".into(), or_unit(symbol.score)),
+                                    ("description".into(), or_unit(symbol.description)),
+                                    (
+                                        "options".into(),
+                                        symbol
+                                            .options
+                                            .into_iter()
+                                            .map(rhai::Dynamic::from)
+                                            .collect::<rhai::Array>()
+                                            .into(),
+                                    ),
+                                ])
+                                .into(),
+                            )
+                        })
+                        .collect::<rhai::Map>()
+                        .into(),
+                ),
+                (
+                    "add_headers".into(),
+                    verdict
+                        .add_headers
+                        .into_iter()
+                        .map(|(name, value)| (name.into(), value.into()))
+                        .collect::<rhai::Map>()
+                        .into(),
+                ),
+                (
+                    "remove_headers".into(),
+                    verdict
+                        .remove_headers
+                        .into_iter()
+                        .map(rhai::Dynamic::from)
+                        .collect::<rhai::Array>()
+                        .into(),
+                ),
+                ("subject".into(), or_unit(verdict.subject)),
+                ("report".into(), or_unit(verdict.report)),
+                ("error".into(), rhai::Dynamic::UNIT),
+            ]),
+            Err(error) => [
+                "score",
+                "required_score",
+                "action",
+                "symbols",
+                "add_headers",
+                "remove_headers",
+                "subject",
+                "report",
+            ]
+            .into_iter()
+            .map(|key| (key.into(), rhai::Dynamic::UNIT))
+            .chain([
+                ("is_spam".into(), false.into()),
+                ("error".into(), format!("{error:#}").into()),
+            ])
+            .collect(),
+        }
+    }
+}
@@ -38,16 +38,30 @@ macro_rules! block_on {
 
 /// DSL specifications for vsl.
 mod dsl {
+    /// Address of the daemons used by the services.
+    pub mod address;
+    /// ClamAV daemon plugin implementation.
+    pub mod clamd;
     /// Command plugin implementation.
     pub mod cmd;
     /// Rules implementation.
     pub mod directives;
+    /// DNS block and allow lists plugin implementation.
+    pub mod dnsbl;
+    /// Milter protocol plugin implementation.
+    pub mod milter;
     /// SMTP plugin implementation.
     pub mod smtp;
+    /// rspamd and spamd plugin implementation.
+    pub mod spam;
 }
 
+pub use dsl::clamd::new_module as new_module_clamd;
 pub use dsl::cmd::new_module as new_module_cmd;
+pub use dsl::dnsbl::new_module as new_module_dnsbl;
+pub use dsl::milter::new_module as new_module_milter;
 pub use dsl::smtp::new_module as new_module_smtp;
+pub use dsl::spam::new_module as new_module_spam;
 pub use rhai;
 
 #[macro_use]
@@ -114,6 +128,8 @@ pub mod api {
     pub mod message;
     /// Default network ranges exposed by vsmtp.
     pub mod net;
+    /// Administration of the quarantined messages.
+    pub mod quarantine;
     /// backend for SPF functionality.
     pub mod spf;
     /// State Engine & filtering backend.
@@ -147,7 +163,7 @@ pub mod api {
 
     /// Get vsmtp static modules.
     #[must_use]
-    pub fn vsmtp_static_modules() -> [(&'static str, rhai::Module); 20] {
+    pub fn vsmtp_static_modules() -> [(&'static str, rhai::Module); 25] {
         [
             ("state", rhai::exported_module!(state)),
             ("envelop", rhai::exported_module!(envelop)),
@@ -165,10 +181,15 @@ pub mod api {
             ("utils", rhai::exported_module!(utils)),
             ("ctx", rhai::exported_module!(mail_context)),
             ("msg", rhai::exported_module!(message)),
+            ("quarantine", rhai::exported_module!(quarantine)),
             ("obj", vsmtp_plugin_vsl::object_module()),
             ("unix", vsmtp_plugin_vsl::unix_module()),
             ("cmd", crate::dsl::cmd::new_module()),
             ("smtp", crate::dsl::smtp::new_module()),
+            ("clamd", crate::dsl::clamd::new_module()),
+            ("milter", crate::dsl::milter::new_module()),
+            ("spam", crate::dsl::spam::new_module()),
+            ("dnsbl", crate::dsl::dnsbl::new_module()),
         ]
     }
 }
@@ -275,6 +275,7 @@ impl RuleEngine {
             server: self.server.clone(),
             mail_context,
             message,
+            results: std::sync::Mutex::default(),
         })
     }
 
@@ -440,6 +441,11 @@ impl RuleEngine {
         };
 
         let status = Script::execute(rule_state, script.ast(), directive, smtp_state);
+        rule_state
+            .results
+            .lock()
+            .expect("Mutex poisoned")
+            .push((smtp_state, status.clone()));
 
         if status.is_finished() {
             tracing::info!(
@@ -14,7 +14,11 @@
  * this program. If not, see https://www.gnu.org/licenses/.
  *
 */
-use crate::api::{Context, Message, Server};
+use crate::{
+    api::{Context, Message, Server},
+    ExecutionStage,
+};
+use vsmtp_common::status::Status;
 use vsmtp_mail_parser::MessageBody;
 
 /// a state container that bridges rhai's & rust contexts.
@@ -24,6 +28,7 @@ pub struct RuleState {
     pub(super) server: Server,
     pub(super) mail_context: Context,
     pub(super) message: Message,
+    pub(super) results: std::sync::Mutex<Vec<(ExecutionStage, Status)>>,
 }
 
 impl RuleState {
@@ -39,6 +44,12 @@ impl RuleState {
         self.message.clone()
     }
 
+    /// fetch the status returned by the rules of each stage run with this state, in order.
+    #[must_use]
+    pub fn results(&self) -> Vec<(ExecutionStage, Status)> {
+        self.results.lock().expect("Mutex poisoned").clone()
+    }
+
     /// get the engine used to evaluate rules for this state.
     #[must_use]
     pub const fn engine(&self) -> &rhai::Engine {
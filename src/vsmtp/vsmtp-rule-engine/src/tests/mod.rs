@@ -15,6 +15,7 @@
  *
 */
 mod errors;
+pub mod service;
 
 use crate::RuleEngine;
 use vqueue::GenericQueueManager;
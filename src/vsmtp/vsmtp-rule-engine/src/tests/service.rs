@@ -0,0 +1,122 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+*/
+
+//! Fake daemons and rule engines shared by the tests of the services
+//! (clamd, milter, spam, dnsbl), the protocols being tested in their own module.
+
+use crate::RuleEngine;
+use vqueue::GenericQueueManager;
+use vsmtp_config::DnsResolvers;
+use vsmtp_test::config::local_test;
+
+/// Serve the connections of a TCP port with `daemon`, each in a task of the current runtime.
+pub async fn tcp_daemon<F, Fut>(daemon: F) -> std::net::SocketAddr
+where
+    F: Fn(tokio::net::TcpStream) -> Fut + Send + 'static,
+    Fut: std::future::Future<Output = ()> + Send + 'static,
+{
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(daemon(stream));
+        }
+    });
+    address
+}
+
+/// Serve the connections of a TCP port with `daemon`, in a runtime of its own,
+/// for the rule engines running their services on their own runtime.
+pub fn tcp_daemon_thread<F, Fut>(daemon: F) -> std::net::SocketAddr
+where
+    F: Fn(tokio::net::TcpStream) -> Fut + Send + 'static,
+    Fut: std::future::Future<Output = ()> + Send + 'static,
+{
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async move {
+            listener.set_nonblocking(true).unwrap();
+            let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(daemon(stream));
+            }
+        });
+    });
+    address
+}
+
+/// Serve the connections of a Unix socket with `daemon`, each in a task of the current runtime.
+///
+/// The socket is removed with the returned directory.
+pub fn unix_daemon<F, Fut>(daemon: F) -> (tempfile::TempDir, std::path::PathBuf)
+where
+    F: Fn(tokio::net::UnixStream) -> Fut + Send + 'static,
+    Fut: std::future::Future<Output = ()> + Send + 'static,
+{
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("daemon.sock");
+    let listener = tokio::net::UnixListener::bind(&path).unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(daemon(stream));
+        }
+    });
+    (dir, path)
+}
+
+/// A TCP address nobody listens on.
+pub fn unreachable_address() -> std::net::SocketAddr {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+/// Run the `connect` stage of the `rules`, and return the message and the status.
+pub fn run_connect(
+    rules: String,
+) -> (vsmtp_mail_parser::MessageBody, vsmtp_common::status::Status) {
+    let results =
+        vsmtp_test::vsl::run(move |builder| Ok(builder.add_root_filter_rules(&rules)?.build()));
+
+    // NOTE: the rule engine of `vsmtp_test` is another instance of this crate.
+    let (_, (_, msg, status)) = results
+        .into_iter()
+        .find(|(stage, _)| stage.to_string() == "connect")
+        .unwrap();
+    (msg, status)
+}
+
+/// Compile the `rules`, declaring the services.
+pub fn compile(rules: &'static str) {
+    let config = std::sync::Arc::new(local_test());
+    let queue_manger = vqueue::temp::QueueManager::init(config.clone(), vec![]).unwrap();
+    let dns_resolvers = std::sync::Arc::new(DnsResolvers::from_config(&config).unwrap());
+
+    RuleEngine::with_hierarchy(
+        |builder| Ok(builder.add_root_filter_rules(rules)?.build()),
+        config,
+        dns_resolvers,
+        queue_manger,
+    )
+    .unwrap();
+}
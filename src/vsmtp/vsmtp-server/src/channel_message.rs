@@ -15,6 +15,8 @@
  *
 */
 
+use vsmtp_common::Priority;
+
 /// Payload sent across the different processes of `vSMTP`.
 #[must_use]
 #[derive(Debug)]
@@ -22,6 +24,8 @@ pub struct ProcessMessage {
     message_uuid: uuid::Uuid,
     /// is the email stored in the delegated queue.
     delegated: bool,
+    /// priority of the email, the highest are processed first.
+    priority: Priority,
 }
 
 impl ProcessMessage {
@@ -30,6 +34,7 @@ impl ProcessMessage {
         Self {
             message_uuid,
             delegated: false,
+            priority: Priority::DEFAULT,
         }
     }
 
@@ -37,12 +42,23 @@ impl ProcessMessage {
         Self {
             message_uuid,
             delegated: true,
+            priority: Priority::DEFAULT,
         }
     }
 
+    /// Set the priority of the message.
+    pub const fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
     pub(crate) const fn is_from_delegation(&self) -> bool {
         self.delegated
     }
+
+    pub(crate) const fn priority(&self) -> Priority {
+        self.priority
+    }
 }
 
 impl AsRef<uuid::Uuid> for ProcessMessage {
@@ -54,6 +70,7 @@ impl AsRef<uuid::Uuid> for ProcessMessage {
 #[cfg(test)]
 mod test {
     use crate::ProcessMessage;
+    use vsmtp_common::Priority;
 
     #[test]
     fn debug() {
@@ -62,6 +79,7 @@ mod test {
             ProcessMessage {
                 message_uuid: uuid::Uuid::nil(),
                 delegated: false,
+                priority: Priority::DEFAULT,
             }
         );
     }
@@ -0,0 +1,370 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+*/
+use crate::{scheduler::Emitter, ProcessMessage, Reloader};
+use anyhow::Context;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+use vqueue::{
+    control::{
+        Counters, FlushQueue, MtaStsPolicy, ReRunStage, Release, Request, Response, Session,
+    },
+    QueueID,
+};
+use vsmtp_delivery::ConnectionPool;
+
+/// Bind the socket of the control API, replacing the one left by a previous run.
+///
+/// The socket is restricted to the user and the group of the server (`0660`),
+/// the control API having no authentication.
+///
+/// # Errors
+///
+/// * failed to remove the previous socket
+/// * failed to bind to the path
+/// * failed to set the permissions of the socket
+/// * failed to set the listener to non blocking
+pub fn control_bind_anyhow(
+    path: &std::path::Path,
+) -> anyhow::Result<std::os::unix::net::UnixListener> {
+    if path.exists() {
+        std::fs::remove_file(path)
+            .with_context(|| format!("Failed to remove the socket: '{}'", path.display()))?;
+    }
+
+    let listener = std::os::unix::net::UnixListener::bind(path)
+        .with_context(|| format!("Failed to bind socket on path: '{}'", path.display()))?;
+
+    std::fs::set_permissions(path, std::os::unix::fs::PermissionsExt::from_mode(0o660))
+        .with_context(|| {
+            format!(
+                "Failed to set the permissions of the socket: '{}'",
+                path.display()
+            )
+        })?;
+
+    listener.set_nonblocking(true).with_context(|| {
+        format!(
+            "Failed to set non-blocking socket on path: '{}'",
+            path.display()
+        )
+    })?;
+
+    Ok(listener)
+}
+
+/// Administration of the running server, see [`vqueue::control`].
+pub struct Control {
+    reloader: std::sync::Arc<Reloader>,
+    emitter: std::sync::Arc<Emitter>,
+    sessions: std::sync::Mutex<std::collections::HashMap<uuid::Uuid, Session>>,
+    pool: std::sync::Mutex<Option<std::sync::Arc<ConnectionPool>>>,
+    connections: std::sync::atomic::AtomicU64,
+    messages_received: std::sync::atomic::AtomicU64,
+    messages_processed: std::sync::atomic::AtomicU64,
+    delivery_attempts: std::sync::atomic::AtomicU64,
+    pub(crate) flush_deliver: tokio::sync::Notify,
+    pub(crate) flush_deferred: tokio::sync::Notify,
+}
+
+impl std::fmt::Debug for Control {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Control")
+            .field("counters", &self.counters())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Remove the session from the list of the [`Control`] when dropped.
+pub struct SessionGuard {
+    control: std::sync::Arc<Control>,
+    uuid: uuid::Uuid,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.control.sessions().remove(&self.uuid);
+    }
+}
+
+impl Control {
+    /// Create the handle used by the different part of the server.
+    #[must_use]
+    pub fn new(reloader: std::sync::Arc<Reloader>, emitter: std::sync::Arc<Emitter>) -> Self {
+        Self {
+            reloader,
+            emitter,
+            sessions: std::sync::Mutex::new(std::collections::HashMap::new()),
+            pool: std::sync::Mutex::new(None),
+            connections: std::sync::atomic::AtomicU64::new(0),
+            messages_received: std::sync::atomic::AtomicU64::new(0),
+            messages_processed: std::sync::atomic::AtomicU64::new(0),
+            delivery_attempts: std::sync::atomic::AtomicU64::new(0),
+            flush_deliver: tokio::sync::Notify::new(),
+            flush_deferred: tokio::sync::Notify::new(),
+        }
+    }
+
+    fn sessions(
+        &self,
+    ) -> std::sync::MutexGuard<'_, std::collections::HashMap<uuid::Uuid, Session>> {
+        self.sessions
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    /// Set the pool of the `delivery` process, whose MTA-STS policies are listed on request.
+    pub fn set_pool(&self, pool: std::sync::Arc<ConnectionPool>) {
+        *self
+            .pool
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner) = Some(pool);
+    }
+
+    pub(crate) fn open_session(self: &std::sync::Arc<Self>, session: Session) -> SessionGuard {
+        let uuid = session.uuid;
+        self.connections
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        self.sessions().insert(uuid, session);
+
+        SessionGuard {
+            control: self.clone(),
+            uuid,
+        }
+    }
+
+    pub(crate) fn message_received(&self) {
+        self.messages_received
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    pub(crate) fn message_processed(&self) {
+        self.messages_processed
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    pub(crate) fn delivery_attempted(&self) {
+        self.delivery_attempts
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    fn counters(&self) -> Counters {
+        let mut counters = Counters::default();
+        counters.connections = self.connections.load(std::sync::atomic::Ordering::Relaxed);
+        counters.sessions = self.sessions().len() as u64;
+        counters.messages_received = self
+            .messages_received
+            .load(std::sync::atomic::Ordering::Relaxed);
+        counters.messages_processed = self
+            .messages_processed
+            .load(std::sync::atomic::Ordering::Relaxed);
+        counters.delivery_attempts = self
+            .delivery_attempts
+            .load(std::sync::atomic::Ordering::Relaxed);
+        counters.reloads = self.reloader.reloads();
+        counters
+    }
+
+    /// Answer the requests received on the `listener`.
+    pub async fn serve(self: std::sync::Arc<Self>, listener: tokio::net::UnixListener) {
+        tracing::info!(path = ?listener.local_addr().ok(), "Listening for control requests.");
+
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(self.clone().serve_client(stream));
+                }
+                Err(error) => tracing::warn!(%error, "Control connection failure."),
+            }
+        }
+    }
+
+    async fn serve_client(self: std::sync::Arc<Self>, stream: tokio::net::UnixStream) {
+        let (read, mut write) = stream.into_split();
+        let mut lines = tokio::io::BufReader::new(read).lines();
+
+        while let Ok(Some(line)) = lines.next_line().await {
+            let response = match serde_json::from_str::<Request>(&line) {
+                Ok(request) => self.handle(request).await,
+                Err(error) => Response::Error(format!("Invalid request: {error}")),
+            };
+
+            let mut response = serde_json::to_vec(&response).expect("response is serializable");
+            response.push(b'\n');
+            if let Err(error) = write.write_all(&response).await {
+                tracing::warn!(%error, "Control response failure.");
+                return;
+            }
+        }
+    }
+
+    /// Execute the request.
+    #[tracing::instrument(name = "control", skip(self))]
+    pub async fn handle(&self, request: Request) -> Response {
+        let response = match request {
+            Request::Flush { queue } => {
+                match queue {
+                    FlushQueue::Deliver => self.flush_deliver.notify_one(),
+                    FlushQueue::Deferred => self.flush_deferred.notify_one(),
+                }
+                Ok(Response::Done)
+            }
+            Request::ReRun { msg, stage, queue } => self
+                .rerun(&msg, stage, queue)
+                .await
+                .map(|()| Response::Done),
+            Request::Release {
+                msg,
+                quarantine,
+                release,
+            } => self
+                .release(&msg, quarantine, release)
+                .await
+                .map(|()| Response::Done),
+            Request::Reload => {
+                let reloader = self.reloader.clone();
+                tokio::task::spawn_blocking(move || reloader.reload())
+                    .await
+                    .map_err(anyhow::Error::new)
+                    .and_then(|reload| reload)
+                    .map(|()| Response::Done)
+            }
+            Request::Sessions => {
+                let mut sessions = self.sessions().values().cloned().collect::<Vec<_>>();
+                sessions.sort_by_key(|session| session.connected_at);
+                Ok(Response::Sessions(sessions))
+            }
+            Request::Counters => Ok(Response::Counters(self.counters())),
+            Request::MtaSts => self.mta_sts().map(Response::MtaSts),
+            _ => Err(anyhow::anyhow!("Unsupported request")),
+        };
+
+        response.unwrap_or_else(|error| {
+            tracing::warn!(%error, "Control request failure.");
+            Response::Error(format!("{error:#}"))
+        })
+    }
+
+    fn mta_sts(&self) -> anyhow::Result<Vec<MtaStsPolicy>> {
+        let pool = self
+            .pool
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .clone()
+            .context("The delivery process has not started")?;
+
+        Ok(pool
+            .mta_sts()
+            .cached()
+            .into_iter()
+            .map(|(domain, cached)| {
+                let expired = cached.is_expired();
+                MtaStsPolicy::new(
+                    domain.to_string(),
+                    cached.id,
+                    cached.policy.mode.to_string(),
+                    cached.policy.mx,
+                    cached.policy.max_age.as_secs(),
+                    cached.fetched_at.into(),
+                    expired,
+                )
+            })
+            .collect())
+    }
+
+    /// Release the message from its quarantine, and send it to the process of its queue.
+    async fn release(
+        &self,
+        msg: &uuid::Uuid,
+        quarantine: Option<String>,
+        release: Release,
+    ) -> anyhow::Result<()> {
+        let (config, rule_engine, _) = self.reloader.snapshot();
+        let (stage, released) = crate::release::release(
+            config,
+            self.reloader.queue_manager().as_ref(),
+            &rule_engine.srv().resolvers,
+            msg,
+            quarantine,
+            release,
+        )
+        .await?;
+
+        self.send_to(stage, released).await
+    }
+
+    async fn send_to(&self, stage: ReRunStage, msg: ProcessMessage) -> anyhow::Result<()> {
+        match stage {
+            ReRunStage::Working => self.emitter.send_to_working(msg).await,
+            ReRunStage::Delivery => self.emitter.send_to_delivery(msg).await,
+        }
+        .context("The process has stopped")
+    }
+
+    /// Move the message from its `queue` to the queue of the `stage` and send it to its process.
+    ///
+    /// The message is searched in all the queues and quarantines if its `queue` is not given.
+    async fn rerun(
+        &self,
+        msg: &uuid::Uuid,
+        stage: ReRunStage,
+        queue: Option<QueueID>,
+    ) -> anyhow::Result<()> {
+        let queue_manager = self.reloader.queue_manager();
+
+        let queues = if let Some(queue) = queue {
+            vec![queue]
+        } else {
+            let mut queues = <QueueID as strum::IntoEnumIterator>::iter()
+                .filter(|queue| !matches!(queue, QueueID::Quarantine { .. }))
+                .collect::<Vec<_>>();
+            queues.extend(
+                queue_manager
+                    .list_quarantines()
+                    .await?
+                    .into_iter()
+                    .map(|name| QueueID::Quarantine { name }),
+            );
+            queues
+        };
+
+        let mut found = None;
+        for queue in queues {
+            if let Ok(ctx) = queue_manager.get_ctx(&queue, msg).await {
+                found = Some((queue, ctx));
+                break;
+            }
+        }
+        let (from, ctx) = found.context("Message does not exist in any queue")?;
+
+        let to = match stage {
+            ReRunStage::Working => QueueID::Working,
+            ReRunStage::Delivery => QueueID::Deliver,
+        };
+        if from != to {
+            queue_manager.write_ctx(&to, &ctx).await?;
+            queue_manager.remove_ctx(&from, msg).await?;
+        }
+
+        self.send_to(
+            stage,
+            ProcessMessage::new(*msg).with_priority(ctx.mail_from.priority),
+        )
+        .await?;
+
+        tracing::info!(%from, %to, "Message sent again.");
+        Ok(())
+    }
+}
@@ -14,50 +14,63 @@
  * this program. If not, see https://www.gnu.org/licenses/.
  *
 */
-use crate::ProcessMessage;
+use crate::{
+    delivery::{dsn::notify_sender, record_attempt, schedule::Scheduled, sendable},
+    ProcessMessage,
+};
 use anyhow::Context;
-use time::ext::NumericalDuration;
 use vqueue::{GenericQueueManager, QueueID};
-use vsmtp_common::transfer::{Error, Status};
-use vsmtp_config::Config;
+use vsmtp_common::{
+    transfer::{error::Queuer, Status},
+    ContextFinished,
+};
+use vsmtp_config::{Config, DnsResolvers};
 use vsmtp_delivery::{split_and_sort_and_send, SenderOutcome};
 
-pub(crate) async fn flush_deferred_queue<Q: GenericQueueManager + Sized + 'static>(
+/// Flush a message taken from the schedule of the deferred queue.
+///
+/// A message whose flush failed is retried after the `deferred_retry_period`.
+pub(crate) async fn flush_scheduled<Q: GenericQueueManager + Sized + 'static>(
     config: std::sync::Arc<Config>,
-    queue_manager: std::sync::Arc<Q>,
+    queue_manager: std::sync::Arc<Scheduled<Q>>,
+    resolvers: std::sync::Arc<DnsResolvers>,
+    message_uuid: uuid::Uuid,
     flushing_at: time::OffsetDateTime,
 ) {
-    let queued = match queue_manager.list(&QueueID::Deferred).await {
-        Ok(queued) => queued,
-        Err(error) => {
-            tracing::error!(%error, "Listing deferred queue failure.");
-            return;
-        }
-    };
-
-    for i in queued {
-        let message_uuid = match i.map(|i| uuid::Uuid::parse_str(&i)) {
-            Ok(Ok(message_uuid)) => message_uuid,
-            Ok(Err(error)) => {
-                tracing::error!(%error, "Invalid message id in deferred queue.");
-                continue;
-            }
-            Err(error) => {
-                tracing::error!(%error, "Deferred message id missing.");
-                continue;
-            }
-        };
+    let result = handle_one(
+        config.clone(),
+        queue_manager.clone(),
+        ProcessMessage::new(message_uuid),
+        resolvers,
+        flushing_at,
+    )
+    .await;
+    if let Err(error) = &result {
+        tracing::error!(%error, "Flushing deferred queue failure.");
+    }
 
-        if let Err(error) = handle_one(
-            config.clone(),
-            queue_manager.clone(),
-            ProcessMessage::new(message_uuid),
-            flushing_at,
+    queue_manager
+        .flushed(
+            &message_uuid,
+            result.is_err(),
+            config.server.queues.delivery.deferred_retry_period,
         )
-        .await
-        {
-            tracing::error!(%error, "Flushing deferred queue failure.");
-        }
+        .await;
+}
+
+/// Outcome of a message whose recipients have not all been processed in the same pass.
+fn outcome_of(ctx: &ContextFinished) -> SenderOutcome {
+    let mut statuses = ctx.rcpt_to.delivery.values().flatten().map(|(_, s)| s);
+
+    if statuses
+        .clone()
+        .all(|status| matches!(status, Status::Sent { .. }))
+    {
+        SenderOutcome::RemoveFromDisk
+    } else if statuses.all(|status| !status.is_sendable()) {
+        SenderOutcome::MoveToDead
+    } else {
+        SenderOutcome::MoveToDeferred
     }
 }
 
@@ -67,6 +80,7 @@ pub async fn handle_one<Q: GenericQueueManager + Sized + 'static>(
     config: std::sync::Arc<Config>,
     queue_manager: std::sync::Arc<Q>,
     process_message: ProcessMessage,
+    resolvers: std::sync::Arc<DnsResolvers>,
     flushing_at: time::OffsetDateTime,
 ) -> anyhow::Result<()> {
     tracing::debug!("Processing email.");
@@ -75,42 +89,105 @@ pub async fn handle_one<Q: GenericQueueManager + Sized + 'static>(
         .get_ctx(&QueueID::Deferred, process_message.as_ref())
         .await?;
 
-    let last_error = ctx
-        .rcpt_to
-        .delivery
-        .values()
-        .flatten()
-        .filter_map(|i| match &i.1 {
-            Status::HeldBack { errors } => errors.last().map(Error::timestamp),
-            _ => None,
-        })
-        .min();
-
-    let held_back_count = ctx
-        .rcpt_to
-        .delivery
-        .values()
-        .flatten()
-        .filter(|i| matches!(i.1, Status::HeldBack { .. }))
-        .count() as i64;
-
-    match last_error {
-        Some(last_error)
-            // last error + (error_count * 5min)
-            if last_error
-                .checked_add(held_back_count.seconds() * 60 * 5)
-                .unwrap()
-                > flushing_at =>
+    let policy = &config.server.queues.delivery.retry;
+    let expire_at = ctx.mail_from.mail_timestamp + policy.bounce_after;
+
+    // recipients not processed in this pass, either not ready yet or expired.
+    let mut put_aside = vec![];
+    let mut expired = false;
+    for (transport, rcpt) in &mut ctx.rcpt_to.delivery {
+        for (addr, status) in core::mem::take(rcpt) {
+            match &status {
+                Status::HeldBack { .. } if flushing_at >= expire_at => {
+                    tracing::warn!(%addr, "Message lifetime expired, giving up on recipient.");
+                    put_aside.push((
+                        transport.clone(),
+                        (addr, Status::failed(Queuer::LifetimeExpired)),
+                    ));
+                    expired = true;
+                }
+                Status::HeldBack { errors }
+                    if policy
+                        .next_attempt(process_message.as_ref(), errors)
+                        .map_or(false, |next| next > flushing_at) =>
+                {
+                    put_aside.push((transport.clone(), (addr, status)));
+                }
+                _ => rcpt.push((addr, status)),
+            }
+        }
+    }
+
+    if !expired
+        && !put_aside.is_empty()
+        && !ctx
+            .rcpt_to
+            .delivery
+            .values()
+            .flatten()
+            .any(|(_, status)| status.is_sendable())
+    {
+        for (transport, rcpt) in put_aside {
+            ctx.rcpt_to
+                .delivery
+                .entry(transport)
+                .or_default()
+                .push(rcpt);
+        }
+        if policy
+            .warn_at(&ctx)
+            .map_or(true, |warn_at| warn_at > flushing_at)
         {
             tracing::debug!("Email is not ready to be flushed.");
             return Ok(());
         }
-        _ => {}
+
+        // no attempt is due, but the sender must be told about the delay.
+        let msg = queue_manager.get_msg(process_message.as_ref()).await?;
+        if let Err(error) =
+            notify_sender(config, queue_manager.clone(), resolvers, &mut ctx, &msg).await
+        {
+            tracing::error!(%error, "Failed to produce the delivery status notification.");
+        }
+
+        return queue_manager
+            .write_ctx(&QueueID::Deferred, &ctx)
+            .await
+            .with_context(|| format!("failed to update context in `{}`", QueueID::Deferred));
     }
 
     let msg = queue_manager.get_msg(process_message.as_ref()).await?;
 
-    match split_and_sort_and_send(config, &mut ctx, &msg).await {
+    let mut attempted = sendable(&ctx);
+    attempted.extend(
+        put_aside
+            .iter()
+            .filter(|(_, (_, status))| !status.is_sendable())
+            .map(|(_, (addr, _))| addr.clone()),
+    );
+    let outcome = split_and_sort_and_send(config.clone(), &mut ctx, &msg).await;
+
+    let outcome = if put_aside.is_empty() {
+        outcome
+    } else {
+        for (transport, rcpt) in put_aside {
+            ctx.rcpt_to
+                .delivery
+                .entry(transport)
+                .or_default()
+                .push(rcpt);
+        }
+        outcome_of(&ctx)
+    };
+    record_attempt(queue_manager.as_ref(), &ctx, &attempted, &outcome).await;
+
+    if let Err(error) =
+        notify_sender(config, queue_manager.clone(), resolvers, &mut ctx, &msg).await
+    {
+        tracing::error!(%error, "Failed to produce the delivery status notification.");
+    }
+
+    match outcome {
         SenderOutcome::MoveToDead => queue_manager
             .move_to(&QueueID::Deferred, &QueueID::Dead, &ctx)
             .await
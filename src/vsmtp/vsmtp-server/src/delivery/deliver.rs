@@ -14,7 +14,11 @@
  * this program. If not, see https://www.gnu.org/licenses/.
  *
 */
-use crate::{delegate, delivery::add_trace_information, ProcessMessage};
+use crate::{
+    delegate,
+    delivery::{add_trace_information, dsn::notify_sender, record_attempt, sendable},
+    ProcessMessage,
+};
 use anyhow::Context;
 use vqueue::{GenericQueueManager, QueueID};
 use vsmtp_common::{
@@ -25,11 +29,10 @@ use vsmtp_config::Config;
 use vsmtp_delivery::{split_and_sort_and_send, SenderOutcome};
 use vsmtp_rule_engine::{ExecutionStage, RuleEngine};
 
-pub(crate) async fn flush_deliver_queue<Q: GenericQueueManager + Sized + 'static>(
-    config: std::sync::Arc<Config>,
-    queue_manager: std::sync::Arc<Q>,
-    rule_engine: std::sync::Arc<RuleEngine>,
-) {
+/// The messages of the deliver queue, in the order of the queue.
+pub(crate) async fn list_deliver_queue<Q: GenericQueueManager + Sized + 'static>(
+    queue_manager: &Q,
+) -> Vec<uuid::Uuid> {
     // FIXME: add span on the function.
     tracing::info!("Flushing deliver queue.");
 
@@ -37,31 +40,23 @@ pub(crate) async fn flush_deliver_queue<Q: GenericQueueManager + Sized + 'static
         Ok(queued) => queued,
         Err(error) => {
             tracing::error!(%error, "Flushing failed");
-            return;
+            return vec![];
         }
     };
 
+    let mut messages = vec![];
     for i in queued {
-        let message_uuid = match i.map(|i| <uuid::Uuid as std::str::FromStr>::from_str(&i)) {
-            Ok(Ok(message_uuid)) => message_uuid,
+        match i.map(|i| <uuid::Uuid as std::str::FromStr>::from_str(&i)) {
+            Ok(Ok(message_uuid)) => messages.push(message_uuid),
             Ok(Err(error)) => {
                 tracing::error!(%error, "Invalid message id in deliver queue.");
-                continue;
             }
             Err(error) => {
                 tracing::error!(%error, "Deliver message id missing.");
-                continue;
             }
-        };
-
-        let _err = handle_one(
-            config.clone(),
-            queue_manager.clone(),
-            ProcessMessage::new(message_uuid),
-            rule_engine.clone(),
-        )
-        .await;
+        }
     }
+    messages
 }
 
 /// Handle one message in the delivery queue.
@@ -152,7 +147,23 @@ pub async fn handle_one<Q: GenericQueueManager + Sized + 'static>(
 
     add_trace_information(&ctx, &mut msg, &result)?;
 
-    match split_and_sort_and_send(config, &mut ctx, &msg).await {
+    let attempted = sendable(&ctx);
+    let outcome = split_and_sort_and_send(config.clone(), &mut ctx, &msg).await;
+    record_attempt(queue_manager.as_ref(), &ctx, &attempted, &outcome).await;
+
+    if let Err(error) = notify_sender(
+        config,
+        queue_manager.clone(),
+        rule_engine.srv().resolvers.clone(),
+        &mut ctx,
+        &msg,
+    )
+    .await
+    {
+        tracing::error!(%error, "Failed to produce the delivery status notification.");
+    }
+
+    match outcome {
         SenderOutcome::MoveToDead => {
             queue_manager.move_to(&queue, &QueueID::Dead, &ctx).await?;
 
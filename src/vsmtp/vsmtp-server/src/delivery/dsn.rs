@@ -0,0 +1,608 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+*/
+use anyhow::Context;
+use core::fmt::Write;
+use time::format_description::well_known::Rfc2822;
+use vqueue::{GenericQueueManager, QueueID};
+use vsmtp_common::{
+    dsn::{self, Action},
+    transfer::{
+        self,
+        error::{Delivery, Rule, Variant},
+    },
+    transport::{AbstractTransport, WrapperSerde},
+    Address, ClientName, ConnectProperties, ContextFinished, FinishedProperties, HeloProperties,
+    MailFromProperties, RcptToProperties, ReplyCode, TransactionType,
+};
+use vsmtp_config::{Config, DnsResolvers};
+use vsmtp_delivery::{split_and_sort_and_send, Deliver, SenderOutcome};
+use vsmtp_mail_parser::MessageBody;
+
+tokio::task_local! {
+    static OUTBOX: tokio::sync::mpsc::UnboundedSender<Notification>;
+}
+
+/// A delivery status notification written to the deliver queue, to be sent.
+pub(crate) struct Notification {
+    ctx: ContextFinished,
+    msg: MessageBody,
+}
+
+/// A recipient reported in a delivery status notification.
+struct Report<'a> {
+    forward_path: &'a Address,
+    status: &'a transfer::Status,
+    action: Action,
+    original_forward_path: Option<&'a dsn::OriginalRecipient>,
+}
+
+/// Produce the delivery status notification of the last delivery attempt of `ctx`,
+/// and inject it in the deliver queue.
+///
+/// The notification is handed to the [`Notification::scope`] to be sent, and without
+/// one, it is sent at the next flush of the deliver queue.
+///
+/// The recipients already notified for the same action are marked in the `ctx`
+/// so that the sender is told only once, the caller must persist the `ctx` afterward.
+#[tracing::instrument(name = "dsn", skip_all)]
+pub(crate) async fn notify_sender<Q: GenericQueueManager + Sized + 'static>(
+    config: std::sync::Arc<Config>,
+    queue_manager: std::sync::Arc<Q>,
+    resolvers: std::sync::Arc<DnsResolvers>,
+    ctx: &mut ContextFinished,
+    msg: &MessageBody,
+) -> anyhow::Result<()> {
+    // RFC 3461: notifications are never sent for a null reverse path.
+    let Some(reverse_path) = ctx.mail_from.reverse_path.clone() else {
+        return Ok(());
+    };
+
+    let now = time::OffsetDateTime::now_utc();
+    let actions = update_notified(ctx, config.server.queues.delivery.retry.warn_after, now);
+    if actions.is_empty() {
+        return Ok(());
+    }
+
+    let reports = ctx
+        .rcpt_to
+        .delivery
+        .values()
+        .flatten()
+        .filter_map(|(forward_path, status)| {
+            actions
+                .iter()
+                .find(|(rcpt, _)| rcpt == forward_path)
+                .map(|(_, action)| Report {
+                    forward_path,
+                    status,
+                    action: *action,
+                    original_forward_path: ctx
+                        .rcpt_to
+                        .dsn
+                        .get(forward_path)
+                        .and_then(|p| p.original_forward_path.as_ref()),
+                })
+        })
+        .collect::<Vec<_>>();
+
+    let message_uuid = uuid::Uuid::new_v4();
+    let report = build_report(ctx, msg, &reports, message_uuid, now)?;
+
+    let transport =
+        std::sync::Arc::new(Deliver::new(resolvers.get_resolver_root(), config.clone()));
+    let notification_ctx = build_context(ctx, reverse_path, transport, message_uuid, now);
+
+    queue_manager
+        .write_both(&QueueID::Deliver, &notification_ctx, &report)
+        .await?;
+
+    tracing::info!(notification = %message_uuid, "Delivery status notification produced.");
+
+    let notification = Notification {
+        ctx: notification_ctx,
+        msg: report,
+    };
+    if !matches!(
+        OUTBOX.try_with(|outbox| outbox.send(notification)),
+        Ok(Ok(()))
+    ) {
+        tracing::debug!(notification = %message_uuid, "Notification left in the deliver queue.");
+    }
+
+    Ok(())
+}
+
+/// Compute the action to report for each recipient, and mark them as notified.
+///
+/// If `warn_after` is set, delays are reported only once the message is older than it,
+/// even for the recipients without a `NOTIFY` parameter.
+fn update_notified(
+    ctx: &mut ContextFinished,
+    warn_after: Option<std::time::Duration>,
+    now: time::OffsetDateTime,
+) -> Vec<(Address, Action)> {
+    let mut out = vec![];
+    let warn = warn_after.map(|warn_after| ctx.mail_from.mail_timestamp + warn_after <= now);
+
+    for (forward_path, status) in ctx.rcpt_to.delivery.values().flatten() {
+        let action = match status {
+            transfer::Status::Sent { .. } => Action::Delivered,
+            transfer::Status::HeldBack { .. } => Action::Delayed,
+            transfer::Status::Failed { .. } => Action::Failed,
+            _ => continue,
+        };
+
+        let parameters = ctx.rcpt_to.dsn.entry(forward_path.clone()).or_default();
+        if parameters.should_notify(action, warn == Some(true))
+            && (action != Action::Delayed || warn != Some(false))
+            && parameters.last_notification != Some(action)
+        {
+            parameters.last_notification = Some(action);
+            out.push((forward_path.clone(), action));
+        }
+    }
+
+    out
+}
+
+impl Notification {
+    /// Run `future`, the notifications it produces are given to `outbox`.
+    pub(crate) async fn scope<F: core::future::Future + Send>(
+        outbox: tokio::sync::mpsc::UnboundedSender<Self>,
+        future: F,
+    ) -> F::Output {
+        OUTBOX.scope(outbox, future).await
+    }
+
+    /// Send the notification, and move it to the right queue depending on the outcome.
+    pub(crate) async fn send<Q: GenericQueueManager + Sized + 'static>(
+        self,
+        config: std::sync::Arc<Config>,
+        queue_manager: std::sync::Arc<Q>,
+    ) {
+        let Self { mut ctx, msg } = self;
+        let message_uuid = ctx.mail_from.message_uuid;
+
+        let result = match split_and_sort_and_send(config, &mut ctx, &msg).await {
+            SenderOutcome::MoveToDead => {
+                queue_manager
+                    .move_to(&QueueID::Deliver, &QueueID::Dead, &ctx)
+                    .await
+            }
+            SenderOutcome::MoveToDeferred => {
+                queue_manager
+                    .move_to(&QueueID::Deliver, &QueueID::Deferred, &ctx)
+                    .await
+            }
+            SenderOutcome::RemoveFromDisk => {
+                queue_manager
+                    .remove_both(&QueueID::Deliver, &message_uuid)
+                    .await
+            }
+        };
+
+        if let Err(error) = result {
+            tracing::error!(%error, notification = %message_uuid, "Failed to send the notification.");
+        }
+    }
+}
+
+/// Envelop of the notification, sent with a null reverse path to the original sender.
+fn build_context(
+    ctx: &ContextFinished,
+    reverse_path: Address,
+    transport: std::sync::Arc<dyn AbstractTransport>,
+    message_uuid: uuid::Uuid,
+    now: time::OffsetDateTime,
+) -> ContextFinished {
+    ContextFinished {
+        connect: ConnectProperties {
+            connect_timestamp: now,
+            connect_uuid: uuid::Uuid::new_v4(),
+            client_addr: ctx.connect.server_addr,
+            server_addr: ctx.connect.server_addr,
+            server_name: ctx.connect.server_name.clone(),
+            skipped: None,
+            tls: None,
+            auth: None,
+        },
+        helo: HeloProperties {
+            client_name: ClientName::Domain(ctx.connect.server_name.clone()),
+            using_deprecated: false,
+        },
+        mail_from: MailFromProperties {
+            reverse_path: None,
+            mail_timestamp: now,
+            message_uuid,
+            spf: None,
+            message_size: None,
+            // RFC 6710: the notification has the priority of the message.
+            priority: ctx.mail_from.priority,
+            dsn: dsn::MailFromParameters::default(),
+        },
+        rcpt_to: RcptToProperties {
+            forward_paths: vec![reverse_path.clone()],
+            delivery: std::iter::once((
+                WrapperSerde::Ready(transport),
+                vec![(reverse_path, transfer::Status::default())],
+            ))
+            .collect(),
+            transaction_type: TransactionType::Outgoing {
+                domain: ctx.connect.server_name.clone(),
+            },
+            dsn: std::collections::HashMap::new(),
+        },
+        finished: FinishedProperties {
+            dkim: None,
+            released: vec![],
+        },
+    }
+}
+
+/// Enhanced status code and diagnostic of the last error of a recipient.
+fn status_and_diagnostic(report: &Report<'_>) -> (String, Option<String>) {
+    let class = match report.action {
+        Action::Failed => 5,
+        Action::Delayed => 4,
+        Action::Delivered => 2,
+    };
+
+    let error = match report.status {
+        transfer::Status::Failed { error } => Some(error),
+        transfer::Status::HeldBack { errors } => errors.last(),
+        _ => None,
+    };
+
+    let Some(error) = error else {
+        return (format!("{class}.0.0"), None);
+    };
+
+    let from_reply = |reply: &ReplyCode| {
+        reply
+            .details()
+            .map_or_else(|| format!("{}.0.0", reply.value() / 100), str::to_owned)
+    };
+
+    let status = match error.variant() {
+        Variant::Delivery(errors) => match errors.last() {
+            Some((_, Delivery::Permanent { reply, .. } | Delivery::Transient { reply, .. })) => {
+                from_reply(reply)
+            }
+            _ => format!("{class}.4.0"),
+        },
+        Variant::Rules(Rule::Denied(reply)) => from_reply(reply.code()),
+        Variant::Lookup(_) => format!("{class}.4.4"),
+        Variant::LocalDelivery(transfer::error::LocalDelivery::MailboxDoNotExist { .. }) => {
+            format!("{class}.1.1")
+        }
+        Variant::Envelop(_) => format!("{class}.1.3"),
+        Variant::Throttled(_) => format!("{class}.4.5"),
+        _ => format!("{class}.0.0"),
+    };
+
+    let diagnostic = match error.variant() {
+        Variant::Delivery(errors) => match errors.last() {
+            Some((
+                _,
+                Delivery::Permanent {
+                    reply, with_source, ..
+                }
+                | Delivery::Transient {
+                    reply, with_source, ..
+                },
+            )) => format!(
+                // NOTE: the text of the reply contains the enhanced status code if any.
+                "smtp; {}{}",
+                reply.value(),
+                with_source
+                    .as_ref()
+                    .map_or_else(String::new, |source| format!(" {source}"))
+            ),
+            _ => format!("X-vSMTP; {}", error.variant()),
+        },
+        Variant::Rules(Rule::Denied(reply)) => {
+            format!("smtp; {}", reply.to_string().trim_end())
+        }
+        variant => format!("X-vSMTP; {variant}"),
+    };
+
+    (status, Some(diagnostic))
+}
+
+/// Build a `multipart/report` message as described in RFC 3464.
+fn build_report(
+    ctx: &ContextFinished,
+    msg: &MessageBody,
+    reports: &[Report<'_>],
+    message_uuid: uuid::Uuid,
+    now: time::OffsetDateTime,
+) -> anyhow::Result<MessageBody> {
+    let server_name = &ctx.connect.server_name;
+    let boundary = format!("{message_uuid}/{server_name}");
+    let date = now.format(&Rfc2822).context("failed to format date")?;
+
+    let has_failure = reports.iter().any(|r| r.action == Action::Failed);
+    let subject = if has_failure {
+        "Undelivered Mail Returned to Sender"
+    } else if reports.iter().any(|r| r.action == Action::Delayed) {
+        "Delayed Mail (still being retried)"
+    } else {
+        "Successful Mail Delivery Report"
+    };
+
+    let headers = vec![
+        format!("From: Mail Delivery System <MAILER-DAEMON@{server_name}>\r\n"),
+        format!(
+            "To: <{}>\r\n",
+            ctx.mail_from
+                .reverse_path
+                .as_ref()
+                .map_or_else(String::new, ToString::to_string)
+        ),
+        format!("Subject: {subject}\r\n"),
+        format!("Date: {date}\r\n"),
+        format!("Message-ID: <{message_uuid}@{server_name}>\r\n"),
+        "Auto-Submitted: auto-replied\r\n".to_owned(),
+        "MIME-Version: 1.0\r\n".to_owned(),
+        format!(
+            "Content-Type: multipart/report; report-type=delivery-status;\r\n\tboundary=\"{boundary}\"\r\n"
+        ),
+    ];
+
+    let mut body = format!(
+        "This is a MIME-encapsulated message.\r\n\r\n--{boundary}\r\n\
+        Content-Type: text/plain; charset=us-ascii\r\n\r\n\
+        This is the mail system at host {server_name}.\r\n\r\n"
+    );
+
+    for report in reports {
+        match report.action {
+            Action::Failed => write!(
+                body,
+                "Your message could not be delivered to <{}>.\r\n",
+                report.forward_path
+            ),
+            Action::Delayed => write!(
+                body,
+                "Your message to <{}> has been delayed, delivery will be retried.\r\n",
+                report.forward_path
+            ),
+            Action::Delivered => write!(
+                body,
+                "Your message has been delivered to <{}>.\r\n",
+                report.forward_path
+            ),
+        }?;
+    }
+
+    write!(
+        body,
+        "\r\n--{boundary}\r\nContent-Type: message/delivery-status\r\n\r\n\
+        Reporting-MTA: dns; {server_name}\r\n"
+    )?;
+    if let Some(envelope_id) = &ctx.mail_from.dsn.envelope_id {
+        write!(
+            body,
+            "Original-Envelope-Id: {}\r\n",
+            dsn::encode_xtext(envelope_id)
+        )?;
+    }
+    write!(
+        body,
+        "Arrival-Date: {}\r\n",
+        ctx.mail_from
+            .mail_timestamp
+            .format(&Rfc2822)
+            .context("failed to format arrival date")?
+    )?;
+
+    for report in reports {
+        let (status, diagnostic) = status_and_diagnostic(report);
+
+        body.push_str("\r\n");
+        if let Some(original_forward_path) = report.original_forward_path {
+            write!(body, "Original-Recipient: {original_forward_path}\r\n")?;
+        }
+        write!(
+            body,
+            "Final-Recipient: rfc822; {}\r\nAction: {}\r\nStatus: {status}\r\n",
+            report.forward_path, report.action
+        )?;
+        if let Some(diagnostic) = diagnostic {
+            write!(body, "Diagnostic-Code: {diagnostic}\r\n")?;
+        }
+    }
+
+    // NOTE: the full message is returned only for failures, unless the sender asked otherwise.
+    let raw = msg.inner();
+    if has_failure && ctx.mail_from.dsn.ret != Some(dsn::Ret::Headers) {
+        write!(
+            body,
+            "\r\n--{boundary}\r\nContent-Type: message/rfc822\r\n\r\n{raw}"
+        )?;
+    } else {
+        write!(
+            body,
+            "\r\n--{boundary}\r\nContent-Type: text/rfc822-headers\r\n\r\n{}",
+            raw.headers_lines().collect::<String>()
+        )?;
+    }
+    write!(body, "\r\n--{boundary}--\r\n")?;
+
+    Ok(MessageBody::new(headers, body))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{build_report, update_notified, Report};
+    use time::format_description::well_known::Rfc2822;
+    use vsmtp_common::{
+        addr,
+        dsn::{self, Action},
+        transfer::{self, error::Delivery, Status},
+        transport::WrapperSerde,
+        ReplyCode,
+    };
+    use vsmtp_test::config::{local_ctx, local_msg};
+
+    #[test]
+    fn notified_once() {
+        let mut ctx = local_ctx();
+        ctx.rcpt_to.delivery.insert(
+            WrapperSerde::Raw("deliver".to_string()),
+            vec![
+                (
+                    addr!("failed@foo.com"),
+                    Status::failed(transfer::error::Envelop::NoRecipient),
+                ),
+                (addr!("sent@foo.com"), Status::sent()),
+                (
+                    addr!("never@foo.com"),
+                    Status::failed(transfer::error::Envelop::NoRecipient),
+                ),
+            ],
+        );
+        ctx.rcpt_to.dsn.insert(
+            addr!("never@foo.com"),
+            dsn::RcptToParameters {
+                notify_on: Some(dsn::NotifyOn::Never),
+                ..Default::default()
+            },
+        );
+
+        let now = time::OffsetDateTime::now_utc();
+        assert_eq!(
+            update_notified(&mut ctx, None, now),
+            vec![(addr!("failed@foo.com"), Action::Failed)]
+        );
+        assert!(update_notified(&mut ctx, None, now).is_empty());
+    }
+
+    #[test]
+    fn delay_warning() {
+        let mut ctx = local_ctx();
+        let mut status = Status::default();
+        status.held_back(transfer::error::Envelop::NoRecipient);
+        ctx.rcpt_to.delivery.insert(
+            WrapperSerde::Raw("deliver".to_string()),
+            vec![(addr!("held@foo.com"), status)],
+        );
+
+        let warn_after = Some(std::time::Duration::from_secs(60 * 60));
+        let now = ctx.mail_from.mail_timestamp;
+
+        assert!(update_notified(&mut ctx, None, now).is_empty());
+        assert!(update_notified(&mut ctx, warn_after, now).is_empty());
+        assert_eq!(
+            update_notified(&mut ctx, warn_after, now + time::Duration::hours(2)),
+            vec![(addr!("held@foo.com"), Action::Delayed)]
+        );
+        assert!(update_notified(&mut ctx, warn_after, now + time::Duration::hours(3)).is_empty());
+    }
+
+    #[test]
+    fn report() {
+        let mut ctx = local_ctx();
+        ctx.mail_from.dsn = dsn::MailFromParameters {
+            ret: Some(dsn::Ret::Headers),
+            envelope_id: Some("foo+bar baz".to_string()),
+        };
+
+        let forward_path = addr!("john@doe.com");
+        let status = Status::failed(transfer::error::Variant::Delivery(vec![(
+            vsmtp_common::Target::Domain("doe.com".parse().unwrap()),
+            Delivery::Permanent {
+                reply: ReplyCode::Enhanced {
+                    code: 550,
+                    enhanced: "5.1.1".to_string(),
+                },
+                with_source: Some("5.1.1 mailbox unavailable".to_string()),
+            },
+        )]));
+        let original_forward_path = dsn::OriginalRecipient {
+            addr_type: "rfc822".to_string(),
+            mailbox: "j+doe@doe.com".to_string(),
+        };
+
+        let now = time::OffsetDateTime::now_utc();
+        let report = build_report(
+            &ctx,
+            &local_msg(),
+            &[Report {
+                forward_path: &forward_path,
+                status: &status,
+                action: Action::Failed,
+                original_forward_path: Some(&original_forward_path),
+            }],
+            uuid::Uuid::nil(),
+            now,
+        )
+        .unwrap();
+
+        let boundary = "00000000-0000-0000-0000-000000000000/testserver.com";
+        pretty_assertions::assert_eq!(
+            report.inner().to_string(),
+            [
+                "From: Mail Delivery System <MAILER-DAEMON@testserver.com>\r\n",
+                "To: <client@testserver.com>\r\n",
+                "Subject: Undelivered Mail Returned to Sender\r\n",
+                &format!("Date: {}\r\n", now.format(&Rfc2822).unwrap()),
+                "Message-ID: <00000000-0000-0000-0000-000000000000@testserver.com>\r\n",
+                "Auto-Submitted: auto-replied\r\n",
+                "MIME-Version: 1.0\r\n",
+                "Content-Type: multipart/report; report-type=delivery-status;\r\n",
+                &format!("\tboundary=\"{boundary}\"\r\n"),
+                "\r\n",
+                "This is a MIME-encapsulated message.\r\n",
+                "\r\n",
+                &format!("--{boundary}\r\n"),
+                "Content-Type: text/plain; charset=us-ascii\r\n",
+                "\r\n",
+                "This is the mail system at host testserver.com.\r\n",
+                "\r\n",
+                "Your message could not be delivered to <john@doe.com>.\r\n",
+                "\r\n",
+                &format!("--{boundary}\r\n"),
+                "Content-Type: message/delivery-status\r\n",
+                "\r\n",
+                "Reporting-MTA: dns; testserver.com\r\n",
+                "Original-Envelope-Id: foo+2Bbar+20baz\r\n",
+                &format!(
+                    "Arrival-Date: {}\r\n",
+                    ctx.mail_from.mail_timestamp.format(&Rfc2822).unwrap()
+                ),
+                "\r\n",
+                "Original-Recipient: rfc822;j+2Bdoe@doe.com\r\n",
+                "Final-Recipient: rfc822; john@doe.com\r\n",
+                "Action: failed\r\n",
+                "Status: 5.1.1\r\n",
+                "Diagnostic-Code: smtp; 550 5.1.1 mailbox unavailable\r\n",
+                "\r\n",
+                &format!("--{boundary}\r\n"),
+                "Content-Type: text/rfc822-headers\r\n",
+                "\r\n",
+                "From: NoBody <nobody@domain.tld>\r\n",
+                "Reply-To: Yuin <yuin@domain.tld>\r\n",
+                "To: Hei <hei@domain.tld>\r\n",
+                "Subject: Happy new year\r\n",
+                "\r\n",
+                &format!("--{boundary}--\r\n"),
+            ]
+            .concat()
+        );
+    }
+}
@@ -16,64 +16,313 @@
  */
 use crate::{
     delivery::{
-        deferred::flush_deferred_queue,
-        deliver::{flush_deliver_queue, handle_one},
+        deferred::flush_scheduled,
+        deliver::{handle_one, list_deliver_queue},
+        dsn::Notification,
+        pending::{Job, Pending},
+        retention::expire,
+        schedule::Scheduled,
     },
     scheduler,
+    shutdown::{self, Shutdown},
+    Control, Reloader,
 };
 use anyhow::Context;
 use time::format_description::well_known::Rfc2822;
-use tokio_stream::StreamExt;
-use vqueue::GenericQueueManager;
+use vqueue::{
+    history::{self, Attempt, Event, EventKind, Outcome},
+    GenericQueueManager,
+};
 use vsmtp_common::status::Status;
-use vsmtp_common::ContextFinished;
-use vsmtp_config::Config;
+use vsmtp_common::{Address, ContextFinished, Priority};
+use vsmtp_delivery::{ConnectionPool, SenderOutcome};
 use vsmtp_mail_parser::MessageBody;
-use vsmtp_rule_engine::RuleEngine;
 
 /// Deferred delivery
 pub mod deferred;
 /// First delivery
 pub mod deliver;
+/// Delivery status notifications
+mod dsn;
+/// Messages waiting for a delivery task
+mod pending;
+/// Expiry of the dead queue and of the quarantines
+mod retention;
+/// Next flush of the messages in the deferred queue
+pub mod schedule;
 
+/// The pool, the flush periods, the limits and the concurrency are set up with the configuration
+/// at startup, the messages are processed with the configuration and rules in force.
+///
+/// At most `concurrency` messages are delivered at the same time, the messages of the channel
+/// and of the flushes of the queues wait their turn and the highest priorities are taken first.
+/// The messages of the deferred queue keep the priority of their schedule, the ones of a flush
+/// of the deliver queue have the default priority.
+///
+/// The messages of the deferred queue are flushed when their next attempt is due,
+/// and the whole queue is flushed on a request of the [`Control`]. The queue is read
+/// at startup to schedule its messages, and again before a flush requested by the
+/// [`Control`] to schedule the messages written to it by another process.
+///
+/// The retention policies of the dead queue and of the quarantines are enforced
+/// at startup and then periodically.
+///
+/// The delivery status notifications are sent as the messages of the channel.
+///
+/// When the `shutdown` is requested, the messages remaining in the channel and the ones of
+/// the flushes of the deliver queue are delivered, and the flushes and the maintenance in
+/// progress are completed, within the grace period. The pooled connections are closed then.
+#[allow(clippy::too_many_lines)]
 pub(crate) async fn start<Q: GenericQueueManager + Sized + 'static>(
-    config: std::sync::Arc<Config>,
-    rule_engine: std::sync::Arc<RuleEngine>,
-    queue_manager: std::sync::Arc<Q>,
+    reloader: std::sync::Arc<Reloader>,
+    queue_manager: std::sync::Arc<Scheduled<Q>>,
     mut receiver: scheduler::Receiver,
+    mut shutdown: Shutdown,
+    control: std::sync::Arc<Control>,
 ) {
-    flush_deliver_queue(config.clone(), queue_manager.clone(), rule_engine.clone()).await;
-
-    let mut flush_deferred_interval =
-        tokio::time::interval(config.server.queues.delivery.deferred_retry_period);
-
-    let delivery_receiver = receiver.as_stream().map(|pm| {
-        tokio::spawn(handle_one(
-            config.clone(),
-            queue_manager.clone(),
-            pm,
-            rule_engine.clone(),
-        ))
-    });
-    tokio::pin!(delivery_receiver);
-
-    loop {
+    let (config, _, _) = reloader.snapshot();
+    let pool = std::sync::Arc::new(ConnectionPool::new(&config.server.queues.delivery));
+    control.set_pool(pool.clone());
+    let (outbox, mut notifications) = tokio::sync::mpsc::unbounded_channel();
+
+    let mut pending = Pending::default();
+    pending.push_queued(list_deliver_queue(queue_manager.as_ref()).await);
+
+    schedule_deferred(queue_manager.clone()).await;
+
+    let mut close_idle_interval =
+        tokio::time::interval(config.server.queues.delivery.connection_pool.idle_timeout);
+    let mut retention_interval = tokio::time::interval(config.server.queues.retention.period);
+
+    let concurrency = config.server.queues.delivery.concurrency;
+    let mut tasks = tokio::task::JoinSet::new();
+    // flushes of the queues and maintenance of the pool and of the queues.
+    let mut background = tokio::task::JoinSet::new();
+    // jobs produced by the flushes.
+    let (flush_sender, mut flush_receiver) = tokio::sync::mpsc::unbounded_channel();
+
+    let spawn = |tasks: &mut tokio::task::JoinSet<_>, job| {
+        let (config, rule_engine, _) = reloader.snapshot();
+        let queue_manager = queue_manager.clone();
+        let (message_uuid, handle) = match job {
+            Job::Deliver(pm) => (
+                *pm.as_ref(),
+                futures_util::future::Either::Left(async move {
+                    let _err = handle_one(config, queue_manager, pm, rule_engine).await;
+                }),
+            ),
+            Job::Deferred {
+                message_uuid,
+                flushing_at,
+                ..
+            } => (
+                message_uuid,
+                futures_util::future::Either::Right(flush_scheduled(
+                    config,
+                    queue_manager,
+                    rule_engine.srv().resolvers.clone(),
+                    message_uuid,
+                    flushing_at,
+                )),
+            ),
+        };
+        let handle = pool
+            .clone()
+            .scope(Notification::scope(outbox.clone(), handle));
+        let control = control.clone();
+        tasks.spawn(async move {
+            handle.await;
+            control.delivery_attempted();
+            Some(message_uuid)
+        });
+    };
+    // the jobs are taken from the channel one at a time, to be ordered with the others.
+    let fill = |tasks: &mut tokio::task::JoinSet<_>,
+                pending: &mut Pending,
+                receiver: &mut scheduler::Receiver| {
+        while tasks.len() < concurrency.get() {
+            if let Some(pm) = receiver.try_recv() {
+                pending.push(Job::Deliver(pm));
+            }
+            match pending.pop() {
+                Some(job) => spawn(tasks, job),
+                None => break,
+            }
+        }
+    };
+    let send = |tasks: &mut tokio::task::JoinSet<_>, notification: Notification| {
+        let (config, _, _) = reloader.snapshot();
+        let send = pool
+            .clone()
+            .scope(notification.send(config, queue_manager.clone()));
+        tasks.spawn(async move {
+            send.await;
+            None
+        });
+    };
+
+    let deadline = loop {
+        fill(&mut tasks, &mut pending, &mut receiver);
+
         tokio::select! {
-            Some(_join_handle) = delivery_receiver.next() => {}
-            _ = flush_deferred_interval.tick() => {
-                tracing::info!("cronjob delay elapsed `{}s`, flushing queue.",
-                    config.server.queues.delivery.deferred_retry_period.as_secs());
-
-                tokio::spawn(
-                    flush_deferred_queue(
-                        config.clone(),
-                        queue_manager.clone(),
+            deadline = shutdown.requested() => break deadline,
+            Some(pm) = receiver.recv(), if tasks.len() < concurrency.get() => {
+                pending.push(Job::Deliver(pm));
+            }
+            Some(done) = tasks.join_next() => {
+                if let Ok(Some(message_uuid)) = done {
+                    pending.done(&message_uuid);
+                }
+            }
+            Some(_) = background.join_next() => {}
+            Some(jobs) = flush_receiver.recv() => match jobs {
+                Flushed::Deliver(messages) => pending.push_queued(messages),
+                Flushed::Deferred(messages, flushing_at) => {
+                    push_deferred(&mut pending, messages, flushing_at);
+                }
+            },
+            Some(notification) = notifications.recv() => send(&mut tasks, notification),
+            () = control.flush_deliver.notified() => {
+                tracing::info!("Flush of the deliver queue requested.");
+
+                let (queue_manager, flush_sender) = (queue_manager.clone(), flush_sender.clone());
+                background.spawn(async move {
+                    let messages = list_deliver_queue(queue_manager.as_ref()).await;
+                    let _closed = flush_sender.send(Flushed::Deliver(messages));
+                });
+            }
+            due = queue_manager.schedule().due() => {
+                tracing::debug!(count = due.len(), "Deferred messages due.");
+
+                push_deferred(&mut pending, due, time::OffsetDateTime::now_utc());
+            }
+            () = control.flush_deferred.notified() => {
+                tracing::info!("Flush of the deferred queue requested.");
+
+                let (queue_manager, flush_sender) = (queue_manager.clone(), flush_sender.clone());
+                background.spawn(async move {
+                    schedule_deferred(queue_manager.clone()).await;
+                    let messages = queue_manager.schedule().pop_all();
+                    let _closed = flush_sender.send(Flushed::Deferred(
+                        messages,
                         time::OffsetDateTime::now_utc(),
-                    )
-                );
+                    ));
+                });
+            }
+            _ = close_idle_interval.tick() => {
+                let pool = pool.clone();
+                background.spawn(async move { pool.close_expired().await });
+            }
+            _ = retention_interval.tick() => {
+                background.spawn(expire(queue_manager.clone()));
             }
         };
+    };
+
+    // NOTE: the messages of the deferred queue are scheduled again at the next start.
+    pending.retain_deliver();
+    receiver.close();
+    // NOTE: the notifications produced from now on are sent at the next start.
+    while let Ok(notification) = notifications.try_recv() {
+        send(&mut tasks, notification);
+    }
+
+    let _elapsed = tokio::time::timeout_at(deadline, async {
+        loop {
+            fill(&mut tasks, &mut pending, &mut receiver);
+            match tasks.join_next().await {
+                Some(Ok(Some(message_uuid))) => pending.done(&message_uuid),
+                Some(_) => {}
+                None => break,
+            }
+        }
+    })
+    .await;
+    if !pending.is_empty() {
+        tracing::warn!(
+            messages = pending.len(),
+            "Grace period elapsed, the messages not started are delivered at the next start."
+        );
+    }
+
+    tokio::join!(
+        shutdown::join_until(&mut tasks, deadline, "delivery"),
+        shutdown::join_until(&mut background, deadline, "delivery flushes"),
+    );
+    pool.close_all().await;
+}
+
+/// Jobs produced by a flush of a queue.
+enum Flushed {
+    /// The messages of the deliver queue.
+    Deliver(Vec<uuid::Uuid>),
+    /// The messages of the deferred queue taken from the schedule, and the date of the flush.
+    Deferred(Vec<(uuid::Uuid, Priority)>, time::OffsetDateTime),
+}
+
+/// Add the messages of the deferred queue taken from the schedule to the `pending` jobs.
+fn push_deferred(
+    pending: &mut Pending,
+    messages: Vec<(uuid::Uuid, Priority)>,
+    flushing_at: time::OffsetDateTime,
+) {
+    for (message_uuid, priority) in messages {
+        pending.push(Job::Deferred {
+            message_uuid,
+            priority,
+            flushing_at,
+        });
+    }
+}
+
+/// Read the deferred queue to schedule its messages.
+async fn schedule_deferred<Q: GenericQueueManager + Sized + 'static>(
+    queue_manager: std::sync::Arc<Scheduled<Q>>,
+) {
+    if let Err(error) = queue_manager.rebuild().await {
+        tracing::error!(%error, "Deferred queue cannot be scheduled.");
+    }
+}
+
+/// Recipients of the message a delivery attempt is made for.
+fn sendable(ctx: &ContextFinished) -> Vec<Address> {
+    ctx.rcpt_to
+        .delivery
+        .values()
+        .flatten()
+        .filter(|(_, status)| status.is_sendable())
+        .map(|(addr, _)| addr.clone())
+        .collect()
+}
+
+/// Record the status of the `attempted` recipients after a delivery attempt in the history
+/// of the message, and its final outcome if it leaves the queues.
+async fn record_attempt<Q: GenericQueueManager + ?Sized>(
+    queue_manager: &Q,
+    ctx: &ContextFinished,
+    attempted: &[Address],
+    outcome: &SenderOutcome,
+) {
+    let mut events = vec![Event::now(EventKind::Delivery {
+        recipients: ctx
+            .rcpt_to
+            .delivery
+            .values()
+            .flatten()
+            .filter(|(addr, _)| attempted.contains(addr))
+            .map(|(addr, status)| Attempt::new(addr.clone(), status))
+            .collect(),
+    })];
+    match outcome {
+        SenderOutcome::RemoveFromDisk => events.push(Event::now(EventKind::Finished {
+            outcome: Outcome::Delivered,
+        })),
+        SenderOutcome::MoveToDead => events.push(Event::now(EventKind::Finished {
+            outcome: Outcome::Dead,
+        })),
+        SenderOutcome::MoveToDeferred => (),
     }
+    history::record(queue_manager, &ctx.mail_from.message_uuid, &events).await;
 }
 
 // <https://datatracker.ietf.org/doc/html/rfc5321#section-4.4>
@@ -0,0 +1,201 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2023 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+*/
+use crate::ProcessMessage;
+use vsmtp_common::Priority;
+
+/// A message waiting for a delivery task.
+#[derive(Debug)]
+pub enum Job {
+    /// A message of the channel, or of the `deliver` queue read by a flush.
+    Deliver(ProcessMessage),
+    /// A message of the `deferred` queue taken from its schedule.
+    Deferred {
+        message_uuid: uuid::Uuid,
+        priority: Priority,
+        flushing_at: time::OffsetDateTime,
+    },
+}
+
+impl Job {
+    fn message_uuid(&self) -> uuid::Uuid {
+        match self {
+            Self::Deliver(process_message) => *process_message.as_ref(),
+            Self::Deferred { message_uuid, .. } => *message_uuid,
+        }
+    }
+
+    const fn priority(&self) -> Priority {
+        match self {
+            Self::Deliver(process_message) => process_message.priority(),
+            Self::Deferred { priority, .. } => *priority,
+        }
+    }
+}
+
+struct Queued {
+    priority: Priority,
+    /// order of arrival, the jobs of the same priority are produced first in first out.
+    sequence: u64,
+    job: Job,
+}
+
+impl PartialEq for Queued {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for Queued {}
+
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Queued {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.sequence.cmp(&self.sequence))
+    }
+}
+
+/// Jobs of the delivery, produced by order of priority and then by order of arrival.
+///
+/// The messages of the `deliver` queue read by a flush are skipped while they are
+/// already waiting or being delivered.
+#[derive(Default)]
+pub struct Pending {
+    queue: std::collections::BinaryHeap<Queued>,
+    sequence: u64,
+    /// messages waiting or being delivered.
+    active: std::collections::HashSet<uuid::Uuid>,
+}
+
+impl Pending {
+    /// Add a job.
+    pub fn push(&mut self, job: Job) {
+        self.active.insert(job.message_uuid());
+        let sequence = self.sequence;
+        self.sequence += 1;
+        self.queue.push(Queued {
+            priority: job.priority(),
+            sequence,
+            job,
+        });
+    }
+
+    /// Add the messages of the `deliver` queue which are neither waiting nor being delivered.
+    pub fn push_queued(&mut self, messages: Vec<uuid::Uuid>) {
+        for message_uuid in messages {
+            if !self.active.contains(&message_uuid) {
+                self.push(Job::Deliver(ProcessMessage::new(message_uuid)));
+            }
+        }
+    }
+
+    /// Take the job with the highest priority, the message is active until
+    /// [`Pending::done`] is called.
+    pub fn pop(&mut self) -> Option<Job> {
+        self.queue.pop().map(|Queued { job, .. }| job)
+    }
+
+    /// The delivery of the message is over.
+    pub fn done(&mut self, message_uuid: &uuid::Uuid) {
+        self.active.remove(message_uuid);
+    }
+
+    /// Drop the jobs of the `deferred` queue, they are scheduled again at the next start.
+    pub fn retain_deliver(&mut self) {
+        self.queue = core::mem::take(&mut self.queue)
+            .into_iter()
+            .filter(|queued| matches!(queued.job, Job::Deliver(_)))
+            .collect();
+    }
+
+    /// Number of jobs waiting.
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Is there no job waiting ?
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deferred(priority: Priority) -> Job {
+        Job::Deferred {
+            message_uuid: uuid::Uuid::new_v4(),
+            priority,
+            flushing_at: time::OffsetDateTime::now_utc(),
+        }
+    }
+
+    #[test]
+    fn highest_priority_first() {
+        let mut pending = Pending::default();
+        let jobs = [
+            Job::Deliver(ProcessMessage::new(uuid::Uuid::new_v4())),
+            deferred(Priority::MAX),
+            Job::Deliver(ProcessMessage::new(uuid::Uuid::new_v4()).with_priority(Priority::MIN)),
+            deferred(Priority::DEFAULT),
+        ];
+        let expected = [1, 0, 3, 2].map(|i| jobs[i].message_uuid());
+        for job in jobs {
+            pending.push(job);
+        }
+        assert_eq!(pending.len(), 4);
+
+        let popped = core::iter::from_fn(|| pending.pop())
+            .map(|job| job.message_uuid())
+            .collect::<Vec<_>>();
+        assert_eq!(popped, expected);
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn skip_active() {
+        let mut pending = Pending::default();
+        let (waiting, running, done) = (
+            uuid::Uuid::new_v4(),
+            uuid::Uuid::new_v4(),
+            uuid::Uuid::new_v4(),
+        );
+
+        pending.push_queued(vec![running, done]);
+        pending.pop().unwrap();
+        pending.pop().unwrap();
+        pending.done(&done);
+        pending.push(Job::Deliver(ProcessMessage::new(waiting)));
+
+        pending.push_queued(vec![waiting, running, done]);
+        assert_eq!(pending.len(), 2);
+
+        pending.push(deferred(Priority::MAX));
+        pending.retain_deliver();
+        let popped = core::iter::from_fn(|| pending.pop())
+            .map(|job| job.message_uuid())
+            .collect::<Vec<_>>();
+        assert_eq!(popped, [waiting, done]);
+    }
+}
@@ -0,0 +1,51 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+*/
+use vqueue::{history, retention, GenericQueueManager};
+
+/// Remove the expired messages of the `dead` queue and of the quarantines,
+/// and write them to the audit log. The expired histories are removed too.
+pub async fn expire<Q: GenericQueueManager + Sized + 'static>(
+    queue_manager: std::sync::Arc<Q>,
+) {
+    let now = time::OffsetDateTime::now_utc();
+    match history::expire(queue_manager.as_ref(), now, false).await {
+        Ok(histories) if !histories.is_empty() => {
+            tracing::info!(count = histories.len(), "Expired histories removed.");
+        }
+        Ok(_) => (),
+        Err(error) => tracing::error!(%error, "Expired histories cannot be removed."),
+    }
+
+    let expired = match retention::enforce(queue_manager.as_ref(), now, false).await {
+        Ok(expired) => expired,
+        Err(error) => {
+            tracing::error!(%error, "Retention policies cannot be enforced.");
+            return;
+        }
+    };
+
+    for i in &expired {
+        tracing::info!(uuid = %i.msg_uuid, queue = %i.queue, reason = %i.reason, "Message expired.");
+    }
+    if let Err(error) = retention::audit(
+        &queue_manager.get_config().server.queues.retention.audit_log,
+        &expired,
+        now,
+    ) {
+        tracing::error!(%error, "Expired messages cannot be written to the audit log.");
+    }
+}
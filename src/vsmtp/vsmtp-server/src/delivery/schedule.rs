@@ -0,0 +1,520 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+*/
+use vqueue::{history::Event, GenericQueueManager, QueueID};
+use vsmtp_common::{transport::DeserializerFn, ContextFinished, Priority};
+use vsmtp_config::Config;
+use vsmtp_mail_parser::MessageBody;
+
+#[derive(Debug, Default)]
+struct Index {
+    by_time: std::collections::BTreeSet<(time::OffsetDateTime, uuid::Uuid)>,
+    by_uuid: std::collections::HashMap<uuid::Uuid, (time::OffsetDateTime, Priority)>,
+    /// Messages returned by [`Schedule::pop_due`] and not flushed yet.
+    flushing: std::collections::HashSet<uuid::Uuid>,
+}
+
+/// Date of the next flush of each message in the `deferred` queue.
+#[derive(Debug, Default)]
+pub struct Schedule {
+    index: std::sync::Mutex<Index>,
+    changed: tokio::sync::Notify,
+}
+
+impl Schedule {
+    fn index(&self) -> std::sync::MutexGuard<'_, Index> {
+        self.index
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    /// Flush the message at `at`, replacing its previous date.
+    pub fn insert(&self, message_uuid: uuid::Uuid, at: time::OffsetDateTime, priority: Priority) {
+        let earliest = {
+            let mut index = self.index();
+            if let Some((previous, _)) = index.by_uuid.insert(message_uuid, (at, priority)) {
+                index.by_time.remove(&(previous, message_uuid));
+            }
+            index.by_time.insert((at, message_uuid));
+            index.by_time.first() == Some(&(at, message_uuid))
+        };
+        if earliest {
+            self.changed.notify_one();
+        }
+    }
+
+    /// Flush the message at `at`, unless it is already scheduled or being flushed.
+    pub fn insert_missing(
+        &self,
+        message_uuid: uuid::Uuid,
+        at: time::OffsetDateTime,
+        priority: Priority,
+    ) {
+        if !self.contains(&message_uuid) {
+            self.insert(message_uuid, at, priority);
+        }
+    }
+
+    /// Is the message scheduled or being flushed ?
+    #[must_use]
+    pub fn contains(&self, message_uuid: &uuid::Uuid) -> bool {
+        let index = self.index();
+        index.by_uuid.contains_key(message_uuid) || index.flushing.contains(message_uuid)
+    }
+
+    /// The flush of a message returned by [`Schedule::pop_due`] is over.
+    pub fn flushed(&self, message_uuid: &uuid::Uuid) {
+        self.index().flushing.remove(message_uuid);
+    }
+
+    /// Do not flush the message anymore.
+    pub fn remove(&self, message_uuid: &uuid::Uuid) {
+        let mut index = self.index();
+        if let Some((at, _)) = index.by_uuid.remove(message_uuid) {
+            index.by_time.remove(&(at, *message_uuid));
+        }
+    }
+
+    /// Date of the next flush of the message, if scheduled.
+    #[must_use]
+    pub fn get(&self, message_uuid: &uuid::Uuid) -> Option<time::OffsetDateTime> {
+        self.index().by_uuid.get(message_uuid).map(|(at, _)| *at)
+    }
+
+    /// Number of messages scheduled.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.index().by_uuid.len()
+    }
+
+    /// Is there no message scheduled ?
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Remove and return the messages due at `now` with their priority, the highest
+    /// priorities first and then the earliest dates.
+    ///
+    /// The messages are considered being flushed until [`Schedule::flushed`] is called.
+    #[must_use]
+    pub fn pop_due(&self, now: time::OffsetDateTime) -> Vec<(uuid::Uuid, Priority)> {
+        self.pop_until(Some(now))
+    }
+
+    /// Remove and return all the messages scheduled, as [`Schedule::pop_due`].
+    #[must_use]
+    pub fn pop_all(&self) -> Vec<(uuid::Uuid, Priority)> {
+        self.pop_until(None)
+    }
+
+    fn pop_until(&self, now: Option<time::OffsetDateTime>) -> Vec<(uuid::Uuid, Priority)> {
+        let mut index = self.index();
+        let mut due = vec![];
+        while let Some((at, message_uuid)) = index.by_time.first().copied() {
+            if now.map_or(false, |now| at > now) {
+                break;
+            }
+            index.by_time.remove(&(at, message_uuid));
+            if let Some((_, priority)) = index.by_uuid.remove(&message_uuid) {
+                index.flushing.insert(message_uuid);
+                due.push((message_uuid, priority));
+            }
+        }
+        drop(index);
+        due.sort_by_key(|(_, priority)| core::cmp::Reverse(*priority));
+        due
+    }
+
+    /// Wait for the next messages to be due, and remove them.
+    ///
+    /// Never completes if no message is scheduled.
+    pub async fn due(&self) -> Vec<(uuid::Uuid, Priority)> {
+        loop {
+            let next = self.index().by_time.first().map(|(at, _)| *at);
+            let now = time::OffsetDateTime::now_utc();
+
+            match next {
+                Some(at) if at <= now => return self.pop_due(now),
+                Some(at) => {
+                    let delay = std::time::Duration::try_from(at - now).unwrap_or_default();
+                    tokio::select! {
+                        () = tokio::time::sleep(delay) => {}
+                        () = self.changed.notified() => {}
+                    }
+                }
+                None => self.changed.notified().await,
+            }
+        }
+    }
+}
+
+/// Queue manager keeping the [`Schedule`] up to date with the messages written to
+/// and removed from the `deferred` queue.
+#[derive(Debug)]
+pub struct Scheduled<Q> {
+    inner: std::sync::Arc<Q>,
+    schedule: Schedule,
+}
+
+impl<Q: GenericQueueManager + Sized + 'static> Scheduled<Q> {
+    /// The schedule of the `deferred` queue.
+    #[must_use]
+    pub const fn schedule(&self) -> &Schedule {
+        &self.schedule
+    }
+
+    fn next_flush(&self, ctx: &ContextFinished) -> time::OffsetDateTime {
+        self.inner
+            .get_config()
+            .server
+            .queues
+            .delivery
+            .retry
+            .next_flush(ctx)
+    }
+
+    fn on_write(&self, queue: &QueueID, ctx: &ContextFinished) {
+        if *queue == QueueID::Deferred {
+            self.schedule.insert(
+                ctx.mail_from.message_uuid,
+                self.next_flush(ctx),
+                ctx.mail_from.priority,
+            );
+        }
+    }
+
+    /// End the flush of a message returned by the schedule.
+    ///
+    /// A message still in the `deferred` queue but not scheduled anymore, because its flush
+    /// failed or it was not written back, is scheduled again at its next flush, and not
+    /// before `retry_after` if its flush `failed`.
+    pub async fn flushed(
+        &self,
+        message_uuid: &uuid::Uuid,
+        failed: bool,
+        retry_after: std::time::Duration,
+    ) {
+        if self.schedule.get(message_uuid).is_none() {
+            if let Ok(ctx) = self.inner.get_ctx(&QueueID::Deferred, message_uuid).await {
+                let next_flush = self.next_flush(&ctx);
+                let at = if failed {
+                    next_flush.max(time::OffsetDateTime::now_utc() + retry_after)
+                } else {
+                    next_flush
+                };
+                self.schedule
+                    .insert(*message_uuid, at, ctx.mail_from.priority);
+            }
+        }
+        self.schedule.flushed(message_uuid);
+    }
+
+    fn on_remove(&self, queue: &QueueID, message_uuid: &uuid::Uuid) {
+        if *queue == QueueID::Deferred {
+            self.schedule.remove(message_uuid);
+        }
+    }
+
+    /// Read the `deferred` queue and schedule its messages which are neither scheduled
+    /// nor being flushed, only their context is read.
+    ///
+    /// # Errors
+    ///
+    /// * the queue cannot be listed
+    pub async fn rebuild(&self) -> anyhow::Result<()> {
+        for message_uuid in self.inner.list(&QueueID::Deferred).await? {
+            let message_uuid = match message_uuid.and_then(|i| Ok(uuid::Uuid::parse_str(&i)?)) {
+                Ok(message_uuid) => message_uuid,
+                Err(error) => {
+                    tracing::error!(%error, "Invalid message id in deferred queue.");
+                    continue;
+                }
+            };
+            if self.schedule.contains(&message_uuid) {
+                continue;
+            }
+            match self.inner.get_ctx(&QueueID::Deferred, &message_uuid).await {
+                Ok(ctx) => self.schedule.insert_missing(
+                    message_uuid,
+                    self.next_flush(&ctx),
+                    ctx.mail_from.priority,
+                ),
+                Err(error) => {
+                    tracing::error!(%error, uuid = %message_uuid, "Cannot schedule deferred message.");
+                }
+            }
+        }
+
+        tracing::info!(scheduled = self.schedule.len(), "Deferred queue scheduled.");
+        Ok(())
+    }
+}
+
+#[allow(clippy::missing_trait_methods)]
+#[async_trait::async_trait]
+impl<Q: GenericQueueManager + Sized + 'static> GenericQueueManager for Scheduled<Q> {
+    fn init(
+        config: std::sync::Arc<Config>,
+        transport_deserializer: Vec<DeserializerFn>,
+    ) -> anyhow::Result<std::sync::Arc<Self>> {
+        Ok(std::sync::Arc::new(Self {
+            inner: Q::init(config, transport_deserializer)?,
+            schedule: Schedule::default(),
+        }))
+    }
+
+    fn get_config(&self) -> &Config {
+        self.inner.get_config()
+    }
+
+    fn get_transport_deserializer(&self) -> &[DeserializerFn] {
+        self.inner.get_transport_deserializer()
+    }
+
+    async fn write_ctx(&self, queue: &QueueID, ctx: &ContextFinished) -> anyhow::Result<()> {
+        self.inner.write_ctx(queue, ctx).await?;
+        self.on_write(queue, ctx);
+        Ok(())
+    }
+
+    async fn write_msg(&self, msg_uuid: &uuid::Uuid, msg: &MessageBody) -> anyhow::Result<()> {
+        self.inner.write_msg(msg_uuid, msg).await
+    }
+
+    async fn write_both(
+        &self,
+        queue: &QueueID,
+        ctx: &ContextFinished,
+        msg: &MessageBody,
+    ) -> anyhow::Result<()> {
+        self.inner.write_both(queue, ctx, msg).await?;
+        self.on_write(queue, ctx);
+        Ok(())
+    }
+
+    async fn remove_ctx(&self, queue: &QueueID, msg_uuid: &uuid::Uuid) -> anyhow::Result<()> {
+        self.inner.remove_ctx(queue, msg_uuid).await?;
+        self.on_remove(queue, msg_uuid);
+        Ok(())
+    }
+
+    async fn remove_msg(&self, msg_uuid: &uuid::Uuid) -> anyhow::Result<()> {
+        self.inner.remove_msg(msg_uuid).await
+    }
+
+    async fn remove_both(&self, queue: &QueueID, msg_uuid: &uuid::Uuid) -> anyhow::Result<()> {
+        self.inner.remove_both(queue, msg_uuid).await?;
+        self.on_remove(queue, msg_uuid);
+        Ok(())
+    }
+
+    async fn list(&self, queue: &QueueID) -> anyhow::Result<Vec<anyhow::Result<String>>> {
+        self.inner.list(queue).await
+    }
+
+    async fn list_quarantines(&self) -> anyhow::Result<Vec<String>> {
+        self.inner.list_quarantines().await
+    }
+
+    async fn get_ctx(
+        &self,
+        queue: &QueueID,
+        msg_uuid: &uuid::Uuid,
+    ) -> anyhow::Result<ContextFinished> {
+        self.inner.get_ctx(queue, msg_uuid).await
+    }
+
+    async fn get_detailed_ctx(
+        &self,
+        queue: &QueueID,
+        msg_uuid: &uuid::Uuid,
+    ) -> anyhow::Result<vqueue::DetailedMailContext> {
+        self.inner.get_detailed_ctx(queue, msg_uuid).await
+    }
+
+    async fn get_msg(&self, msg_uuid: &uuid::Uuid) -> anyhow::Result<MessageBody> {
+        self.inner.get_msg(msg_uuid).await
+    }
+
+    async fn get_msg_size(&self, msg_uuid: &uuid::Uuid) -> anyhow::Result<usize> {
+        self.inner.get_msg_size(msg_uuid).await
+    }
+
+    async fn move_to(
+        &self,
+        before: &QueueID,
+        after: &QueueID,
+        ctx: &ContextFinished,
+    ) -> anyhow::Result<()> {
+        self.inner.move_to(before, after, ctx).await?;
+        self.on_remove(before, &ctx.mail_from.message_uuid);
+        self.on_write(after, ctx);
+        Ok(())
+    }
+
+    async fn append_history(&self, msg_uuid: &uuid::Uuid, events: &[Event]) -> anyhow::Result<()> {
+        self.inner.append_history(msg_uuid, events).await
+    }
+
+    async fn get_history(&self, msg_uuid: &uuid::Uuid) -> anyhow::Result<Vec<Event>> {
+        self.inner.get_history(msg_uuid).await
+    }
+
+    async fn list_history(&self) -> anyhow::Result<Vec<(uuid::Uuid, time::OffsetDateTime)>> {
+        self.inner.list_history().await
+    }
+
+    async fn remove_history(&self, msg_uuid: &uuid::Uuid) -> anyhow::Result<()> {
+        self.inner.remove_history(msg_uuid).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uuids(due: Vec<(uuid::Uuid, Priority)>) -> Vec<uuid::Uuid> {
+        due.into_iter()
+            .map(|(message_uuid, _)| message_uuid)
+            .collect()
+    }
+
+    #[test]
+    fn pop_in_order() {
+        let schedule = Schedule::default();
+        let now = time::OffsetDateTime::now_utc();
+        let (first, second, later) = (
+            uuid::Uuid::new_v4(),
+            uuid::Uuid::new_v4(),
+            uuid::Uuid::new_v4(),
+        );
+
+        schedule.insert(second, now - time::Duration::seconds(1), Priority::DEFAULT);
+        schedule.insert(later, now + time::Duration::hours(1), Priority::DEFAULT);
+        schedule.insert(first, now - time::Duration::minutes(1), Priority::DEFAULT);
+        assert_eq!(schedule.len(), 3);
+
+        assert_eq!(uuids(schedule.pop_due(now)), vec![first, second]);
+        assert_eq!(schedule.get(&later), Some(now + time::Duration::hours(1)));
+
+        schedule.insert(later, now, Priority::DEFAULT);
+        assert_eq!(uuids(schedule.pop_due(now)), vec![later]);
+        assert!(schedule.is_empty());
+    }
+
+    #[test]
+    fn pop_all() {
+        let schedule = Schedule::default();
+        let now = time::OffsetDateTime::now_utc();
+        let (due, later) = (uuid::Uuid::new_v4(), uuid::Uuid::new_v4());
+
+        schedule.insert(later, now + time::Duration::days(1), Priority::MAX);
+        schedule.insert(due, now, Priority::DEFAULT);
+
+        assert_eq!(
+            schedule.pop_all(),
+            vec![(later, Priority::MAX), (due, Priority::DEFAULT)]
+        );
+        assert!(schedule.is_empty());
+        assert!(schedule.contains(&later));
+    }
+
+    #[test]
+    fn pop_by_priority() {
+        let schedule = Schedule::default();
+        let now = time::OffsetDateTime::now_utc();
+        let (bulk, normal, urgent) = (
+            uuid::Uuid::new_v4(),
+            uuid::Uuid::new_v4(),
+            uuid::Uuid::new_v4(),
+        );
+
+        schedule.insert(bulk, now - time::Duration::hours(1), Priority::MIN);
+        schedule.insert(normal, now - time::Duration::minutes(1), Priority::DEFAULT);
+        schedule.insert(urgent, now, Priority::MAX);
+
+        assert_eq!(
+            schedule.pop_due(now),
+            vec![
+                (urgent, Priority::MAX),
+                (normal, Priority::DEFAULT),
+                (bulk, Priority::MIN)
+            ]
+        );
+    }
+
+    #[test]
+    fn remove() {
+        let schedule = Schedule::default();
+        let now = time::OffsetDateTime::now_utc();
+        let message_uuid = uuid::Uuid::new_v4();
+
+        schedule.insert(message_uuid, now, Priority::DEFAULT);
+        schedule.remove(&message_uuid);
+        assert!(uuids(schedule.pop_due(now)).is_empty());
+        assert_eq!(schedule.get(&message_uuid), None);
+    }
+
+    #[test]
+    fn insert_missing() {
+        let schedule = Schedule::default();
+        let now = time::OffsetDateTime::now_utc();
+        let (scheduled, flushing) = (uuid::Uuid::new_v4(), uuid::Uuid::new_v4());
+
+        schedule.insert(scheduled, now + time::Duration::hours(1), Priority::DEFAULT);
+        schedule.insert(flushing, now, Priority::DEFAULT);
+        assert_eq!(uuids(schedule.pop_due(now)), vec![flushing]);
+
+        schedule.insert_missing(scheduled, now, Priority::DEFAULT);
+        schedule.insert_missing(flushing, now, Priority::DEFAULT);
+        assert_eq!(
+            schedule.get(&scheduled),
+            Some(now + time::Duration::hours(1))
+        );
+        assert_eq!(schedule.get(&flushing), None);
+
+        assert!(schedule.contains(&flushing));
+
+        schedule.flushed(&flushing);
+        assert!(!schedule.contains(&flushing));
+        schedule.insert_missing(flushing, now, Priority::DEFAULT);
+        assert_eq!(uuids(schedule.pop_due(now)), vec![flushing]);
+    }
+
+    #[tokio::test]
+    async fn wake_up_when_due() {
+        let schedule = std::sync::Arc::new(Schedule::default());
+        let message_uuid = uuid::Uuid::new_v4();
+
+        let waiting = tokio::spawn({
+            let schedule = schedule.clone();
+            async move { schedule.due().await }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        schedule.insert(
+            message_uuid,
+            time::OffsetDateTime::now_utc() + time::Duration::milliseconds(50),
+            Priority::DEFAULT,
+        );
+
+        let due = tokio::time::timeout(std::time::Duration::from_secs(5), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(due, vec![(message_uuid, Priority::DEFAULT)]);
+    }
+}
@@ -29,6 +29,9 @@
 //
 
 mod channel_message;
+mod control;
+mod release;
+mod reload;
 mod runtime;
 mod server;
 mod receiver {
@@ -41,12 +44,16 @@ mod receiver {
 pub mod delivery;
 /// This module is responsible of the communication between the different part of the software.
 pub mod scheduler;
+/// This module coordinates the stop of the different part of the software.
+pub mod shutdown;
 /// This module execute logics on message after taking their responsibility, and before sending them.
 pub mod working;
 
 pub use channel_message::ProcessMessage;
+pub use control::{control_bind_anyhow, Control};
 pub use receiver::handler::Handler;
 pub use receiver::pre_transaction::ValidationVSL;
+pub use reload::Reloader;
 pub use runtime::start_runtime;
 pub use server::{socket_bind_anyhow, Server};
 
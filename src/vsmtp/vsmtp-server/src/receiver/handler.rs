@@ -15,9 +15,9 @@
  *
 */
 use tokio_rustls::rustls;
-use vqueue::GenericQueueManager;
+use vqueue::{history::Event, GenericQueueManager};
 use vsmtp_common::{
-    status::Status, Address, ContextFinished, Domain, Reply, Stage, TransactionType,
+    dsn, status::Status, Address, ContextFinished, Domain, Priority, Reply, Stage, TransactionType,
 };
 use vsmtp_config::Config;
 use vsmtp_delivery::Deliver;
@@ -45,6 +45,9 @@ where
     // FIXME: find another way to do this
     pub(super) state_internal: Option<std::sync::Arc<RuleState>>,
     pub(super) skipped: Option<Status>,
+    // Results of the pre-queue rules of the messages received, recorded in their history
+    // once they are queued.
+    pub(super) rules: std::collections::HashMap<uuid::Uuid, Vec<Event>>,
     //
     pub(super) config: std::sync::Arc<Config>,
     pub(super) rustls_config: Option<std::sync::Arc<rustls::ServerConfig>>,
@@ -87,6 +90,7 @@ where
             ),
             state_internal: None,
             skipped: None,
+            rules: std::collections::HashMap::new(),
             config,
             rustls_config,
             rule_engine,
@@ -151,16 +155,42 @@ impl<Parser: MailParser + Send + Sync, ParserFactory: Fn() -> Parser + Send + Sy
     }
 
     async fn on_mail_from(&mut self, ctx: &mut ReceiverContext, args: MailFromArgs) -> Reply {
+        // RFC 1870: reject as soon as the declared size is known to be too big.
+        if args
+            .message_size
+            .map_or(false, |size| size > self.config.server.message_size_limit)
+        {
+            return "552 5.3.4 Message size exceeds fixed maximum message size\r\n"
+                .parse::<Reply>()
+                .unwrap();
+        }
+
         let reverse_path = args
             .reverse_path
             .map(|reverse_path| reverse_path.parse().expect("handle invalid mailbox"));
 
-        self.state
-            .context()
-            .write()
-            .expect("state poisoned")
-            .to_mail_from(reverse_path)
+        {
+            let ctx = self.state.context();
+            let mut ctx = ctx.write().expect("state poisoned");
+            ctx.to_mail_from(reverse_path).expect("bad state");
+            ctx.set_message_size(args.message_size).expect("bad state");
+            // RFC 6710: the priority is only trusted for authenticated clients,
+            // the rules can set it for the others.
+            let priority = match args.priority {
+                Some(priority) if ctx.is_authenticated() => priority,
+                Some(priority) => {
+                    tracing::debug!(%priority, "Priority of an unauthenticated client ignored.");
+                    Priority::default()
+                }
+                None => Priority::default(),
+            };
+            ctx.set_priority(priority).expect("bad state");
+            ctx.set_mail_from_dsn(dsn::MailFromParameters {
+                ret: args.ret,
+                envelope_id: args.envelope_id,
+            })
             .expect("bad state");
+        }
 
         match self
             .rule_engine
@@ -200,6 +230,12 @@ impl<Parser: MailParser + Send + Sync, ParserFactory: Fn() -> Parser + Send + Sy
             .parse::<Address>()
             .expect("todo: handle invalid mailbox");
 
+        let rcpt_dsn = dsn::RcptToParameters {
+            notify_on: args.notify_on,
+            original_forward_path: args.original_forward_path,
+            last_notification: None,
+        };
+
         let is_internal = {
             let ctx = self.state.context();
             let mut ctx = ctx.write().expect("state poisoned");
@@ -249,13 +285,16 @@ impl<Parser: MailParser + Send + Sync, ParserFactory: Fn() -> Parser + Send + Sy
                     let mut internal_guard = internal_ctx.write().expect("state poisoned");
                     internal_guard
                         .add_forward_path(
-                            forward_path,
+                            forward_path.clone(),
                             std::sync::Arc::new(Deliver::new(
                                 self.rule_engine.srv().resolvers.get_resolver_root(),
                                 self.config.clone(),
                             )),
                         )
                         .expect("bad state");
+                    internal_guard
+                        .set_rcpt_to_dsn(forward_path, rcpt_dsn)
+                        .expect("bad state");
                     internal_guard
                         .set_transaction_type(TransactionType::Internal)
                         .expect("bad state");
@@ -275,13 +314,15 @@ impl<Parser: MailParser + Send + Sync, ParserFactory: Fn() -> Parser + Send + Sy
                     );
 
                     ctx.add_forward_path(
-                        forward_path,
+                        forward_path.clone(),
                         std::sync::Arc::new(Deliver::new(
                             self.rule_engine.srv().resolvers.get_resolver_root(),
                             self.config.clone(),
                         )),
                     )
                     .expect("bad state");
+                    ctx.set_rcpt_to_dsn(forward_path, rcpt_dsn)
+                        .expect("bad state");
                     ctx.set_transaction_type(reverse_path.as_ref().map_or(
                         TransactionType::Incoming(None),
                         |reverse_path| TransactionType::Outgoing {
@@ -308,13 +349,15 @@ impl<Parser: MailParser + Send + Sync, ParserFactory: Fn() -> Parser + Send + Sy
                     ))
                     .expect("bad state");
                     ctx.add_forward_path(
-                        forward_path,
+                        forward_path.clone(),
                         std::sync::Arc::new(Deliver::new(
                             self.rule_engine.srv().resolvers.get_resolver_root(),
                             self.config.clone(),
                         )),
                     )
                     .expect("bad state");
+                    ctx.set_rcpt_to_dsn(forward_path, rcpt_dsn)
+                        .expect("bad state");
 
                     false
                 }
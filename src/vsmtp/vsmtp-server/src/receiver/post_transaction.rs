@@ -17,7 +17,10 @@
 
 use crate::{Handler, ProcessMessage};
 use tokio_stream::StreamExt;
-use vqueue::QueueID;
+use vqueue::{
+    history::{self, Event, EventKind},
+    QueueID,
+};
 use vsmtp_common::{
     status::{self, Status},
     transfer::{self, error::Rule},
@@ -76,9 +79,17 @@ where
         status
     }
 
+    fn rules_events(state: &RuleState) -> Vec<Event> {
+        state
+            .results()
+            .iter()
+            .map(|(stage, status)| Event::now(EventKind::rules(stage, status)))
+            .collect()
+    }
+
     // TODO: enhance error handling
     pub(super) async fn on_message_completed_inner(
-        &self,
+        &mut self,
         mut ctx: ContextFinished,
         msg: MessageBody,
     ) -> Option<Reply> {
@@ -88,6 +99,9 @@ where
             .parse::<Reply>()
             .unwrap();
 
+        let mut events = vec![Event::now(EventKind::received(&ctx))];
+        events.extend(self.rules.remove(&message_uuid).unwrap_or_default());
+
         let (queue, should_skip_working, delegated) = match &skipped {
             Some(status @ status::Status::Quarantine(path)) => {
                 let quarantine = QueueID::Quarantine { name: path.into() };
@@ -95,6 +109,9 @@ where
                     Ok(()) => (),
                     Err(_e) => return Some(denied),
                 };
+                events.push(Event::now(EventKind::Queued {
+                    queue: quarantine.to_string(),
+                }));
 
                 tracing::warn!(status = status.as_ref(), "Rules skipped.");
                 (None, None, false)
@@ -148,13 +165,19 @@ where
                     return Some(denied);
                 }
             }
+            events.push(Event::now(EventKind::Queued {
+                queue: queue.to_string(),
+            }));
         }
 
+        history::record(self.queue_manager.as_ref(), &message_uuid, &events).await;
+
         let process_msg = if delegated {
             ProcessMessage::delegated
         } else {
             ProcessMessage::new
-        }(message_uuid);
+        }(message_uuid)
+        .with_priority(ctx.mail_from.priority);
 
         let process = match &should_skip_working {
             Some(false) => self.emitter.send_to_working(process_msg).await,
@@ -215,6 +238,7 @@ where
                 self.skipped.clone(),
                 mail.clone(),
             );
+            let rules = Self::rules_events(state_internal);
 
             let (mail_ctx, message) = std::mem::replace(&mut self.state_internal, None)
                 .unwrap()
@@ -230,6 +254,7 @@ where
                 }
                 Status::Delegated(_) => unreachable!(),
                 status => {
+                    self.rules.insert(mail_ctx.mail_from.message_uuid, rules);
                     mail_ctx.connect.skipped = Some(status);
                     Some((
                         "250 Ok\r\n".parse::<Reply>().unwrap(),
@@ -247,6 +272,7 @@ where
                 self.skipped.clone(),
                 mail,
             );
+            let rules = Self::rules_events(&self.state);
             let (client_addr, server_addr, server_name, timestamp, uuid) = {
                 let ctx = self.state.context();
                 let ctx = ctx.read().expect("state poisoned");
@@ -293,6 +319,7 @@ where
                     }
                     Status::Delegated(_) => unreachable!(),
                     status => {
+                        self.rules.insert(mail_ctx.mail_from.message_uuid, rules);
                         mail_ctx.connect.skipped = Some(status);
                         Some((
                             "250 Ok\r\n".parse::<Reply>().unwrap(),
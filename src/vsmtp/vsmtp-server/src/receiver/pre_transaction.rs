@@ -284,6 +284,7 @@ where
         }
     }
 
+    #[allow(clippy::too_many_lines)]
     pub(super) fn on_ehlo_inner(&mut self, ctx: &mut ReceiverContext, args: EhloArgs) -> Reply {
         let vsl_ctx = self.state.context();
 
@@ -322,7 +323,16 @@ where
                                     .join(" ")
                             )
                         }),
+                        Some("250-PIPELINING\r\n".to_string()),
+                        Some(format!(
+                            "250-SIZE {}\r\n",
+                            self.config.server.message_size_limit
+                        )),
                         Some("250-8BITMIME\r\n".to_string()),
+                        Some("250-BINARYMIME\r\n".to_string()),
+                        Some("250-CHUNKING\r\n".to_string()),
+                        Some("250-DSN\r\n".to_string()),
+                        Some("250-MT-PRIORITY\r\n".to_string()),
                         Some("250 SMTPUTF8\r\n".to_string()),
                     ]
                     .into_iter()
@@ -363,7 +373,16 @@ where
                             }
                         }),
                         Some("250-STARTTLS\r\n".to_string()),
+                        Some("250-PIPELINING\r\n".to_string()),
+                        Some(format!(
+                            "250-SIZE {}\r\n",
+                            self.config.server.message_size_limit
+                        )),
                         Some("250-8BITMIME\r\n".to_string()),
+                        Some("250-BINARYMIME\r\n".to_string()),
+                        Some("250-CHUNKING\r\n".to_string()),
+                        Some("250-DSN\r\n".to_string()),
+                        Some("250-MT-PRIORITY\r\n".to_string()),
                         Some("250 SMTPUTF8\r\n".to_string()),
                     ]
                     .into_iter()
@@ -0,0 +1,237 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+*/
+use crate::ProcessMessage;
+use anyhow::Context;
+use vqueue::{
+    control::{ReRunStage, Release, ReleaseAction},
+    history::{self, Event, EventKind},
+    GenericQueueManager, QueueID,
+};
+use vsmtp_common::{
+    status::Status,
+    transfer::{self, error::Rule},
+    transport::WrapperSerde,
+    Address, ContextFinished, Reply, TransactionType,
+};
+use vsmtp_config::{Config, DnsResolvers};
+use vsmtp_delivery::Deliver;
+
+/// Find the message in the quarantine `name`, or in all of them.
+async fn find(
+    queue_manager: &dyn GenericQueueManager,
+    msg_uuid: &uuid::Uuid,
+    name: Option<String>,
+) -> anyhow::Result<(String, ContextFinished)> {
+    let names = match name {
+        Some(name) => vec![name],
+        None => queue_manager.list_quarantines().await?,
+    };
+
+    for name in names {
+        if let Ok(ctx) = queue_manager
+            .get_ctx(&QueueID::Quarantine { name: name.clone() }, msg_uuid)
+            .await
+        {
+            return Ok((name, ctx));
+        }
+    }
+    anyhow::bail!("Message does not exist in the quarantines")
+}
+
+/// Status making the rule engine skip the rules of the next stages.
+fn skip_rules() -> Status {
+    Status::Faccept("250 Ok\r\n".parse::<Reply>().expect("valid reply"))
+}
+
+/// Keep only the recipients for which `keep` returns true.
+fn retain_recipients(ctx: &mut ContextFinished, keep: impl Fn(&Address) -> bool) {
+    ctx.rcpt_to.forward_paths.retain(&keep);
+    for rcpt in ctx.rcpt_to.delivery.values_mut() {
+        rcpt.retain(|(addr, _)| keep(addr));
+    }
+    ctx.rcpt_to.delivery.retain(|_, rcpt| !rcpt.is_empty());
+    ctx.rcpt_to.dsn.retain(|addr, _| keep(addr));
+}
+
+/// Copy of the message sent to `to` only, without notification to the sender.
+fn forward_copy(
+    ctx: &ContextFinished,
+    to: Address,
+    config: std::sync::Arc<Config>,
+    resolvers: &DnsResolvers,
+    now: time::OffsetDateTime,
+) -> ContextFinished {
+    let mut copy = ctx.clone();
+    copy.connect.skipped = Some(skip_rules());
+    copy.mail_from.message_uuid = uuid::Uuid::new_v4();
+    copy.mail_from.mail_timestamp = now;
+    copy.mail_from.reverse_path = None;
+    copy.mail_from.dsn = vsmtp_common::dsn::MailFromParameters::default();
+    copy.rcpt_to.forward_paths = vec![to.clone()];
+    copy.rcpt_to.delivery = std::iter::once((
+        WrapperSerde::Ready(std::sync::Arc::new(Deliver::new(
+            resolvers.get_resolver_root(),
+            config,
+        ))),
+        vec![(to, transfer::Status::default())],
+    ))
+    .collect();
+    copy.rcpt_to.transaction_type = TransactionType::Outgoing {
+        domain: ctx.connect.server_name.clone(),
+    };
+    copy.rcpt_to.dsn.clear();
+    copy
+}
+
+/// Release the message from its quarantine, and record the `release` in its context
+/// and in its history.
+///
+/// The released message is written in the queue of the process it must be sent to,
+/// and returned to be sent to it, with the priority of the quarantined message. A rejected message is sent to the delivery, without rules,
+/// which produces the bounce and moves it to the `dead` queue.
+///
+/// # Errors
+///
+/// * the message is not in the quarantine(s)
+/// * a recipient to release the message to is not a recipient of the message
+/// * the queues cannot be written
+#[tracing::instrument(
+    name = "release",
+    skip(config, queue_manager, resolvers, release),
+    fields(operator = %release.operator, reason = %release.reason)
+)]
+pub async fn release(
+    config: std::sync::Arc<Config>,
+    queue_manager: &dyn GenericQueueManager,
+    resolvers: &DnsResolvers,
+    msg_uuid: &uuid::Uuid,
+    quarantine: Option<String>,
+    release: Release,
+) -> anyhow::Result<(ReRunStage, ProcessMessage)> {
+    let (name, mut ctx) = find(queue_manager, msg_uuid, quarantine).await?;
+    let from = QueueID::Quarantine { name: name.clone() };
+
+    let now = time::OffsetDateTime::now_utc();
+    let released = EventKind::Released {
+        quarantine: name.clone(),
+        operator: release.operator.clone(),
+        action: release.action.to_string(),
+    };
+    ctx.finished.released.push(vsmtp_common::Release {
+        released_at: now,
+        quarantine: name,
+        operator: release.operator,
+        reason: release.reason.clone(),
+        action: release.action.to_string(),
+    });
+
+    let (stage, to, uuid) = match release.action {
+        ReleaseAction::Resume {
+            stage,
+            rerun,
+            recipients,
+        } => {
+            if let Some(unknown) = recipients
+                .iter()
+                .find(|rcpt| !ctx.rcpt_to.forward_paths.contains(rcpt))
+            {
+                anyhow::bail!("'{unknown}' is not a recipient of the message");
+            }
+            let to = match stage {
+                ReRunStage::Working => QueueID::Working,
+                ReRunStage::Delivery => QueueID::Deliver,
+            };
+
+            let mut released = ctx.clone();
+            released.connect.skipped = if rerun { None } else { Some(skip_rules()) };
+
+            if recipients.is_empty()
+                || ctx
+                    .rcpt_to
+                    .forward_paths
+                    .iter()
+                    .all(|rcpt| recipients.contains(rcpt))
+            {
+                queue_manager.write_ctx(&to, &released).await?;
+                queue_manager.remove_ctx(&from, msg_uuid).await?;
+            } else {
+                // the released recipients are sent as a new message,
+                // the others stay in quarantine.
+                released.mail_from.message_uuid = uuid::Uuid::new_v4();
+                retain_recipients(&mut released, |rcpt| recipients.contains(rcpt));
+                retain_recipients(&mut ctx, |rcpt| !recipients.contains(rcpt));
+
+                let msg = queue_manager.get_msg(msg_uuid).await?;
+                queue_manager
+                    .write_msg(&released.mail_from.message_uuid, &msg)
+                    .await?;
+                queue_manager.write_ctx(&to, &released).await?;
+                queue_manager.write_ctx(&from, &ctx).await?;
+            }
+
+            tracing::info!(%from, %to, uuid = %released.mail_from.message_uuid, "Message released.");
+            (stage, to, released.mail_from.message_uuid)
+        }
+        ReleaseAction::Reject => {
+            let reply = format!("550 5.7.1 {}\r\n", release.reason)
+                .parse::<Reply>()
+                .context("The reason cannot be sent in a reply")?;
+            for rcpt in ctx.rcpt_to.delivery.values_mut().flatten() {
+                rcpt.1 = transfer::Status::failed(Rule::Denied(reply.clone()));
+            }
+            ctx.connect.skipped = Some(skip_rules());
+
+            queue_manager.write_ctx(&QueueID::Deliver, &ctx).await?;
+            queue_manager.remove_ctx(&from, msg_uuid).await?;
+
+            tracing::info!(%from, "Message rejected.");
+            (ReRunStage::Delivery, QueueID::Deliver, *msg_uuid)
+        }
+        ReleaseAction::Forward { to } => {
+            let copy = forward_copy(&ctx, to, config, resolvers, now);
+
+            let msg = queue_manager.get_msg(msg_uuid).await?;
+            queue_manager
+                .write_msg(&copy.mail_from.message_uuid, &msg)
+                .await?;
+            queue_manager.write_ctx(&QueueID::Deliver, &copy).await?;
+            queue_manager.write_ctx(&from, &ctx).await?;
+
+            tracing::info!(%from, copy = %copy.mail_from.message_uuid, "Message forwarded.");
+            (
+                ReRunStage::Delivery,
+                QueueID::Deliver,
+                copy.mail_from.message_uuid,
+            )
+        }
+    };
+
+    history::record(queue_manager, msg_uuid, &[Event::now(released)]).await;
+    history::record(
+        queue_manager,
+        &uuid,
+        &[Event::now(EventKind::Queued {
+            queue: to.to_string(),
+        })],
+    )
+    .await;
+
+    Ok((
+        stage,
+        ProcessMessage::new(uuid).with_priority(ctx.mail_from.priority),
+    ))
+}
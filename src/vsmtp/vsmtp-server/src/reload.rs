@@ -0,0 +1,209 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+*/
+use anyhow::Context;
+use tokio_rustls::rustls;
+use vqueue::GenericQueueManager;
+use vsmtp_config::{get_rustls_config, Config, DnsResolvers};
+use vsmtp_rule_engine::RuleEngine;
+
+/// The configuration and the rules in force, always replaced together.
+#[derive(Clone)]
+struct State {
+    config: std::sync::Arc<Config>,
+    rule_engine: std::sync::Arc<RuleEngine>,
+    tls_config: Option<std::sync::Arc<rustls::ServerConfig>>,
+}
+
+impl State {
+    fn new(
+        config: std::sync::Arc<Config>,
+        rule_engine: std::sync::Arc<RuleEngine>,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            tls_config: config
+                .server
+                .tls
+                .as_ref()
+                .map(|smtps| get_rustls_config(smtps, &config.server.r#virtual))
+                .transpose()?
+                .map(std::sync::Arc::new),
+            config,
+            rule_engine,
+        })
+    }
+}
+
+/// Handle on the configuration and the rules, which can be reloaded while the server is running.
+///
+/// A transaction uses the version in force when it started until its end, the new version
+/// is used by the next ones.
+pub struct Reloader {
+    state: std::sync::RwLock<State>,
+    queue_manager: std::sync::Arc<dyn GenericQueueManager>,
+    reloads: std::sync::atomic::AtomicU64,
+}
+
+impl std::fmt::Debug for Reloader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Reloader")
+            .field("config", &self.config().path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Reloader {
+    /// Create a handle on the configuration and the rules built at startup.
+    ///
+    /// # Errors
+    ///
+    /// * cannot initialize [rustls] config
+    pub fn new(
+        config: std::sync::Arc<Config>,
+        rule_engine: std::sync::Arc<RuleEngine>,
+        queue_manager: std::sync::Arc<dyn GenericQueueManager>,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            state: std::sync::RwLock::new(State::new(config, rule_engine)?),
+            queue_manager,
+            reloads: std::sync::atomic::AtomicU64::new(0),
+        })
+    }
+
+    fn state(&self) -> State {
+        self.state
+            .read()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .clone()
+    }
+
+    /// The configuration in force.
+    #[must_use]
+    pub fn config(&self) -> std::sync::Arc<Config> {
+        self.state().config
+    }
+
+    /// The rule engine in force.
+    #[must_use]
+    pub fn rule_engine(&self) -> std::sync::Arc<RuleEngine> {
+        self.state().rule_engine
+    }
+
+    /// The configuration, the rule engine and the TLS configuration in force, from the same version.
+    #[must_use]
+    pub fn snapshot(
+        &self,
+    ) -> (
+        std::sync::Arc<Config>,
+        std::sync::Arc<RuleEngine>,
+        Option<std::sync::Arc<rustls::ServerConfig>>,
+    ) {
+        let State {
+            config,
+            rule_engine,
+            tls_config,
+        } = self.state();
+        (config, rule_engine, tls_config)
+    }
+
+    /// The queue manager, which is not affected by the reloads.
+    #[must_use]
+    pub fn queue_manager(&self) -> std::sync::Arc<dyn GenericQueueManager> {
+        self.queue_manager.clone()
+    }
+
+    /// Number of reloads succeeded since the start.
+    #[must_use]
+    pub fn reloads(&self) -> u64 {
+        self.reloads.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Read the configuration file again, compile the rules and use them for the next transactions.
+    ///
+    /// # Errors
+    ///
+    /// * the configuration has no path
+    /// * see [`Reloader::reload_with`]
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let path = self
+            .config()
+            .path
+            .clone()
+            .context("the configuration has not been loaded from a file")?;
+
+        self.reload_with(Config::from_vsl_file(&path).context("Cannot parse the configuration")?)
+    }
+
+    /// Compile the rules of the configuration and use them for the next transactions.
+    ///
+    /// The listening sockets, the threads, the queues and the logs are set up at startup,
+    /// a change of those fields is ignored until the server is restarted.
+    /// On failure, the previous configuration and rules are kept.
+    ///
+    /// # Errors
+    ///
+    /// * could not initialize the DNS resolvers
+    /// * failed to compile the rules
+    /// * cannot initialize [rustls] config
+    #[tracing::instrument(name = "reload", skip_all)]
+    pub fn reload_with(&self, config: Config) -> anyhow::Result<()> {
+        let config = std::sync::Arc::new(config);
+        let previous = self.config();
+
+        for (field, changed) in [
+            (
+                "server.interfaces",
+                config.server.interfaces != previous.server.interfaces,
+            ),
+            (
+                "server.system",
+                config.server.system != previous.server.system,
+            ),
+            (
+                "server.queues",
+                config.server.queues != previous.server.queues,
+            ),
+            ("server.logs", config.server.logs != previous.server.logs),
+        ] {
+            if changed {
+                tracing::warn!(
+                    field,
+                    "The field cannot be reloaded, restart the server to apply it."
+                );
+            }
+        }
+
+        let resolvers = std::sync::Arc::new(
+            DnsResolvers::from_config(&config).context("could not initialize dns")?,
+        );
+        let rule_engine = std::sync::Arc::new(RuleEngine::new(
+            config.clone(),
+            resolvers,
+            self.queue_manager.clone(),
+        )?);
+        let state = State::new(config, rule_engine)?;
+
+        *self
+            .state
+            .write()
+            .unwrap_or_else(std::sync::PoisonError::into_inner) = state;
+        self.reloads
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+
+        tracing::info!("Configuration and rules reloaded.");
+        Ok(())
+    }
+}
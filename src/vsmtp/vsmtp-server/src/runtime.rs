@@ -14,15 +14,17 @@
  * this program. If not, see https://www.gnu.org/licenses/.
  *
 */
-use crate::{delivery, scheduler, working, Server};
+use crate::{
+    control_bind_anyhow, delivery, scheduler, shutdown, working, Control, Reloader, Server,
+};
 use anyhow::Context;
 use vsmtp_common::transport::{AbstractTransport, DeserializerFn, DESERIALIZER_SYMBOL_NAME};
-use vsmtp_config::{Config, DnsResolvers};
+use vsmtp_config::{field::QueueBackend, Config, DnsResolvers};
 use vsmtp_delivery::{Deliver, Forward, MBox, Maildir};
 use vsmtp_rule_engine::RuleEngine;
 
 fn init_runtime<F>(
-    sender: tokio::sync::mpsc::Sender<()>,
+    sender: tokio::sync::mpsc::UnboundedSender<()>,
     name: impl Into<String>,
     worker_thread_count: usize,
     future: F,
@@ -57,13 +59,13 @@ where
 
                 match timeout {
                     Some(duration) => {
-                        tokio::time::timeout(duration, future).await.unwrap_err();
+                        let _elapsed = tokio::time::timeout(duration, future).await;
                     }
                     None => future.await,
                 }
             });
 
-            sender.blocking_send(())?;
+            sender.send(())?;
             Ok(())
         })
         .map_err(anyhow::Error::new)
@@ -146,14 +148,36 @@ pub fn start_runtime(
     );
     let transport_deserializer = get_transport_deserializer(&libs);
 
-    let mut error_handler = tokio::sync::mpsc::channel::<()>(3);
+    match config.server.queues.backend {
+        QueueBackend::Filesystem => {
+            run::<vqueue::fs::QueueManager>(config, transport_deserializer, sockets, timeout)
+        }
+        QueueBackend::Embedded => {
+            run::<vqueue::embedded::QueueManager>(config, transport_deserializer, sockets, timeout)
+        }
+    }
+}
+
+#[allow(clippy::too_many_lines, clippy::needless_pass_by_value)]
+fn run<Q: vqueue::GenericQueueManager + Sized + 'static>(
+    config: std::sync::Arc<Config>,
+    transport_deserializer: Vec<DeserializerFn>,
+    sockets: (
+        Vec<std::net::TcpListener>,
+        Vec<std::net::TcpListener>,
+        Vec<std::net::TcpListener>,
+    ),
+    timeout: Option<std::time::Duration>,
+) -> anyhow::Result<()> {
+    // NOTE: unbounded, so that neither the runtimes nor the signals block on a stop in progress.
+    let mut error_handler = tokio::sync::mpsc::unbounded_channel::<()>();
 
     let (emitter, working_rx, delivery_rx) = scheduler::init(
         config.server.queues.working.channel_size,
         config.server.queues.delivery.channel_size,
     );
 
-    let queue_manager = <vqueue::fs::QueueManager as vqueue::GenericQueueManager>::init(
+    let queue_manager = <delivery::schedule::Scheduled<Q> as vqueue::GenericQueueManager>::init(
         config.clone(),
         transport_deserializer,
     )?;
@@ -168,55 +192,87 @@ pub fn start_runtime(
         queue_manager.clone(),
     )?);
 
-    let _tasks_delivery = init_runtime(
+    let reloader = std::sync::Arc::new(Reloader::new(
+        config.clone(),
+        rule_engine,
+        queue_manager.clone(),
+    )?);
+
+    let control = std::sync::Arc::new(Control::new(reloader.clone(), emitter.clone()));
+    let control_socket = vqueue::control::socket_path(&config);
+    let control_listener = match control_bind_anyhow(&control_socket) {
+        Ok(listener) => Some(listener),
+        Err(error) => {
+            tracing::warn!(%error, "The control API is not available.");
+            None
+        }
+    };
+
+    let (delivery_trigger, delivery_shutdown) = shutdown::channel();
+    let tasks_delivery = init_runtime(
         error_handler.0.clone(),
         "delivery",
         config.server.system.thread_pool.delivery.get(),
         delivery::start(
-            config.clone(),
-            rule_engine.clone(),
+            reloader.clone(),
             queue_manager.clone(),
             delivery_rx,
+            delivery_shutdown,
+            control.clone(),
         ),
         timeout,
     )?;
 
-    let _tasks_processing = init_runtime(
+    let (working_trigger, working_shutdown) = shutdown::channel();
+    let tasks_processing = init_runtime(
         error_handler.0.clone(),
         "working",
         config.server.system.thread_pool.processing.get(),
         working::start(
-            rule_engine.clone(),
-            queue_manager.clone(),
+            reloader.clone(),
+            queue_manager,
             emitter.clone(),
             working_rx,
+            working_shutdown,
+            control.clone(),
         ),
         timeout,
     )?;
 
-    let _tasks_receiver = init_runtime(
+    let (receiver_trigger, receiver_shutdown) = shutdown::channel();
+    let tasks_receiver = init_runtime(
         error_handler.0.clone(),
         "receiver",
         config.server.system.thread_pool.receiver.get(),
-        async move {
-            let server = match Server::new(
-                config.clone(),
-                rule_engine.clone(),
-                queue_manager.clone(),
-                emitter,
-            ) {
-                Ok(server) => server,
-                Err(error) => {
-                    tracing::error!(%error, "Receiver build failure.");
-                    return;
+        {
+            let reloader = reloader.clone();
+            async move {
+                match control_listener.map(tokio::net::UnixListener::from_std) {
+                    Some(Ok(listener)) => {
+                        tokio::spawn(control.clone().serve(listener));
+                    }
+                    Some(Err(error)) => {
+                        tracing::warn!(%error, "The control API is not available.");
+                    }
+                    None => {}
+                }
+
+                let server = match Server::with_reloader(reloader, emitter) {
+                    Ok(server) => server
+                        .with_shutdown(receiver_shutdown)
+                        .with_control(control),
+                    Err(error) => {
+                        tracing::error!(%error, "Receiver build failure.");
+                        return;
+                    }
+                };
+                if let Err(error) = server.listen(sockets).await {
+                    tracing::error!(%error, "Receiver failure.");
                 }
-            };
-            if let Err(error) = server.listen(sockets).await {
-                tracing::error!(%error, "Receiver failure.");
             }
         },
         timeout,
-    );
+    )?;
 
     let error_handler_sig = error_handler.0.clone();
     let mut signals = signal_hook::iterator::Signals::new([
@@ -224,26 +280,56 @@ pub fn start_runtime(
         signal_hook::consts::SIGTERM,
         // Ctrl+C on a terminal
         signal_hook::consts::SIGINT,
+        // Send by `systemctl reload`
+        signal_hook::consts::SIGHUP,
     ])?;
     let _signal_handler = std::thread::spawn(move || {
         for sig in signals.forever() {
+            if sig == signal_hook::consts::SIGHUP {
+                tracing::info!(signal = sig, "Reloading vSMTP configuration.");
+                if let Err(error) = reloader.reload() {
+                    tracing::error!(%error, "Reload failure, the previous configuration is kept.");
+                    error
+                        .chain()
+                        .skip(1)
+                        .for_each(|cause| tracing::error!(reason = %cause));
+                }
+                continue;
+            }
             tracing::warn!(signal = sig, "Stopping vSMTP server.");
-            error_handler_sig
-                .blocking_send(())
-                .expect("failed to send terminating instruction");
+            // NOTE: the receiver is dropped once the server is stopped.
+            let _closed = error_handler_sig.send(());
         }
     });
 
     error_handler.1.blocking_recv();
 
-    Ok(())
+    // NOTE: the parts are stopped in the order of the flow of the messages, so that the
+    // messages accepted by the receiver are processed by the working and delivery pools.
+    let grace_period = config.server.system.shutdown_grace_period;
+    tracing::info!(?grace_period, "Shutting down.");
+    let deadline = tokio::time::Instant::now() + grace_period;
+
+    for (name, trigger, tasks) in [
+        ("receiver", receiver_trigger, tasks_receiver),
+        ("working", working_trigger, tasks_processing),
+        ("delivery", delivery_trigger, tasks_delivery),
+    ] {
+        trigger.shutdown(deadline);
+        match tasks.join() {
+            Ok(Ok(())) => tracing::info!(name, "Runtime stopped."),
+            Ok(Err(error)) => tracing::error!(name, %error, "Runtime failure."),
+            Err(_panic) => tracing::error!(name, "Runtime panicked."),
+        }
+    }
 
-    // if the runtime panicked (receiver/processing/delivery)
-    // .join() would return an error,
-    // but the join is CPU heavy and he blocking (so we can't join all of them)
-    // for i in [tasks_receiver, tasks_delivery, tasks_processing] {
-    //     i.join().map_err(|e| anyhow::anyhow!("{e:?}"))??;
-    // }
+    if control_socket.exists() {
+        if let Err(error) = std::fs::remove_file(&control_socket) {
+            tracing::warn!(%error, "Failed to remove the control socket.");
+        }
+    }
+
+    Ok(())
 }
 
 #[cfg(test)]
@@ -254,8 +340,13 @@ mod tests {
 
     #[test]
     fn basic() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = config::local_test();
+        config.server.queues.dirpath = dir.path().join("spool");
+        config.app.dirpath = dir.path().join("app");
+
         start_runtime(
-            config::local_test(),
+            config,
             (
                 vec![std::net::TcpListener::bind("0.0.0.0:22001").unwrap()],
                 vec![std::net::TcpListener::bind("0.0.0.0:22002").unwrap()],
@@ -265,4 +356,21 @@ mod tests {
         )
         .unwrap();
     }
+
+    #[test]
+    fn stop_already_notified() {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        // a stop requested twice, by two signals.
+        sender.send(()).unwrap();
+        sender.send(()).unwrap();
+
+        let runtimes = ["delivery", "working", "receiver"]
+            .into_iter()
+            .map(|name| init_runtime(sender.clone(), name, 1, async {}, None).unwrap())
+            .collect::<Vec<_>>();
+        for runtime in runtimes {
+            runtime.join().unwrap().unwrap();
+        }
+        drop(receiver);
+    }
 }
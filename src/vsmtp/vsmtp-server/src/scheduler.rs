@@ -16,34 +16,148 @@
 */
 
 use crate::ProcessMessage;
+use vsmtp_common::Priority;
+
+struct Queued {
+    priority: Priority,
+    /// order of arrival, the messages of the same priority are produced first in first out.
+    sequence: u64,
+    message: ProcessMessage,
+}
+
+impl PartialEq for Queued {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for Queued {}
+
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Queued {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.sequence.cmp(&self.sequence))
+    }
+}
+
+#[derive(Default)]
+struct State {
+    queue: std::collections::BinaryHeap<Queued>,
+    sequence: u64,
+    closed: bool,
+}
+
+/// Bounded channel producing the messages with the highest priority first.
+struct Channel {
+    state: std::sync::Mutex<State>,
+    /// free slots of the channel, the senders wait for one when it is full.
+    capacity: tokio::sync::Semaphore,
+    available: tokio::sync::Notify,
+}
+
+impl Channel {
+    fn new(size: usize) -> Self {
+        Self {
+            state: std::sync::Mutex::new(State::default()),
+            capacity: tokio::sync::Semaphore::new(size),
+            available: tokio::sync::Notify::new(),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    async fn send(&self, message: ProcessMessage) -> std::io::Result<()> {
+        let closed = || std::io::Error::from(std::io::ErrorKind::ConnectionAborted);
+
+        // the slot is given back when the message is received.
+        self.capacity
+            .acquire()
+            .await
+            .map_err(|_err| closed())?
+            .forget();
+        {
+            let mut state = self.state();
+            if state.closed {
+                return Err(closed());
+            }
+            let sequence = state.sequence;
+            state.sequence += 1;
+            state.queue.push(Queued {
+                priority: message.priority(),
+                sequence,
+                message,
+            });
+        }
+        self.available.notify_one();
+        Ok(())
+    }
+
+    async fn recv(&self) -> Option<ProcessMessage> {
+        loop {
+            {
+                let mut state = self.state();
+                if let Some(message) = self.pop(&mut state) {
+                    return Some(message);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            self.available.notified().await;
+        }
+    }
+
+    fn try_recv(&self) -> Option<ProcessMessage> {
+        self.pop(&mut self.state())
+    }
+
+    fn pop(&self, state: &mut State) -> Option<ProcessMessage> {
+        let Queued { message, .. } = state.queue.pop()?;
+        self.capacity.add_permits(1);
+        Some(message)
+    }
+
+    fn close(&self) {
+        self.state().closed = true;
+        self.capacity.close();
+        self.available.notify_one();
+    }
+}
 
 /// This instance can emit message to the different part of the software.
 pub struct Emitter {
-    working: tokio::sync::mpsc::Sender<ProcessMessage>,
-    delivery: tokio::sync::mpsc::Sender<ProcessMessage>,
+    working: std::sync::Arc<Channel>,
+    delivery: std::sync::Arc<Channel>,
 }
 
 impl Emitter {
     #[tracing::instrument(skip(self))]
     pub(crate) async fn send_to_delivery(&self, message: ProcessMessage) -> std::io::Result<()> {
-        match self.delivery.send(message).await {
-            Ok(()) => Ok(()),
-            Err(_err) => Err(std::io::Error::from(std::io::ErrorKind::ConnectionAborted)),
-        }
+        self.delivery.send(message).await
     }
 
     #[tracing::instrument(skip(self))]
     pub(crate) async fn send_to_working(&self, message: ProcessMessage) -> std::io::Result<()> {
-        match self.working.send(message).await {
-            Ok(()) => Ok(()),
-            Err(_err) => Err(std::io::Error::from(std::io::ErrorKind::ConnectionAborted)),
-        }
+        self.working.send(message).await
     }
 }
 
 /// This instance can receive message from the different part of the software.
+///
+/// The messages are produced by order of priority, and then by order of arrival.
 pub struct Receiver {
-    inner: tokio::sync::mpsc::Receiver<ProcessMessage>,
+    inner: std::sync::Arc<Channel>,
 }
 
 impl Receiver {
@@ -55,6 +169,29 @@ impl Receiver {
             }
         }
     }
+
+    /// Receive the message with the highest priority, wait for one if the channel is empty.
+    ///
+    /// Return `None` when the channel is closed and empty.
+    pub async fn recv(&mut self) -> Option<ProcessMessage> {
+        self.inner.recv().await
+    }
+
+    /// Receive the message with the highest priority, if any.
+    pub fn try_recv(&mut self) -> Option<ProcessMessage> {
+        self.inner.try_recv()
+    }
+
+    /// Stop receiving new messages, the ones already sent are still produced by the stream.
+    pub fn close(&mut self) {
+        self.inner.close();
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        self.inner.close();
+    }
 }
 
 /// This instance is responsible of the communication between the different part of the software.
@@ -65,15 +202,87 @@ pub fn init(
     working_channel_size: usize,
     delivery_channel_size: usize,
 ) -> (std::sync::Arc<Emitter>, Receiver, Receiver) {
-    let (working_tx, working_rx) = tokio::sync::mpsc::channel(working_channel_size);
-    let (delivery_tx, delivery_rx) = tokio::sync::mpsc::channel(delivery_channel_size);
+    let working = std::sync::Arc::new(Channel::new(working_channel_size));
+    let delivery = std::sync::Arc::new(Channel::new(delivery_channel_size));
 
     (
         std::sync::Arc::new(Emitter {
-            working: working_tx,
-            delivery: delivery_tx,
+            working: working.clone(),
+            delivery: delivery.clone(),
         }),
-        Receiver { inner: working_rx },
-        Receiver { inner: delivery_rx },
+        Receiver { inner: working },
+        Receiver { inner: delivery },
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_stream::StreamExt;
+
+    fn message(priority: i8) -> ProcessMessage {
+        ProcessMessage::new(uuid::Uuid::new_v4()).with_priority(priority.try_into().unwrap())
+    }
+
+    #[tokio::test]
+    async fn highest_priority_first() {
+        let (emitter, _, mut delivery) = init(1, 8);
+
+        let sent = [message(0), message(-2), message(5), message(0), message(9)]
+            .map(|pm| (*pm.as_ref(), pm.priority()));
+        for (uuid, priority) in sent {
+            emitter
+                .send_to_delivery(ProcessMessage::new(uuid).with_priority(priority))
+                .await
+                .unwrap();
+        }
+        delivery.close();
+
+        let received = delivery
+            .as_stream()
+            .map(|pm| *pm.as_ref())
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            received,
+            [sent[4].0, sent[2].0, sent[0].0, sent[3].0, sent[1].0]
+        );
+    }
+
+    #[tokio::test]
+    async fn try_recv() {
+        let (emitter, _, mut delivery) = init(1, 1);
+        assert!(delivery.try_recv().is_none());
+
+        let sent = message(3);
+        let message_uuid = *sent.as_ref();
+        emitter.send_to_delivery(sent).await.unwrap();
+        assert_eq!(
+            delivery.try_recv().map(|pm| *pm.as_ref()),
+            Some(message_uuid)
+        );
+
+        // the slot has been given back.
+        emitter.send_to_delivery(message(0)).await.unwrap();
+        delivery.close();
+        assert!(delivery.recv().await.is_some());
+        assert!(delivery.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn closed() {
+        let (emitter, mut working, delivery) = init(1, 1);
+        drop(delivery);
+        emitter.send_to_delivery(message(0)).await.unwrap_err();
+
+        emitter.send_to_working(message(0)).await.unwrap();
+        let full = tokio::spawn({
+            let emitter = emitter.clone();
+            async move { emitter.send_to_working(message(0)).await }
+        });
+        working.close();
+        full.await.unwrap().unwrap_err();
+
+        assert_eq!(working.as_stream().collect::<Vec<_>>().await.len(), 1);
+    }
+}
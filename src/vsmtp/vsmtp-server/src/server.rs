@@ -14,13 +14,16 @@
  * this program. If not, see https://www.gnu.org/licenses/.
  *
 */
-use crate::{receiver::handler::Handler, scheduler::Emitter, ValidationVSL};
+use crate::{
+    receiver::handler::Handler, scheduler::Emitter, shutdown::Shutdown, Control, Reloader,
+    ValidationVSL,
+};
 use anyhow::Context;
 use tokio_rustls::rustls;
 use tokio_stream::StreamExt;
 use vqueue::GenericQueueManager;
 use vsmtp_common::Reply;
-use vsmtp_config::{get_rustls_config, Config};
+use vsmtp_config::Config;
 use vsmtp_mail_parser::BasicParser;
 use vsmtp_protocol::{AcceptArgs, ConnectionKind};
 use vsmtp_rule_engine::RuleEngine;
@@ -29,11 +32,10 @@ use vsmtp_rule_engine::RuleEngine;
 pub struct Server {
     conn_max_reach_reply: Reply,
 
-    config: std::sync::Arc<Config>,
-    tls_config: Option<std::sync::Arc<rustls::ServerConfig>>,
-    rule_engine: std::sync::Arc<RuleEngine>,
-    queue_manager: std::sync::Arc<dyn GenericQueueManager>,
+    reloader: std::sync::Arc<Reloader>,
     emitter: std::sync::Arc<Emitter>,
+    shutdown: Option<Shutdown>,
+    control: Option<std::sync::Arc<Control>>,
 }
 
 /// Create a `TCPListener` ready to be listened to
@@ -81,6 +83,22 @@ impl Server {
         queue_manager: std::sync::Arc<dyn GenericQueueManager>,
         emitter: std::sync::Arc<Emitter>,
     ) -> anyhow::Result<Self> {
+        Self::with_reloader(
+            std::sync::Arc::new(Reloader::new(config, rule_engine, queue_manager)?),
+            emitter,
+        )
+    }
+
+    /// Create a server using the configuration and the rules in force in the [`Reloader`].
+    ///
+    /// # Errors
+    ///
+    /// * `spool_dir` does not exist and failed to be created
+    pub fn with_reloader(
+        reloader: std::sync::Arc<Reloader>,
+        emitter: std::sync::Arc<Emitter>,
+    ) -> anyhow::Result<Self> {
+        let config = reloader.config();
         if !config.server.queues.dirpath.exists() {
             std::fs::DirBuilder::new()
                 .recursive(true)
@@ -91,21 +109,28 @@ impl Server {
             conn_max_reach_reply: "554 Cannot process connection, closing\r\n"
                 .parse::<Reply>()
                 .expect("valid smtp reply"),
-            tls_config: if let Some(smtps) = &config.server.tls {
-                Some(std::sync::Arc::new(get_rustls_config(
-                    smtps,
-                    &config.server.r#virtual,
-                )?))
-            } else {
-                None
-            },
-            rule_engine,
-            queue_manager,
-            config,
+            reloader,
             emitter,
+            shutdown: None,
+            control: None,
         })
     }
 
+    /// Register the sessions and count the messages received in the [`Control`].
+    #[must_use]
+    pub fn with_control(mut self, control: std::sync::Arc<Control>) -> Self {
+        self.control = Some(control);
+        self
+    }
+
+    /// Stop listening when the [`Shutdown`] is requested, the clients are answered `421`
+    /// after their current transaction, and are given until the deadline to complete it.
+    #[must_use]
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
     #[tracing::instrument(name = "handle-client", skip_all, fields(client = %client_addr, server = %server_addr))]
     async fn handle_client(
         &self,
@@ -117,12 +142,15 @@ impl Server {
     ) {
         tracing::info!(%kind, "Connection accepted.");
 
-        if self.config.server.client_count_max != -1
+        // NOTE: the session uses this version of the configuration and rules until its end.
+        let (config, rule_engine, tls_config) = self.reloader.snapshot();
+
+        if config.server.client_count_max != -1
             && client_counter.load(std::sync::atomic::Ordering::SeqCst)
-                >= self.config.server.client_count_max
+                >= config.server.client_count_max
         {
             tracing::warn!(
-                max = self.config.server.client_count_max,
+                max = config.server.client_count_max,
                 "Connection count max reached, rejecting connection.",
             );
 
@@ -143,24 +171,38 @@ impl Server {
 
         client_counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
 
-        let session = Self::serve(
-            AcceptArgs::new(
-                client_addr,
-                stream.local_addr().expect("retrieve local address"),
-                time::OffsetDateTime::now_utc(),
-                uuid::Uuid::new_v4(),
-                kind,
-            ),
+        let args = AcceptArgs::new(
+            client_addr,
+            stream.local_addr().expect("retrieve local address"),
+            time::OffsetDateTime::now_utc(),
+            uuid::Uuid::new_v4(),
+            kind,
+        );
+        let session = self.control.as_ref().map(|control| {
+            control.open_session(vqueue::control::Session::new(
+                args.uuid,
+                args.client_addr,
+                args.server_addr,
+                args.kind.to_string(),
+                args.timestamp,
+            ))
+        });
+
+        let serve = Self::serve(
+            args,
             stream,
-            self.tls_config.clone(),
-            self.config.clone(),
-            self.rule_engine.clone(),
-            self.queue_manager.clone(),
+            tls_config,
+            config,
+            rule_engine,
+            self.reloader.queue_manager(),
             self.emitter.clone(),
+            self.shutdown.clone(),
+            self.control.clone(),
         );
         let client_counter_copy = client_counter.clone();
         tokio::spawn(async move {
-            let _err = session.await;
+            let _err = serve.await;
+            drop(session);
 
             client_counter_copy.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
         });
@@ -188,7 +230,7 @@ impl Server {
                 .collect::<std::io::Result<Vec<tokio::net::TcpListener>>>()
         }
 
-        if self.config.server.tls.is_none() && !sockets.2.is_empty() {
+        if self.reloader.config().server.tls.is_none() && !sockets.2.is_empty() {
             tracing::warn!(
                 "No TLS configuration provided, listening on submissions protocol (port 465) will cause issue"
             );
@@ -224,9 +266,23 @@ impl Server {
             "Listening for clients.",
         );
 
-        while let Some((server_addr, (kind, client))) =
-            tokio_stream::StreamExt::next(&mut map).await
-        {
+        let mut shutdown = self.shutdown.clone();
+        let requested = async {
+            match &mut shutdown {
+                Some(shutdown) => shutdown.requested().await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::pin!(requested);
+
+        let deadline = loop {
+            let (server_addr, (kind, client)) = tokio::select! {
+                deadline = &mut requested => break deadline,
+                next = tokio_stream::StreamExt::next(&mut map) => match next {
+                    Some(next) => next,
+                    None => return Ok(()),
+                },
+            };
             let (stream, client_addr) = client?;
 
             self.handle_client(
@@ -237,6 +293,20 @@ impl Server {
                 server_addr,
             )
             .await;
+        };
+
+        drop(map);
+        tracing::info!("Stopped listening, waiting for the clients to disconnect.");
+
+        while client_counter.load(std::sync::atomic::Ordering::SeqCst) > 0 {
+            if tokio::time::Instant::now() >= deadline {
+                tracing::warn!(
+                    clients = client_counter.load(std::sync::atomic::Ordering::SeqCst),
+                    "Grace period elapsed, closing the remaining connections."
+                );
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
         }
         Ok(())
     }
@@ -253,6 +323,8 @@ impl Server {
         rule_engine: std::sync::Arc<RuleEngine>,
         queue_manager: std::sync::Arc<dyn GenericQueueManager>,
         emitter: std::sync::Arc<Emitter>,
+        shutdown: Option<Shutdown>,
+        control: Option<std::sync::Arc<Control>>,
     ) -> anyhow::Result<()> {
         let smtp_handler = Handler::new(
             config.clone(),
@@ -275,6 +347,10 @@ impl Server {
             config.server.smtp.error.hard_count,
             config.server.message_size_limit,
         );
+        let smtp_receiver = match shutdown {
+            Some(shutdown) => smtp_receiver.with_shutdown(shutdown.signal()),
+            None => smtp_receiver,
+        };
         let smtp_stream = smtp_receiver.into_stream(
             args.client_addr,
             args.server_addr,
@@ -283,7 +359,11 @@ impl Server {
         );
         tokio::pin!(smtp_stream);
 
-        while matches!(smtp_stream.next().await, Some(Ok(()))) {}
+        while matches!(smtp_stream.next().await, Some(Ok(()))) {
+            if let Some(control) = &control {
+                control.message_received();
+            }
+        }
 
         log::info!("Connection closed cleanly.");
         Ok(())
@@ -0,0 +1,97 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+*/
+
+/// Request the stop of a part of the server.
+#[derive(Debug)]
+pub struct ShutdownTrigger {
+    inner: tokio::sync::watch::Sender<Option<tokio::time::Instant>>,
+}
+
+impl ShutdownTrigger {
+    /// Ask to stop, the work in progress must be completed before the `deadline`.
+    pub fn shutdown(&self, deadline: tokio::time::Instant) {
+        self.inner.send_replace(Some(deadline));
+    }
+}
+
+/// Signal received by a part of the server when it must stop.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    inner: tokio::sync::watch::Receiver<Option<tokio::time::Instant>>,
+}
+
+impl Shutdown {
+    /// Wait for the stop to be requested, and return the deadline of the work in progress.
+    ///
+    /// Never completes if the [`ShutdownTrigger`] has been dropped without requesting it.
+    pub async fn requested(&mut self) -> tokio::time::Instant {
+        loop {
+            let deadline = *self.inner.borrow_and_update();
+            if let Some(deadline) = deadline {
+                return deadline;
+            }
+            if self.inner.changed().await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+
+    /// A future completed when the stop is requested.
+    pub fn signal(&self) -> impl std::future::Future<Output = ()> + Send + 'static {
+        let mut shutdown = self.clone();
+        async move {
+            shutdown.requested().await;
+        }
+    }
+}
+
+/// Create a pair of handles to stop a part of the server.
+#[must_use]
+pub fn channel() -> (ShutdownTrigger, Shutdown) {
+    let (sender, receiver) = tokio::sync::watch::channel(None);
+    (
+        ShutdownTrigger { inner: sender },
+        Shutdown { inner: receiver },
+    )
+}
+
+/// Wait for the `tasks` to complete, the ones still running at the `deadline` are aborted.
+pub(crate) async fn join_until<T: 'static>(
+    tasks: &mut tokio::task::JoinSet<T>,
+    deadline: tokio::time::Instant,
+    name: &str,
+) {
+    tracing::info!(
+        name,
+        tasks = tasks.len(),
+        "Waiting for the tasks to complete."
+    );
+
+    let completed = tokio::time::timeout_at(deadline, async {
+        while tasks.join_next().await.is_some() {}
+    })
+    .await;
+
+    if completed.is_err() {
+        tracing::warn!(
+            name,
+            tasks = tasks.len(),
+            "Grace period elapsed, the remaining messages are processed at the next start."
+        );
+        tasks.shutdown().await;
+    }
+}
@@ -17,34 +17,73 @@
 use crate::{
     delegate,
     scheduler::{self, Emitter},
-    ProcessMessage,
+    shutdown::{self, Shutdown},
+    Control, ProcessMessage, Reloader,
 };
 use anyhow::Context;
 use tokio_stream::StreamExt;
-use vqueue::{GenericQueueManager, QueueID};
+use vqueue::{
+    history::{self, Event, EventKind},
+    GenericQueueManager, QueueID,
+};
 use vsmtp_common::{
     status,
     transfer::{self, error::Rule},
 };
 use vsmtp_rule_engine::{ExecutionStage, RuleEngine};
 
+/// Process the messages received until the `shutdown` is requested, and then the ones
+/// remaining in the channel, within the grace period.
+///
+/// At most `concurrency` messages (set up with the configuration at startup) are processed
+/// at the same time, the others wait in the channel and the highest priorities are taken first.
 pub(super) async fn start<Q: GenericQueueManager + Sized + 'static>(
-    rule_engine: std::sync::Arc<RuleEngine>,
+    reloader: std::sync::Arc<Reloader>,
     queue_manager: std::sync::Arc<Q>,
     emitter: std::sync::Arc<Emitter>,
     mut receiver: scheduler::Receiver,
+    mut shutdown: Shutdown,
+    control: std::sync::Arc<Control>,
 ) {
-    let working_receiver = receiver.as_stream().map(|pm| {
-        tokio::spawn(handle_one(
-            rule_engine.clone(),
+    let concurrency = reloader.config().server.queues.working.concurrency;
+    let mut tasks = tokio::task::JoinSet::new();
+    let spawn = |tasks: &mut tokio::task::JoinSet<_>, pm| {
+        let handle = handle_one(
+            reloader.rule_engine(),
             queue_manager.clone(),
             pm,
             emitter.clone(),
-        ))
-    });
+        );
+        let control = control.clone();
+        tasks.spawn(async move {
+            let _err = handle.await;
+            control.message_processed();
+        });
+    };
+
+    let deadline = {
+        let working_receiver = receiver.as_stream();
+        tokio::pin!(working_receiver);
+
+        loop {
+            tokio::select! {
+                deadline = shutdown.requested() => break deadline,
+                Some(pm) = working_receiver.next(), if tasks.len() < concurrency.get() => {
+                    spawn(&mut tasks, pm);
+                }
+                Some(_) = tasks.join_next() => {}
+            }
+        }
+    };
+
+    receiver.close();
+    let working_receiver = receiver.as_stream();
     tokio::pin!(working_receiver);
+    while let Some(pm) = working_receiver.next().await {
+        spawn(&mut tasks, pm);
+    }
 
-    while let Some(_join_handle) = working_receiver.next().await {}
+    shutdown::join_until(&mut tasks, deadline, "working").await;
 }
 
 /// Handle one message in the working queue.
@@ -77,12 +116,18 @@ pub async fn handle_one<Q: GenericQueueManager + Sized + 'static>(
         .await?;
 
     let mut skipped = ctx.connect.skipped.clone();
-    let (ctx, mail_message, _) = rule_engine.just_run_when(
+    let (ctx, mail_message, status) = rule_engine.just_run_when(
         &mut skipped,
         ExecutionStage::PostQ,
         vsmtp_common::Context::Finished(ctx),
         mail_message,
     );
+    history::record(
+        queue_manager.as_ref(),
+        process_message.as_ref(),
+        &[Event::now(EventKind::rules(ExecutionStage::PostQ, &status))],
+    )
+    .await;
 
     let mut ctx = ctx.unwrap_finished().context("context is not finished")?;
 
@@ -179,11 +224,14 @@ pub async fn handle_one<Q: GenericQueueManager + Sized + 'static>(
 
     if send_to_delivery {
         emitter
-            .send_to_delivery(if delegated {
-                ProcessMessage::delegated
-            } else {
-                ProcessMessage::new
-            }(*process_message.as_ref()))
+            .send_to_delivery(
+                if delegated {
+                    ProcessMessage::delegated
+                } else {
+                    ProcessMessage::new
+                }(*process_message.as_ref())
+                .with_priority(ctx.mail_from.priority),
+            )
             .await?;
     }
 
@@ -121,13 +121,20 @@ pub fn local_ctx() -> ContextFinished {
             message_uuid: uuid::Uuid::new_v4(),
             reverse_path: Some("client@testserver.com".to_string().parse().expect("")),
             spf: None,
+            message_size: None,
+            priority: vsmtp_common::Priority::default(),
+            dsn: vsmtp_common::dsn::MailFromParameters::default(),
         },
         rcpt_to: RcptToProperties {
             forward_paths: vec!["recipient@testserver.com".to_string().parse().expect("")],
             delivery: std::collections::HashMap::new(),
             transaction_type: TransactionType::Internal,
+            dsn: std::collections::HashMap::new(),
+        },
+        finished: FinishedProperties {
+            dkim: None,
+            released: vec![],
         },
-        finished: FinishedProperties { dkim: None },
     }
 }
 
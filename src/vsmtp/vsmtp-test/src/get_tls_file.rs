@@ -37,3 +37,39 @@ pub const fn get_pkcs8_key() -> &'static str {
 pub const fn get_ec256_key() -> &'static str {
     include_str!("./template/certs/private_key.ec256.key")
 }
+
+///
+#[must_use]
+pub const fn get_dane_ca_certificate() -> &'static str {
+    include_str!("./template/certs/dane/ca.crt")
+}
+
+///
+#[must_use]
+pub const fn get_dane_mx_certificate() -> &'static str {
+    include_str!("./template/certs/dane/mx.crt")
+}
+
+///
+#[must_use]
+pub const fn get_dane_mx_pkcs8_key() -> &'static str {
+    include_str!("./template/certs/dane/mx.pkcs8.key")
+}
+
+///
+#[must_use]
+pub const fn get_mta_sts_ca_certificate() -> &'static str {
+    include_str!("./template/certs/mta-sts/ca.crt")
+}
+
+///
+#[must_use]
+pub const fn get_mta_sts_certificate() -> &'static str {
+    include_str!("./template/certs/mta-sts/mta-sts.crt")
+}
+
+///
+#[must_use]
+pub const fn get_mta_sts_pkcs8_key() -> &'static str {
+    include_str!("./template/certs/mta-sts/mta-sts.pkcs8.key")
+}
@@ -22,9 +22,12 @@ mod examples {
     mod message;
 }
 mod protocol {
+    mod chunking;
     mod clair;
+    mod dsn;
     mod mail_from;
     mod message_max_size;
+    mod pipelining;
     mod rset;
     mod vrfy;
 
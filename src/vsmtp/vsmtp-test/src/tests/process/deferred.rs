@@ -18,7 +18,10 @@
 use crate::config::{local_ctx, local_msg, local_test};
 use vqueue::{GenericQueueManager, QueueID};
 use vsmtp_common::{
-    transfer::Status,
+    transfer::{
+        error::{Queuer, Throttle, Variant},
+        Status,
+    },
     transport::{AbstractTransport, WrapperSerde},
 };
 use vsmtp_config::DnsResolvers;
@@ -63,6 +66,7 @@ async fn move_to_deferred() {
         config.clone(),
         queue_manager.clone(),
         ProcessMessage::new(message_uuid),
+        resolvers.clone(),
         time::OffsetDateTime::UNIX_EPOCH,
     )
     .await
@@ -89,6 +93,7 @@ async fn move_to_dead() {
     let queue_manager =
         <vqueue::temp::QueueManager as vqueue::GenericQueueManager>::init(config.clone(), vec![])
             .unwrap();
+    let resolvers = std::sync::Arc::new(DnsResolvers::from_config(&config).unwrap());
 
     let mut ctx = local_ctx();
     let message_uuid = uuid::Uuid::new_v4();
@@ -103,6 +108,7 @@ async fn move_to_dead() {
         config.clone(),
         queue_manager.clone(),
         ProcessMessage::new(message_uuid),
+        resolvers.clone(),
         time::OffsetDateTime::UNIX_EPOCH,
     )
     .await
@@ -118,3 +124,213 @@ async fn move_to_dead() {
         .await
         .unwrap();
 }
+
+fn held_back_ctx(
+    config: &std::sync::Arc<vsmtp_config::Config>,
+    resolvers: &DnsResolvers,
+    error: impl Into<Variant>,
+) -> vsmtp_common::ContextFinished {
+    let mut ctx = local_ctx();
+    ctx.mail_from.message_uuid = uuid::Uuid::new_v4();
+
+    let mut status = Status::default();
+    status.held_back(error);
+
+    ctx.rcpt_to.delivery.insert(
+        WrapperSerde::Ready(std::sync::Arc::new(Deliver::new(
+            resolvers.get_resolver_root(),
+            config.clone(),
+        ))),
+        vec![("test@localhost".parse().unwrap(), status)],
+    );
+    ctx
+}
+
+#[tokio::test]
+async fn not_ready_to_be_flushed() {
+    let config = std::sync::Arc::new(local_test());
+    let queue_manager = <vqueue::temp::QueueManager as vqueue::GenericQueueManager>::init(
+        config.clone(),
+        vec![Deliver::get_symbol()],
+    )
+    .unwrap();
+    let resolvers = std::sync::Arc::new(DnsResolvers::from_config(&config).unwrap());
+
+    let ctx = held_back_ctx(&config, &resolvers, Queuer::StillWaiting);
+    let message_uuid = ctx.mail_from.message_uuid;
+
+    queue_manager
+        .write_both(&QueueID::Deferred, &ctx, &local_msg())
+        .await
+        .unwrap();
+
+    handle_one(
+        config.clone(),
+        queue_manager.clone(),
+        ProcessMessage::new(message_uuid),
+        resolvers.clone(),
+        time::OffsetDateTime::now_utc(),
+    )
+    .await
+    .unwrap();
+
+    let deferred = queue_manager
+        .get_ctx(&QueueID::Deferred, &message_uuid)
+        .await
+        .unwrap();
+    assert_eq!(deferred.rcpt_to.delivery, ctx.rcpt_to.delivery);
+}
+
+#[tokio::test]
+async fn lifetime_expired() {
+    let config = std::sync::Arc::new(local_test());
+    let queue_manager = <vqueue::temp::QueueManager as vqueue::GenericQueueManager>::init(
+        config.clone(),
+        vec![Deliver::get_symbol()],
+    )
+    .unwrap();
+    let resolvers = std::sync::Arc::new(DnsResolvers::from_config(&config).unwrap());
+
+    let mut ctx = held_back_ctx(&config, &resolvers, Queuer::StillWaiting);
+    ctx.mail_from.reverse_path = None;
+    let message_uuid = ctx.mail_from.message_uuid;
+
+    queue_manager
+        .write_both(&QueueID::Deferred, &ctx, &local_msg())
+        .await
+        .unwrap();
+
+    handle_one(
+        config.clone(),
+        queue_manager.clone(),
+        ProcessMessage::new(message_uuid),
+        resolvers.clone(),
+        ctx.mail_from.mail_timestamp + config.server.queues.delivery.retry.bounce_after,
+    )
+    .await
+    .unwrap();
+
+    queue_manager
+        .get_ctx(&QueueID::Deferred, &message_uuid)
+        .await
+        .unwrap_err();
+
+    let dead = queue_manager
+        .get_ctx(&QueueID::Dead, &message_uuid)
+        .await
+        .unwrap();
+    assert!(dead
+        .rcpt_to
+        .delivery
+        .values()
+        .flatten()
+        .all(|(_, status)| *status == Status::failed(Queuer::LifetimeExpired)));
+}
+
+#[tokio::test]
+async fn throttled_is_retried_after_delay() {
+    let config = std::sync::Arc::new(local_test());
+    let queue_manager = <vqueue::temp::QueueManager as vqueue::GenericQueueManager>::init(
+        config.clone(),
+        vec![Deliver::get_symbol()],
+    )
+    .unwrap();
+    let resolvers = std::sync::Arc::new(DnsResolvers::from_config(&config).unwrap());
+
+    let ctx = held_back_ctx(
+        &config,
+        &resolvers,
+        Throttle::Rate {
+            domain: "localhost".parse().unwrap(),
+        },
+    );
+    let message_uuid = ctx.mail_from.message_uuid;
+
+    queue_manager
+        .write_both(&QueueID::Deferred, &ctx, &local_msg())
+        .await
+        .unwrap();
+
+    let attempts = || async {
+        queue_manager
+            .get_ctx(&QueueID::Deferred, &message_uuid)
+            .await
+            .unwrap()
+            .rcpt_to
+            .delivery
+            .values()
+            .flatten()
+            .map(|(_, status)| match status {
+                Status::HeldBack { errors } => errors.len(),
+                _ => 0,
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let now = time::OffsetDateTime::now_utc();
+    for (flushing_at, expected) in [
+        (now, 1),
+        (now + config.server.queues.delivery.retry.throttle_delay, 2),
+    ] {
+        handle_one(
+            config.clone(),
+            queue_manager.clone(),
+            ProcessMessage::new(message_uuid),
+            resolvers.clone(),
+            flushing_at,
+        )
+        .await
+        .unwrap();
+
+        assert!(attempts().await.iter().all(|count| *count == expected));
+    }
+}
+
+#[tokio::test]
+async fn delay_notified_before_next_attempt() {
+    let mut config = local_test();
+    config.server.queues.delivery.retry.warn_after = Some(std::time::Duration::from_secs(60 * 60));
+    let config = std::sync::Arc::new(config);
+    let queue_manager = <vqueue::temp::QueueManager as vqueue::GenericQueueManager>::init(
+        config.clone(),
+        vec![Deliver::get_symbol()],
+    )
+    .unwrap();
+    let resolvers = std::sync::Arc::new(DnsResolvers::from_config(&config).unwrap());
+    let retry = &config.server.queues.delivery.retry;
+
+    let mut ctx = held_back_ctx(&config, &resolvers, Queuer::StillWaiting);
+    let now = time::OffsetDateTime::now_utc();
+    ctx.mail_from.mail_timestamp = now - time::Duration::hours(2);
+    let message_uuid = ctx.mail_from.message_uuid;
+
+    // the next attempt is not due yet, but the delay notification is.
+    assert!(retry.next_flush(&ctx) <= now);
+
+    queue_manager
+        .write_both(&QueueID::Deferred, &ctx, &local_msg())
+        .await
+        .unwrap();
+
+    handle_one(
+        config.clone(),
+        queue_manager.clone(),
+        ProcessMessage::new(message_uuid),
+        resolvers.clone(),
+        now,
+    )
+    .await
+    .unwrap();
+
+    let deferred = queue_manager
+        .get_ctx(&QueueID::Deferred, &message_uuid)
+        .await
+        .unwrap();
+    assert_eq!(deferred.rcpt_to.delivery, ctx.rcpt_to.delivery);
+    assert_eq!(retry.warn_at(&deferred), None);
+    assert!(retry.next_flush(&deferred) > now);
+    assert_eq!(
+        queue_manager.list(&QueueID::Deliver).await.unwrap().len(),
+        1
+    );
+}
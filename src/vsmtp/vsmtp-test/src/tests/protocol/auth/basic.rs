@@ -33,7 +33,13 @@ run_test! {
         "250-testserver.com\r\n",
         "250-AUTH \r\n",
         "250-STARTTLS\r\n",
+        "250-PIPELINING\r\n",
+        "250-SIZE 10000000\r\n",
         "250-8BITMIME\r\n",
+        "250-BINARYMIME\r\n",
+        "250-CHUNKING\r\n",
+        "250-DSN\r\n",
+        "250-MT-PRIORITY\r\n",
         "250 SMTPUTF8\r\n",
         "538 5.7.11 Encryption required for requested authentication mechanism\r\n",
     ],
@@ -56,7 +62,13 @@ run_test! {
         "250-testserver.com\r\n",
         "250-AUTH PLAIN LOGIN CRAM-MD5 ANONYMOUS\r\n",
         "250-STARTTLS\r\n",
+        "250-PIPELINING\r\n",
+        "250-SIZE 10000000\r\n",
         "250-8BITMIME\r\n",
+        "250-BINARYMIME\r\n",
+        "250-CHUNKING\r\n",
+        "250-DSN\r\n",
+        "250-MT-PRIORITY\r\n",
         "250 SMTPUTF8\r\n",
         "235 2.7.0 Authentication succeeded\r\n",
         "250 Ok\r\n",
@@ -81,6 +93,43 @@ run_test! {
     },
 }
 
+run_test! {
+    fn plain_with_mt_priority,
+    input = [
+        "EHLO client.com\r\n",
+        &format!("AUTH PLAIN {}\r\n", STANDARD.encode(format!("\0{}\0{}", "hello", "world"))),
+        "MAIL FROM:<foo@bar> MT-PRIORITY=+5\r\n",
+        "RCPT TO:<joe@doe>\r\n",
+        "DATA\r\n",
+        ".\r\n",
+        "QUIT\r\n"
+    ],
+    expected = [
+        "220 testserver.com Service ready\r\n",
+        "250-testserver.com\r\n",
+        "250-AUTH PLAIN LOGIN CRAM-MD5 ANONYMOUS\r\n",
+        "250-STARTTLS\r\n",
+        "250-PIPELINING\r\n",
+        "250-SIZE 10000000\r\n",
+        "250-8BITMIME\r\n",
+        "250-BINARYMIME\r\n",
+        "250-CHUNKING\r\n",
+        "250-DSN\r\n",
+        "250-MT-PRIORITY\r\n",
+        "250 SMTPUTF8\r\n",
+        "235 2.7.0 Authentication succeeded\r\n",
+        "250 Ok\r\n",
+        "250 Ok\r\n",
+        "354 Start mail input; end with <CRLF>.<CRLF>\r\n",
+        "250 Ok\r\n",
+        "221 Service closing transmission channel\r\n"
+    ],
+    config = unsafe_auth_config(),
+    mail_handler = |ctx: ContextFinished, _: MessageBody| {
+        assert_eq!(ctx.mail_from.priority.get(), 5);
+    },
+}
+
 run_test! {
     fn login_in_clair_unsecured,
     input = [
@@ -99,7 +148,13 @@ run_test! {
         "250-testserver.com\r\n",
         "250-AUTH PLAIN LOGIN CRAM-MD5 ANONYMOUS\r\n",
         "250-STARTTLS\r\n",
+        "250-PIPELINING\r\n",
+        "250-SIZE 10000000\r\n",
         "250-8BITMIME\r\n",
+        "250-BINARYMIME\r\n",
+        "250-CHUNKING\r\n",
+        "250-DSN\r\n",
+        "250-MT-PRIORITY\r\n",
         "250 SMTPUTF8\r\n",
         &format!("334 {}\r\n", STANDARD.encode("User Name\0")),
         &format!("334 {}\r\n", STANDARD.encode("Password\0")),
@@ -142,7 +197,13 @@ run_test! {
         "250-testserver.com\r\n",
         "250-AUTH PLAIN LOGIN CRAM-MD5 ANONYMOUS\r\n",
         "250-STARTTLS\r\n",
+        "250-PIPELINING\r\n",
+        "250-SIZE 10000000\r\n",
         "250-8BITMIME\r\n",
+        "250-BINARYMIME\r\n",
+        "250-CHUNKING\r\n",
+        "250-DSN\r\n",
+        "250-MT-PRIORITY\r\n",
         "250 SMTPUTF8\r\n",
         "235 2.7.0 Authentication succeeded\r\n",
         "250 Ok\r\n",
@@ -183,7 +244,13 @@ run_test! {
         "250-testserver.com\r\n",
         "250-AUTH PLAIN LOGIN CRAM-MD5 ANONYMOUS\r\n",
         "250-STARTTLS\r\n",
+        "250-PIPELINING\r\n",
+        "250-SIZE 10000000\r\n",
         "250-8BITMIME\r\n",
+        "250-BINARYMIME\r\n",
+        "250-CHUNKING\r\n",
+        "250-DSN\r\n",
+        "250-MT-PRIORITY\r\n",
         "250 SMTPUTF8\r\n",
         "235 2.7.0 Authentication succeeded\r\n",
         "250 Ok\r\n",
@@ -220,7 +287,13 @@ run_test! {
         "250-testserver.com\r\n",
         "250-AUTH PLAIN LOGIN CRAM-MD5 ANONYMOUS\r\n",
         "250-STARTTLS\r\n",
+        "250-PIPELINING\r\n",
+        "250-SIZE 10000000\r\n",
         "250-8BITMIME\r\n",
+        "250-BINARYMIME\r\n",
+        "250-CHUNKING\r\n",
+        "250-DSN\r\n",
+        "250-MT-PRIORITY\r\n",
         "250 SMTPUTF8\r\n",
         "535 5.7.8 Authentication credentials invalid\r\n"
     ],
@@ -245,7 +318,13 @@ run_test! {
         "250-testserver.com\r\n",
         "250-AUTH PLAIN LOGIN CRAM-MD5 ANONYMOUS\r\n",
         "250-STARTTLS\r\n",
+        "250-PIPELINING\r\n",
+        "250-SIZE 10000000\r\n",
         "250-8BITMIME\r\n",
+        "250-BINARYMIME\r\n",
+        "250-CHUNKING\r\n",
+        "250-DSN\r\n",
+        "250-MT-PRIORITY\r\n",
         "250 SMTPUTF8\r\n",
         "334 \r\n",
         "501 Authentication canceled by client\r\n",
@@ -275,7 +354,13 @@ run_test! {
         "250-testserver.com\r\n",
         "250-AUTH PLAIN LOGIN CRAM-MD5 ANONYMOUS\r\n",
         "250-STARTTLS\r\n",
+        "250-PIPELINING\r\n",
+        "250-SIZE 10000000\r\n",
         "250-8BITMIME\r\n",
+        "250-BINARYMIME\r\n",
+        "250-CHUNKING\r\n",
+        "250-DSN\r\n",
+        "250-MT-PRIORITY\r\n",
         "250 SMTPUTF8\r\n",
         "501 5.5.2 Invalid, not base64\r\n",
         "221 Service closing transmission channel\r\n"
@@ -300,7 +385,13 @@ run_test! {
         "250-testserver.com\r\n",
         "250-AUTH PLAIN LOGIN CRAM-MD5 ANONYMOUS\r\n",
         "250-STARTTLS\r\n",
+        "250-PIPELINING\r\n",
+        "250-SIZE 10000000\r\n",
         "250-8BITMIME\r\n",
+        "250-BINARYMIME\r\n",
+        "250-CHUNKING\r\n",
+        "250-DSN\r\n",
+        "250-MT-PRIORITY\r\n",
         "250 SMTPUTF8\r\n",
         // See https://datatracker.ietf.org/doc/html/rfc4422#section-5 2.a
         "334 \r\n",
@@ -339,7 +430,13 @@ run_test! {
         "250-testserver.com\r\n",
         "250-AUTH PLAIN LOGIN CRAM-MD5 ANONYMOUS\r\n",
         "250-STARTTLS\r\n",
+        "250-PIPELINING\r\n",
+        "250-SIZE 10000000\r\n",
         "250-8BITMIME\r\n",
+        "250-BINARYMIME\r\n",
+        "250-CHUNKING\r\n",
+        "250-DSN\r\n",
+        "250-MT-PRIORITY\r\n",
         "250 SMTPUTF8\r\n",
         "530 5.7.0 Authentication required\r\n",
     ],
@@ -367,7 +464,13 @@ run_test! {
         "250-testserver.com\r\n",
         "250-AUTH PLAIN LOGIN CRAM-MD5 ANONYMOUS\r\n",
         "250-STARTTLS\r\n",
+        "250-PIPELINING\r\n",
+        "250-SIZE 10000000\r\n",
         "250-8BITMIME\r\n",
+        "250-BINARYMIME\r\n",
+        "250-CHUNKING\r\n",
+        "250-DSN\r\n",
+        "250-MT-PRIORITY\r\n",
         "250 SMTPUTF8\r\n",
         "501 5.7.0 Client must not start with this mechanism\r\n"
     ],
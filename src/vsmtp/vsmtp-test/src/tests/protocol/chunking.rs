@@ -0,0 +1,343 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+*/
+use crate::config;
+use crate::run_test;
+use vsmtp_common::addr;
+use vsmtp_common::ContextFinished;
+use vsmtp_mail_parser::BodyType;
+use vsmtp_mail_parser::Mail;
+use vsmtp_mail_parser::MailHeaders;
+use vsmtp_mail_parser::MailMimeParser;
+use vsmtp_mail_parser::MessageBody;
+
+const EHLO_REPLY: [&str; 10] = [
+    "250-testserver.com\r\n",
+    "250-STARTTLS\r\n",
+    "250-PIPELINING\r\n",
+    "250-SIZE 10000000\r\n",
+    "250-8BITMIME\r\n",
+    "250-BINARYMIME\r\n",
+    "250-CHUNKING\r\n",
+    "250-DSN\r\n",
+    "250-MT-PRIORITY\r\n",
+    "250 SMTPUTF8\r\n",
+];
+
+fn assert_body(mut body: MessageBody) {
+    assert_eq!(
+        *body.parsed::<MailMimeParser>().unwrap(),
+        Mail {
+            headers: MailHeaders(
+                [
+                    ("from", "a b <a@b>"),
+                    ("date", "tue, 30 nov 2021 20:54:27 +0100"),
+                ]
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<Vec<_>>()
+            ),
+            body: BodyType::Regular(vec![".mail content wow".to_string()])
+        }
+    );
+}
+
+run_test! {
+    fn bdat_single_chunk,
+    input = [
+        "EHLO foobar\r\n",
+        "MAIL FROM:<a@b> BODY=BINARYMIME\r\n",
+        "RCPT TO:<b@c>\r\n",
+        concat!(
+            "BDAT 77 LAST\r\n",
+            "from: a b <a@b>\r\n",
+            "date: tue, 30 nov 2021 20:54:27 +0100\r\n",
+            "\r\n",
+            ".mail content wow\r\n",
+        ),
+        "QUIT\r\n",
+    ],
+    expected = [
+        &["220 testserver.com Service ready\r\n"][..],
+        &EHLO_REPLY[..],
+        &[
+            "250 Ok\r\n",
+            "250 Ok\r\n",
+            "250 Ok\r\n",
+            "221 Service closing transmission channel\r\n",
+        ][..],
+    ].concat(),
+    mail_handler = |ctx: ContextFinished, body: MessageBody| {
+        assert_eq!(ctx.mail_from.reverse_path, Some(addr!("a@b")));
+        assert_body(body);
+    },
+}
+
+run_test! {
+    fn bdat_multiple_chunks,
+    input = [
+        "EHLO foobar\r\n",
+        "MAIL FROM:<a@b>\r\n",
+        "RCPT TO:<b@c>\r\n",
+        "BDAT 17\r\nfrom: a b <a@b>\r\n",
+        "BDAT 10\r\ndate: tue,",
+        "BDAT 31\r\n 30 nov 2021 20:54:27 +0100\r\n\r\n",
+        "BDAT 19 LAST\r\n.mail content wow\r\n",
+        "QUIT\r\n",
+    ],
+    expected = [
+        &["220 testserver.com Service ready\r\n"][..],
+        &EHLO_REPLY[..],
+        &[
+            "250 Ok\r\n",
+            "250 Ok\r\n",
+            "250 17 octets received\r\n",
+            "250 10 octets received\r\n",
+            "250 31 octets received\r\n",
+            "250 Ok\r\n",
+            "221 Service closing transmission channel\r\n",
+        ][..],
+    ].concat(),
+    mail_handler = |ctx: ContextFinished, body: MessageBody| {
+        assert_eq!(ctx.mail_from.reverse_path, Some(addr!("a@b")));
+        assert_body(body);
+    },
+}
+
+run_test! {
+    fn bdat_pipelined,
+    input = [
+        "EHLO foobar\r\n",
+        concat!(
+            "MAIL FROM:<a@b>\r\n",
+            "RCPT TO:<b@c>\r\n",
+            "BDAT 58\r\n",
+            "from: a b <a@b>\r\n",
+            "date: tue, 30 nov 2021 20:54:27 +0100\r\n",
+            "\r\n",
+            "BDAT 19 LAST\r\n",
+            ".mail content wow\r\n",
+        ),
+        "",
+        "",
+        "",
+        "QUIT\r\n",
+    ],
+    expected = [
+        &["220 testserver.com Service ready\r\n"][..],
+        &EHLO_REPLY[..],
+        &[
+            "250 Ok\r\n",
+            "250 Ok\r\n",
+            "250 58 octets received\r\n",
+            "250 Ok\r\n",
+            "221 Service closing transmission channel\r\n",
+        ][..],
+    ].concat(),
+    mail_handler = |ctx: ContextFinished, body: MessageBody| {
+        assert_eq!(ctx.mail_from.reverse_path, Some(addr!("a@b")));
+        assert_body(body);
+    },
+}
+
+run_test! {
+    fn bdat_bad_sequence,
+    input = [
+        "EHLO foobar\r\n",
+        "BDAT 12\r\nRSET\r\nQUIT\r\n",
+        "MAIL FROM:<a@b>\r\n",
+        "BDAT 5 LAST\r\nabcde",
+        "QUIT\r\n",
+    ],
+    expected = [
+        &["220 testserver.com Service ready\r\n"][..],
+        &EHLO_REPLY[..],
+        &[
+            "503 Bad sequence of commands\r\n",
+            "250 Ok\r\n",
+            "503 Bad sequence of commands\r\n",
+            "221 Service closing transmission channel\r\n",
+        ][..],
+    ].concat(),
+}
+
+run_test! {
+    fn bdat_invalid_args,
+    input = [
+        "EHLO foobar\r\n",
+        "MAIL FROM:<a@b>\r\n",
+        "RCPT TO:<b@c>\r\n",
+        "BDAT five\r\nabcde",
+        "QUIT\r\n",
+    ],
+    expected = [
+        &["220 testserver.com Service ready\r\n"][..],
+        &EHLO_REPLY[..],
+        &[
+            "250 Ok\r\n",
+            "250 Ok\r\n",
+            "501 Syntax error in parameters or arguments\r\n",
+        ][..],
+    ].concat(),
+}
+
+run_test! {
+    fn bdat_invalid_keyword,
+    input = [
+        "EHLO foobar\r\n",
+        "MAIL FROM:<a@b>\r\n",
+        "RCPT TO:<b@c>\r\n",
+        "BDAT 5 TRUNCATED\r\nabcde",
+        "QUIT\r\n",
+    ],
+    expected = [
+        &["220 testserver.com Service ready\r\n"][..],
+        &EHLO_REPLY[..],
+        &[
+            "250 Ok\r\n",
+            "250 Ok\r\n",
+            "501 Syntax error in parameters or arguments\r\n",
+        ][..],
+    ].concat(),
+}
+
+run_test! {
+    fn data_after_bdat,
+    input = [
+        "EHLO foobar\r\n",
+        "MAIL FROM:<a@b>\r\n",
+        "RCPT TO:<b@c>\r\n",
+        "BDAT 5\r\nabcde",
+        "DATA\r\n",
+        "RSET\r\n",
+        "QUIT\r\n",
+    ],
+    expected = [
+        &["220 testserver.com Service ready\r\n"][..],
+        &EHLO_REPLY[..],
+        &[
+            "250 Ok\r\n",
+            "250 Ok\r\n",
+            "250 5 octets received\r\n",
+            "503 Bad sequence of commands\r\n",
+            "250 Ok\r\n",
+            "221 Service closing transmission channel\r\n",
+        ][..],
+    ].concat(),
+}
+
+run_test! {
+    fn data_with_binarymime,
+    input = [
+        "EHLO foobar\r\n",
+        "MAIL FROM:<a@b> BODY=BINARYMIME\r\n",
+        "RCPT TO:<b@c>\r\n",
+        "DATA\r\n",
+        "QUIT\r\n",
+    ],
+    expected = [
+        &["220 testserver.com Service ready\r\n"][..],
+        &EHLO_REPLY[..],
+        &[
+            "250 Ok\r\n",
+            "250 Ok\r\n",
+            "503 Bad sequence of commands\r\n",
+            "221 Service closing transmission channel\r\n",
+        ][..],
+    ].concat(),
+}
+
+run_test! {
+    fn bdat_message_at_limit,
+    input = [
+        "EHLO foobar\r\n",
+        "MAIL FROM:<a@b> BODY=BINARYMIME\r\n",
+        "RCPT TO:<b@c>\r\n",
+        concat!(
+            "BDAT 56\r\n",
+            "from: a b <a@b>\r\n",
+            "date: tue, 30 nov 2021 20:54:27 +0100\r\n",
+        ),
+        concat!("BDAT 21 LAST\r\n", "\r\n", ".mail content wow\r\n"),
+        "QUIT\r\n",
+    ],
+    expected = [
+        &["220 testserver.com Service ready\r\n"][..],
+        &[
+            "250-testserver.com\r\n",
+            "250-STARTTLS\r\n",
+            "250-PIPELINING\r\n",
+            "250-SIZE 77\r\n",
+            "250-8BITMIME\r\n",
+            "250-BINARYMIME\r\n",
+            "250-CHUNKING\r\n",
+            "250-DSN\r\n",
+            "250-MT-PRIORITY\r\n",
+            "250 SMTPUTF8\r\n",
+        ][..],
+        &[
+            "250 Ok\r\n",
+            "250 Ok\r\n",
+            "250 56 octets received\r\n",
+            "250 Ok\r\n",
+            "221 Service closing transmission channel\r\n",
+        ][..],
+    ].concat(),
+    config = {
+        let mut config = config::local_test();
+        config.server.message_size_limit = 77;
+        config
+    },
+}
+
+run_test! {
+    fn bdat_message_too_big,
+    input = [
+        "EHLO foobar\r\n",
+        "MAIL FROM:<a@b>\r\n",
+        "RCPT TO:<b@c>\r\n",
+        &format!("BDAT 600\r\n{}", "X".repeat(600)),
+        &format!("BDAT 600 LAST\r\n{}", "X".repeat(600)),
+        "QUIT\r\n",
+    ],
+    expected = [
+        &["220 testserver.com Service ready\r\n"][..],
+        &[
+            "250-testserver.com\r\n",
+            "250-STARTTLS\r\n",
+            "250-PIPELINING\r\n",
+            "250-SIZE 1000\r\n",
+            "250-8BITMIME\r\n",
+            "250-BINARYMIME\r\n",
+            "250-CHUNKING\r\n",
+            "250-DSN\r\n",
+            "250-MT-PRIORITY\r\n",
+            "250 SMTPUTF8\r\n",
+        ][..],
+        &[
+            "250 Ok\r\n",
+            "250 Ok\r\n",
+            "250 600 octets received\r\n",
+            "552 4.3.1 Message size exceeds fixed maximum message size\r\n",
+            "221 Service closing transmission channel\r\n",
+        ][..],
+    ].concat(),
+    config = {
+        let mut config = config::local_test();
+        config.server.message_size_limit = 1000;
+        config
+    },
+}
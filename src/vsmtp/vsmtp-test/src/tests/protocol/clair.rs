@@ -184,7 +184,13 @@ run_test! {
         "220 testserver.com Service ready\r\n",
         "250-testserver.com\r\n",
         "250-STARTTLS\r\n",
+        "250-PIPELINING\r\n",
+        "250-SIZE 10000000\r\n",
         "250-8BITMIME\r\n",
+        "250-BINARYMIME\r\n",
+        "250-CHUNKING\r\n",
+        "250-DSN\r\n",
+        "250-MT-PRIORITY\r\n",
         "250 SMTPUTF8\r\n",
         "250 Ok\r\n",
         "250 Ok\r\n",
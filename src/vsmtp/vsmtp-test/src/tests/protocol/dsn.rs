@@ -0,0 +1,109 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+*/
+use crate::run_test;
+use vsmtp_common::addr;
+use vsmtp_common::dsn;
+use vsmtp_common::ContextFinished;
+use vsmtp_mail_parser::MessageBody;
+
+run_test! {
+    fn dsn_parameters,
+    input = [
+        "HELO foobar\r\n",
+        "MAIL FROM:<a@b> RET=HDRS ENVID=QQ314159+2Bfoo\r\n",
+        "RCPT TO:<b@c> NOTIFY=SUCCESS,DELAY ORCPT=rfc822;b+2Bold@c\r\n",
+        "RCPT TO:<c@d> NOTIFY=NEVER\r\n",
+        "RCPT TO:<d@e>\r\n",
+        "DATA\r\n",
+        ".\r\n",
+        "QUIT\r\n",
+    ],
+    expected = [
+        "220 testserver.com Service ready\r\n",
+        "250 Ok\r\n",
+        "250 Ok\r\n",
+        "250 Ok\r\n",
+        "250 Ok\r\n",
+        "250 Ok\r\n",
+        "354 Start mail input; end with <CRLF>.<CRLF>\r\n",
+        "250 Ok\r\n",
+        "221 Service closing transmission channel\r\n",
+    ],
+    mail_handler = |ctx: ContextFinished, _: MessageBody| {
+        assert_eq!(
+            ctx.mail_from.dsn,
+            dsn::MailFromParameters {
+                ret: Some(dsn::Ret::Headers),
+                envelope_id: Some("QQ314159+foo".to_string()),
+            }
+        );
+        assert_eq!(
+            ctx.rcpt_to.dsn[&addr!("b@c")],
+            dsn::RcptToParameters {
+                notify_on: Some(dsn::NotifyOn::Some {
+                    success: true,
+                    failure: false,
+                    delay: true,
+                }),
+                original_forward_path: Some(dsn::OriginalRecipient {
+                    addr_type: "rfc822".to_string(),
+                    mailbox: "b+old@c".to_string(),
+                }),
+                last_notification: None,
+            }
+        );
+        assert_eq!(ctx.rcpt_to.dsn[&addr!("c@d")].notify_on, Some(dsn::NotifyOn::Never));
+        assert_eq!(ctx.rcpt_to.dsn[&addr!("d@e")], dsn::RcptToParameters::default());
+    },
+}
+
+run_test! {
+    fn dsn_parameters_invalid,
+    input = [
+        "HELO foobar\r\n",
+        "MAIL FROM:<a@b> RET=ALL\r\n",
+        "MAIL FROM:<a@b> RET=FULL RET=HDRS\r\n",
+        "MAIL FROM:<a@b> ENVID=\r\n",
+        "MAIL FROM:<a@b> ENVID=foo=bar\r\n",
+        "MAIL FROM:<a@b> ENVID=x+0D+0ABcc:+20victim@example.com\r\n",
+        "MAIL FROM:<a@b> RET=FULL\r\n",
+        "RCPT TO:<b@c> NOTIFY=NEVER,SUCCESS\r\n",
+        "RCPT TO:<b@c> NOTIFY=\r\n",
+        "RCPT TO:<b@c> ORCPT=b@c\r\n",
+        "RCPT TO:<b@c> ORCPT=rfc822;\r\n",
+        "RCPT TO:<b@c> ORCPT=rfc822;b@c+0D+0ABcc:+20victim@example.com\r\n",
+        "RCPT TO:<b@c> FOO=BAR\r\n",
+        "QUIT\r\n",
+    ],
+    expected = [
+        "220 testserver.com Service ready\r\n",
+        "250 Ok\r\n",
+        "501 Syntax error in parameters or arguments\r\n",
+        "501 Syntax error in parameters or arguments\r\n",
+        "501 Syntax error in parameters or arguments\r\n",
+        "501 Syntax error in parameters or arguments\r\n",
+        "501 Syntax error in parameters or arguments\r\n",
+        "250 Ok\r\n",
+        "501 Syntax error in parameters or arguments\r\n",
+        "501 Syntax error in parameters or arguments\r\n",
+        "501 Syntax error in parameters or arguments\r\n",
+        "501 Syntax error in parameters or arguments\r\n",
+        "501 Syntax error in parameters or arguments\r\n",
+        "501 Syntax error in parameters or arguments\r\n",
+        "221 Service closing transmission channel\r\n",
+    ],
+}
@@ -26,17 +26,25 @@ use vsmtp_mail_parser::MessageBody;
 // TODO: add errors tests
 
 #[rstest::rstest]
-#[case("<foo@bar>", Some("foo@bar"))]
-#[case::null("<>", None)]
-#[case::null_with_body("<> BODY=8BITMIME", None)]
-#[case::whitespace_before("       <foo@bar>", Some("foo@bar"))]
-#[case::whitespace_after("<foo@bar>           ", Some("foo@bar"))]
-#[case::bit7("<foo@bar> BODY=7BIT", Some("foo@bar"))]
-#[case::bitmime8("<foo@bar> BODY=8BITMIME", Some("foo@bar"))]
-#[case::bit7_whitespace("<foo@bar>      BODY=7BIT", Some("foo@bar"))]
-#[case::bitmime8_whitespace("      <foo@bar>      BODY=8BITMIME   ", Some("foo@bar"))]
+#[case("<foo@bar>", Some("foo@bar"), None)]
+#[case::null("<>", None, None)]
+#[case::null_with_body("<> BODY=8BITMIME", None, None)]
+#[case::whitespace_before("       <foo@bar>", Some("foo@bar"), None)]
+#[case::whitespace_after("<foo@bar>           ", Some("foo@bar"), None)]
+#[case::bit7("<foo@bar> BODY=7BIT", Some("foo@bar"), None)]
+#[case::bitmime8("<foo@bar> BODY=8BITMIME", Some("foo@bar"), None)]
+#[case::bit7_whitespace("<foo@bar>      BODY=7BIT", Some("foo@bar"), None)]
+#[case::bitmime8_whitespace("      <foo@bar>      BODY=8BITMIME   ", Some("foo@bar"), None)]
+#[case::size("<foo@bar> SIZE=1000", Some("foo@bar"), Some(1000))]
+#[case::null_with_size("<> SIZE=0", None, Some(0))]
+#[case::size_and_body("<foo@bar> SIZE=1000 BODY=8BITMIME", Some("foo@bar"), Some(1000))]
+#[case::body_and_size("<foo@bar> BODY=7BIT SIZE=10000000", Some("foo@bar"), Some(10_000_000))]
 #[trace]
-fn test(#[case] mail_from: &str, #[case] reverse_path: Option<&str>) {
+fn test(
+    #[case] mail_from: &str,
+    #[case] reverse_path: Option<&str>,
+    #[case] message_size: Option<usize>,
+) {
     let runtime = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
@@ -57,7 +65,13 @@ fn test(#[case] mail_from: &str, #[case] reverse_path: Option<&str>) {
                 "220 testserver.com Service ready\r\n",
                 "250-testserver.com\r\n",
                 "250-STARTTLS\r\n",
+                "250-PIPELINING\r\n",
+                "250-SIZE 10000000\r\n",
                 "250-8BITMIME\r\n",
+                "250-BINARYMIME\r\n",
+                "250-CHUNKING\r\n",
+                "250-DSN\r\n",
+                "250-MT-PRIORITY\r\n",
                 "250 SMTPUTF8\r\n",
                 "250 Ok\r\n",
                 "250 Ok\r\n",
@@ -68,20 +82,69 @@ fn test(#[case] mail_from: &str, #[case] reverse_path: Option<&str>) {
                 #[derive(Clone)]
                 struct T {
                     reverse_path: Option<Address>,
+                    message_size: Option<usize>,
                 }
 
                 impl crate::recv_handler_wrapper::OnMessageCompletedHook for T {
                     fn on_message_completed(self, ctx: ContextFinished, _: MessageBody) {
                         assert_eq!(ctx.helo.client_name, ClientName::Domain("foobar".parse().unwrap()));
                         assert_eq!(ctx.mail_from.reverse_path, self.reverse_path);
+                        assert_eq!(ctx.mail_from.message_size, self.message_size);
 
                     }
                 }
 
                 T {
                     reverse_path,
+                    message_size,
                 }
             }
         }
     });
 }
+
+run_test! {
+    fn mt_priority_ignored_unauthenticated,
+    input = [
+        "HELO foobar\r\n",
+        "MAIL FROM:<foo@bar> MT-PRIORITY=5\r\n",
+        "RCPT TO:<bar@foo>\r\n",
+        "DATA\r\n",
+        ".\r\n",
+        "QUIT\r\n",
+    ],
+    expected = [
+        "220 testserver.com Service ready\r\n",
+        "250 Ok\r\n",
+        "250 Ok\r\n",
+        "250 Ok\r\n",
+        "354 Start mail input; end with <CRLF>.<CRLF>\r\n",
+        "250 Ok\r\n",
+        "221 Service closing transmission channel\r\n",
+    ],
+    mail_handler = |ctx: ContextFinished, _: MessageBody| {
+        assert_eq!(ctx.mail_from.reverse_path, Some(addr!("foo@bar")));
+        assert_eq!(ctx.mail_from.priority, vsmtp_common::Priority::DEFAULT);
+    },
+}
+
+run_test! {
+    fn mt_priority_invalid,
+    input = [
+        "HELO foobar\r\n",
+        "MAIL FROM:<foo@bar> MT-PRIORITY=10\r\n",
+        "MAIL FROM:<foo@bar> MT-PRIORITY=high\r\n",
+        "MAIL FROM:<foo@bar> MT-PRIORITY=1 MT-PRIORITY=2\r\n",
+        "MAIL FROM:<foo@bar> MT-PRIORITY=-9\r\n",
+        "QUIT\r\n",
+    ],
+    expected = [
+        "220 testserver.com Service ready\r\n",
+        "250 Ok\r\n",
+        "501 Syntax error in parameters or arguments\r\n",
+        "501 Syntax error in parameters or arguments\r\n",
+        "501 Syntax error in parameters or arguments\r\n",
+        "250 Ok\r\n",
+        "221 Service closing transmission channel\r\n",
+    ],
+}
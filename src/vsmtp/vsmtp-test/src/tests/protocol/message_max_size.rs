@@ -43,3 +43,89 @@ run_test! {
         config
     },
 }
+
+run_test! {
+    fn test_declared_message_size_ko,
+    input = [
+        "EHLO foobar\r\n",
+        "MAIL FROM:<john@doe> SIZE=1000001\r\n",
+        "RCPT TO:<aa@bb>\r\n",
+        "MAIL FROM:<john@doe> SIZE=1000000\r\n",
+        "QUIT\r\n",
+    ],
+    expected = [
+        "220 testserver.com Service ready\r\n",
+        "250-testserver.com\r\n",
+        "250-STARTTLS\r\n",
+        "250-PIPELINING\r\n",
+        "250-SIZE 1000000\r\n",
+        "250-8BITMIME\r\n",
+        "250-BINARYMIME\r\n",
+        "250-CHUNKING\r\n",
+        "250-DSN\r\n",
+        "250-MT-PRIORITY\r\n",
+        "250 SMTPUTF8\r\n",
+        "552 5.3.4 Message size exceeds fixed maximum message size\r\n",
+        "503 Bad sequence of commands\r\n",
+        "250 Ok\r\n",
+        "221 Service closing transmission channel\r\n",
+    ],
+    config = {
+        let mut config = config::local_test();
+        config.server.message_size_limit = 1_000_000;
+        config
+    },
+}
+
+run_test! {
+    fn test_declared_message_size_invalid,
+    input = [
+        "HELO foobar\r\n",
+        "MAIL FROM:<john@doe> SIZE=\r\n",
+        "MAIL FROM:<john@doe> SIZE=-1\r\n",
+        "MAIL FROM:<john@doe> SIZE=1000 SIZE=1000\r\n",
+        "MAIL FROM:<john@doe> SIZE=99999999999999999999999\r\n",
+        "QUIT\r\n",
+    ],
+    expected = [
+        "220 testserver.com Service ready\r\n",
+        "250 Ok\r\n",
+        "501 Syntax error in parameters or arguments\r\n",
+        "501 Syntax error in parameters or arguments\r\n",
+        "501 Syntax error in parameters or arguments\r\n",
+        "501 Syntax error in parameters or arguments\r\n",
+        "221 Service closing transmission channel\r\n",
+    ],
+}
+
+run_test! {
+    fn test_message_size_at_limit,
+    input = [
+        "HELO foobar\r\n",
+        "MAIL FROM:<john@doe>\r\n",
+        "RCPT TO:<aa@bb>\r\n",
+        "DATA\r\n",
+        concat!(
+            "from: a b <a@b>\r\n",
+            "date: tue, 30 nov 2021 20:54:27 +0100\r\n",
+            "\r\n",
+            "..mail content wow\r\n",
+            ".\r\n",
+        ),
+        "QUIT\r\n",
+    ],
+    expected = [
+        "220 testserver.com Service ready\r\n",
+        "250 Ok\r\n",
+        "250 Ok\r\n",
+        "250 Ok\r\n",
+        "354 Start mail input; end with <CRLF>.<CRLF>\r\n",
+        "250 Ok\r\n",
+        "221 Service closing transmission channel\r\n",
+    ],
+    config = {
+        let mut config = config::local_test();
+        config.server.message_size_limit = 77;
+        config
+    },
+}
@@ -0,0 +1,237 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+*/
+use crate::run_test;
+use vsmtp_common::addr;
+use vsmtp_common::ContextFinished;
+use vsmtp_mail_parser::MessageBody;
+
+// NOTE: the client of `run_test!` sends the next input after each reply,
+// so empty inputs are used to wait for the replies of the whole group.
+
+run_test! {
+    fn pipelined_envelope,
+    input = [
+        "EHLO foobar\r\n",
+        concat!(
+            "MAIL FROM:<a@b>\r\n",
+            "RCPT TO:<b@c>\r\n",
+            "RCPT TO:<c@d>\r\n",
+            "DATA\r\n",
+        ),
+        "",
+        "",
+        "",
+        concat!(
+            "from: a b <a@b>\r\n",
+            "date: tue, 30 nov 2021 20:54:27 +0100\r\n",
+            "\r\n",
+            "mail content wow\r\n",
+            ".\r\n",
+        ),
+        "QUIT\r\n",
+    ],
+    expected = [
+        "220 testserver.com Service ready\r\n",
+        "250-testserver.com\r\n",
+        "250-STARTTLS\r\n",
+        "250-PIPELINING\r\n",
+        "250-SIZE 10000000\r\n",
+        "250-8BITMIME\r\n",
+        "250-BINARYMIME\r\n",
+        "250-CHUNKING\r\n",
+        "250-DSN\r\n",
+        "250-MT-PRIORITY\r\n",
+        "250 SMTPUTF8\r\n",
+        "250 Ok\r\n",
+        "250 Ok\r\n",
+        "250 Ok\r\n",
+        "354 Start mail input; end with <CRLF>.<CRLF>\r\n",
+        "250 Ok\r\n",
+        "221 Service closing transmission channel\r\n",
+    ],
+    mail_handler = |ctx: ContextFinished, _: MessageBody| {
+        assert_eq!(ctx.mail_from.reverse_path, Some(addr!("a@b")));
+        assert!(ctx.rcpt_to.delivery
+            .values()
+            .flatten()
+            .map(|(addr, _)| addr)
+            .cloned()
+            .eq([addr!("b@c"), addr!("c@d")])
+        );
+    },
+}
+
+run_test! {
+    fn pipelined_whole_transaction,
+    input = [
+        "EHLO foobar\r\n",
+        concat!(
+            "MAIL FROM:<a@b>\r\n",
+            "RCPT TO:<b@c>\r\n",
+            "DATA\r\n",
+            "from: a b <a@b>\r\n",
+            "date: tue, 30 nov 2021 20:54:27 +0100\r\n",
+            "\r\n",
+            "mail content wow\r\n",
+            ".\r\n",
+            "QUIT\r\n",
+        ),
+        "",
+        "",
+        "",
+        "",
+    ],
+    expected = [
+        "220 testserver.com Service ready\r\n",
+        "250-testserver.com\r\n",
+        "250-STARTTLS\r\n",
+        "250-PIPELINING\r\n",
+        "250-SIZE 10000000\r\n",
+        "250-8BITMIME\r\n",
+        "250-BINARYMIME\r\n",
+        "250-CHUNKING\r\n",
+        "250-DSN\r\n",
+        "250-MT-PRIORITY\r\n",
+        "250 SMTPUTF8\r\n",
+        "250 Ok\r\n",
+        "250 Ok\r\n",
+        "354 Start mail input; end with <CRLF>.<CRLF>\r\n",
+        "250 Ok\r\n",
+        "221 Service closing transmission channel\r\n",
+    ],
+    mail_handler = |ctx: ContextFinished, _: MessageBody| {
+        assert_eq!(ctx.mail_from.reverse_path, Some(addr!("a@b")));
+    },
+}
+
+run_test! {
+    fn pipelined_after_message,
+    input = [
+        "EHLO foobar\r\n",
+        concat!(
+            "MAIL FROM:<a@b>\r\n",
+            "RCPT TO:<b@c>\r\n",
+            "DATA\r\n",
+        ),
+        "",
+        "",
+        concat!(
+            "from: a b <a@b>\r\n",
+            "\r\n",
+            "first\r\n",
+            ".\r\n",
+            "RSET\r\n",
+            "MAIL FROM:<a@b>\r\n",
+            "RCPT TO:<c@d>\r\n",
+            "QUIT\r\n",
+        ),
+        "",
+        "",
+        "",
+        "",
+    ],
+    expected = [
+        "220 testserver.com Service ready\r\n",
+        "250-testserver.com\r\n",
+        "250-STARTTLS\r\n",
+        "250-PIPELINING\r\n",
+        "250-SIZE 10000000\r\n",
+        "250-8BITMIME\r\n",
+        "250-BINARYMIME\r\n",
+        "250-CHUNKING\r\n",
+        "250-DSN\r\n",
+        "250-MT-PRIORITY\r\n",
+        "250 SMTPUTF8\r\n",
+        "250 Ok\r\n",
+        "250 Ok\r\n",
+        "354 Start mail input; end with <CRLF>.<CRLF>\r\n",
+        "250 Ok\r\n",
+        "250 Ok\r\n",
+        "250 Ok\r\n",
+        "250 Ok\r\n",
+        "221 Service closing transmission channel\r\n",
+    ],
+}
+
+run_test! {
+    fn pipelined_mail_from_rejected,
+    input = [
+        "EHLO foobar\r\n",
+        concat!(
+            "MAIL FROM:<a@b> FOO=BAR\r\n",
+            "RCPT TO:<b@c>\r\n",
+            "DATA\r\n",
+        ),
+        "",
+        "",
+        "QUIT\r\n",
+    ],
+    expected = [
+        "220 testserver.com Service ready\r\n",
+        "250-testserver.com\r\n",
+        "250-STARTTLS\r\n",
+        "250-PIPELINING\r\n",
+        "250-SIZE 10000000\r\n",
+        "250-8BITMIME\r\n",
+        "250-BINARYMIME\r\n",
+        "250-CHUNKING\r\n",
+        "250-DSN\r\n",
+        "250-MT-PRIORITY\r\n",
+        "250 SMTPUTF8\r\n",
+        "501 Syntax error in parameters or arguments\r\n",
+        "503 Bad sequence of commands\r\n",
+        "503 Bad sequence of commands\r\n",
+        "221 Service closing transmission channel\r\n",
+    ],
+}
+
+run_test! {
+    fn pipelined_all_rcpt_rejected,
+    input = [
+        "EHLO foobar\r\n",
+        concat!(
+            "MAIL FROM:<a@b>\r\n",
+            "RCPT TO:b@c\r\n",
+            "RCPT TO:c@d\r\n",
+            "DATA\r\n",
+        ),
+        "",
+        "",
+        "",
+        "RSET\r\n",
+        "QUIT\r\n",
+    ],
+    expected = [
+        "220 testserver.com Service ready\r\n",
+        "250-testserver.com\r\n",
+        "250-STARTTLS\r\n",
+        "250-PIPELINING\r\n",
+        "250-SIZE 10000000\r\n",
+        "250-8BITMIME\r\n",
+        "250-BINARYMIME\r\n",
+        "250-CHUNKING\r\n",
+        "250-DSN\r\n",
+        "250-MT-PRIORITY\r\n",
+        "250 SMTPUTF8\r\n",
+        "250 Ok\r\n",
+        "501 Syntax error in parameters or arguments\r\n",
+        "501 Syntax error in parameters or arguments\r\n",
+        "503 Bad sequence of commands\r\n",
+        "250 Ok\r\n",
+        "221 Service closing transmission channel\r\n",
+    ],
+}
@@ -29,11 +29,23 @@ run_test! {
         "220 testserver.com Service ready\r\n",
         "250-testserver.com\r\n",
         "250-STARTTLS\r\n",
+        "250-PIPELINING\r\n",
+        "250-SIZE 10000000\r\n",
         "250-8BITMIME\r\n",
+        "250-BINARYMIME\r\n",
+        "250-CHUNKING\r\n",
+        "250-DSN\r\n",
+        "250-MT-PRIORITY\r\n",
         "250 SMTPUTF8\r\n",
         "220 TLS go ahead\r\n",
         "250-testserver.com\r\n",
+        "250-PIPELINING\r\n",
+        "250-SIZE 10000000\r\n",
         "250-8BITMIME\r\n",
+        "250-BINARYMIME\r\n",
+        "250-CHUNKING\r\n",
+        "250-DSN\r\n",
+        "250-MT-PRIORITY\r\n",
         "250 SMTPUTF8\r\n",
         "250 Ok\r\n",
         "250 Ok\r\n",
@@ -166,11 +178,23 @@ run_test! {
         "220 testserver.com Service ready\r\n",
         "250-testserver.com\r\n",
         "250-STARTTLS\r\n",
+        "250-PIPELINING\r\n",
+        "250-SIZE 10000000\r\n",
         "250-8BITMIME\r\n",
+        "250-BINARYMIME\r\n",
+        "250-CHUNKING\r\n",
+        "250-DSN\r\n",
+        "250-MT-PRIORITY\r\n",
         "250 SMTPUTF8\r\n",
         "220 TLS go ahead\r\n",
         "250-testserver.com\r\n",
+        "250-PIPELINING\r\n",
+        "250-SIZE 10000000\r\n",
         "250-8BITMIME\r\n",
+        "250-BINARYMIME\r\n",
+        "250-CHUNKING\r\n",
+        "250-DSN\r\n",
+        "250-MT-PRIORITY\r\n",
         "250 SMTPUTF8\r\n",
         "554 5.5.1 Error: TLS already active\r\n",
         "221 Service closing transmission channel\r\n",
@@ -222,7 +246,13 @@ run_test! {
         "220 testserver.com Service ready\r\n",
         "250-testserver.com\r\n",
         "250-STARTTLS\r\n",
+        "250-PIPELINING\r\n",
+        "250-SIZE 10000000\r\n",
         "250-8BITMIME\r\n",
+        "250-BINARYMIME\r\n",
+        "250-CHUNKING\r\n",
+        "250-DSN\r\n",
+        "250-MT-PRIORITY\r\n",
         "250 SMTPUTF8\r\n",
         "454 TLS not available due to temporary reason\r\n",
         "221 Service closing transmission channel\r\n",
@@ -239,7 +269,13 @@ run_test! {
         "220 testserver.com Service ready\r\n",
         "250-testserver.com\r\n",
         "250-STARTTLS\r\n",
+        "250-PIPELINING\r\n",
+        "250-SIZE 10000000\r\n",
         "250-8BITMIME\r\n",
+        "250-BINARYMIME\r\n",
+        "250-CHUNKING\r\n",
+        "250-DSN\r\n",
+        "250-MT-PRIORITY\r\n",
         "250 SMTPUTF8\r\n",
         "451 5.7.3 Must issue a STARTTLS command first\r\n",
     ],
@@ -137,7 +137,13 @@ fn expected(
                 "220 testserver.com Service ready\r\n",
                 "250-testserver.com\r\n",
                 "250-STARTTLS\r\n",
+                "250-PIPELINING\r\n",
+                "250-SIZE 10000000\r\n",
                 "250-8BITMIME\r\n",
+                "250-BINARYMIME\r\n",
+                "250-CHUNKING\r\n",
+                "250-DSN\r\n",
+                "250-MT-PRIORITY\r\n",
                 "250 SMTPUTF8\r\n",
                 "250 Ok\r\n",
                 "250 Ok\r\n",
@@ -178,7 +184,13 @@ fn expected(
                 "220 testserver.com Service ready\r\n",
                 "250-testserver.com\r\n",
                 "250-STARTTLS\r\n",
+                "250-PIPELINING\r\n",
+                "250-SIZE 10000000\r\n",
                 "250-8BITMIME\r\n",
+                "250-BINARYMIME\r\n",
+                "250-CHUNKING\r\n",
+                "250-DSN\r\n",
+                "250-MT-PRIORITY\r\n",
                 "250 SMTPUTF8\r\n",
                 "554 permanent problems with the remote server\r\n",
             ],
@@ -233,15 +245,14 @@ fn each(
     #[with(snippet, stage)] expected: (Vec<&'static str>, Option<String>),
 ) {
     let (expected, logs) = expected;
-    let filename = uuid::Uuid::new_v4();
-
-    let _x = std::fs::create_dir("./tmp");
+    let dir = tempfile::tempdir().unwrap();
+    let filename = dir.path().join("logs");
 
     let subscriber = tracing_subscriber::fmt()
         .with_max_level(tracing::Level::TRACE)
         .with_ansi(false)
         .with_writer(std::sync::Arc::new(
-            std::fs::File::create(format!("tmp/{filename}")).unwrap(),
+            std::fs::File::create(&filename).unwrap(),
         ))
         .finish();
     tracing::subscriber::set_global_default(subscriber).unwrap();
@@ -285,7 +296,7 @@ fn each(
         });
 
     if let Some(logs) = logs {
-        let content = std::fs::read_to_string(format!("tmp/{filename}")).unwrap();
+        let content = std::fs::read_to_string(&filename).unwrap();
         dbg!(&logs);
 
         let content = content.lines();
@@ -107,15 +107,14 @@ fn each(
     #[values(INNER_DOMAIN, OUTER_DOMAIN)] rcpt_to: &str,
     #[with(mail_from, rcpt_to)] logs: &[&'static str],
 ) {
-    let filename = uuid::Uuid::new_v4();
-
-    let _x = std::fs::create_dir("./tmp");
+    let dir = tempfile::tempdir().unwrap();
+    let filename = dir.path().join("logs");
 
     let subscriber = tracing_subscriber::fmt()
         .with_ansi(false)
         .with_max_level(tracing::Level::WARN)
         .with_writer(std::sync::Arc::new(
-            std::fs::File::create(format!("tmp/{filename}")).unwrap(),
+            std::fs::File::create(&filename).unwrap(),
         ))
         .finish();
     tracing::subscriber::set_global_default(subscriber).unwrap();
@@ -145,7 +144,13 @@ fn each(
                     "220 testserver.com Service ready\r\n",
                     "250-testserver.com\r\n",
                     "250-STARTTLS\r\n",
+                    "250-PIPELINING\r\n",
+                    "250-SIZE 10000000\r\n",
                     "250-8BITMIME\r\n",
+                    "250-BINARYMIME\r\n",
+                    "250-CHUNKING\r\n",
+                    "250-DSN\r\n",
+                    "250-MT-PRIORITY\r\n",
                     "250 SMTPUTF8\r\n",
                     "250 Ok\r\n",
                     "250 Ok\r\n",
@@ -157,7 +162,7 @@ fn each(
             }
         });
 
-    let content = std::fs::read_to_string(format!("tmp/{filename}")).unwrap();
+    let content = std::fs::read_to_string(&filename).unwrap();
     println!("{content}");
 
     for log in logs {
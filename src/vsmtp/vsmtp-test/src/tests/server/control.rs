@@ -0,0 +1,492 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+*/
+use crate::config;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+use tokio_stream::StreamExt;
+use vqueue::{
+    control::{Client, FlushQueue, ReRunStage, Release, ReleaseAction, Request, Response},
+    GenericQueueManager, QueueID,
+};
+use vsmtp_common::transport::{AbstractTransport, WrapperSerde};
+use vsmtp_config::DnsResolvers;
+use vsmtp_delivery::Deliver;
+use vsmtp_rule_engine::RuleEngine;
+use vsmtp_server::{control_bind_anyhow, scheduler, socket_bind_anyhow, Control, Reloader, Server};
+
+struct Harness {
+    control: std::sync::Arc<Control>,
+    client: Client,
+    queue_manager: std::sync::Arc<dyn GenericQueueManager>,
+    working: scheduler::Receiver,
+    delivery: scheduler::Receiver,
+    socket: std::path::PathBuf,
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        let _err = std::fs::remove_file(&self.socket);
+    }
+}
+
+async fn harness() -> Harness {
+    let config = std::sync::Arc::new(config::local_test());
+    let queue_manager = <vqueue::temp::QueueManager as vqueue::GenericQueueManager>::init(
+        config.clone(),
+        vec![<Deliver as AbstractTransport>::get_symbol()],
+    )
+    .unwrap();
+    let (emitter, working, delivery) = scheduler::init(
+        config.server.queues.working.channel_size,
+        config.server.queues.delivery.channel_size,
+    );
+    let resolvers = std::sync::Arc::new(DnsResolvers::from_config(&config).unwrap());
+    let rule_engine = std::sync::Arc::new(
+        RuleEngine::new(config.clone(), resolvers, queue_manager.clone()).unwrap(),
+    );
+    let reloader =
+        std::sync::Arc::new(Reloader::new(config, rule_engine, queue_manager.clone()).unwrap());
+    let control = std::sync::Arc::new(Control::new(reloader, emitter));
+
+    let socket = std::env::temp_dir().join(format!("vsmtp-{}.sock", uuid::Uuid::new_v4()));
+    let listener =
+        tokio::net::UnixListener::from_std(control_bind_anyhow(&socket).unwrap()).unwrap();
+    tokio::spawn(control.clone().serve(listener));
+
+    Harness {
+        control,
+        client: Client::connect(&socket).await.unwrap(),
+        queue_manager,
+        working,
+        delivery,
+        socket,
+    }
+}
+
+#[tokio::test]
+async fn sessions_and_counters() {
+    let mut harness = harness().await;
+
+    let Response::Counters(counters) = harness.client.send(&Request::Counters).await.unwrap()
+    else {
+        panic!("unexpected response");
+    };
+    assert_eq!(counters, vqueue::control::Counters::default());
+
+    let (emitter, _working, _delivery) = scheduler::init(1, 1);
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = config::local_test();
+    config.server.queues.dirpath = dir.path().to_path_buf();
+    let config = std::sync::Arc::new(config);
+    let resolvers = std::sync::Arc::new(DnsResolvers::from_config(&config).unwrap());
+    let server = Server::new(
+        config.clone(),
+        std::sync::Arc::new(
+            RuleEngine::new(config, resolvers, harness.queue_manager.clone()).unwrap(),
+        ),
+        harness.queue_manager.clone(),
+        emitter,
+    )
+    .unwrap()
+    .with_control(harness.control.clone());
+
+    let listener = socket_bind_anyhow("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(server.listen((vec![listener], vec![], vec![])));
+
+    let (read, mut write) = tokio::net::TcpStream::connect(addr)
+        .await
+        .unwrap()
+        .into_split();
+    let mut lines = tokio::io::BufReader::new(read).lines();
+    assert!(lines
+        .next_line()
+        .await
+        .unwrap()
+        .unwrap()
+        .starts_with("220 "));
+
+    let Response::Sessions(sessions) = harness.client.send(&Request::Sessions).await.unwrap()
+    else {
+        panic!("unexpected response");
+    };
+    assert_eq!(sessions.len(), 1);
+    assert_eq!(sessions[0].server_addr, addr);
+    assert_eq!(sessions[0].kind, "relay");
+
+    write.write_all(b"QUIT\r\n").await.unwrap();
+    assert!(lines
+        .next_line()
+        .await
+        .unwrap()
+        .unwrap()
+        .starts_with("221 "));
+    assert!(lines.next_line().await.unwrap().is_none());
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+    let Response::Counters(counters) = harness.client.send(&Request::Counters).await.unwrap()
+    else {
+        panic!("unexpected response");
+    };
+    assert_eq!(counters.connections, 1);
+    assert_eq!(counters.sessions, 0);
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn rerun() {
+    let mut harness = harness().await;
+
+    let mut ctx = config::local_ctx();
+    let msg = uuid::Uuid::new_v4();
+    ctx.mail_from.message_uuid = msg;
+    harness
+        .queue_manager
+        .write_ctx(&QueueID::Dead, &ctx)
+        .await
+        .unwrap();
+    harness
+        .queue_manager
+        .write_msg(&msg, &config::local_msg())
+        .await
+        .unwrap();
+
+    assert_eq!(
+        harness
+            .client
+            .send(&Request::ReRun {
+                msg,
+                stage: ReRunStage::Working,
+                queue: None,
+            })
+            .await
+            .unwrap(),
+        Response::Done
+    );
+
+    let working = harness.working.as_stream();
+    tokio::pin!(working);
+    let sent = working.next().await.unwrap();
+    assert_eq!(*sent.as_ref(), msg);
+    harness
+        .queue_manager
+        .get_ctx(&QueueID::Working, &msg)
+        .await
+        .unwrap();
+    harness
+        .queue_manager
+        .get_ctx(&QueueID::Dead, &msg)
+        .await
+        .unwrap_err();
+
+    assert!(matches!(
+        harness
+            .client
+            .send(&Request::ReRun {
+                msg: uuid::Uuid::new_v4(),
+                stage: ReRunStage::Delivery,
+                queue: None,
+            })
+            .await
+            .unwrap(),
+        Response::Error(_)
+    ));
+}
+
+/// Write a message with two recipients in the quarantine `spam`.
+async fn quarantined(harness: &Harness) -> (uuid::Uuid, QueueID) {
+    let spam = QueueID::Quarantine {
+        name: "spam".to_owned(),
+    };
+    let mut ctx = config::local_ctx();
+    let msg = uuid::Uuid::new_v4();
+    ctx.mail_from.message_uuid = msg;
+    ctx.rcpt_to.forward_paths = vec![
+        "a@testserver.com".parse().unwrap(),
+        "b@testserver.com".parse().unwrap(),
+    ];
+    let config = std::sync::Arc::new(config::local_test());
+    let resolvers = DnsResolvers::from_config(&config).unwrap();
+    ctx.rcpt_to.delivery.insert(
+        WrapperSerde::Ready(std::sync::Arc::new(Deliver::new(
+            resolvers.get_resolver_root(),
+            config,
+        ))),
+        ctx.rcpt_to
+            .forward_paths
+            .iter()
+            .map(|rcpt| (rcpt.clone(), vsmtp_common::transfer::Status::default()))
+            .collect(),
+    );
+    harness.queue_manager.write_ctx(&spam, &ctx).await.unwrap();
+    harness
+        .queue_manager
+        .write_msg(&msg, &config::local_msg())
+        .await
+        .unwrap();
+    (msg, spam)
+}
+
+#[tokio::test]
+async fn rerun_quarantined() {
+    let mut harness = harness().await;
+    let (msg, spam) = quarantined(&harness).await;
+
+    assert_eq!(
+        harness
+            .client
+            .send(&Request::ReRun {
+                msg,
+                stage: ReRunStage::Delivery,
+                queue: None,
+            })
+            .await
+            .unwrap(),
+        Response::Done
+    );
+
+    let delivery = harness.delivery.as_stream();
+    tokio::pin!(delivery);
+    assert_eq!(*delivery.next().await.unwrap().as_ref(), msg);
+    harness
+        .queue_manager
+        .get_ctx(&QueueID::Deliver, &msg)
+        .await
+        .unwrap();
+    harness
+        .queue_manager
+        .get_ctx(&spam, &msg)
+        .await
+        .unwrap_err();
+}
+
+#[tokio::test]
+async fn release_to_recipients() {
+    let mut harness = harness().await;
+    let (msg, spam) = quarantined(&harness).await;
+
+    assert_eq!(
+        harness
+            .client
+            .send(&Request::Release {
+                msg,
+                quarantine: Some("spam".to_owned()),
+                release: Release::new(
+                    "jdoe".to_owned(),
+                    "false positive".to_owned(),
+                    ReleaseAction::Resume {
+                        stage: ReRunStage::Working,
+                        rerun: true,
+                        recipients: vec!["b@testserver.com".parse().unwrap()],
+                    },
+                ),
+            })
+            .await
+            .unwrap(),
+        Response::Done
+    );
+
+    let working = harness.working.as_stream();
+    tokio::pin!(working);
+    let released = *working.next().await.unwrap().as_ref();
+    assert_ne!(released, msg);
+
+    let ctx = harness
+        .queue_manager
+        .get_ctx(&QueueID::Working, &released)
+        .await
+        .unwrap();
+    assert_eq!(ctx.connect.skipped, None);
+    assert_eq!(
+        ctx.rcpt_to.forward_paths,
+        vec!["b@testserver.com".parse().unwrap()]
+    );
+    assert_eq!(ctx.finished.released.len(), 1);
+    assert_eq!(ctx.finished.released[0].quarantine, "spam");
+    assert_eq!(ctx.finished.released[0].operator, "jdoe");
+    assert_eq!(ctx.finished.released[0].reason, "false positive");
+    assert_eq!(
+        ctx.finished.released[0].action,
+        "resume in working for b@testserver.com"
+    );
+    harness.queue_manager.get_msg(&released).await.unwrap();
+
+    let kept = harness.queue_manager.get_ctx(&spam, &msg).await.unwrap();
+    assert_eq!(
+        kept.rcpt_to.forward_paths,
+        vec!["a@testserver.com".parse().unwrap()]
+    );
+
+    assert!(matches!(
+        harness
+            .client
+            .send(&Request::Release {
+                msg,
+                quarantine: None,
+                release: Release::new(
+                    "jdoe".to_owned(),
+                    "typo".to_owned(),
+                    ReleaseAction::Resume {
+                        stage: ReRunStage::Delivery,
+                        rerun: false,
+                        recipients: vec!["c@testserver.com".parse().unwrap()],
+                    },
+                ),
+            })
+            .await
+            .unwrap(),
+        Response::Error(_)
+    ));
+}
+
+#[tokio::test]
+async fn release_reject_and_forward() {
+    let mut harness = harness().await;
+    let (msg, spam) = quarantined(&harness).await;
+
+    assert_eq!(
+        harness
+            .client
+            .send(&Request::Release {
+                msg,
+                quarantine: None,
+                release: Release::new(
+                    "jdoe".to_owned(),
+                    "to analyze".to_owned(),
+                    ReleaseAction::Forward {
+                        to: "analyst@testserver.com".parse().unwrap(),
+                    },
+                ),
+            })
+            .await
+            .unwrap(),
+        Response::Done
+    );
+
+    let delivery = harness.delivery.as_stream();
+    tokio::pin!(delivery);
+    let copy = *delivery.next().await.unwrap().as_ref();
+    let ctx = harness
+        .queue_manager
+        .get_ctx(&QueueID::Deliver, &copy)
+        .await
+        .unwrap();
+    assert_eq!(ctx.mail_from.reverse_path, None);
+    assert_eq!(
+        ctx.rcpt_to.forward_paths,
+        vec!["analyst@testserver.com".parse().unwrap()]
+    );
+    assert!(ctx.connect.skipped.is_some());
+    let kept = harness.queue_manager.get_ctx(&spam, &msg).await.unwrap();
+    assert_eq!(kept.finished.released.len(), 1);
+    assert_eq!(kept.rcpt_to.forward_paths.len(), 2);
+
+    assert_eq!(
+        harness
+            .client
+            .send(&Request::Release {
+                msg,
+                quarantine: Some("spam".to_owned()),
+                release: Release::new(
+                    "jdoe".to_owned(),
+                    "malware".to_owned(),
+                    ReleaseAction::Reject,
+                ),
+            })
+            .await
+            .unwrap(),
+        Response::Done
+    );
+
+    assert_eq!(*delivery.next().await.unwrap().as_ref(), msg);
+    let ctx = harness
+        .queue_manager
+        .get_ctx(&QueueID::Deliver, &msg)
+        .await
+        .unwrap();
+    assert!(ctx
+        .rcpt_to
+        .delivery
+        .values()
+        .flatten()
+        .all(|(_, status)| matches!(status, vsmtp_common::transfer::Status::Failed { .. })));
+    assert_eq!(ctx.finished.released.len(), 2);
+    assert_eq!(ctx.finished.released[1].action, "reject");
+    harness
+        .queue_manager
+        .get_ctx(&spam, &msg)
+        .await
+        .unwrap_err();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn flush_and_reload() {
+    let mut harness = harness().await;
+
+    assert_eq!(
+        harness
+            .client
+            .send(&Request::Flush {
+                queue: FlushQueue::Deferred,
+            })
+            .await
+            .unwrap(),
+        Response::Done
+    );
+
+    // the configuration of the tests is not a vSL file.
+    assert!(matches!(
+        harness.client.send(&Request::Reload).await.unwrap(),
+        Response::Error(_)
+    ));
+
+    let Response::Counters(counters) = harness.client.send(&Request::Counters).await.unwrap()
+    else {
+        panic!("unexpected response");
+    };
+    assert_eq!(counters.reloads, 0);
+}
+
+#[tokio::test]
+async fn socket_permissions() {
+    let harness = harness().await;
+
+    let mode = std::os::unix::fs::PermissionsExt::mode(
+        &std::fs::metadata(&harness.socket).unwrap().permissions(),
+    );
+    assert_eq!(mode & 0o777, 0o660);
+}
+
+#[tokio::test]
+async fn mta_sts() {
+    let mut harness = harness().await;
+
+    assert!(matches!(
+        harness.client.send(&Request::MtaSts).await.unwrap(),
+        Response::Error(_)
+    ));
+
+    harness
+        .control
+        .set_pool(std::sync::Arc::new(vsmtp_delivery::ConnectionPool::new(
+            &config::local_test().server.queues.delivery,
+        )));
+    assert_eq!(
+        harness.client.send(&Request::MtaSts).await.unwrap(),
+        Response::MtaSts(vec![])
+    );
+}
@@ -20,10 +20,16 @@ use vsmtp_config::DnsResolvers;
 use vsmtp_rule_engine::RuleEngine;
 use vsmtp_server::{socket_bind_anyhow, Server};
 
+mod control;
+mod reload;
+mod shutdown;
+
 macro_rules! listen_with {
     ($addr:expr, $addr_submission:expr, $addr_submissions:expr, $timeout:expr, $client_count_max:expr) => {{
+        let dir = tempfile::tempdir().unwrap();
         let config = std::sync::Arc::new({
             let mut config = config::local_test();
+            config.server.queues.dirpath = dir.path().to_path_buf();
             config.server.interfaces.addr = $addr;
             config.server.interfaces.addr_submission = $addr_submission;
             config.server.interfaces.addr_submissions = $addr_submissions;
@@ -0,0 +1,79 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+*/
+use crate::config;
+use vsmtp_config::DnsResolvers;
+use vsmtp_rule_engine::RuleEngine;
+use vsmtp_server::Reloader;
+
+fn reloader() -> Reloader {
+    let config = std::sync::Arc::new(config::local_test());
+    let queue_manager =
+        <vqueue::temp::QueueManager as vqueue::GenericQueueManager>::init(config.clone(), vec![])
+            .unwrap();
+    let resolvers = std::sync::Arc::new(DnsResolvers::from_config(&config).unwrap());
+    let rule_engine = std::sync::Arc::new(
+        RuleEngine::new(config.clone(), resolvers, queue_manager.clone()).unwrap(),
+    );
+
+    Reloader::new(config, rule_engine, queue_manager).unwrap()
+}
+
+#[test]
+fn reload() {
+    let reloader = reloader();
+    let (config, rule_engine, _) = reloader.snapshot();
+
+    let mut new_config = config::local_test();
+    new_config.server.name = "reloaded.com".parse().unwrap();
+    reloader.reload_with(new_config).unwrap();
+
+    let (reloaded_config, reloaded_rule_engine, _) = reloader.snapshot();
+    assert_eq!(reloaded_config.server.name.to_string(), "reloaded.com");
+    assert!(!std::sync::Arc::ptr_eq(&rule_engine, &reloaded_rule_engine));
+    assert_eq!(
+        reloaded_rule_engine.srv().config.server.name.to_string(),
+        "reloaded.com"
+    );
+
+    // a transaction started before the reload keeps its version.
+    assert_eq!(config.server.name.to_string(), "testserver.com");
+    assert_eq!(
+        rule_engine.srv().config.server.name.to_string(),
+        "testserver.com"
+    );
+}
+
+#[test]
+fn reload_failure() {
+    let reloader = reloader();
+    let (config, rule_engine, _) = reloader.snapshot();
+
+    let dir = tempfile::tempdir().unwrap();
+    let filter_path = dir.path().join("filter.vsl");
+    std::fs::write(&filter_path, "#{ this is not vsl").unwrap();
+
+    let mut new_config = config::local_test();
+    new_config.app.vsl.filter_path = Some(filter_path);
+    assert!(reloader.reload_with(new_config).is_err());
+
+    // the path of the configuration is not a vsl file.
+    assert!(reloader.reload().is_err());
+
+    let (kept_config, kept_rule_engine, _) = reloader.snapshot();
+    assert!(std::sync::Arc::ptr_eq(&config, &kept_config));
+    assert!(std::sync::Arc::ptr_eq(&rule_engine, &kept_rule_engine));
+}
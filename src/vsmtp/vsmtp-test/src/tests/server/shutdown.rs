@@ -0,0 +1,116 @@
+/*
+ * vSMTP mail transfer agent
+ * Copyright (C) 2022 viridIT SAS
+ *
+ * This program is free software: you can redistribute it and/or modify it under
+ * the terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or any later version.
+ *
+ * This program is distributed in the hope that it will be useful, but WITHOUT
+ * ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE.  See the GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * this program. If not, see https://www.gnu.org/licenses/.
+ *
+*/
+use crate::config;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+use vsmtp_config::DnsResolvers;
+use vsmtp_rule_engine::RuleEngine;
+use vsmtp_server::{shutdown, socket_bind_anyhow, Server};
+
+struct Client {
+    lines: tokio::io::Lines<tokio::io::BufReader<tokio::net::tcp::OwnedReadHalf>>,
+    write: tokio::net::tcp::OwnedWriteHalf,
+}
+
+impl Client {
+    async fn connect(addr: std::net::SocketAddr) -> Self {
+        let (read, write) = tokio::net::TcpStream::connect(addr)
+            .await
+            .unwrap()
+            .into_split();
+        let mut client = Self {
+            lines: tokio::io::BufReader::new(read).lines(),
+            write,
+        };
+        assert!(client.reply().await.starts_with("220 "));
+        client
+    }
+
+    /// Read the last line of the next reply.
+    async fn reply(&mut self) -> String {
+        loop {
+            let line = self.lines.next_line().await.unwrap().unwrap();
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return line;
+            }
+        }
+    }
+
+    async fn command(&mut self, command: &str) -> String {
+        self.write
+            .write_all(format!("{command}\r\n").as_bytes())
+            .await
+            .unwrap();
+        self.reply().await
+    }
+}
+
+#[test_log::test(tokio::test(flavor = "multi_thread", worker_threads = 2))]
+async fn shutdown() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = config::local_test();
+    config.server.queues.dirpath = dir.path().to_path_buf();
+    let config = std::sync::Arc::new(config);
+    let queue_manager =
+        <vqueue::temp::QueueManager as vqueue::GenericQueueManager>::init(config.clone(), vec![])
+            .unwrap();
+    let (emitter, _working, _delivery) = vsmtp_server::scheduler::init(
+        config.server.queues.working.channel_size,
+        config.server.queues.delivery.channel_size,
+    );
+    let resolvers = std::sync::Arc::new(DnsResolvers::from_config(&config).unwrap());
+    let rule_engine = std::sync::Arc::new(
+        RuleEngine::new(config.clone(), resolvers, queue_manager.clone()).unwrap(),
+    );
+
+    let (trigger, shutdown) = shutdown::channel();
+    let server = Server::new(config, rule_engine, queue_manager, emitter)
+        .unwrap()
+        .with_shutdown(shutdown);
+
+    let listener = socket_bind_anyhow("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(server.listen((vec![listener], vec![], vec![])));
+
+    let mut idle = Client::connect(addr).await;
+    assert!(idle.command("EHLO client.com").await.starts_with("250 "));
+
+    let mut busy = Client::connect(addr).await;
+    assert!(busy.command("EHLO client.com").await.starts_with("250 "));
+    assert!(busy
+        .command("MAIL FROM:<john@doe.com>")
+        .await
+        .starts_with("250 "));
+
+    trigger.shutdown(tokio::time::Instant::now() + std::time::Duration::from_secs(10));
+
+    // the client waiting between two transactions is disconnected.
+    assert!(idle.reply().await.starts_with("421 "));
+    assert!(idle.lines.next_line().await.unwrap().is_none());
+
+    // the transaction in progress is completed first.
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    assert!(!server.is_finished());
+    assert!(busy.command("RSET").await.starts_with("250 "));
+    assert!(busy.reply().await.starts_with("421 "));
+
+    tokio::time::timeout(std::time::Duration::from_secs(5), server)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert!(tokio::net::TcpStream::connect(addr).await.is_err());
+}
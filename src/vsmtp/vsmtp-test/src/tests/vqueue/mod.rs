@@ -24,11 +24,7 @@ async fn init_fail() {
             .unwrap_err();
 }
 
-#[tokio::test]
-async fn write_get_and_delete_ctx() {
-    let config = arc!(local_test());
-    let queue_manager = vqueue::temp::QueueManager::init(config, vec![]).unwrap();
-
+async fn write_get_and_delete_ctx(queue_manager: std::sync::Arc<impl GenericQueueManager>) {
     for i in [
         QueueID::Working,
         QueueID::Deliver,
@@ -66,7 +62,9 @@ async fn write_msg_after_dir_deleted() {
     let config = arc!(local_test());
     let queue_manager = vqueue::temp::QueueManager::init(config.clone(), vec![]).unwrap();
 
-    std::fs::remove_dir_all(&config.server.queues.dirpath).unwrap();
+    let working =
+        vqueue::FilesystemQueueManagerExt::get_queue_path(&*queue_manager, &QueueID::Working);
+    std::fs::remove_dir_all(working.parent().unwrap()).unwrap();
     let msg_uuid = uuid::Uuid::new_v4();
 
     let msg = local_msg();
@@ -76,24 +74,22 @@ async fn write_msg_after_dir_deleted() {
     queue_manager.remove_msg(&msg_uuid).await.unwrap();
 }
 
-#[tokio::test]
-async fn write_get_and_delete_msg() {
-    let config = arc!(local_test());
-    let queue_manager = vqueue::temp::QueueManager::init(config, vec![]).unwrap();
+async fn write_get_and_delete_msg(queue_manager: std::sync::Arc<impl GenericQueueManager>) {
     let msg_uuid = uuid::Uuid::new_v4();
 
     let msg = local_msg();
     queue_manager.write_msg(&msg_uuid, &msg).await.unwrap();
     let msg_read = queue_manager.get_msg(&msg_uuid).await.unwrap();
     pretty_assertions::assert_eq!(msg, msg_read);
+    assert_eq!(
+        queue_manager.get_msg_size(&msg_uuid).await.unwrap(),
+        msg.inner().to_string().len()
+    );
     queue_manager.remove_msg(&msg_uuid).await.unwrap();
+    queue_manager.get_msg_size(&msg_uuid).await.unwrap_err();
 }
 
-#[tokio::test]
-async fn write_get_and_delete_both() {
-    let config = arc!(local_test());
-    let queue_manager = vqueue::temp::QueueManager::init(config, vec![]).unwrap();
-
+async fn write_get_and_delete_both(queue_manager: std::sync::Arc<impl GenericQueueManager>) {
     for i in [
         QueueID::Working,
         QueueID::Deliver,
@@ -116,11 +112,7 @@ async fn write_get_and_delete_both() {
     }
 }
 
-#[tokio::test]
-async fn move_same_queue() {
-    let config = arc!(local_test());
-    let queue_manager = vqueue::temp::QueueManager::init(config.clone(), vec![]).unwrap();
-
+async fn move_same_queue(queue_manager: std::sync::Arc<impl GenericQueueManager>) {
     let ctx = local_ctx();
     queue_manager
         .move_to(&QueueID::Working, &QueueID::Working, &ctx)
@@ -128,11 +120,7 @@ async fn move_same_queue() {
         .unwrap_err();
 }
 
-#[tokio::test]
-async fn move_to() {
-    let config = arc!(local_test());
-    let queue_manager = vqueue::temp::QueueManager::init(config.clone(), vec![]).unwrap();
-
+async fn move_to(queue_manager: std::sync::Arc<impl GenericQueueManager>) {
     let mut ctx = local_ctx();
     let msg_uuid = uuid::Uuid::new_v4();
 
@@ -176,11 +164,7 @@ async fn move_to() {
         .unwrap();
 }
 
-#[tokio::test]
-async fn move_to_from_id() {
-    let config = arc!(local_test());
-    let queue_manager = vqueue::temp::QueueManager::init(config.clone(), vec![]).unwrap();
-
+async fn move_to_from_id(queue_manager: std::sync::Arc<impl GenericQueueManager>) {
     let mut ctx = local_ctx();
     let msg_uuid = uuid::Uuid::new_v4();
     ctx.mail_from.message_uuid = msg_uuid;
@@ -203,3 +187,166 @@ async fn move_to_from_id() {
         .await
         .unwrap();
 }
+
+fn temp() -> (
+    Option<tempfile::TempDir>,
+    std::sync::Arc<vqueue::temp::QueueManager>,
+) {
+    (
+        None,
+        vqueue::temp::QueueManager::init(arc!(local_test()), vec![]).unwrap(),
+    )
+}
+
+fn embedded() -> (
+    Option<tempfile::TempDir>,
+    std::sync::Arc<vqueue::embedded::QueueManager>,
+) {
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = local_test();
+    config.server.queues.dirpath = dir.path().to_path_buf();
+    (
+        Some(dir),
+        vqueue::embedded::QueueManager::init(arc!(config), vec![]).unwrap(),
+    )
+}
+
+/// Run the tests above with each queue manager implementation.
+macro_rules! test_suite {
+    ( $( $backend:ident ),* ; $tests:tt ) => {
+        $( test_suite!(@backend $backend $tests); )*
+    };
+    ( @backend $backend:ident [ $( $test:ident ),* ] ) => {
+        mod $backend {
+            $(
+                #[tokio::test(flavor = "multi_thread")]
+                async fn $test() {
+                    let (_dir, queue_manager) = super::$backend();
+                    super::$test(queue_manager).await;
+                }
+            )*
+        }
+    };
+}
+
+test_suite!(
+    temp, embedded;
+    [
+        write_get_and_delete_ctx,
+        write_get_and_delete_msg,
+        write_get_and_delete_both,
+        move_same_queue,
+        move_to,
+        move_to_from_id
+    ]
+);
+
+#[tokio::test]
+async fn embedded_shared_and_reopen() {
+    let (dir, queue_manager) = embedded();
+    let mut config = local_test();
+    config.server.queues.dirpath = queue_manager.get_config().server.queues.dirpath.clone();
+    let config = arc!(config);
+    let other =
+        <vqueue::embedded::QueueManager as GenericQueueManager>::init(config.clone(), vec![])
+            .unwrap();
+
+    let mut ctx = local_ctx();
+    let msg_uuid = uuid::Uuid::new_v4();
+    ctx.mail_from.message_uuid = msg_uuid;
+    queue_manager
+        .write_both(&QueueID::Deliver, &ctx, &local_msg())
+        .await
+        .unwrap();
+    pretty_assertions::assert_eq!(
+        ctx,
+        other.get_ctx(&QueueID::Deliver, &msg_uuid).await.unwrap()
+    );
+
+    other
+        .move_to(&QueueID::Deliver, &QueueID::Working, &ctx)
+        .await
+        .unwrap();
+    queue_manager
+        .move_to(&QueueID::Working, &QueueID::Deliver, &ctx)
+        .await
+        .unwrap();
+    drop(queue_manager);
+    drop(other);
+
+    let queue_manager = vqueue::embedded::QueueManager::init(config, vec![]).unwrap();
+    let (ctx_read, msg_read) = queue_manager
+        .get_both(&QueueID::Deliver, &msg_uuid)
+        .await
+        .unwrap();
+    pretty_assertions::assert_eq!(ctx, ctx_read);
+    pretty_assertions::assert_eq!(local_msg(), msg_read);
+    drop(dir);
+}
+
+#[tokio::test]
+async fn embedded_indexes() {
+    let (_dir, queue_manager) = embedded();
+
+    let mut ctx = local_ctx();
+    let msg_uuid = uuid::Uuid::new_v4();
+    ctx.mail_from.message_uuid = msg_uuid;
+    ctx.rcpt_to.forward_paths = vec!["john@Example.com".parse().unwrap()];
+
+    queue_manager
+        .write_ctx(&QueueID::Deliver, &ctx)
+        .await
+        .unwrap();
+    assert_eq!(
+        queue_manager.by_domain("example.com").await.unwrap(),
+        vec![(QueueID::Deliver, msg_uuid)]
+    );
+    assert!(queue_manager
+        .due(time::OffsetDateTime::now_utc())
+        .await
+        .unwrap()
+        .is_empty());
+
+    queue_manager
+        .move_to(&QueueID::Deliver, &QueueID::Deferred, &ctx)
+        .await
+        .unwrap();
+    assert_eq!(
+        queue_manager.by_domain("EXAMPLE.COM").await.unwrap(),
+        vec![(QueueID::Deferred, msg_uuid)]
+    );
+    // no recipient to deliver, the message is due at the end of its lifetime.
+    assert!(queue_manager
+        .due(time::OffsetDateTime::now_utc())
+        .await
+        .unwrap()
+        .is_empty());
+    let expire_at = ctx.mail_from.mail_timestamp
+        + queue_manager
+            .get_config()
+            .server
+            .queues
+            .delivery
+            .retry
+            .bounce_after;
+    assert_eq!(queue_manager.due(expire_at).await.unwrap(), vec![msg_uuid]);
+
+    queue_manager
+        .remove_ctx(&QueueID::Deferred, &msg_uuid)
+        .await
+        .unwrap();
+    assert!(queue_manager
+        .by_domain("example.com")
+        .await
+        .unwrap()
+        .is_empty());
+    assert!(queue_manager
+        .due(time::OffsetDateTime::now_utc())
+        .await
+        .unwrap()
+        .is_empty());
+    queue_manager
+        .remove_ctx(&QueueID::Deferred, &msg_uuid)
+        .await
+        .unwrap_err();
+}